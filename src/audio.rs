@@ -7,10 +7,15 @@ use bevy_procedural_audio::prelude::*;
 
 use uuid::Uuid;
 
-use crate::bevy_midi::input::MidiData;
+use crate::bevy_midi::mpe::{MpeSettings, MpeTranslator};
+use crate::bevy_midi::timing::{LatencyStats, MidiTimestamps};
+use crate::bevy_midi::ump::Midi2Message;
+use crate::panic::MidiPanic;
+use crate::routing::{RouteTarget, RoutedMidi};
 use crate::synth::{Filter, SynthEngine, Waveform};
 
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 // fn main() {
 //     App::new()
@@ -37,8 +42,10 @@ impl<F: Send + Sync + 'static + Fn() -> Box<dyn AudioUnit>> DspGraph for PianoDs
 #[derive(Debug, Resource)]
 struct PianoId(Uuid);
 
+/// [`Resource`](bevy::ecs::system::Resource) sharing the [`SynthEngine`] with its audio
+/// graph.
 #[derive(Resource)]
-struct SharedSynthEngine(Arc<Mutex<SynthEngine>>);
+pub struct SharedSynthEngine(pub Arc<Mutex<SynthEngine>>);
 
 impl Plugin for PianoPlugin {
     fn build(&self, app: &mut App) {
@@ -55,23 +62,49 @@ impl Plugin for PianoPlugin {
         app.add_dsp_source(piano_dsp, SourceType::Dynamic)
             .insert_resource(SharedSynthEngine(synth_mutex))
             .insert_resource(PianoId(piano_id))
+            .init_resource::<SynthLatency>()
             .add_systems(Update, switch_key)
             .add_systems(PostStartup, play_piano);
     }
 }
 
-fn switch_key(mut midi_events: EventReader<MidiData>, synth: Res<SharedSynthEngine>) {
+/// [`Resource`](bevy::ecs::system::Resource) measuring how long after a key is pressed the
+/// synth plays it.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct SynthLatency(pub LatencyStats);
+
+fn switch_key(
+    mut midi_events: EventReader<RoutedMidi>,
+    synth: Res<SharedSynthEngine>,
+    mpe: Res<MpeSettings>,
+    timestamps: Res<MidiTimestamps>,
+    mut latency: ResMut<SynthLatency>,
+    mut translator: Local<MpeTranslator>,
+    mut panic: EventReader<MidiPanic>,
+) {
+    // The panic released every note, so forget the ones held on member channels
+    if panic.read().count() > 0 {
+        *translator = MpeTranslator::default();
+    }
     for data in midi_events.read() {
-        if data.message.is_note_on() {
-            let note = data.message.msg[1];
-            let velocity = data.message.msg[2] as f32 / 127.0;
-            let mut synth = synth.0.lock().unwrap();
-            synth.note_on(note, velocity);
+        if data.target != RouteTarget::Synth {
+            continue;
+        }
+        let messages = translator.translate(&mpe, data.message);
+        if messages.is_empty() {
+            continue;
         }
-        if data.message.is_note_off() {
-            let note = data.message.msg[1];
-            let mut synth = synth.0.lock().unwrap();
-            synth.note_off(note);
+        let mut synth = synth.0.lock().unwrap();
+        for message in &messages {
+            match timestamps.instant(data.stamp) {
+                Some(at) => {
+                    let delay = synth.handle_at(message, at);
+                    if matches!(message, Midi2Message::NoteOn { .. }) {
+                        latency.0.record(Duration::from_secs_f64(delay.max(0.0)));
+                    }
+                }
+                None => synth.handle(message),
+            }
         }
     }
 }
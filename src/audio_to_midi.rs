@@ -0,0 +1,369 @@
+use crate::bevy_mic::microphone::{MicrophoneSettings, Resampler};
+use crate::bevy_midi::prelude::*;
+use crate::recorder::{RecordedEvent, Take};
+use crate::transcription::{TranscribedEvent, Transcriber, TranscriptionSettings};
+use anyhow::{Context, bail};
+use midly::Format;
+use std::path::{Path, PathBuf};
+
+/// The first argument that runs the converter instead of the game.
+pub const COMMAND: &str = "transcribe";
+
+const USAGE: &str = "usage: orion_v3 transcribe <input.wav> [output.mid] \
+    [--tempo <bpm>|auto] [--quantize <division>|off] [--threshold <activation>]";
+
+/// How the converter was asked to run.
+#[derive(Clone, Debug)]
+pub struct ConvertOptions {
+    pub input: PathBuf,
+    /// Defaults to the input with a `.mid` extension.
+    pub output: PathBuf,
+    /// Beats per minute, or `None` to estimate it from the onsets.
+    pub tempo: Option<f64>,
+    /// Snaps notes to this fraction of a whole note, e.g. 16 for sixteenths.
+    pub quantize: Option<u32>,
+    pub transcription: TranscriptionSettings,
+}
+
+impl ConvertOptions {
+    /// Parses the arguments following [`COMMAND`].
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut paths = Vec::new();
+        let mut tempo = None;
+        let mut quantize = None;
+        let mut transcription = TranscriptionSettings::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--tempo" => {
+                    tempo = match value()?.as_str() {
+                        "auto" => None,
+                        bpm => Some(bpm.parse().context("--tempo")?),
+                    }
+                }
+                "--quantize" => {
+                    quantize = match value()?.as_str() {
+                        "off" => None,
+                        division => Some(division.parse().context("--quantize")?),
+                    }
+                }
+                "--threshold" => {
+                    transcription.on_threshold = value()?.parse().context("--threshold")?;
+                    transcription.off_threshold = transcription.on_threshold / 2.0;
+                }
+                flag if flag.starts_with("--") => bail!("unknown option {flag}\n{USAGE}"),
+                path => paths.push(PathBuf::from(path)),
+            }
+        }
+        if tempo.is_some_and(|bpm: f64| bpm <= 0.0) || quantize == Some(0) {
+            bail!("tempo and quantize must be positive\n{USAGE}");
+        }
+        let (input, output) = match paths.as_slice() {
+            [input] => (input.clone(), input.with_extension("mid")),
+            [input, output] => (input.clone(), output.clone()),
+            _ => bail!(USAGE),
+        };
+        Ok(Self {
+            input,
+            output,
+            tempo,
+            quantize,
+            transcription,
+        })
+    }
+}
+
+/// A transcribed note, in seconds from the start of the recording.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TranscribedNote {
+    pub start: f64,
+    pub end: f64,
+    pub message: MidiMessage,
+}
+
+/// Converts a WAV recording to a Standard MIDI File, as asked by `args`.
+pub fn run(args: &[String]) -> anyhow::Result<()> {
+    let options = ConvertOptions::parse(args)?;
+    let (samples, sample_rate) = read_wav(&options.input)
+        .with_context(|| format!("couldn't read {}", options.input.display()))?;
+    let notes = transcribe(&samples, sample_rate, &options.transcription);
+    let bpm = options
+        .tempo
+        .or_else(|| estimate_tempo(&notes))
+        .unwrap_or(60_000_000.0 / f64::from(crate::songs::DEFAULT_TEMPO));
+    let take = to_take(&notes, bpm, options.quantize);
+    take.save(&options.output, Format::SingleTrack)
+        .with_context(|| format!("couldn't write {}", options.output.display()))?;
+    println!(
+        "Wrote {} notes at {:.1} bpm to {}",
+        notes.len(),
+        bpm,
+        options.output.display()
+    );
+    Ok(())
+}
+
+/// Reads a WAV file of any sample format, mixed down to mono.
+pub fn read_wav(path: &Path) -> anyhow::Result<(Vec<f32>, u32)> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 * scale))
+                .collect::<Result<_, _>>()?
+        }
+    };
+    let channels = usize::from(spec.channels.max(1));
+    let mono = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect();
+    Ok((mono, spec.sample_rate))
+}
+
+/// Transcribes a whole recording, pairing each note on with its note off.
+#[must_use]
+pub fn transcribe(
+    samples: &[f32],
+    sample_rate: u32,
+    settings: &TranscriptionSettings,
+) -> Vec<TranscribedNote> {
+    // Analysed at the microphone's rate, which the window and hop are chosen for
+    let analysis_rate = MicrophoneSettings::default().sample_rate;
+    let mut resampled = Vec::new();
+    Resampler::new(sample_rate, analysis_rate).process(samples, &mut resampled);
+    let mut transcriber = Transcriber::new(analysis_rate, settings);
+    let mut events = Vec::new();
+    transcriber.push(&resampled, &mut events);
+    // Let the last window run past the end
+    transcriber.push(&vec![0.0; settings.window], &mut events);
+    transcriber.finish(&mut events);
+
+    let mut held: [Option<TranscribedEvent>; 128] = [None; 128];
+    let mut notes = Vec::new();
+    for event in events {
+        let key = usize::from(event.message.msg[1] & 0x7F);
+        if let Some(on) = held[key].take() {
+            notes.push(TranscribedNote {
+                start: on.time,
+                end: event.time,
+                message: on.message,
+            });
+        }
+        if event.message.is_note_on() {
+            held[key] = Some(event);
+        }
+    }
+    notes.sort_by(|a, b| a.start.total_cmp(&b.start));
+    notes
+}
+
+/// Estimates the tempo in beats per minute from how regularly notes start, or `None` if
+/// there are too few notes to tell.
+///
+/// Onsets are weighted by velocity and autocorrelated, favouring tempos near 120 bpm so a
+/// steady pulse isn't mistaken for half or double its speed.
+#[must_use]
+pub fn estimate_tempo(notes: &[TranscribedNote]) -> Option<f64> {
+    const RESOLUTION: f64 = 0.01;
+    const MIN_BPM: f64 = 50.0;
+    const MAX_BPM: f64 = 200.0;
+    if notes.len() < 4 {
+        return None;
+    }
+
+    let first = notes[0].start;
+    let last = notes.iter().map(|n| n.start).fold(first, f64::max);
+    let mut envelope = vec![0.0; ((last - first) / RESOLUTION).round() as usize + 3];
+    for note in notes {
+        let at = ((note.start - first) / RESOLUTION).round() as usize + 1;
+        let strength = f64::from(note.message.msg[2]) / 127.0;
+        // Spread over the neighbours, since onsets are only found to within a hop
+        envelope[at - 1] += strength / 2.0;
+        envelope[at] += strength;
+        envelope[at + 1] += strength / 2.0;
+    }
+
+    let lags = (60.0 / MAX_BPM / RESOLUTION) as usize..=(60.0 / MIN_BPM / RESOLUTION) as usize;
+    let score = |lag: usize| {
+        let correlation: f64 = envelope
+            .iter()
+            .zip(envelope.iter().skip(lag))
+            .map(|(a, b)| a * b)
+            .sum();
+        let bpm = 60.0 / (lag as f64 * RESOLUTION);
+        correlation * (-0.5 * (bpm / 120.0).log2().powi(2)).exp()
+    };
+    let best = lags
+        .clone()
+        .max_by(|&a, &b| score(a).total_cmp(&score(b)))?;
+    if score(best) <= 0.0 {
+        return None;
+    }
+    let offset = if lags.contains(&(best - 1)) && lags.contains(&(best + 1)) {
+        let (left, middle, right) = (score(best - 1), score(best), score(best + 1));
+        let curvature = left - 2.0 * middle + right;
+        if curvature < 0.0 {
+            0.5 * (left - right) / curvature
+        } else {
+            0.0
+        }
+    } else {
+        0.0
+    };
+    Some(60.0 / ((best as f64 + offset) * RESOLUTION))
+}
+
+/// Lays the notes out at `bpm`, starting with the first note, snapped to a grid of
+/// `quantize` notes per whole note if given.
+#[must_use]
+pub fn to_take(notes: &[TranscribedNote], bpm: f64, quantize: Option<u32>) -> Take {
+    let tempo = (60_000_000.0 / bpm).round() as u32;
+    let first = notes.first().map_or(0.0, |n| n.start);
+    let grid = quantize.map(|division| f64::from(tempo) * 4.0 / f64::from(division));
+    let micros = |seconds: f64| {
+        let micros = (seconds - first).max(0.0) * 1_000_000.0;
+        match grid {
+            // Rounded up, so the tick the take works out is exactly on the grid
+            Some(grid) => ((micros / grid).round() * grid).ceil(),
+            None => micros,
+        }
+    };
+
+    let mut events = Vec::with_capacity(notes.len() * 2);
+    for note in notes {
+        let start = micros(note.start);
+        let end = micros(note.end).max(start + grid.unwrap_or(1.0));
+        let [status, key, _] = note.message.msg;
+        events.push(RecordedEvent {
+            micros: start as u64,
+            message: note.message,
+        });
+        events.push(RecordedEvent {
+            micros: end as u64,
+            message: MidiMessage::note_off(status & 0x0F, key, 0),
+        });
+    }
+    // Releases first, so a note repeated on the grid isn't cut off by its own release
+    events.sort_by_key(|e| (e.micros, e.message.is_note_on()));
+    Take {
+        events,
+        tempo,
+        time_signature: (4, 4),
+    }
+}
+
+#[test]
+fn test_converts_a_recording_to_a_midi_file() {
+    use midly::{Smf, Timing, TrackEventKind};
+
+    let dir = std::env::temp_dir().join(format!("orion_transcribe_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("scale.wav");
+
+    // Quarter notes at 100 bpm up a C major arpeggio, with a stereo int WAV
+    let sample_rate = 44_100;
+    let beat = 0.6;
+    let melody = [60u8, 64, 67, 72, 67, 64, 60, 64];
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&input, spec).unwrap();
+    let length = (melody.len() as f64 * beat * f64::from(sample_rate)) as usize;
+    for i in 0..length {
+        let t = i as f64 / f64::from(sample_rate);
+        let note = melody[(t / beat) as usize];
+        let since = t % beat;
+        let sample = if since < beat * 0.8 {
+            let hz = 440.0 * 2f64.powf((f64::from(note) - 69.0) / 12.0);
+            (1..=4)
+                .map(|h| {
+                    0.2 * 0.6f64.powi(h - 1) * (std::f64::consts::TAU * hz * h as f64 * t).sin()
+                })
+                .sum::<f64>()
+                * (-since * 3.0).exp()
+        } else {
+            0.0
+        };
+        let sample = (sample * f64::from(i16::MAX)) as i16;
+        writer.write_sample(sample).unwrap();
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    let args: Vec<String> = [input.to_str().unwrap(), "--quantize", "16"]
+        .map(String::from)
+        .to_vec();
+    let options = ConvertOptions::parse(&args).unwrap();
+    assert_eq!(options.output, dir.join("scale.mid"));
+    assert_eq!((options.tempo, options.quantize), (None, Some(16)));
+    assert!(ConvertOptions::parse(&["a.wav".to_string(), "--tempo".to_string()]).is_err());
+
+    let (samples, rate) = read_wav(&input).unwrap();
+    let notes = transcribe(&samples, rate, &options.transcription);
+    let keys: Vec<u8> = notes.iter().map(|n| n.message.msg[1]).collect();
+    assert_eq!(keys, melody);
+    let bpm = estimate_tempo(&notes).unwrap();
+    assert!((bpm - 100.0).abs() < 3.0, "{bpm}");
+
+    run(&args).unwrap();
+    let bytes = std::fs::read(&options.output).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    let Timing::Metrical(ticks_per_beat) = smf.header.timing else {
+        panic!("{:?}", smf.header.timing);
+    };
+    let sixteenth = u32::from(ticks_per_beat.as_int()) / 4;
+    let mut tick = 0;
+    let mut starts = Vec::new();
+    for event in &smf.tracks[0] {
+        tick += event.delta.as_int();
+        if let TrackEventKind::Midi {
+            message: midly::MidiMessage::NoteOn { key, vel },
+            ..
+        } = event.kind
+            && vel > 0
+        {
+            assert_eq!(tick % sixteenth, 0);
+            starts.push((tick, key.as_int()));
+        }
+    }
+    let expected: Vec<(u32, u8)> = melody
+        .iter()
+        .enumerate()
+        .map(|(i, &key)| (i as u32 * sixteenth * 4, key))
+        .collect();
+    assert_eq!(starts, expected);
+
+    // A telephone-quality recording is resampled rather than cut short at Nyquist
+    let low = dir.join("low.wav");
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 8_000,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&low, spec).unwrap();
+    for i in 0..8_000 {
+        let t = i as f32 / 8_000.0;
+        writer
+            .write_sample(0.3 * (std::f32::consts::TAU * 440.0 * t).sin() * (-t * 3.0).exp())
+            .unwrap();
+    }
+    writer.finalize().unwrap();
+    run(&[low.to_str().unwrap().to_string()]).unwrap();
+    let (samples, rate) = read_wav(&low).unwrap();
+    let keys: Vec<u8> = transcribe(&samples, rate, &options.transcription)
+        .iter()
+        .map(|n| n.message.msg[1])
+        .collect();
+    assert_eq!(keys, [69]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
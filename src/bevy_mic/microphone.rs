@@ -1,10 +1,24 @@
+use anyhow::{Context, Result};
 use bevy::app::App;
 use bevy::log::{debug, error, info};
 #[allow(deprecated)]
-use bevy::prelude::{Commands, Resource, Startup, warn};
+use bevy::prelude::{
+    ButtonInput, Color, Commands, Component, DetectChanges, Event, EventWriter,
+    IntoScheduleConfigs, KeyCode, Local, Node, NonSendMut, PositionType, PreUpdate, Query, Res,
+    ResMut, Resource, Startup, Text, TextColor, TextFont, TextShadow, Update, Val, Visibility,
+    With, default, resource_changed, warn,
+};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use flume::Receiver;
+use cpal::{
+    BuildStreamError, FromSample, SampleFormat, SampleRate, SizedSample, SupportedStreamConfig,
+    SupportedStreamConfigRange,
+};
+use std::fmt;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ring_buffer::{RingConsumer, RingProducer, ring_buffer};
 
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::{Closure, wasm_bindgen};
@@ -20,18 +34,80 @@ pub struct MicrophonePlugin;
 
 impl bevy::prelude::Plugin for MicrophonePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, create_microphone);
+        app.init_resource::<MicrophoneSettings>()
+            .init_resource::<MicrophoneDevices>()
+            .insert_non_send_resource(MicrophoneStream::default())
+            .add_event::<MicrophoneSamples>()
+            .add_systems(Startup, spawn_hud)
+            .add_systems(
+                PreUpdate,
+                (
+                    create_microphone.run_if(resource_changed::<MicrophoneSettings>),
+                    forward_samples,
+                )
+                    .chain(),
+            )
+            .add_systems(Update, (control_on_keys, update_hud).chain());
+    }
+}
+
+/// Settings for [`MicrophonePlugin`]. Changing the device or the sample rate reopens the
+/// microphone.
+#[derive(Resource, Clone, Debug)]
+pub struct MicrophoneSettings {
+    /// The name of the input device to record from, or `None` for the system default.
+    pub device: Option<String>,
+    /// The rate [`MicrophoneSamples`] are resampled to for analysis. Devices recording at
+    /// this rate are preferred.
+    pub sample_rate: u32,
+    /// How much audio is kept for the game to read. Audio recorded while it's full is
+    /// dropped.
+    pub buffer: Duration,
+    /// Whether the microphone config and the input devices are shown over the game.
+    pub show_hud: bool,
+    pub toggle_key: KeyCode,
+    /// Switches to the next of the [`MicrophoneDevices`], while they're shown.
+    pub next_device_key: KeyCode,
+}
+
+impl Default for MicrophoneSettings {
+    fn default() -> Self {
+        Self {
+            device: None,
+            sample_rate: 48_000,
+            buffer: Duration::from_secs(1),
+            show_hud: false,
+            toggle_key: KeyCode::F10,
+            next_device_key: KeyCode::Tab,
+        }
+    }
+}
+
+/// [`Resource`] listing the names of the input devices, as of when the microphone was last
+/// opened.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct MicrophoneDevices(pub Vec<String>);
+
+impl MicrophoneDevices {
+    /// The device after `current` in the list, going round, or the first if `current`
+    /// isn't in it.
+    #[must_use]
+    pub fn after(&self, current: Option<&str>) -> Option<&str> {
+        let next = current
+            .and_then(|current| self.0.iter().position(|name| name == current))
+            .map_or(0, |index| index + 1);
+        self.0.get(next % self.0.len().max(1)).map(String::as_str)
     }
 }
 
 #[derive(Resource)]
 pub struct MicrophoneAudio {
-    audio_data: Receiver<Vec<f32>>,
-    pub config: MicrophoneConfig,
+    /// Mono samples at the [`MicrophoneConfig::sample_rate`] of the device.
+    audio_data: RingConsumer,
 }
 
 impl Deref for MicrophoneAudio {
-    type Target = Receiver<Vec<f32>>;
+    type Target = RingConsumer;
 
     fn deref(&self) -> &Self::Target {
         &self.audio_data
@@ -44,25 +120,301 @@ impl DerefMut for MicrophoneAudio {
     }
 }
 
-#[derive(Debug)]
+/// [`Resource`] describing how the open microphone records. It's removed while no
+/// microphone is open.
+#[derive(Resource, Clone, Debug, PartialEq)]
 pub struct MicrophoneConfig {
+    pub device: String,
+    /// Channels recorded, which are mixed down to mono.
     pub channels: u16,
+    /// The rate the device records at.
     pub sample_rate: u32,
+    pub sample_format: SampleFormat,
+    /// The rate [`MicrophoneSamples`] are resampled to.
+    pub analysis_rate: u32,
 }
 
-impl Default for MicrophoneConfig {
-    fn default() -> Self {
+impl fmt::Display for MicrophoneConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ch {} at {} Hz",
+            self.device, self.channels, self.sample_format, self.sample_rate
+        )?;
+        if self.sample_rate != self.analysis_rate {
+            write!(f, ", resampled to {} Hz", self.analysis_rate)?;
+        }
+        Ok(())
+    }
+}
+
+/// The stream recording from the microphone, which stops when dropped.
+#[derive(Default)]
+pub struct MicrophoneStream(Option<cpal::Stream>);
+
+/// An [`Event`] with the audio the microphone recorded since the last frame, so several
+/// systems can each read all of it.
+///
+/// This event fires from [`PreUpdate`](bevy::prelude::PreUpdate).
+#[derive(Event, Clone, Debug)]
+pub struct MicrophoneSamples {
+    /// Mono samples, with the channels of the microphone mixed down. The buffer is reused
+    /// for later events once every copy of this one is dropped.
+    pub samples: Arc<Vec<f32>>,
+    /// The [`MicrophoneConfig::analysis_rate`].
+    pub sample_rate: u32,
+}
+
+pub(crate) fn forward_samples(
+    mic: Option<ResMut<MicrophoneAudio>>,
+    config: Option<Res<MicrophoneConfig>>,
+    mut resampler: Local<Option<Resampler>>,
+    mut recorded: Local<Vec<f32>>,
+    mut buffers: Local<Vec<Arc<Vec<f32>>>>,
+    mut events: EventWriter<MicrophoneSamples>,
+) {
+    let (Some(mut mic), Some(config)) = (mic, config) else {
+        return;
+    };
+    let stale = resampler
+        .as_ref()
+        .is_none_or(|r| r.from() != config.sample_rate || r.to() != config.analysis_rate);
+    if stale || config.is_added() {
+        *resampler = Some(Resampler::new(config.sample_rate, config.analysis_rate));
+    }
+    let Some(resampler) = resampler.as_mut() else {
+        return;
+    };
+    recorded.clear();
+    if mic.read(&mut recorded) == 0 {
+        return;
+    }
+    // Events are dropped two frames on, so a few buffers go round without allocating
+    let free = buffers.iter_mut().position(|b| Arc::get_mut(b).is_some());
+    let index = free.unwrap_or_else(|| {
+        buffers.push(Arc::default());
+        buffers.len() - 1
+    });
+    let Some(samples) = Arc::get_mut(&mut buffers[index]) else {
+        return;
+    };
+    samples.clear();
+    resampler.process(&recorded, samples);
+    events.write(MicrophoneSamples {
+        samples: buffers[index].clone(),
+        sample_rate: config.analysis_rate,
+    });
+}
+
+/// Converts a stream of mono samples from one sample rate to another, by interpolating
+/// linearly between neighbouring samples.
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    /// Input samples between consecutive output samples.
+    step: f64,
+    /// Where the next output sample falls, in input samples after `previous`.
+    position: f64,
+    /// The last sample of the previous input.
+    previous: f32,
+}
+
+impl Resampler {
+    #[must_use]
+    pub fn new(from: u32, to: u32) -> Self {
         Self {
-            channels: 1,
-            sample_rate: 48_000,
+            from,
+            to,
+            step: f64::from(from.max(1)) / f64::from(to.max(1)),
+            position: 1.0,
+            previous: 0.0,
+        }
+    }
+
+    #[must_use]
+    pub fn from(&self) -> u32 {
+        self.from
+    }
+
+    #[must_use]
+    pub fn to(&self) -> u32 {
+        self.to
+    }
+
+    /// Resamples `input`, which carries on from the previous input, adding the samples to
+    /// `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.from == self.to {
+            output.extend_from_slice(input);
+            return;
+        }
+        let Some(&last) = input.last() else {
+            return;
+        };
+        let len = input.len() as f64;
+        while self.position < len {
+            let index = self.position as usize;
+            let before = index.checked_sub(1).map_or(self.previous, |i| input[i]);
+            let after = input[index];
+            let t = self.position.fract() as f32;
+            output.push(before + (after - before) * t);
+            self.position += self.step;
+        }
+        self.position -= len;
+        self.previous = last;
+    }
+}
+
+/// Splits the [`MicrophoneSamples`] into frames of a fixed size that start every `hop`
+/// samples, however the audio was chunked.
+pub struct SampleFrames {
+    producer: RingProducer,
+    consumer: RingConsumer,
+    frame: Vec<f32>,
+    hop: usize,
+}
+
+impl SampleFrames {
+    #[must_use]
+    pub fn new(size: usize, hop: usize) -> Self {
+        let size = size.max(1);
+        // Room for a frame still being filled and a whole frame's worth of new samples
+        let (producer, consumer) = ring_buffer(size * 2);
+        Self {
+            producer,
+            consumer,
+            frame: vec![0.0; size],
+            hop: hop.clamp(1, size),
         }
     }
+
+    /// Adds `samples`, calling `analyse` with each frame they complete.
+    pub fn push(&mut self, samples: &[f32], mut analyse: impl FnMut(&[f32])) {
+        // Less than a frame is left after reading, so a frame's worth always fits
+        for piece in samples.chunks(self.frame.len()) {
+            self.producer.push(piece.iter().copied());
+            while self.consumer.read_frame(&mut self.frame, self.hop) {
+                analyse(&self.frame);
+            }
+        }
+    }
+}
+
+/// Mixes interleaved samples of `channels` channels down to mono.
+pub fn downmix(interleaved: &[f32], channels: u16) -> impl Iterator<Item = f32> + '_ {
+    interleaved
+        .chunks(usize::from(channels.max(1)))
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+}
+
+/// Picks the config to record with out of those a device supports, or `None` if it has no
+/// sample format that can be read.
+///
+/// Configs recording at `sample_rate` come first, so nothing is lost resampling, then
+/// those nearest it. After that F32 is preferred over I16 over U16, then fewer channels.
+#[must_use]
+pub fn choose_config(
+    configs: impl IntoIterator<Item = SupportedStreamConfigRange>,
+    sample_rate: u32,
+) -> Option<SupportedStreamConfig> {
+    configs
+        .into_iter()
+        .filter_map(|config| {
+            let format = match config.sample_format() {
+                SampleFormat::F32 => 0,
+                SampleFormat::I16 => 1,
+                SampleFormat::U16 => 2,
+                _ => return None,
+            };
+            let rate = sample_rate.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+            Some((
+                (rate.abs_diff(sample_rate), format, config.channels()),
+                rate,
+                config,
+            ))
+        })
+        .min_by_key(|(rank, ..)| *rank)
+        .map(|(_, rate, config)| config.with_sample_rate(SampleRate(rate)))
+}
+
+/// Builds a stream recording from `device` with `config`, handing `callback` the
+/// interleaved samples as f32 whatever the sample format, a whole number of frames at a
+/// time.
+///
+/// Sample formats other than F32, I16 and U16 aren't supported.
+pub fn build_input_stream(
+    device: &cpal::Device,
+    config: &SupportedStreamConfig,
+    callback: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError> {
+    let stream_config = config.config();
+    match config.sample_format() {
+        SampleFormat::F32 => build_stream::<f32>(device, &stream_config, callback),
+        SampleFormat::I16 => build_stream::<i16>(device, &stream_config, callback),
+        SampleFormat::U16 => build_stream::<u16>(device, &stream_config, callback),
+        _ => Err(BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut callback: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<cpal::Stream, BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    // Converted a piece at a time into a buffer made up front, so the audio thread never
+    // allocates
+    const PIECE: usize = 1024;
+    let frames = (PIECE / usize::from(config.channels.max(1))).max(1);
+    let piece = frames * usize::from(config.channels.max(1));
+    let mut converted = vec![0.0; piece];
+    device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            for chunk in data.chunks(piece) {
+                let converted = &mut converted[..chunk.len()];
+                for (to, from) in converted.iter_mut().zip(chunk) {
+                    *to = from.to_sample::<f32>();
+                }
+                callback(converted);
+            }
+        },
+        |err| error!("an error occurred on the input audio stream: {}", err),
+        None,
+    )
 }
 
-pub fn create_microphone(mut commands: Commands) {
-    let (tx, rx) = flume::unbounded();
+/// Opens the microphone chosen in the [`MicrophoneSettings`], closing any open before.
+pub fn create_microphone(
+    mut commands: Commands,
+    settings: Res<MicrophoneSettings>,
+    mut stream: NonSendMut<MicrophoneStream>,
+    mut devices: ResMut<MicrophoneDevices>,
+    mut opened: Local<Option<(Option<String>, u32, Duration)>>,
+) {
+    // Other settings don't need the microphone reopened
+    let wanted = (
+        settings.device.clone(),
+        settings.sample_rate,
+        settings.buffer,
+    );
+    if opened.as_ref() == Some(&wanted) {
+        return;
+    }
+    *opened = Some(wanted);
+    stream.0 = None;
+    commands.remove_resource::<MicrophoneAudio>();
+    commands.remove_resource::<MicrophoneConfig>();
+
     #[cfg(target_arch = "wasm32")]
     {
+        let capacity = (settings.buffer.as_secs_f64() * f64::from(settings.sample_rate)) as usize;
+        let (producer, consumer) = ring_buffer(capacity);
+        // Moved into the audio callback once the stream is granted
+        let mut producer = Some(producer);
         let window = window().unwrap();
         let navigator = window.navigator();
         let media_devices = navigator.media_devices().unwrap();
@@ -77,8 +429,10 @@ pub fn create_microphone(mut commands: Commands) {
             .get_user_media_with_constraints(&constraints)
             .unwrap();
 
-        let tx = tx.clone();
         let closure = Closure::wrap(Box::new(move |stream: JsValue| {
+            let Some(mut producer) = producer.take() else {
+                return;
+            };
             let media_stream = MediaStream::from(stream);
 
             // Create an AudioContext
@@ -104,7 +458,6 @@ pub fn create_microphone(mut commands: Commands) {
                 .connect_with_audio_node(&audio_context.destination())
                 .unwrap();
 
-            let tx = tx.clone();
             // Set up an audio processing callback
             let onaudioprocess_closure = Closure::wrap(Box::new(move |event: JsValue| {
                 let audio_event = event.dyn_into::<web_sys::AudioProcessingEvent>().unwrap();
@@ -113,9 +466,7 @@ pub fn create_microphone(mut commands: Commands) {
                 // Get the first channel (mono audio)
                 let channel_data = input_buffer.get_channel_data(0).unwrap(); // Float32Array
 
-                // Here you can process the raw PCM data
-                // For example, logging the first 10 samples
-                tx.send(channel_data).unwrap();
+                producer.push(channel_data);
             }) as Box<dyn FnMut(JsValue)>);
 
             script_processor_node
@@ -125,91 +476,254 @@ pub fn create_microphone(mut commands: Commands) {
 
         promise.then(&closure);
         closure.forget();
-        commands.insert_resource(MicrophoneAudio(Mutex::new(rx)));
+        commands.insert_resource(MicrophoneAudio {
+            audio_data: consumer,
+        });
+        // Browsers record at the rate of the audio context, which is usually this one
+        commands.insert_resource(MicrophoneConfig {
+            device: "browser".to_string(),
+            channels: 1,
+            sample_rate: settings.sample_rate,
+            sample_format: SampleFormat::F32,
+            analysis_rate: settings.sample_rate,
+        });
         return;
     }
 
-    #[allow(unused_mut)]
-    let mut microphone_config = MicrophoneConfig::default();
-
-    // Setup microphone device
-    let device = match cpal::default_host().default_input_device() {
-        None => {
-            return warn!("no audio input device found, microphone functionality will be disabled");
+    let host = cpal::default_host();
+    devices.0 = host
+        .input_devices()
+        .map(|devices| devices.filter_map(|device| device.name().ok()).collect())
+        .unwrap_or_default();
+
+    match open_microphone(&host, &settings) {
+        Ok((new_stream, audio, config)) => {
+            info!("using microphone {}", config);
+            stream.0 = Some(new_stream);
+            commands.insert_resource(audio);
+            commands.insert_resource(config);
         }
-        Some(device) => device,
-    };
-    let configs = match device.supported_input_configs() {
-        Ok(configs) => configs,
-        Err(err) => {
-            return warn!(
-                "supported stream config error, microphone functionality will be disabled, error: {}",
-                err
-            );
+        Err(err) => warn!("{:#}, microphone functionality will be disabled", err),
+    }
+}
+
+fn open_microphone(
+    host: &cpal::Host,
+    settings: &MicrophoneSettings,
+) -> Result<(cpal::Stream, MicrophoneAudio, MicrophoneConfig)> {
+    let named = settings.device.as_ref().and_then(|name| {
+        let device = host
+            .input_devices()
+            .ok()?
+            .find(|device| device.name().is_ok_and(|n| &n == name));
+        if device.is_none() {
+            warn!("no audio input device named {}, using the default", name);
         }
-    };
-    for config in configs {
+        device
+    });
+    let device = named
+        .or_else(|| host.default_input_device())
+        .context("no audio input device found")?;
+    let name = device.name().unwrap_or_else(|_| "unknown".to_string());
+
+    let configs: Vec<_> = device
+        .supported_input_configs()
+        .context("supported stream config error")?
+        .collect();
+    for config in &configs {
         debug!("supported microphone config: {:#?}", config);
     }
-    let mut configs = match device.supported_input_configs() {
-        Ok(configs) => configs,
-        Err(err) => {
-            return warn!(
-                "supported stream config error, microphone functionality will be disabled, error: {}",
-                err
-            );
-        }
+    let config = choose_config(configs, settings.sample_rate)
+        .with_context(|| format!("no supported sample format on {name}"))?;
+    let channels = config.channels();
+
+    let sample_rate = config.sample_rate().0;
+    let capacity = (settings.buffer.as_secs_f64() * f64::from(sample_rate)).ceil() as usize;
+    let (mut producer, consumer) = ring_buffer(capacity);
+    let stream = build_input_stream(&device, &config, move |data| {
+        producer.push(downmix(data, channels));
+    })
+    .context("failed to build audio input stream")?;
+    stream.play().context("failed to play audio stream")?;
+
+    let config = MicrophoneConfig {
+        device: name,
+        channels,
+        sample_rate,
+        sample_format: config.sample_format(),
+        analysis_rate: settings.sample_rate,
     };
+    Ok((
+        stream,
+        MicrophoneAudio {
+            audio_data: consumer,
+        },
+        config,
+    ))
+}
 
-    #[cfg(target_os = "android")]
-    {
-        microphone_config.channels = 2;
-    }
-
-    let config = match configs.find(|c| {
-        c.sample_format() == cpal::SampleFormat::F32
-            && c.channels() == microphone_config.channels
-            && c.min_sample_rate().0 <= microphone_config.sample_rate
-            && c.max_sample_rate().0 >= microphone_config.sample_rate
-    }) {
-        None => {
-            return warn!(
-            "microphone config of {:?} not supported, microphone functionality will be disabled",
-            microphone_config
-        )
+fn control_on_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    devices: Res<MicrophoneDevices>,
+    config: Option<Res<MicrophoneConfig>>,
+    mut settings: ResMut<MicrophoneSettings>,
+) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.show_hud = !settings.show_hud;
+    }
+    if settings.show_hud && keys.just_pressed(settings.next_device_key) {
+        let current = config.as_ref().map(|c| c.device.as_str());
+        if let Some(next) = devices.after(current) {
+            settings.device = Some(next.to_string());
         }
-        Some(config) => config,
     }
-        .with_sample_rate(cpal::SampleRate(microphone_config.sample_rate));
+}
 
-    // Run microphone audio through our channel
-    let err_fn = |err| error!("an error occurred on the output audio stream: {}", err);
+/// The text showing the [`MicrophoneConfig`] and the [`MicrophoneDevices`].
+#[derive(Component)]
+struct MicrophoneHud;
+
+fn spawn_hud(mut commands: Commands) {
+    commands.spawn((
+        MicrophoneHud,
+        Text::new(""),
+        TextFont {
+            font_size: 18.0,
+            ..default()
+        },
+        TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        TextShadow::default(),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            left: Val::Px(8.0),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+}
 
-    info!(
-        "using microphone config: {:?} with sample rate: {:#?}",
-        config, microphone_config
-    );
+fn update_hud(
+    settings: Res<MicrophoneSettings>,
+    devices: Res<MicrophoneDevices>,
+    config: Option<Res<MicrophoneConfig>>,
+    mic: Option<Res<MicrophoneAudio>>,
+    mut shown_dropped: Local<u64>,
+    mut hud: Query<(&mut Text, &mut Visibility), With<MicrophoneHud>>,
+) {
+    let config_changed = config.as_ref().is_some_and(|c| c.is_changed());
+    // The ring buffer counts drops without change detection
+    let dropped = mic.as_ref().map_or(0, |mic| mic.dropped());
+    if !settings.is_changed()
+        && !devices.is_changed()
+        && !config_changed
+        && dropped == *shown_dropped
+    {
+        return;
+    }
+    *shown_dropped = dropped;
+    for (mut text, mut visibility) in &mut hud {
+        *visibility = if settings.show_hud {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        let mut lines = vec![config.as_ref().map_or_else(
+            || "No microphone".to_string(),
+            |config| format!("Microphone {}", **config),
+        )];
+        if let Some(mic) = mic.as_ref().filter(|_| dropped > 0) {
+            lines.push(format!(
+                "{} samples dropped in {} overflows",
+                dropped,
+                mic.overflows()
+            ));
+        }
+        for device in &devices.0 {
+            let current = config.as_ref().is_some_and(|c| &c.device == device);
+            lines.push(format!("{} {}", if current { ">" } else { " " }, device));
+        }
+        lines.push(format!(
+            "{:?} for the next device",
+            settings.next_device_key
+        ));
+        text.0 = lines.join("\n");
+    }
+}
 
-    let stream = device
-        .build_input_stream(
-            &config.into(),
-            move |d: &[f32], _| {
-                // sending errors imply the receiver is dropped.
-                tx.send(d.to_vec()).ok();
-            },
-            err_fn,
-            None,
+#[test]
+fn test_negotiates_and_resamples_microphone_audio() {
+    use cpal::SupportedBufferSize;
+    let range = |channels, min, max, format| {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
         )
-        .expect("failed to build audio input stream");
+    };
 
-    // we wanna share the output from our thread loop thing in here continuously with the rest of bevy.
-    commands.insert_resource(MicrophoneAudio {
-        audio_data: rx,
-        config: microphone_config,
-    });
+    // The rate matters most, then the format, then the channels
+    let configs = [
+        range(2, 44_100, 44_100, SampleFormat::F32),
+        range(4, 8_000, 96_000, SampleFormat::I16),
+        range(2, 8_000, 96_000, SampleFormat::U16),
+        range(2, 8_000, 96_000, SampleFormat::I16),
+        range(1, 48_000, 48_000, SampleFormat::I32),
+    ];
+    let config = choose_config(configs, 48_000).unwrap();
+    assert_eq!(config.sample_format(), SampleFormat::I16);
+    assert_eq!(config.channels(), 2);
+    assert_eq!(config.sample_rate(), SampleRate(48_000));
+    let config = choose_config(configs[..1].to_vec(), 48_000).unwrap();
+    assert_eq!(config.sample_rate(), SampleRate(44_100));
+    assert!(choose_config(configs[4..].to_vec(), 48_000).is_none());
+
+    let mono: Vec<f32> = downmix(&[1.0, 0.0, 0.5, 0.5, -1.0, 0.0], 2).collect();
+    assert_eq!(mono, [0.5, 0.5, -0.5]);
+
+    // A 1 kHz tone recorded at 44.1 kHz, in uneven chunks
+    let tone =
+        |rate: u32, i: usize| (std::f32::consts::TAU * 1000.0 * i as f32 / rate as f32).sin();
+    let input: Vec<f32> = (0..44_100).map(|i| tone(44_100, i)).collect();
+    let mut resampler = Resampler::new(44_100, 48_000);
+    let mut output = Vec::new();
+    let (mut rest, mut size) = (&input[..], 1);
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(size.min(rest.len()));
+        resampler.process(chunk, &mut output);
+        (rest, size) = (tail, size * 7 % 1000 + 1);
+    }
+    let expected = 44_100.0 * 48.0 / 44.1;
+    assert!(
+        (output.len() as f32 - expected).abs() <= 1.0,
+        "{}",
+        output.len()
+    );
+    for (i, sample) in output.iter().enumerate() {
+        assert!((sample - tone(48_000, i)).abs() < 0.01, "{i}: {sample}");
+    }
+
+    let mut same = Vec::new();
+    Resampler::new(48_000, 48_000).process(&[0.1, 0.2], &mut same);
+    assert_eq!(same, [0.1, 0.2]);
+
+    // Frames of 4 every 3 samples, whatever the chunks
+    let mut frames = SampleFrames::new(4, 3);
+    let mut starts = Vec::new();
+    let samples: Vec<f32> = (0..20).map(|i| i as f32).collect();
+    for chunk in [&samples[..1], &samples[1..11], &samples[11..]] {
+        frames.push(chunk, |frame| {
+            assert_eq!(frame, [0.0, 1.0, 2.0, 3.0].map(|i| frame[0] + i));
+            starts.push(frame[0]);
+        });
+    }
+    assert_eq!(starts, [0.0, 3.0, 6.0, 9.0, 12.0, 15.0]);
 
-    // we play the stream, and then in order to not drop the stuff we have here, to continue to play it continously
-    // we have to loop, we sleep for 100 seconds so this thread rarely ever does anything.
-    stream.play().expect("failed to play audio stream");
-    Box::leak(Box::new(stream));
+    let devices = MicrophoneDevices(vec!["a".to_string(), "b".to_string()]);
+    assert_eq!(devices.after(None), Some("a"));
+    assert_eq!(devices.after(Some("a")), Some("b"));
+    assert_eq!(devices.after(Some("b")), Some("a"));
+    assert_eq!(MicrophoneDevices::default().after(None), None);
 }
@@ -1,5 +1,6 @@
 pub mod audio_output;
 pub mod microphone;
+pub mod ring_buffer;
 pub mod spatial_audio;
 
 use audio_output::AudioOutputPlugin;
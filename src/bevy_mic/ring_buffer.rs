@@ -0,0 +1,189 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+/// Makes a lock-free ring buffer holding up to `capacity` samples, split into the end the
+/// audio thread writes to and the end the game reads from.
+///
+/// Neither end allocates or blocks once made. When the reader falls behind and the buffer
+/// fills up, new samples are dropped and counted instead of growing it.
+#[must_use]
+pub fn ring_buffer(capacity: usize) -> (RingProducer, RingConsumer) {
+    let ring = Arc::new(RingBuffer {
+        slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        dropped: AtomicU64::new(0),
+        overflows: AtomicU64::new(0),
+    });
+    (RingProducer { ring: ring.clone() }, RingConsumer { ring })
+}
+
+struct RingBuffer {
+    /// Samples as their bits, so they can be shared without locks or unsafe code.
+    slots: Box<[AtomicU32]>,
+    /// Samples ever written and read. Only the producer moves `written`, and only the
+    /// consumer moves `read`.
+    written: AtomicUsize,
+    read: AtomicUsize,
+    dropped: AtomicU64,
+    overflows: AtomicU64,
+}
+
+impl RingBuffer {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn len(&self) -> usize {
+        let written = self.written.load(Ordering::Acquire);
+        written.wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+
+    fn slot(&self, index: usize) -> &AtomicU32 {
+        &self.slots[index % self.slots.len()]
+    }
+}
+
+/// The writing end of a [`ring_buffer`], for the audio thread.
+pub struct RingProducer {
+    ring: Arc<RingBuffer>,
+}
+
+impl RingProducer {
+    /// Writes as many of `samples` as fit, returning how many. The rest are dropped and
+    /// counted in [`RingConsumer::dropped`].
+    pub fn push(&mut self, samples: impl IntoIterator<Item = f32>) -> usize {
+        let ring = &*self.ring;
+        let written = ring.written.load(Ordering::Relaxed);
+        let free = ring.capacity() - ring.len();
+        let mut pushed = 0;
+        let mut dropped = 0;
+        for sample in samples {
+            if pushed < free {
+                ring.slot(written.wrapping_add(pushed))
+                    .store(sample.to_bits(), Ordering::Relaxed);
+                pushed += 1;
+            } else {
+                dropped += 1;
+            }
+        }
+        // Publishes the samples stored above to the consumer
+        ring.written
+            .store(written.wrapping_add(pushed), Ordering::Release);
+        if dropped > 0 {
+            ring.dropped.fetch_add(dropped, Ordering::Relaxed);
+            ring.overflows.fetch_add(1, Ordering::Relaxed);
+        }
+        pushed
+    }
+}
+
+/// The reading end of a [`ring_buffer`].
+pub struct RingConsumer {
+    ring: Arc<RingBuffer>,
+}
+
+impl RingConsumer {
+    /// Samples waiting to be read.
+    fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    /// Samples dropped because the buffer was full.
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        self.ring.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Writes that found the buffer full and dropped samples.
+    #[must_use]
+    pub fn overflows(&self) -> u64 {
+        self.ring.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Reads all the waiting samples, adding them to `samples` and returning how many.
+    pub fn read(&mut self, samples: &mut Vec<f32>) -> usize {
+        let len = self.len();
+        let read = self.ring.read.load(Ordering::Relaxed);
+        samples.extend((0..len).map(|i| self.sample(read.wrapping_add(i))));
+        self.advance(len);
+        len
+    }
+
+    /// Fills `frame` with the oldest waiting samples and moves past `hop` of them, so
+    /// consecutive frames overlap when `hop` is shorter than the frame. Returns `false`,
+    /// reading nothing, until enough samples are waiting for both.
+    pub fn read_frame(&mut self, frame: &mut [f32], hop: usize) -> bool {
+        if frame.len() > self.ring.capacity() || self.len() < frame.len().max(hop) {
+            return false;
+        }
+        let read = self.ring.read.load(Ordering::Relaxed);
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = self.sample(read.wrapping_add(i));
+        }
+        self.advance(hop);
+        true
+    }
+
+    fn sample(&self, index: usize) -> f32 {
+        f32::from_bits(self.ring.slot(index).load(Ordering::Relaxed))
+    }
+
+    /// Frees the oldest `count` samples for the producer to write over.
+    fn advance(&mut self, count: usize) {
+        let read = self.ring.read.load(Ordering::Relaxed);
+        self.ring
+            .read
+            .store(read.wrapping_add(count), Ordering::Release);
+    }
+}
+
+#[test]
+fn test_ring_buffer_counts_overflows_and_reads_overlapping_frames() {
+    let (mut producer, mut consumer) = ring_buffer(8);
+    assert_eq!(consumer.len(), 0);
+
+    // Overflowing drops the newest samples
+    assert_eq!(producer.push((0..6).map(|i| i as f32)), 6);
+    assert_eq!(producer.push((6..12).map(|i| i as f32)), 2);
+    assert_eq!(
+        (consumer.len(), consumer.dropped(), consumer.overflows()),
+        (8, 4, 1)
+    );
+
+    // Frames of 4 every 2 samples
+    let mut frame = [0.0; 4];
+    assert!(consumer.read_frame(&mut frame, 2));
+    assert_eq!(frame, [0.0, 1.0, 2.0, 3.0]);
+    assert!(consumer.read_frame(&mut frame, 2));
+    assert_eq!(frame, [2.0, 3.0, 4.0, 5.0]);
+    assert!(consumer.read_frame(&mut frame, 2));
+    assert!(!consumer.read_frame(&mut frame, 2));
+    assert_eq!(frame, [4.0, 5.0, 6.0, 7.0]);
+
+    // Wrapping round the end of the slots
+    assert_eq!(producer.push([8.0, 9.0, 10.0]), 3);
+    assert!(consumer.read_frame(&mut frame, 2));
+    assert_eq!(frame, [6.0, 7.0, 8.0, 9.0]);
+    assert!(!consumer.read_frame(&mut frame, 2));
+    let mut samples = Vec::new();
+    assert_eq!(consumer.read(&mut samples), 3);
+    assert_eq!(samples, [8.0, 9.0, 10.0]);
+    assert_eq!(consumer.len(), 0);
+
+    // Nothing is lost or reordered across threads while the reader keeps up
+    let (mut producer, mut consumer) = ring_buffer(1024);
+    let writer = std::thread::spawn(move || {
+        let mut next = 0;
+        while next < 100_000 {
+            next += producer.push((next..(next + 100).min(100_000)).map(|i| i as f32));
+            std::thread::yield_now();
+        }
+    });
+    samples.clear();
+    while samples.len() < 100_000 {
+        consumer.read(&mut samples);
+    }
+    writer.join().unwrap();
+    assert!(samples.iter().enumerate().all(|(i, &s)| s == i as f32));
+}
@@ -0,0 +1,695 @@
+use super::input::{MidiInputError, MidiInputPort, MidiInputSettings};
+use super::output::{MidiOutputError, MidiOutputPort, MidiOutputSettings};
+use midir::ConnectErrorKind;
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Callback a [`MidiInputBackend`] invokes for every incoming message, along with its
+/// timestamp in microseconds.
+pub type MidiCallback = Box<dyn FnMut(u64, &[u8]) + Send + 'static>;
+
+/// A source of midi messages, driven by the [`MidiInputPlugin`](super::input::MidiInputPlugin) worker.
+pub trait MidiInputBackend: Send + 'static {
+    /// Get the available input ports, and their names.
+    fn ports(&mut self) -> Result<Vec<(String, MidiInputPort)>, MidiInputError>;
+
+    /// Connects to `port`, replacing any current connection.
+    ///
+    /// On failure the backend is left disconnected.
+    fn connect(
+        &mut self,
+        port: &MidiInputPort,
+        callback: MidiCallback,
+    ) -> Result<(), MidiInputError>;
+
+    /// Disconnects from the current port, if any.
+    fn disconnect(&mut self);
+
+    #[must_use]
+    fn is_connected(&self) -> bool;
+}
+
+/// A sink for midi messages, driven by the [`MidiOutputPlugin`](super::output::MidiOutputPlugin) worker.
+pub trait MidiOutputBackend: Send + 'static {
+    /// Get the available output ports, and their names.
+    fn ports(&mut self) -> Result<Vec<(String, MidiOutputPort)>, MidiOutputError>;
+
+    /// Connects to `port`, replacing any current connection.
+    ///
+    /// On failure the backend is left disconnected.
+    fn connect(&mut self, port: &MidiOutputPort) -> Result<(), MidiOutputError>;
+
+    /// Disconnects from the current port, if any.
+    fn disconnect(&mut self);
+
+    /// Sends raw bytes to the connected port.
+    fn send(&mut self, message: &[u8]) -> Result<(), MidiOutputError>;
+
+    #[must_use]
+    fn is_connected(&self) -> bool;
+}
+
+/// The default [`MidiInputBackend`], backed by the system's midi API through midir.
+pub struct MidirInput {
+    settings: MidiInputSettings,
+    // A second client used only to list ports, so refreshing them doesn't drop the connection.
+    ports: Option<midir::MidiInput>,
+    // Invariant: at most one of `input` or `connection` is Some, and both are None only if
+    // the midi client couldn't be created
+    input: Option<midir::MidiInput>,
+    connection: Option<midir::MidiInputConnection<MidiCallback>>,
+}
+
+impl MidirInput {
+    #[must_use]
+    pub fn new(settings: &MidiInputSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            ports: midir::MidiInput::new(settings.client_name).ok(),
+            input: midir::MidiInput::new(settings.client_name)
+                .ok()
+                .map(|mut i| {
+                    i.ignore(settings.ignore);
+                    i
+                }),
+            connection: None,
+        }
+    }
+}
+
+impl MidiInputBackend for MidirInput {
+    // If there's an error getting port names, it's because the available ports changed,
+    // so it tries again (up to 10 times)
+    fn ports(&mut self) -> Result<Vec<(String, MidiInputPort)>, MidiInputError> {
+        let input = self
+            .ports
+            .as_ref()
+            .ok_or(MidiInputError::PortRefreshError)?;
+        for _ in 0..10 {
+            let ports: Result<Vec<_>, _> = input
+                .ports()
+                .into_iter()
+                .map(|p| input.port_name(&p).map(|n| (n, MidiInputPort::new(p.id()))))
+                .collect();
+            if let Ok(ports) = ports {
+                return Ok(ports);
+            }
+        }
+        Err(MidiInputError::PortRefreshError)
+    }
+
+    fn connect(
+        &mut self,
+        port: &MidiInputPort,
+        callback: MidiCallback,
+    ) -> Result<(), MidiInputError> {
+        self.disconnect();
+        let Some(input) = self.input.take() else {
+            return Err(MidiInputError::ConnectionError(ConnectErrorKind::Other(
+                "midi input is unavailable",
+            )));
+        };
+        let Some(midir_port) = input.find_port_by_id(port.id().to_string()) else {
+            self.input = Some(input);
+            return Err(MidiInputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        };
+        match input.connect(
+            &midir_port,
+            self.settings.port_name,
+            |stamp, message, callback| callback(stamp, message),
+            callback,
+        ) {
+            Ok(conn) => {
+                self.connection = Some(conn);
+                Ok(())
+            }
+            Err(conn_err) => {
+                let kind = conn_err.kind();
+                self.input = Some(conn_err.into_inner());
+                Err(MidiInputError::ConnectionError(kind))
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(conn) = self.connection.take() {
+            self.input = Some(conn.close().0);
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+}
+
+/// The default [`MidiOutputBackend`], backed by the system's midi API through midir.
+pub struct MidirOutput {
+    settings: MidiOutputSettings,
+    // A second client used only to list ports, so refreshing them doesn't drop the connection.
+    ports: Option<midir::MidiOutput>,
+    // Invariant: at most one of `output` or `connection` is Some, and both are None only if
+    // the midi client couldn't be created
+    output: Option<midir::MidiOutput>,
+    connection: Option<midir::MidiOutputConnection>,
+}
+
+impl MidirOutput {
+    #[must_use]
+    pub fn new(settings: &MidiOutputSettings) -> Self {
+        Self {
+            settings: settings.clone(),
+            ports: midir::MidiOutput::new(settings.port_name).ok(),
+            output: midir::MidiOutput::new(settings.port_name).ok(),
+            connection: None,
+        }
+    }
+}
+
+impl MidiOutputBackend for MidirOutput {
+    // If there's an error getting port names, it's because the available ports changed,
+    // so it tries again (up to 10 times)
+    fn ports(&mut self) -> Result<Vec<(String, MidiOutputPort)>, MidiOutputError> {
+        let output = self
+            .ports
+            .as_ref()
+            .ok_or(MidiOutputError::PortRefreshError)?;
+        for _ in 0..10 {
+            let ports: Result<Vec<_>, _> = output
+                .ports()
+                .into_iter()
+                .map(|p| {
+                    output
+                        .port_name(&p)
+                        .map(|n| (n, MidiOutputPort::new(p.id())))
+                })
+                .collect();
+            if let Ok(ports) = ports {
+                return Ok(ports);
+            }
+        }
+        Err(MidiOutputError::PortRefreshError)
+    }
+
+    fn connect(&mut self, port: &MidiOutputPort) -> Result<(), MidiOutputError> {
+        self.disconnect();
+        let Some(output) = self.output.take() else {
+            return Err(MidiOutputError::ConnectionError(ConnectErrorKind::Other(
+                "midi output is unavailable",
+            )));
+        };
+        let Some(midir_port) = output.find_port_by_id(port.id().to_string()) else {
+            self.output = Some(output);
+            return Err(MidiOutputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        };
+        match output.connect(&midir_port, self.settings.port_name) {
+            Ok(conn) => {
+                self.connection = Some(conn);
+                Ok(())
+            }
+            Err(conn_err) => {
+                let kind = conn_err.kind();
+                self.output = Some(conn_err.into_inner());
+                Err(MidiOutputError::ConnectionError(kind))
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(conn) = self.connection.take() {
+            self.output = Some(conn.close());
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), MidiOutputError> {
+        match &mut self.connection {
+            Some(conn) => conn.send(message).map_err(MidiOutputError::SendError),
+            None => Err(MidiOutputError::SendError(midir::SendError::Other(
+                "output is disconnected",
+            ))),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+}
+
+/// Combines several [`MidiInputBackend`]s, listing all their ports side by side.
+///
+/// Ports are told apart by id, so each backend's ids must be distinct from the others'.
+pub struct MultiMidiInput {
+    backends: Vec<Box<dyn MidiInputBackend>>,
+    connected: Option<usize>,
+}
+
+impl MultiMidiInput {
+    #[must_use]
+    pub fn new(backends: Vec<Box<dyn MidiInputBackend>>) -> Self {
+        Self {
+            backends,
+            connected: None,
+        }
+    }
+}
+
+impl MidiInputBackend for MultiMidiInput {
+    // Only fails if every backend does, so one broken backend doesn't hide the others' ports
+    fn ports(&mut self) -> Result<Vec<(String, MidiInputPort)>, MidiInputError> {
+        let mut ports = Vec::new();
+        let mut error = None;
+        for backend in &mut self.backends {
+            match backend.ports() {
+                Ok(p) => ports.extend(p),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if ports.is_empty() => Err(e),
+            _ => Ok(ports),
+        }
+    }
+
+    fn connect(
+        &mut self,
+        port: &MidiInputPort,
+        callback: MidiCallback,
+    ) -> Result<(), MidiInputError> {
+        self.disconnect();
+        let owner = self.backends.iter_mut().position(|b| {
+            b.ports()
+                .is_ok_and(|ports| ports.iter().any(|(_, p)| p == port))
+        });
+        let Some(owner) = owner else {
+            return Err(MidiInputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        };
+        self.backends[owner].connect(port, callback)?;
+        self.connected = Some(owner);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(connected) = self.connected.take() {
+            self.backends[connected].disconnect();
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+            .is_some_and(|connected| self.backends[connected].is_connected())
+    }
+}
+
+/// Combines several [`MidiOutputBackend`]s, listing all their ports side by side.
+///
+/// Ports are told apart by id, so each backend's ids must be distinct from the others'.
+pub struct MultiMidiOutput {
+    backends: Vec<Box<dyn MidiOutputBackend>>,
+    connected: Option<usize>,
+}
+
+impl MultiMidiOutput {
+    #[must_use]
+    pub fn new(backends: Vec<Box<dyn MidiOutputBackend>>) -> Self {
+        Self {
+            backends,
+            connected: None,
+        }
+    }
+}
+
+impl MidiOutputBackend for MultiMidiOutput {
+    // Only fails if every backend does, so one broken backend doesn't hide the others' ports
+    fn ports(&mut self) -> Result<Vec<(String, MidiOutputPort)>, MidiOutputError> {
+        let mut ports = Vec::new();
+        let mut error = None;
+        for backend in &mut self.backends {
+            match backend.ports() {
+                Ok(p) => ports.extend(p),
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if ports.is_empty() => Err(e),
+            _ => Ok(ports),
+        }
+    }
+
+    fn connect(&mut self, port: &MidiOutputPort) -> Result<(), MidiOutputError> {
+        self.disconnect();
+        let owner = self.backends.iter_mut().position(|b| {
+            b.ports()
+                .is_ok_and(|ports| ports.iter().any(|(_, p)| p == port))
+        });
+        let Some(owner) = owner else {
+            return Err(MidiOutputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        };
+        self.backends[owner].connect(port)?;
+        self.connected = Some(owner);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(connected) = self.connected.take() {
+            self.backends[connected].disconnect();
+        }
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), MidiOutputError> {
+        match self.connected {
+            Some(connected) => self.backends[connected].send(message),
+            None => Err(MidiOutputError::SendError(midir::SendError::Other(
+                "output is disconnected",
+            ))),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+            .is_some_and(|connected| self.backends[connected].is_connected())
+    }
+}
+
+/// In-memory midi devices, for driving the midi plugins from tests without hardware.
+///
+/// Clones share the same devices: hand [`MockMidi::input`] and [`MockMidi::output`] to the
+/// plugins, and keep a clone to inject input and inspect what was sent.
+#[derive(Clone, Default)]
+pub struct MockMidi {
+    state: Arc<(Mutex<MockState>, Condvar)>,
+}
+
+#[derive(Default)]
+struct MockState {
+    input_ports: Vec<String>,
+    output_ports: Vec<String>,
+    input: Option<(MidiInputPort, MidiCallback)>,
+    output: Option<MidiOutputPort>,
+    // Injected while no input was connected, delivered on the next connect
+    pending: VecDeque<(u64, Vec<u8>)>,
+    sent: Vec<Vec<u8>>,
+}
+
+impl MockMidi {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an input port called `name`.
+    #[must_use]
+    pub fn with_input_port(self, name: &str) -> Self {
+        self.lock().input_ports.push(name.to_string());
+        self
+    }
+
+    /// Adds an output port called `name`.
+    #[must_use]
+    pub fn with_output_port(self, name: &str) -> Self {
+        self.lock().output_ports.push(name.to_string());
+        self
+    }
+
+    /// A [`MidiInputBackend`] reading from these devices.
+    #[must_use]
+    pub fn input(&self) -> MockMidiInput {
+        MockMidiInput(self.clone())
+    }
+
+    /// A [`MidiOutputBackend`] writing to these devices.
+    #[must_use]
+    pub fn output(&self) -> MockMidiOutput {
+        MockMidiOutput(self.clone())
+    }
+
+    /// Plays `message` on the connected input port with the given timestamp.
+    ///
+    /// If no input is connected yet, the message is queued and delivered as soon as one is,
+    /// so a whole performance can be scripted up front.
+    pub fn inject(&self, stamp: u64, message: &[u8]) {
+        let state = &mut *self.lock();
+        match &mut state.input {
+            Some((_, callback)) => callback(stamp, message),
+            None => state.pending.push_back((stamp, message.to_vec())),
+        }
+    }
+
+    /// Takes every message sent to the output so far, oldest first.
+    #[must_use]
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.lock().sent)
+    }
+
+    /// The port the input is connected to, if any.
+    #[must_use]
+    pub fn connected_input(&self) -> Option<MidiInputPort> {
+        self.lock().input.as_ref().map(|(port, _)| port.clone())
+    }
+
+    /// The port the output is connected to, if any.
+    #[must_use]
+    pub fn connected_output(&self) -> Option<MidiOutputPort> {
+        self.lock().output.clone()
+    }
+
+    /// Blocks until the input connects, since the plugins connect from their worker.
+    ///
+    /// Returns `false` if `timeout` elapsed first.
+    pub fn wait_for_input(&self, timeout: Duration) -> bool {
+        self.wait_until(timeout, |state| state.input.is_some())
+    }
+
+    /// Blocks until the output connects, since the plugins connect from their worker.
+    ///
+    /// Returns `false` if `timeout` elapsed first.
+    pub fn wait_for_output(&self, timeout: Duration) -> bool {
+        self.wait_until(timeout, |state| state.output.is_some())
+    }
+
+    fn wait_until(&self, timeout: Duration, condition: impl Fn(&MockState) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        while !condition(&state) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .state
+                .1
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        true
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn notify(&self) {
+        self.state.1.notify_all();
+    }
+}
+
+/// The [`MidiInputBackend`] half of a [`MockMidi`].
+pub struct MockMidiInput(MockMidi);
+
+impl MidiInputBackend for MockMidiInput {
+    fn ports(&mut self) -> Result<Vec<(String, MidiInputPort)>, MidiInputError> {
+        Ok(self
+            .0
+            .lock()
+            .input_ports
+            .iter()
+            .map(|name| (name.clone(), MidiInputPort::new(format!("mock:{name}"))))
+            .collect())
+    }
+
+    fn connect(
+        &mut self,
+        port: &MidiInputPort,
+        mut callback: MidiCallback,
+    ) -> Result<(), MidiInputError> {
+        let mut state = self.0.lock();
+        state.input = None;
+        if !state
+            .input_ports
+            .iter()
+            .any(|name| port.id() == format!("mock:{name}"))
+        {
+            return Err(MidiInputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        }
+        for (stamp, message) in state.pending.drain(..) {
+            callback(stamp, &message);
+        }
+        state.input = Some((port.clone(), callback));
+        drop(state);
+        self.0.notify();
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.0.lock().input = None;
+        self.0.notify();
+    }
+
+    fn is_connected(&self) -> bool {
+        self.0.lock().input.is_some()
+    }
+}
+
+/// The [`MidiOutputBackend`] half of a [`MockMidi`].
+pub struct MockMidiOutput(MockMidi);
+
+impl MidiOutputBackend for MockMidiOutput {
+    fn ports(&mut self) -> Result<Vec<(String, MidiOutputPort)>, MidiOutputError> {
+        Ok(self
+            .0
+            .lock()
+            .output_ports
+            .iter()
+            .map(|name| (name.clone(), MidiOutputPort::new(format!("mock:{name}"))))
+            .collect())
+    }
+
+    fn connect(&mut self, port: &MidiOutputPort) -> Result<(), MidiOutputError> {
+        let mut state = self.0.lock();
+        state.output = None;
+        if !state
+            .output_ports
+            .iter()
+            .any(|name| port.id() == format!("mock:{name}"))
+        {
+            return Err(MidiOutputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        }
+        state.output = Some(port.clone());
+        drop(state);
+        self.0.notify();
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.0.lock().output = None;
+        self.0.notify();
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), MidiOutputError> {
+        let mut state = self.0.lock();
+        if state.output.is_none() {
+            return Err(MidiOutputError::SendError(midir::SendError::Other(
+                "output is disconnected",
+            )));
+        }
+        state.sent.push(message.to_vec());
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.0.lock().output.is_some()
+    }
+}
+
+#[test]
+fn test_mock_midi_delivers_scripted_input() {
+    let mock = MockMidi::new().with_input_port("keys");
+    mock.inject(10, &[0x90, 60, 100]);
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let mut input = mock.input();
+    let (_, port) = input.ports().unwrap().remove(0);
+    input
+        .connect(
+            &port,
+            Box::new(move |stamp, message| sink.lock().unwrap().push((stamp, message.to_vec()))),
+        )
+        .unwrap();
+    mock.inject(20, &[0x80, 60, 0]);
+
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![(10, vec![0x90, 60, 100]), (20, vec![0x80, 60, 0])]
+    );
+    assert!(
+        input
+            .connect(&MidiInputPort::new("mock:missing"), Box::new(|_, _| {}))
+            .is_err()
+    );
+    assert!(!input.is_connected());
+
+    // Like midir, the output only sends while connected
+    let mock = MockMidi::new().with_output_port("piano");
+    let mut output = mock.output();
+    assert!(output.send(&[0x90, 60, 100]).is_err());
+    let (_, port) = output.ports().unwrap().remove(0);
+    output.connect(&port).unwrap();
+    output.send(&[0x90, 60, 100]).unwrap();
+    output.disconnect();
+    assert!(output.send(&[0x80, 60, 0]).is_err());
+    assert_eq!(mock.take_sent(), vec![vec![0x90, 60, 100]]);
+}
+
+#[test]
+fn test_mock_midi_plugins_in_headless_app() {
+    use super::prelude::*;
+    use bevy::prelude::*;
+
+    let mock = MockMidi::new()
+        .with_input_port("keys")
+        .with_output_port("piano");
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .insert_resource(MidiInputBackendOverride::new(mock.input()))
+        .insert_resource(MidiOutputBackendOverride::new(mock.output()))
+        .add_plugins((MidiInputPlugin, MidiOutputPlugin));
+
+    let timeout = Duration::from_secs(5);
+    let deadline = Instant::now() + timeout;
+    // Startup inserts the resources
+    app.update();
+    while app.world().resource::<MidiInput>().ports().is_empty()
+        || app.world().resource::<MidiOutput>().ports().is_empty()
+    {
+        assert!(Instant::now() < deadline, "ports were never listed");
+        app.update();
+    }
+
+    let input = app.world().resource::<MidiInput>();
+    input.connect(input.ports()[0].1.clone());
+    let output = app.world().resource::<MidiOutput>();
+    output.connect(output.ports()[0].1.clone());
+    assert!(mock.wait_for_input(timeout));
+    assert!(mock.wait_for_output(timeout));
+
+    mock.inject(1_000, &[0x90, 60, 100]);
+    app.update();
+    let events = app.world().resource::<Events<MidiData>>();
+    let received: Vec<_> = events.iter_current_update_events().collect();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].stamp, 1_000);
+    assert!(received[0].message.is_note_on());
+
+    app.world()
+        .resource::<MidiOutput>()
+        .send([0x80, 60, 0].into());
+    let deadline = Instant::now() + timeout;
+    let mut sent = Vec::new();
+    while sent.is_empty() && Instant::now() < deadline {
+        sent = mock.take_sent();
+    }
+    assert_eq!(sent, vec![vec![0x80, 60, 0]]);
+}
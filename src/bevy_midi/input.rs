@@ -1,13 +1,19 @@
-use super::{KEY_RANGE, MidiMessage};
-use MidiInputError::{ConnectionError, PortRefreshError};
+use super::backend::{MidiInputBackend, MidirInput, MultiMidiInput};
+use super::mpe::{MpeSettings, PerNoteExpression, update_expression};
+use super::state::{HeldNotesChanged, MidiState, update_state};
+use super::timing::MidiTimestamps;
+use super::{KEY_RANGE, MidiClockMessage, MidiMessage};
+use MidiInputError::{ConnectionError, PortRefreshError, WorkerStopped};
 use bevy::prelude::Plugin;
-use bevy::{prelude::*, tasks::IoTaskPool};
-use crossbeam_channel::{Receiver, Sender};
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, SendError, Sender};
 use midir::ConnectErrorKind; // XXX: do we expose this?
-pub use midir::{Ignore, MidiInputPort};
+pub use midir::Ignore;
 use std::error::Error;
 use std::fmt::Display;
-use std::future::Future;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 pub struct MidiInputPlugin;
 
@@ -15,14 +21,31 @@ impl Plugin for MidiInputPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<MidiInputSettings>()
             .init_resource::<MidiInputConnection>()
+            .init_resource::<MidiState>()
+            .init_resource::<MpeSettings>()
+            .init_resource::<PerNoteExpression>()
+            .init_resource::<MidiTimestamps>()
             .add_event::<MidiInputError>()
             .add_event::<MidiData>()
+            .add_event::<MidiClockData>()
+            .add_event::<HeldNotesChanged>()
             .add_systems(Startup, setup)
-            .add_systems(PreUpdate, reply)
-            .add_systems(Update, debug);
+            .add_systems(
+                PreUpdate,
+                (reply, update_state, update_expression)
+                    .chain()
+                    .in_set(MidiInputSystems),
+            )
+            .add_systems(Update, debug)
+            .add_systems(Last, shutdown);
     }
 }
 
+/// The [`PreUpdate`] systems that receive midi and fire [`MidiData`]; order after this to
+/// see a frame's messages in the same frame.
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MidiInputSystems;
+
 /// Settings for [`MidiInputPlugin`].
 ///
 /// This resource must be added before [`MidiInputPlugin`] to take effect.
@@ -43,42 +66,93 @@ impl Default for MidiInputSettings {
     }
 }
 
+/// Replaces the [`MidiInputBackend`] used by [`MidiInputPlugin`], e.g. with a
+/// [`MockMidiInput`](super::backend::MockMidiInput) in tests.
+///
+/// This resource must be added before [`MidiInputPlugin`]'s `Startup` runs; without it the
+/// plugin talks to the system through [`MidirInput`].
+#[derive(Resource)]
+pub struct MidiInputBackendOverride(Mutex<Option<Box<dyn MidiInputBackend>>>);
+
+impl MidiInputBackendOverride {
+    #[must_use]
+    pub fn new(backend: impl MidiInputBackend) -> Self {
+        Self(Mutex::new(Some(Box::new(backend))))
+    }
+
+    fn take(&self) -> Option<Box<dyn MidiInputBackend>> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+/// Extra [`MidiInputBackend`]s whose ports are listed alongside the main backend's, e.g.
+/// network sessions.
+///
+/// Like [`MidiInputBackendOverride`], backends must be added before [`MidiInputPlugin`]'s
+/// `Startup` runs.
+#[derive(Resource, Default)]
+pub struct MidiInputBackendExtras(Mutex<Vec<Box<dyn MidiInputBackend>>>);
+
+impl MidiInputBackendExtras {
+    pub fn push(&self, backend: impl MidiInputBackend) {
+        if let Ok(mut backends) = self.0.lock() {
+            backends.push(Box::new(backend));
+        }
+    }
+
+    fn take(&self) -> Vec<Box<dyn MidiInputBackend>> {
+        self.0
+            .lock()
+            .map(|mut backends| std::mem::take(&mut *backends))
+            .unwrap_or_default()
+    }
+}
+
+/// An input port, identified by the stable id its backend gave it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MidiInputPort(String);
+
+impl MidiInputPort {
+    #[must_use]
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
 /// [`Resource`](bevy::ecs::system::Resource) for receiving midi messages.
 ///
 /// Change detection will only fire when its input ports are refreshed.
-
 #[derive(Resource)]
 pub struct MidiInput {
     receiver: Receiver<Reply>,
     sender: Sender<Message>,
+    // Lets the main thread report its own failures through `reply`
+    errors: Sender<Reply>,
+    worker: Option<JoinHandle<()>>,
     ports: Vec<(String, MidiInputPort)>,
 }
 
 impl MidiInput {
     /// Update the available input ports.
     ///
-    /// This method temporarily disconnects from the current midi port, so
-    /// some [`MidiData`] events may be missed.
-    ///
     /// Change detection is fired when the ports are refreshed.
     pub fn refresh_ports(&self) {
-        self.sender
-            .send(Message::RefreshPorts)
-            .expect("Couldn't refresh input ports");
+        self.request(Message::RefreshPorts);
     }
 
     /// Connects to the given `port`.
     pub fn connect(&self, port: MidiInputPort) {
-        self.sender
-            .send(Message::ConnectToPort(port))
-            .expect("Failed to connect to port");
+        self.request(Message::ConnectToPort(port));
     }
 
     /// Disconnects from the current input port.
     pub fn disconnect(&self) {
-        self.sender
-            .send(Message::DisconnectFromPort)
-            .expect("Failed to disconnect from port");
+        self.request(Message::DisconnectFromPort);
     }
 
     /// Get the current input ports, and their names.
@@ -86,6 +160,31 @@ impl MidiInput {
     pub fn ports(&self) -> &Vec<(String, MidiInputPort)> {
         &self.ports
     }
+
+    // If the worker has stopped, the failure is reported as a `MidiInputError` event
+    // instead of panicking.
+    fn request(&self, msg: Message) {
+        if self.sender.send(msg).is_err() {
+            // We own the receiving end, so this can't fail.
+            let _ = self.errors.send(Reply::Error(WorkerStopped));
+        }
+    }
+
+    /// Stops the worker thread, closing any open connection.
+    fn shutdown(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = self.sender.send(Message::Shutdown);
+            if worker.join().is_err() {
+                warn!("midi input worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for MidiInput {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
 
 /// [`Resource`](bevy::ecs::system::Resource) for checking whether [`MidiInput`] is
@@ -95,6 +194,7 @@ impl MidiInput {
 #[derive(Resource, Default)]
 pub struct MidiInputConnection {
     connected: bool,
+    port: Option<MidiInputPort>,
 }
 
 impl MidiInputConnection {
@@ -102,6 +202,12 @@ impl MidiInputConnection {
     pub fn is_connected(&self) -> bool {
         self.connected
     }
+
+    /// The port we're connected to, if any.
+    #[must_use]
+    pub fn port(&self) -> Option<&MidiInputPort> {
+        self.port.as_ref()
+    }
 }
 
 /// An [`Event`](bevy::ecs::event::Event) for incoming midi data.
@@ -113,11 +219,22 @@ pub struct MidiData {
     pub message: MidiMessage,
 }
 
+/// An [`Event`](bevy::ecs::event::Event) for incoming beat clock and transport messages.
+///
+/// This event fires from [`PreUpdate`]. Midi clock is only received if
+/// [`MidiInputSettings::ignore`] lets timing messages through.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct MidiClockData {
+    pub stamp: u64,
+    pub message: MidiClockMessage,
+}
+
 /// The [`Error`] type for midi input operations, accessible as an [`Event`](bevy::ecs::event::Event).
 #[derive(Clone, Debug, Event)]
 pub enum MidiInputError {
     ConnectionError(ConnectErrorKind),
     PortRefreshError,
+    WorkerStopped,
 }
 
 impl Error for MidiInputError {}
@@ -133,6 +250,7 @@ impl Display for MidiInputError {
                 }
             },
             PortRefreshError => write!(f, "Couldn't refresh input ports")?,
+            WorkerStopped => write!(f, "Midi input worker has stopped")?,
         }
         Ok(())
     }
@@ -143,6 +261,8 @@ fn reply(
     mut conn: ResMut<MidiInputConnection>,
     mut err: EventWriter<MidiInputError>,
     mut midi: EventWriter<MidiData>,
+    mut clock: EventWriter<MidiClockData>,
+    mut timestamps: ResMut<MidiTimestamps>,
 ) {
     while let Ok(msg) = input.receiver.try_recv() {
         match msg {
@@ -151,39 +271,75 @@ fn reply(
             }
             Reply::Error(e) => {
                 warn!("{}", e);
-                err.send(e);
+                err.write(e);
             }
-            Reply::Connected => {
+            Reply::Connected(port) => {
+                timestamps.reset();
                 conn.connected = true;
+                conn.port = Some(port);
             }
             Reply::Disconnected => {
                 conn.connected = false;
+                conn.port = None;
+            }
+            Reply::Midi(m, received) => {
+                timestamps.observe(m.stamp, received);
+                if let Some(latency) = timestamps.latency(m.stamp) {
+                    timestamps.delivery.record(latency);
+                }
+                midi.write(m);
             }
-            Reply::Midi(m) => {
-                midi.send(m);
+            Reply::Clock(c) => {
+                clock.write(c);
             }
         }
     }
 }
 
-fn setup(mut commands: Commands, settings: Res<MidiInputSettings>) {
+fn shutdown(mut input: ResMut<MidiInput>, mut exit: EventReader<AppExit>) {
+    if exit.read().next().is_some() {
+        input.bypass_change_detection().shutdown();
+    }
+}
+
+fn setup(
+    mut commands: Commands,
+    settings: Res<MidiInputSettings>,
+    backend: Option<Res<MidiInputBackendOverride>>,
+    extras: Option<Res<MidiInputBackendExtras>>,
+) {
     let (m_sender, m_receiver) = crossbeam_channel::unbounded::<Message>();
     let (r_sender, r_receiver) = crossbeam_channel::unbounded::<Reply>();
 
-    let thread_pool = IoTaskPool::get();
-    thread_pool
-        .spawn(MidiInputTask {
-            receiver: m_receiver,
-            sender: r_sender,
-            settings: settings.clone(),
-            input: None,
-            connection: None,
-        })
-        .detach();
+    let backend = backend
+        .and_then(|b| b.take())
+        .unwrap_or_else(|| Box::new(MidirInput::new(&settings)));
+    commands.remove_resource::<MidiInputBackendOverride>();
+    let extras = extras.map(|e| e.take()).unwrap_or_default();
+    let backend: Box<dyn MidiInputBackend> = if extras.is_empty() {
+        backend
+    } else {
+        let mut backends = vec![backend];
+        backends.extend(extras);
+        Box::new(MultiMidiInput::new(backends))
+    };
+
+    let worker = MidiInputWorker {
+        receiver: m_receiver,
+        sender: r_sender.clone(),
+        backend,
+    };
+    let worker = thread::Builder::new()
+        .name("bevy_midi input".to_string())
+        .spawn(move || worker.run())
+        .map_err(|e| warn!("Couldn't start midi input worker: {}", e))
+        .ok();
 
     commands.insert_resource(MidiInput {
         sender: m_sender,
         receiver: r_receiver,
+        errors: r_sender,
+        worker,
         ports: Vec::new(),
     });
 }
@@ -192,152 +348,99 @@ enum Message {
     RefreshPorts,
     ConnectToPort(MidiInputPort),
     DisconnectFromPort,
+    Shutdown,
 }
 
 enum Reply {
     AvailablePorts(Vec<(String, MidiInputPort)>),
     Error(MidiInputError),
-    Connected,
+    Connected(MidiInputPort),
     Disconnected,
-    Midi(MidiData),
+    /// With when the worker received it.
+    Midi(MidiData, Instant),
+    Clock(MidiClockData),
 }
 
-struct MidiInputTask {
+struct MidiInputWorker {
     receiver: Receiver<Message>,
     sender: Sender<Reply>,
-    settings: MidiInputSettings,
-
-    // Invariant: exactly one of `input` or `connection` is Some
-    input: Option<midir::MidiInput>,
-    connection: Option<(midir::MidiInputConnection<()>, MidiInputPort)>,
+    backend: Box<dyn MidiInputBackend>,
 }
 
-impl Future for MidiInputTask {
-    type Output = ();
-
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        if self.input.is_none() && self.connection.is_none() {
-            self.input = midir::MidiInput::new(self.settings.client_name).ok();
-            self.sender
-                .send(get_available_ports(self.input.as_ref().unwrap()))
-                .unwrap();
+impl MidiInputWorker {
+    // Runs until told to shut down, or until the app drops its end of either channel.
+    fn run(mut self) {
+        let ports = get_available_ports(self.backend.as_mut());
+        if self.sender.send(ports).is_ok() {
+            while let Ok(msg) = self.receiver.recv() {
+                if matches!(msg, Message::Shutdown) || self.handle(msg).is_err() {
+                    break;
+                }
+            }
         }
+        self.backend.disconnect();
+    }
 
-        if let Ok(msg) = self.receiver.recv() {
-            use Message::{ConnectToPort, DisconnectFromPort, RefreshPorts};
-
-            match msg {
-                ConnectToPort(port) => {
-                    let was_connected = self.input.is_none();
-                    let s = self.sender.clone();
-                    let i = self
-                        .input
-                        .take()
-                        .unwrap_or_else(|| self.connection.take().unwrap().0.close().0);
-                    let conn = i.connect(
-                        &port,
-                        self.settings.port_name,
-                        move |stamp, message, _| {
-                            if message.len() != 3 {
-                                return;
-                            }
-                            let _ = s.send(Reply::Midi(MidiData {
-                                stamp,
-                                message: [message[0], message[1], message[2]].into(),
-                            }));
-                        },
-                        (),
-                    );
-                    match conn {
-                        Ok(conn) => {
-                            self.sender.send(Reply::Connected).unwrap();
-                            self.connection = Some((conn, port));
-                            self.input = None;
-                        }
-                        Err(conn_err) => {
-                            self.sender
-                                .send(Reply::Error(ConnectionError(conn_err.kind())))
-                                .unwrap();
-                            if was_connected {
-                                self.sender.send(Reply::Disconnected).unwrap();
-                            }
-                            self.connection = None;
-                            self.input = Some(conn_err.into_inner());
-                        }
-                    }
-                }
-                DisconnectFromPort => {
-                    if let Some((conn, _)) = self.connection.take() {
-                        self.input = Some(conn.close().0);
-                        self.connection = None;
-                        self.sender.send(Reply::Disconnected).unwrap();
-                    }
-                }
-                RefreshPorts => match &self.input {
-                    Some(i) => {
-                        self.sender.send(get_available_ports(i)).unwrap();
-                    }
-                    None => {
-                        let (conn, port) = self.connection.take().unwrap();
-                        let i = conn.close().0;
-
-                        self.sender.send(get_available_ports(&i)).unwrap();
-
-                        let s = self.sender.clone();
-                        let conn = i.connect(
-                            &port,
-                            self.settings.port_name,
-                            move |stamp, message, _| {
-                                let _ = s.send(Reply::Midi(MidiData {
+    fn handle(&mut self, msg: Message) -> Result<(), SendError<Reply>> {
+        use Message::{ConnectToPort, DisconnectFromPort, RefreshPorts, Shutdown};
+
+        match msg {
+            ConnectToPort(port) => {
+                let was_connected = self.backend.is_connected();
+                let s = self.sender.clone();
+                let conn = self.backend.connect(
+                    &port,
+                    Box::new(move |stamp, message| {
+                        let reply = if let Some(message) = MidiClockMessage::parse(message) {
+                            Reply::Clock(MidiClockData { stamp, message })
+                        } else if message.len() == 3 {
+                            Reply::Midi(
+                                MidiData {
                                     stamp,
                                     message: [message[0], message[1], message[2]].into(),
-                                }));
-                            },
-                            (),
-                        );
-                        match conn {
-                            Ok(conn) => {
-                                self.connection = Some((conn, port));
-                                self.input = None;
-                            }
-                            Err(conn_err) => {
-                                self.sender
-                                    .send(Reply::Error(ConnectionError(conn_err.kind())))
-                                    .unwrap();
-                                self.sender.send(Reply::Disconnected).unwrap();
-                                self.connection = None;
-                                self.input = Some(conn_err.into_inner());
-                            }
+                                },
+                                Instant::now(),
+                            )
+                        } else {
+                            return;
+                        };
+                        let _ = s.send(reply);
+                    }),
+                );
+                match conn {
+                    Ok(()) => self.sender.send(Reply::Connected(port))?,
+                    Err(e) => {
+                        self.sender.send(Reply::Error(e))?;
+                        if was_connected {
+                            self.sender.send(Reply::Disconnected)?;
                         }
                     }
-                },
+                }
+            }
+            DisconnectFromPort => {
+                if self.backend.is_connected() {
+                    self.backend.disconnect();
+                    self.sender.send(Reply::Disconnected)?;
+                }
+            }
+            RefreshPorts => {
+                self.sender
+                    .send(get_available_ports(self.backend.as_mut()))?;
             }
+            Shutdown => {}
         }
-        cx.waker().wake_by_ref();
-        std::task::Poll::Pending
+        Ok(())
     }
 }
 
 // Helper for above.
 //
-// Returns either Reply::AvailablePorts or Reply::PortRefreshError
-// If there's an error getting port names, it's because the available ports changed,
-// so it tries again (up to 10 times)
-fn get_available_ports(input: &midir::MidiInput) -> Reply {
-    for _ in 0..10 {
-        let ports = input.ports();
-        let ports: Result<Vec<_>, _> = ports
-            .into_iter()
-            .map(|p| input.port_name(&p).map(|n| (n, p)))
-            .collect();
-        if let Ok(ports) = ports {
-            return Reply::AvailablePorts(ports);
-        }
+// Returns either Reply::AvailablePorts or Reply::Error
+fn get_available_ports(backend: &mut dyn MidiInputBackend) -> Reply {
+    match backend.ports() {
+        Ok(ports) => Reply::AvailablePorts(ports),
+        Err(e) => Reply::Error(e),
     }
-    Reply::Error(PortRefreshError)
 }
 
 // A system which debug prints note events
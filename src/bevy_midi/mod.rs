@@ -1,8 +1,16 @@
+pub mod backend;
 pub mod input;
+pub mod mpe;
 pub mod output;
+pub mod rtp;
+pub mod state;
+pub mod timing;
+pub mod ump;
 
 pub mod prelude {
-    pub use super::{input::*, output::*, *};
+    pub use super::{
+        backend::*, input::*, mpe::*, output::*, rtp::*, state::*, timing::*, ump::*, *,
+    };
 }
 
 pub const KEY_RANGE: [&str; 12] = [
@@ -11,6 +19,22 @@ pub const KEY_RANGE: [&str; 12] = [
 
 const NOTE_ON_STATUS: u8 = 0b1001_0000;
 const NOTE_OFF_STATUS: u8 = 0b1000_0000;
+const CONTROL_CHANGE_STATUS: u8 = 0b1011_0000;
+const PITCH_BEND_STATUS: u8 = 0b1110_0000;
+const SONG_POSITION_STATUS: u8 = 0xF2;
+const TIMING_CLOCK_STATUS: u8 = 0xF8;
+const START_STATUS: u8 = 0xFA;
+const CONTINUE_STATUS: u8 = 0xFB;
+const STOP_STATUS: u8 = 0xFC;
+
+/// Controller number of the sustain (damper) pedal.
+pub const CONTROL_SUSTAIN: u8 = 64;
+/// Channel mode message silencing every voice immediately, including released ones.
+pub const CONTROL_ALL_SOUND_OFF: u8 = 120;
+/// Channel mode message resetting controllers and pitch bend to their defaults.
+pub const CONTROL_RESET_ALL_CONTROLLERS: u8 = 121;
+/// Channel mode message releasing every held note.
+pub const CONTROL_ALL_NOTES_OFF: u8 = 123;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub struct MidiMessage {
@@ -24,6 +48,36 @@ impl From<[u8; 3]> for MidiMessage {
 }
 
 impl MidiMessage {
+    #[must_use]
+    pub fn note_on(channel: u8, note: u8, velocity: u8) -> Self {
+        [
+            NOTE_ON_STATUS | (channel & 0x0f),
+            note & 0x7f,
+            velocity & 0x7f,
+        ]
+        .into()
+    }
+
+    #[must_use]
+    pub fn note_off(channel: u8, note: u8, velocity: u8) -> Self {
+        [
+            NOTE_OFF_STATUS | (channel & 0x0f),
+            note & 0x7f,
+            velocity & 0x7f,
+        ]
+        .into()
+    }
+
+    #[must_use]
+    pub fn control_change(channel: u8, controller: u8, value: u8) -> Self {
+        [
+            CONTROL_CHANGE_STATUS | (channel & 0x0f),
+            controller & 0x7f,
+            value & 0x7f,
+        ]
+        .into()
+    }
+
     #[must_use]
     pub fn is_note_on(&self) -> bool {
         (self.msg[0] & 0b1111_0000) == NOTE_ON_STATUS && self.msg[2] != 0
@@ -35,9 +89,69 @@ impl MidiMessage {
             || ((self.msg[0] & 0b1111_0000) == NOTE_ON_STATUS && self.msg[2] == 0)
     }
 
+    #[must_use]
+    pub fn is_control_change(&self) -> bool {
+        (self.msg[0] & 0b1111_0000) == CONTROL_CHANGE_STATUS
+    }
+
+    #[must_use]
+    pub fn is_pitch_bend(&self) -> bool {
+        (self.msg[0] & 0b1111_0000) == PITCH_BEND_STATUS
+    }
+
     /// Get the channel of a message, assuming the message is not a system message.
     #[must_use]
     pub fn channel(&self) -> u8 {
         self.msg[0] & 0b0000_1111
     }
 }
+
+/// A beat clock or transport message, used to keep the tempo and position of several devices
+/// in sync.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MidiClockMessage {
+    /// Timing clock, sent [`PULSES_PER_BEAT`](Self::PULSES_PER_BEAT) times per beat.
+    Clock,
+    /// Start playing from the beginning.
+    Start,
+    /// Resume playing from the current song position.
+    Continue,
+    Stop,
+    /// Song Position Pointer, in sixteenth notes from the beginning.
+    SongPosition(u16),
+}
+
+impl MidiClockMessage {
+    pub const PULSES_PER_BEAT: u32 = 24;
+
+    /// Parses `bytes`, returning `None` if they aren't a clock or transport message.
+    #[must_use]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [TIMING_CLOCK_STATUS] => Some(Self::Clock),
+            [START_STATUS] => Some(Self::Start),
+            [CONTINUE_STATUS] => Some(Self::Continue),
+            [STOP_STATUS] => Some(Self::Stop),
+            [SONG_POSITION_STATUS, lsb, msb] => Some(Self::SongPosition(
+                (u16::from(msb & 0x7f) << 7) | u16::from(lsb & 0x7f),
+            )),
+            _ => None,
+        }
+    }
+
+    /// The raw bytes of the message.
+    #[must_use]
+    pub fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Clock => vec![TIMING_CLOCK_STATUS],
+            Self::Start => vec![START_STATUS],
+            Self::Continue => vec![CONTINUE_STATUS],
+            Self::Stop => vec![STOP_STATUS],
+            Self::SongPosition(sixteenths) => vec![
+                SONG_POSITION_STATUS,
+                (sixteenths & 0x7f) as u8,
+                ((sixteenths >> 7) & 0x7f) as u8,
+            ],
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! MIDI Polyphonic Expression, where a controller plays each note on its own channel so
+//! the note can be bent, pressed and brightened without touching the others.
+//!
+//! The channels are split into a lower zone, managed from the first channel, and an upper
+//! zone, managed from the last. [`MpeTranslator`] turns the expression sent on a zone's
+//! member channels into MIDI 2.0 per-note messages, and copies what the manager channel
+//! sends for the whole zone to the member channels.
+
+use super::MidiMessage;
+use super::input::MidiData;
+use super::ump::{
+    Midi2Message, PITCH_BEND_CENTER_32, controller_unit, pitch_bend_unit, velocity_unit,
+};
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+/// Controller number of the MPE timbre controller.
+pub const CONTROL_TIMBRE: u8 = 74;
+const CONTROL_DATA_ENTRY: u8 = 6;
+const CONTROL_RPN_LSB: u8 = 100;
+const CONTROL_RPN_MSB: u8 = 101;
+/// Registered parameter number of the MPE Configuration Message.
+const RPN_MPE_CONFIGURATION: (u8, u8) = (0, 6);
+
+/// One of the two MPE zones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MpeZone {
+    /// Managed from channel 1, with member channels counting up from channel 2.
+    Lower,
+    /// Managed from channel 16, with member channels counting down from channel 15.
+    Upper,
+}
+
+impl MpeZone {
+    /// The channel, counting from 0, that sends messages for the whole zone.
+    #[must_use]
+    pub fn manager_channel(self) -> u8 {
+        match self {
+            Self::Lower => 0,
+            Self::Upper => 15,
+        }
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) with the MPE zones of the input.
+///
+/// Both zones are off by default, so every channel plays as plain MIDI. Controllers that
+/// send an MPE Configuration Message set up their zones themselves.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MpeSettings {
+    /// How many member channels the lower zone has, or 0 if it is off.
+    pub lower_members: u8,
+    /// How many member channels the upper zone has, or 0 if it is off.
+    pub upper_members: u8,
+}
+
+impl MpeSettings {
+    /// Gives `zone` `members` channels, shrinking the other zone if they would overlap.
+    pub fn set_zone(&mut self, zone: MpeZone, members: u8) {
+        let members = members.min(15);
+        let (this, other) = match zone {
+            MpeZone::Lower => (&mut self.lower_members, &mut self.upper_members),
+            MpeZone::Upper => (&mut self.upper_members, &mut self.lower_members),
+        };
+        *this = members;
+        // The two managers leave 14 channels to share
+        *other = (*other).min(14u8.saturating_sub(members));
+    }
+
+    /// The zone `channel` manages, if any.
+    #[must_use]
+    pub fn managed_zone(&self, channel: u8) -> Option<MpeZone> {
+        match channel {
+            0 if self.lower_members > 0 => Some(MpeZone::Lower),
+            15 if self.upper_members > 0 => Some(MpeZone::Upper),
+            _ => None,
+        }
+    }
+
+    /// The member channels of `zone`.
+    pub fn member_channels(&self, zone: MpeZone) -> impl Iterator<Item = u8> {
+        match zone {
+            MpeZone::Lower => 1..self.lower_members + 1,
+            MpeZone::Upper => 15 - self.upper_members..15,
+        }
+    }
+
+    /// The zone `channel` is a member channel of, if any.
+    #[must_use]
+    pub fn member_zone(&self, channel: u8) -> Option<MpeZone> {
+        if (1..=self.lower_members).contains(&channel) {
+            Some(MpeZone::Lower)
+        } else if self.upper_members > 0 && (15 - self.upper_members..15).contains(&channel) {
+            Some(MpeZone::Upper)
+        } else {
+            None
+        }
+    }
+
+    /// Applies `message` if it completes an MPE Configuration Message on a manager channel,
+    /// returning whether it did. `rpn` holds the registered parameter selected on each
+    /// channel.
+    pub fn configure(&mut self, rpn: &mut [(u8, u8); 16], message: MidiMessage) -> bool {
+        if !message.is_control_change() {
+            return false;
+        }
+        let [_, controller, value] = message.msg;
+        let channel = message.channel();
+        let selected = &mut rpn[usize::from(channel)];
+        match controller {
+            CONTROL_RPN_MSB => selected.0 = value,
+            CONTROL_RPN_LSB => selected.1 = value,
+            CONTROL_DATA_ENTRY if *selected == RPN_MPE_CONFIGURATION => {
+                let zone = match channel {
+                    0 => MpeZone::Lower,
+                    15 => MpeZone::Upper,
+                    _ => return false,
+                };
+                self.set_zone(zone, value);
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// The messages that configure these zones on an MPE synth, sent from the manager
+    /// channels.
+    #[must_use]
+    pub fn configuration_messages(&self) -> Vec<MidiMessage> {
+        [
+            (MpeZone::Lower, self.lower_members),
+            (MpeZone::Upper, self.upper_members),
+        ]
+        .into_iter()
+        .flat_map(|(zone, members)| {
+            let channel = zone.manager_channel();
+            [
+                (CONTROL_RPN_MSB, RPN_MPE_CONFIGURATION.0),
+                (CONTROL_RPN_LSB, RPN_MPE_CONFIGURATION.1),
+                (CONTROL_DATA_ENTRY, members),
+            ]
+            .map(|(controller, value)| MidiMessage::control_change(channel, controller, value))
+        })
+        .collect()
+    }
+}
+
+/// Turns MPE into MIDI 2.0 per-note messages.
+///
+/// Notes stay on their member channels, so the same pitch played on two of them sounds
+/// twice, and the pitch bend, pressure and timbre of a member channel become per-note pitch
+/// bend, poly pressure and per-note controller [`CONTROL_TIMBRE`] of its notes. Expression
+/// sent before a note starts is applied to it as it starts. Controllers, program changes,
+/// pressure and pitch bend sent on a manager channel apply to the whole zone, so they are
+/// copied to its member channels.
+/// Messages outside the zones are only upscaled.
+#[derive(Clone, Debug)]
+pub struct MpeTranslator {
+    /// Held notes of each member channel.
+    held: [u128; 16],
+    bend: [u32; 16],
+    pressure: [u32; 16],
+    /// The timbre of each member channel, once it has sent one.
+    timbre: [Option<u32>; 16],
+}
+
+impl Default for MpeTranslator {
+    fn default() -> Self {
+        Self {
+            held: [0; 16],
+            bend: [PITCH_BEND_CENTER_32; 16],
+            pressure: [0; 16],
+            timbre: [None; 16],
+        }
+    }
+}
+
+impl MpeTranslator {
+    pub fn translate(&mut self, settings: &MpeSettings, message: MidiMessage) -> Vec<Midi2Message> {
+        let Some(upscaled) = Midi2Message::from_midi1(message) else {
+            return Vec::new();
+        };
+        let channel = message.channel();
+        if let Some(zone) = settings.managed_zone(channel)
+            && matches!(
+                upscaled,
+                Midi2Message::ControlChange { .. }
+                    | Midi2Message::ProgramChange { .. }
+                    | Midi2Message::ChannelPressure { .. }
+                    | Midi2Message::PitchBend { .. }
+            )
+        {
+            return std::iter::once(upscaled)
+                .chain(
+                    settings
+                        .member_channels(zone)
+                        .map(|member| upscaled.with_channel(member)),
+                )
+                .collect();
+        }
+        if settings.member_zone(channel).is_none() {
+            return vec![upscaled];
+        }
+        let member = usize::from(channel);
+        let held = self.held[member];
+        let notes = move || (0..128u8).filter(move |note| held & (1 << note) != 0);
+
+        match upscaled {
+            Midi2Message::NoteOn { note, velocity, .. } => {
+                self.held[member] |= 1 << note;
+                let mut messages = vec![
+                    Midi2Message::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                    },
+                    Midi2Message::PerNotePitchBend {
+                        channel,
+                        note,
+                        value: self.bend[member],
+                    },
+                    Midi2Message::PolyPressure {
+                        channel,
+                        note,
+                        value: self.pressure[member],
+                    },
+                ];
+                messages
+                    .extend(self.timbre[member].map(|value| timbre_message(channel, note, value)));
+                messages
+            }
+            Midi2Message::NoteOff { note, velocity, .. } => {
+                self.held[member] &= !(1 << note);
+                vec![Midi2Message::NoteOff {
+                    channel,
+                    note,
+                    velocity,
+                }]
+            }
+            Midi2Message::PitchBend { value, .. } => {
+                self.bend[member] = value;
+                notes()
+                    .map(|note| Midi2Message::PerNotePitchBend {
+                        channel,
+                        note,
+                        value,
+                    })
+                    .collect()
+            }
+            Midi2Message::ChannelPressure { value, .. } => {
+                self.pressure[member] = value;
+                notes()
+                    .map(|note| Midi2Message::PolyPressure {
+                        channel,
+                        note,
+                        value,
+                    })
+                    .collect()
+            }
+            Midi2Message::ControlChange {
+                index: CONTROL_TIMBRE,
+                value,
+                ..
+            } => {
+                self.timbre[member] = Some(value);
+                notes()
+                    .map(|note| timbre_message(channel, note, value))
+                    .collect()
+            }
+            other => vec![other],
+        }
+    }
+}
+
+fn timbre_message(channel: u8, note: u8, value: u32) -> Midi2Message {
+    Midi2Message::PerNoteController {
+        channel,
+        note,
+        index: CONTROL_TIMBRE,
+        registered: false,
+        value,
+    }
+}
+
+/// The expression of a held note, each from 0.0 to 1.0 except `bend`, which goes from -1.0
+/// to 1.0 of the per-note bend range.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NoteExpression {
+    pub velocity: f32,
+    pub pressure: f32,
+    pub bend: f32,
+    pub timbre: f32,
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) with the expression of every held note, from
+/// MPE member channels and poly pressure.
+///
+/// Updated from [`PreUpdate`] by [`MidiInputPlugin`](super::input::MidiInputPlugin).
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PerNoteExpression {
+    /// By channel and note.
+    notes: HashMap<(u8, u8), NoteExpression>,
+    translator: MpeTranslator,
+}
+
+impl PerNoteExpression {
+    /// The expression of `note`, if it is held. When it's held on several channels, the
+    /// most pressed one.
+    #[must_use]
+    pub fn note(&self, note: u8) -> Option<&NoteExpression> {
+        self.notes
+            .iter()
+            .filter(|((_, n), _)| *n == note)
+            .map(|(_, expression)| expression)
+            .max_by(|a, b| a.pressure.total_cmp(&b.pressure))
+    }
+
+    /// Applies an incoming message.
+    pub fn apply(&mut self, settings: &MpeSettings, message: MidiMessage) {
+        for message in self.translator.translate(settings, message) {
+            match message {
+                Midi2Message::NoteOn {
+                    channel,
+                    note,
+                    velocity,
+                } => {
+                    self.notes.insert(
+                        (channel, note),
+                        NoteExpression {
+                            velocity: velocity_unit(velocity),
+                            timbre: 0.5,
+                            ..default()
+                        },
+                    );
+                }
+                Midi2Message::NoteOff { channel, note, .. } => {
+                    self.notes.remove(&(channel, note));
+                }
+                Midi2Message::PolyPressure {
+                    channel,
+                    note,
+                    value,
+                } => {
+                    if let Some(expression) = self.notes.get_mut(&(channel, note)) {
+                        expression.pressure = controller_unit(value);
+                    }
+                }
+                Midi2Message::PerNotePitchBend {
+                    channel,
+                    note,
+                    value,
+                } => {
+                    if let Some(expression) = self.notes.get_mut(&(channel, note)) {
+                        expression.bend = pitch_bend_unit(value);
+                    }
+                }
+                Midi2Message::PerNoteController {
+                    channel,
+                    note,
+                    index: CONTROL_TIMBRE,
+                    value,
+                    ..
+                } => {
+                    if let Some(expression) = self.notes.get_mut(&(channel, note)) {
+                        expression.timbre = controller_unit(value);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+pub(crate) fn update_expression(
+    mut settings: ResMut<MpeSettings>,
+    mut expression: ResMut<PerNoteExpression>,
+    mut midi: EventReader<MidiData>,
+    mut rpn: Local<[(u8, u8); 16]>,
+) {
+    for data in midi.read() {
+        let mut configured = *settings;
+        if configured.configure(&mut rpn, data.message) {
+            *settings = configured;
+        }
+        expression.apply(&settings, data.message);
+    }
+}
+
+#[test]
+fn test_mpe_member_channels_become_per_note_messages() {
+    let mut settings = MpeSettings::default();
+    let mut rpn = [(0, 0); 16];
+    let configuration = MpeSettings {
+        lower_members: 3,
+        upper_members: 0,
+    };
+    for message in configuration.configuration_messages() {
+        settings.configure(&mut rpn, message);
+    }
+    assert_eq!(settings.lower_members, 3);
+    assert_eq!(settings.member_zone(2), Some(MpeZone::Lower));
+    assert_eq!(settings.member_zone(4), None);
+    settings.set_zone(MpeZone::Upper, 13);
+    assert_eq!((settings.lower_members, settings.upper_members), (1, 13));
+    assert_eq!(settings.member_zone(2), Some(MpeZone::Upper));
+    settings.set_zone(MpeZone::Upper, 0);
+    settings.set_zone(MpeZone::Lower, 3);
+
+    let mut translator = MpeTranslator::default();
+    // Expression sent before the note applies to it
+    translator.translate(&settings, [0xE1, 0x00, 0x60].into());
+    let on = translator.translate(&settings, MidiMessage::note_on(1, 60, 100));
+    assert_eq!(on.len(), 3);
+    assert!(matches!(
+        on[0],
+        Midi2Message::NoteOn {
+            channel: 1,
+            note: 60,
+            ..
+        }
+    ));
+    assert!(matches!(
+        on[1],
+        Midi2Message::PerNotePitchBend { channel: 1, note: 60, value } if value > PITCH_BEND_CENTER_32
+    ));
+    assert!(matches!(
+        translator.translate(&settings, [0xD1, 127, 0].into())[..],
+        [Midi2Message::PolyPressure {
+            channel: 1,
+            note: 60,
+            value: u32::MAX
+        }]
+    ));
+    // The manager channel bends the whole zone
+    let bend = Midi2Message::PitchBend {
+        channel: 0,
+        value: PITCH_BEND_CENTER_32,
+    };
+    assert_eq!(
+        translator.translate(&settings, [0xE0, 0x00, 0x40].into()),
+        (0..4)
+            .map(|channel| bend.with_channel(channel))
+            .collect::<Vec<_>>()
+    );
+    // Channels outside the zone play as plain MIDI
+    assert!(matches!(
+        translator.translate(&settings, MidiMessage::note_on(9, 36, 100))[..],
+        [Midi2Message::NoteOn { channel: 9, .. }]
+    ));
+
+    let mut expression = PerNoteExpression::default();
+    expression.apply(&settings, MidiMessage::note_on(2, 64, 127));
+    expression.apply(
+        &settings,
+        MidiMessage::control_change(2, CONTROL_TIMBRE, 127),
+    );
+    expression.apply(&settings, [0xD2, 64, 0].into());
+    let note = expression.note(64).unwrap();
+    assert_eq!((note.velocity, note.timbre), (1.0, 1.0));
+    assert!((note.pressure - 0.5).abs() < 0.01);
+    // The same pitch on another member channel is a note of its own
+    expression.apply(&settings, MidiMessage::note_on(3, 64, 127));
+    expression.apply(&settings, MidiMessage::note_off(2, 64, 0));
+    assert_eq!(expression.note(64).map(|n| n.pressure), Some(0.0));
+    expression.apply(&settings, MidiMessage::note_off(3, 64, 0));
+    assert_eq!(expression.note(64), None);
+}
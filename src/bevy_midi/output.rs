@@ -1,12 +1,15 @@
-use super::MidiMessage;
-use MidiOutputError::{ConnectionError, PortRefreshError, SendDisconnectedError, SendError};
+use super::backend::{MidiOutputBackend, MidirOutput, MultiMidiOutput};
+use super::{MidiClockMessage, MidiMessage};
+use MidiOutputError::{
+    ConnectionError, PortRefreshError, SendDisconnectedError, SendError, WorkerStopped,
+};
 use bevy::prelude::*;
-use bevy::tasks::IoTaskPool;
 use crossbeam_channel::{Receiver, Sender};
 use midir::ConnectErrorKind;
-pub use midir::MidiOutputPort;
+use std::error::Error;
 use std::fmt::Display;
-use std::{error::Error, future::Future};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
 
 pub struct MidiOutputPlugin;
 
@@ -16,7 +19,8 @@ impl Plugin for MidiOutputPlugin {
             .init_resource::<MidiOutputConnection>()
             .add_event::<MidiOutputError>()
             .add_systems(Startup, setup)
-            .add_systems(PreUpdate, reply);
+            .add_systems(PreUpdate, reply)
+            .add_systems(Last, shutdown);
     }
 }
 
@@ -36,6 +40,64 @@ impl Default for MidiOutputSettings {
     }
 }
 
+/// Replaces the [`MidiOutputBackend`] used by [`MidiOutputPlugin`], e.g. with a
+/// [`MockMidiOutput`](super::backend::MockMidiOutput) in tests.
+///
+/// This resource must be added before [`MidiOutputPlugin`]'s `Startup` runs; without it the
+/// plugin talks to the system through [`MidirOutput`].
+#[derive(Resource)]
+pub struct MidiOutputBackendOverride(Mutex<Option<Box<dyn MidiOutputBackend>>>);
+
+impl MidiOutputBackendOverride {
+    #[must_use]
+    pub fn new(backend: impl MidiOutputBackend) -> Self {
+        Self(Mutex::new(Some(Box::new(backend))))
+    }
+
+    fn take(&self) -> Option<Box<dyn MidiOutputBackend>> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+/// Extra [`MidiOutputBackend`]s whose ports are listed alongside the main backend's, e.g.
+/// network sessions.
+///
+/// Like [`MidiOutputBackendOverride`], backends must be added before [`MidiOutputPlugin`]'s
+/// `Startup` runs.
+#[derive(Resource, Default)]
+pub struct MidiOutputBackendExtras(Mutex<Vec<Box<dyn MidiOutputBackend>>>);
+
+impl MidiOutputBackendExtras {
+    pub fn push(&self, backend: impl MidiOutputBackend) {
+        if let Ok(mut backends) = self.0.lock() {
+            backends.push(Box::new(backend));
+        }
+    }
+
+    fn take(&self) -> Vec<Box<dyn MidiOutputBackend>> {
+        self.0
+            .lock()
+            .map(|mut backends| std::mem::take(&mut *backends))
+            .unwrap_or_default()
+    }
+}
+
+/// An output port, identified by the stable id its backend gave it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MidiOutputPort(String);
+
+impl MidiOutputPort {
+    #[must_use]
+    pub fn new(id: impl Into<String>) -> Self {
+        Self(id.into())
+    }
+
+    #[must_use]
+    pub fn id(&self) -> &str {
+        &self.0
+    }
+}
+
 /// [`Resource`](bevy::ecs::system::Resource) for sending midi messages.
 ///
 /// Change detection will only fire when its input ports are refreshed.
@@ -43,36 +105,46 @@ impl Default for MidiOutputSettings {
 pub struct MidiOutput {
     sender: Sender<Message>,
     receiver: Receiver<Reply>,
+    // Lets the main thread report its own failures through `reply`
+    errors: Sender<Reply>,
+    worker: Option<JoinHandle<()>>,
     ports: Vec<(String, MidiOutputPort)>,
 }
 
 impl MidiOutput {
     /// Update the available output ports.
     pub fn refresh_ports(&self) {
-        self.sender
-            .send(Message::RefreshPorts)
-            .expect("Couldn't refresh output ports");
+        self.request(Message::RefreshPorts);
     }
 
     /// Connect to the given `port`.
     pub fn connect(&self, port: MidiOutputPort) {
-        self.sender
-            .send(Message::ConnectToPort(port))
-            .expect("Failed to connect to port");
+        self.request(Message::ConnectToPort(port));
     }
 
     /// Disconnect from the current output port.
     pub fn disconnect(&self) {
-        self.sender
-            .send(Message::DisconnectFromPort)
-            .expect("Failed to disconnect from port");
+        self.request(Message::DisconnectFromPort);
     }
 
     /// Send a midi message.
     pub fn send(&self, msg: MidiMessage) {
-        self.sender
-            .send(Message::Midi(msg))
-            .expect("Couldn't send MIDI message");
+        self.request(Message::Midi(msg));
+    }
+
+    /// Send a beat clock or transport message.
+    ///
+    /// Unlike [`send`](Self::send), this is silently dropped while disconnected, since
+    /// clock is sent continuously.
+    pub fn send_clock(&self, msg: MidiClockMessage) {
+        self.request(Message::Clock(msg));
+    }
+
+    /// Send raw bytes, e.g. a SysEx message.
+    ///
+    /// Like [`send_clock`](Self::send_clock), this is silently dropped while disconnected.
+    pub fn send_raw(&self, bytes: Vec<u8>) {
+        self.request(Message::Raw(bytes));
     }
 
     /// Get the current output ports, and their names.
@@ -80,6 +152,66 @@ impl MidiOutput {
     pub fn ports(&self) -> &Vec<(String, MidiOutputPort)> {
         &self.ports
     }
+
+    /// Get a handle for sending midi messages from outside the ECS, e.g. from a
+    /// scheduler thread.
+    #[must_use]
+    pub fn handle(&self) -> MidiOutputHandle {
+        MidiOutputHandle {
+            sender: self.sender.clone(),
+            errors: self.errors.clone(),
+        }
+    }
+
+    // If the worker has stopped, the failure is reported as a `MidiOutputError` event
+    // instead of panicking.
+    fn request(&self, msg: Message) {
+        if self.sender.send(msg).is_err() {
+            // We own the receiving end, so this can't fail.
+            let _ = self.errors.send(Reply::Error(WorkerStopped));
+        }
+    }
+
+    /// Stops the worker thread, closing any open connection.
+    fn shutdown(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = self.sender.send(Message::Shutdown);
+            if worker.join().is_err() {
+                warn!("midi output worker panicked");
+            }
+        }
+    }
+}
+
+impl Drop for MidiOutput {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A cloneable, thread-safe handle to [`MidiOutput`], for sending messages off the frame loop.
+///
+/// Sending after [`MidiOutput`] is gone does nothing.
+#[derive(Clone)]
+pub struct MidiOutputHandle {
+    sender: Sender<Message>,
+    errors: Sender<Reply>,
+}
+
+impl MidiOutputHandle {
+    /// Send a midi message.
+    pub fn send(&self, msg: MidiMessage) {
+        if self.sender.send(Message::Midi(msg)).is_err() {
+            let _ = self.errors.send(Reply::Error(WorkerStopped));
+        }
+    }
+
+    /// Send a beat clock or transport message, see [`MidiOutput::send_clock`].
+    pub fn send_clock(&self, msg: MidiClockMessage) {
+        if self.sender.send(Message::Clock(msg)).is_err() {
+            let _ = self.errors.send(Reply::Error(WorkerStopped));
+        }
+    }
 }
 
 /// [`Resource`](bevy::ecs::system::Resource) for checking whether [`MidiOutput`] is
@@ -105,6 +237,7 @@ pub enum MidiOutputError {
     SendError(midir::SendError),
     SendDisconnectedError(MidiMessage),
     PortRefreshError,
+    WorkerStopped,
 }
 
 impl Error for MidiOutputError {}
@@ -126,33 +259,60 @@ impl Display for MidiOutputError {
                 }
             },
             PortRefreshError => write!(f, "Couldn't refresh output ports")?,
+            WorkerStopped => write!(f, "Midi output worker has stopped")?,
         }
         Ok(())
     }
 }
 
-fn setup(mut commands: Commands, settings: Res<MidiOutputSettings>) {
+fn setup(
+    mut commands: Commands,
+    settings: Res<MidiOutputSettings>,
+    backend: Option<Res<MidiOutputBackendOverride>>,
+    extras: Option<Res<MidiOutputBackendExtras>>,
+) {
     let (m_sender, m_receiver) = crossbeam_channel::unbounded();
     let (r_sender, r_receiver) = crossbeam_channel::unbounded();
 
-    let thread_pool = IoTaskPool::get();
-    thread_pool
-        .spawn(MidiOutputTask {
-            receiver: m_receiver,
-            sender: r_sender,
-            settings: settings.clone(),
-            output: None,
-            connection: None,
-        })
-        .detach();
+    let backend = backend
+        .and_then(|b| b.take())
+        .unwrap_or_else(|| Box::new(MidirOutput::new(&settings)));
+    commands.remove_resource::<MidiOutputBackendOverride>();
+    let extras = extras.map(|e| e.take()).unwrap_or_default();
+    let backend: Box<dyn MidiOutputBackend> = if extras.is_empty() {
+        backend
+    } else {
+        let mut backends = vec![backend];
+        backends.extend(extras);
+        Box::new(MultiMidiOutput::new(backends))
+    };
+
+    let worker = MidiOutputWorker {
+        receiver: m_receiver,
+        sender: r_sender.clone(),
+        backend,
+    };
+    let worker = thread::Builder::new()
+        .name("bevy_midi output".to_string())
+        .spawn(move || worker.run())
+        .map_err(|e| warn!("Couldn't start midi output worker: {}", e))
+        .ok();
 
     commands.insert_resource(MidiOutput {
         sender: m_sender,
         receiver: r_receiver,
+        errors: r_sender,
+        worker,
         ports: Vec::new(),
     });
 }
 
+fn shutdown(mut output: ResMut<MidiOutput>, mut exit: EventReader<AppExit>) {
+    if exit.read().next().is_some() {
+        output.bypass_change_detection().shutdown();
+    }
+}
+
 fn reply(
     mut output: ResMut<MidiOutput>,
     mut conn: ResMut<MidiOutputConnection>,
@@ -165,7 +325,7 @@ fn reply(
             }
             Reply::Error(e) => {
                 warn!("{}", e);
-                err.send(e);
+                err.write(e);
             }
             Reply::Connected => {
                 conn.connected = true;
@@ -182,6 +342,9 @@ enum Message {
     ConnectToPort(MidiOutputPort),
     DisconnectFromPort,
     Midi(MidiMessage),
+    Clock(MidiClockMessage),
+    Raw(Vec<u8>),
+    Shutdown,
 }
 
 enum Reply {
@@ -191,125 +354,88 @@ enum Reply {
     Disconnected,
 }
 
-struct MidiOutputTask {
+struct MidiOutputWorker {
     receiver: Receiver<Message>,
     sender: Sender<Reply>,
-    settings: MidiOutputSettings,
-
-    // Invariant: exactly one of `output` or `connection` is Some
-    output: Option<midir::MidiOutput>,
-    connection: Option<(midir::MidiOutputConnection, MidiOutputPort)>,
+    backend: Box<dyn MidiOutputBackend>,
 }
 
-impl Future for MidiOutputTask {
-    type Output = ();
-
-    fn poll(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Self::Output> {
-        if self.output.is_none() && self.connection.is_none() {
-            self.output = midir::MidiOutput::new(self.settings.port_name).ok();
-            self.sender
-                .send(get_available_ports(self.output.as_ref().unwrap()))
-                .unwrap();
+impl MidiOutputWorker {
+    // Runs until told to shut down, or until the app drops its end of either channel.
+    fn run(mut self) {
+        let ports = get_available_ports(self.backend.as_mut());
+        if self.sender.send(ports).is_ok() {
+            while let Ok(msg) = self.receiver.recv() {
+                if matches!(msg, Message::Shutdown) || self.handle(msg).is_err() {
+                    break;
+                }
+            }
         }
+        self.backend.disconnect();
+    }
 
-        if let Ok(msg) = self.receiver.recv() {
-            use Message::{ConnectToPort, DisconnectFromPort, Midi, RefreshPorts};
-
-            match msg {
-                ConnectToPort(port) => {
-                    let was_connected = self.output.is_none();
-                    let out = self
-                        .output
-                        .take()
-                        .unwrap_or_else(|| self.connection.take().unwrap().0.close());
-                    match out.connect(&port, self.settings.port_name) {
-                        Ok(conn) => {
-                            self.connection = Some((conn, port));
-                            self.output = None;
-                            self.sender.send(Reply::Connected).unwrap();
-                        }
-                        Err(conn_err) => {
-                            self.sender
-                                .send(Reply::Error(ConnectionError(conn_err.kind())))
-                                .unwrap();
-                            if was_connected {
-                                self.sender.send(Reply::Disconnected).unwrap();
-                            }
-                            self.connection = None;
-                            self.output = Some(conn_err.into_inner());
+    fn handle(&mut self, msg: Message) -> Result<(), crossbeam_channel::SendError<Reply>> {
+        use Message::{
+            Clock, ConnectToPort, DisconnectFromPort, Midi, Raw, RefreshPorts, Shutdown,
+        };
+
+        match msg {
+            ConnectToPort(port) => {
+                let was_connected = self.backend.is_connected();
+                match self.backend.connect(&port) {
+                    Ok(()) => self.sender.send(Reply::Connected)?,
+                    Err(e) => {
+                        self.sender.send(Reply::Error(e))?;
+                        if was_connected {
+                            self.sender.send(Reply::Disconnected)?;
                         }
                     }
                 }
-                DisconnectFromPort => {
-                    if let Some((conn, _)) = self.connection.take() {
-                        self.output = Some(conn.close());
-                        self.connection = None;
-                        self.sender.send(Reply::Disconnected).unwrap();
-                    }
+            }
+            DisconnectFromPort => {
+                if self.backend.is_connected() {
+                    self.backend.disconnect();
+                    self.sender.send(Reply::Disconnected)?;
                 }
-                RefreshPorts => match &self.output {
-                    Some(out) => {
-                        self.sender.send(get_available_ports(out)).unwrap();
-                    }
-                    None => {
-                        let (conn, port) = self.connection.take().unwrap();
-                        let out = conn.close();
-
-                        self.sender.send(get_available_ports(&out)).unwrap();
-
-                        match out.connect(&port, self.settings.port_name) {
-                            Ok(conn) => {
-                                self.connection = Some((conn, port));
-                                self.output = None;
-                            }
-                            Err(conn_err) => {
-                                self.sender
-                                    .send(Reply::Error(ConnectionError(conn_err.kind())))
-                                    .unwrap();
-                                self.sender.send(Reply::Disconnected).unwrap();
-                                self.connection = None;
-                                self.output = Some(conn_err.into_inner());
-                            }
-                        }
-                    }
-                },
-                Midi(message) => {
-                    if let Some((conn, _)) = &mut self.connection {
-                        if let Err(e) = conn.send(&message.msg) {
-                            self.sender.send(Reply::Error(SendError(e))).unwrap();
-                        }
-                    } else {
-                        self.sender
-                            .send(Reply::Error(SendDisconnectedError(message)))
-                            .unwrap();
+            }
+            RefreshPorts => {
+                self.sender
+                    .send(get_available_ports(self.backend.as_mut()))?;
+            }
+            Midi(message) => {
+                if self.backend.is_connected() {
+                    if let Err(e) = self.backend.send(&message.msg) {
+                        self.sender.send(Reply::Error(e))?;
                     }
+                } else {
+                    self.sender
+                        .send(Reply::Error(SendDisconnectedError(message)))?;
                 }
             }
+            Clock(message) => self.send_connected(&message.to_bytes())?,
+            Raw(bytes) => self.send_connected(&bytes)?,
+            Shutdown => {}
         }
+        Ok(())
+    }
 
-        cx.waker().wake_by_ref();
-        std::task::Poll::Pending
+    // Sends `bytes` if connected, dropping them otherwise.
+    fn send_connected(&mut self, bytes: &[u8]) -> Result<(), crossbeam_channel::SendError<Reply>> {
+        if self.backend.is_connected()
+            && let Err(e) = self.backend.send(bytes)
+        {
+            self.sender.send(Reply::Error(e))?;
+        }
+        Ok(())
     }
 }
 
 // Helper for above.
 //
-// Returns either Reply::AvailablePorts or Reply::PortRefreshError
-// If there's an error getting port names, it's because the available ports changed,
-// so it tries again (up to 10 times)
-fn get_available_ports(output: &midir::MidiOutput) -> Reply {
-    for _ in 0..10 {
-        let ports = output.ports();
-        let ports: Result<Vec<_>, _> = ports
-            .into_iter()
-            .map(|p| output.port_name(&p).map(|n| (n, p)))
-            .collect();
-        if let Ok(ports) = ports {
-            return Reply::AvailablePorts(ports);
-        }
+// Returns either Reply::AvailablePorts or Reply::Error
+fn get_available_ports(backend: &mut dyn MidiOutputBackend) -> Reply {
+    match backend.ports() {
+        Ok(ports) => Reply::AvailablePorts(ports),
+        Err(e) => Reply::Error(e),
     }
-    Reply::Error(PortRefreshError)
 }
@@ -0,0 +1,1375 @@
+use super::backend::{MidiCallback, MidiInputBackend, MidiOutputBackend};
+use super::input::{MidiInputBackendExtras, MidiInputError, MidiInputPort};
+use super::output::{MidiOutputBackendExtras, MidiOutputError, MidiOutputPort};
+use super::{CONTROL_ALL_NOTES_OFF, CONTROL_ALL_SOUND_OFF};
+use bevy::prelude::*;
+use midir::ConnectErrorKind;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Shares midi with another machine over the network, using RTP-MIDI (RFC 6295) and the
+/// AppleMIDI session protocol spoken by macOS, iOS and rtpMIDI on Windows.
+///
+/// The session shows up as one more port in [`MidiInput`](super::input::MidiInput) and
+/// [`MidiOutput`](super::output::MidiOutput). The session is off until enabled in
+/// [`RtpMidiSettings`], which has to be inserted before adding the plugin; there's no
+/// Bonjour discovery, so peers have to be added by address.
+pub struct RtpMidiPlugin;
+
+impl Plugin for RtpMidiPlugin {
+    fn build(&self, app: &mut App) {
+        let settings = app
+            .world_mut()
+            .get_resource_or_init::<RtpMidiSettings>()
+            .clone();
+        if !settings.enabled {
+            return;
+        }
+        match RtpMidiSession::start(&settings) {
+            Ok(session) => {
+                app.world_mut()
+                    .get_resource_or_init::<MidiInputBackendExtras>()
+                    .push(RtpMidiInput::new(session.clone()));
+                app.world_mut()
+                    .get_resource_or_init::<MidiOutputBackendExtras>()
+                    .push(RtpMidiOutput::new(session.clone()));
+                app.insert_resource(session);
+            }
+            Err(e) => warn!("Couldn't start RTP-MIDI session: {}", e),
+        }
+    }
+}
+
+/// Which end of an [`RtpMidiSession`] sets it up.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RtpMidiRole {
+    /// Waits to be invited, with the control port on `port` and the data port on
+    /// `port + 1`.
+    Responder { port: u16 },
+    /// Invites the responder whose control port is `remote`, and keeps inviting it until
+    /// it answers. `port` is our own control port; 0 picks any free pair.
+    Initiator { remote: SocketAddr, port: u16 },
+}
+
+/// Settings for [`RtpMidiPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct RtpMidiSettings {
+    /// Whether to start the session at all.
+    pub enabled: bool,
+    /// The name peers see for us.
+    pub name: String,
+    pub role: RtpMidiRole,
+    /// The address to listen on. Loopback only lets this machine in; other machines need
+    /// [`Ipv4Addr::UNSPECIFIED`] or the address of the interface they're on.
+    pub address: IpAddr,
+    /// Peers whose invitations are accepted. Invitations from anywhere else are declined.
+    pub allowed_peers: Vec<IpAddr>,
+}
+
+impl Default for RtpMidiSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            name: "Orion".to_string(),
+            role: RtpMidiRole::Responder { port: 5004 },
+            address: Ipv4Addr::LOCALHOST.into(),
+            allowed_peers: vec![Ipv4Addr::LOCALHOST.into()],
+        }
+    }
+}
+
+const SIGNATURE: [u8; 2] = [0xFF, 0xFF];
+const PROTOCOL_VERSION: u32 = 2;
+
+/// A packet of the AppleMIDI session protocol, sent on both the control and data ports.
+///
+/// Timestamps are in units of 100 microseconds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AppleMidiPacket {
+    Invitation {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Accepted {
+        token: u32,
+        ssrc: u32,
+        name: String,
+    },
+    Rejected {
+        token: u32,
+        ssrc: u32,
+    },
+    End {
+        ssrc: u32,
+    },
+    /// Clock synchronization, a three-way exchange with `count` 0, 1 and 2.
+    Sync {
+        ssrc: u32,
+        count: u8,
+        timestamps: [u64; 3],
+    },
+    /// Receiver feedback: every packet up to `sequence` has arrived.
+    Feedback {
+        ssrc: u32,
+        sequence: u16,
+    },
+}
+
+impl AppleMidiPacket {
+    #[must_use]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader(bytes);
+        if r.take(2)? != SIGNATURE {
+            return None;
+        }
+        let command = r.take(2)?;
+        match command {
+            b"IN" | b"OK" | b"NO" | b"BY" => {
+                let _version = r.u32()?;
+                let token = r.u32()?;
+                let ssrc = r.u32()?;
+                let name = r.0.split(|b| *b == 0).next().unwrap_or_default();
+                let name = String::from_utf8_lossy(name).into_owned();
+                Some(match command {
+                    b"IN" => Self::Invitation { token, ssrc, name },
+                    b"OK" => Self::Accepted { token, ssrc, name },
+                    b"NO" => Self::Rejected { token, ssrc },
+                    _ => Self::End { ssrc },
+                })
+            }
+            b"CK" => {
+                let ssrc = r.u32()?;
+                let count = r.u8()?;
+                r.take(3)?;
+                Some(Self::Sync {
+                    ssrc,
+                    count,
+                    timestamps: [r.u64()?, r.u64()?, r.u64()?],
+                })
+            }
+            b"RS" => Some(Self::Feedback {
+                ssrc: r.u32()?,
+                sequence: (r.u32()? >> 16) as u16,
+            }),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = SIGNATURE.to_vec();
+        match self {
+            Self::Invitation { token, ssrc, name } => {
+                write_session(&mut out, b"IN", *token, *ssrc, Some(name.as_str()))
+            }
+            Self::Accepted { token, ssrc, name } => {
+                write_session(&mut out, b"OK", *token, *ssrc, Some(name.as_str()))
+            }
+            Self::Rejected { token, ssrc } => write_session(&mut out, b"NO", *token, *ssrc, None),
+            Self::End { ssrc } => write_session(&mut out, b"BY", 0, *ssrc, None),
+            Self::Sync {
+                ssrc,
+                count,
+                timestamps,
+            } => {
+                out.extend(b"CK");
+                out.extend(ssrc.to_be_bytes());
+                out.extend([*count, 0, 0, 0]);
+                for timestamp in timestamps {
+                    out.extend(timestamp.to_be_bytes());
+                }
+            }
+            Self::Feedback { ssrc, sequence } => {
+                out.extend(b"RS");
+                out.extend(ssrc.to_be_bytes());
+                out.extend((u32::from(*sequence) << 16).to_be_bytes());
+            }
+        }
+        out
+    }
+}
+
+fn write_session(out: &mut Vec<u8>, command: &[u8; 2], token: u32, ssrc: u32, name: Option<&str>) {
+    out.extend(command);
+    out.extend(PROTOCOL_VERSION.to_be_bytes());
+    out.extend(token.to_be_bytes());
+    out.extend(ssrc.to_be_bytes());
+    if let Some(name) = name {
+        out.extend(name.as_bytes());
+        out.push(0);
+    }
+}
+
+const RTP_VERSION: u8 = 0x80;
+const RTP_MIDI_PAYLOAD_TYPE: u8 = 0x61;
+
+/// An RTP packet carrying midi commands, and a recovery journal for the ones lost before
+/// it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtpMidiPacket {
+    pub sequence: u16,
+    /// The sender's clock, in units of 100 microseconds.
+    pub timestamp: u32,
+    pub ssrc: u32,
+    /// Complete midi messages; together they must fit in 4095 bytes.
+    pub commands: Vec<Vec<u8>>,
+    pub journal: Option<RecoveryJournal>,
+}
+
+impl RtpMidiPacket {
+    #[must_use]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader(bytes);
+        let [flags, _payload_type] = r.take(2)?.try_into().ok()?;
+        if flags & 0xC0 != RTP_VERSION {
+            return None;
+        }
+        let sequence = r.u16()?;
+        let timestamp = r.u32()?;
+        let ssrc = r.u32()?;
+        r.take(4 * usize::from(flags & 0x0F))?;
+        if flags & 0x10 != 0 {
+            r.take(2)?;
+            let words = r.u16()?;
+            r.take(4 * usize::from(words))?;
+        }
+        if flags & 0x20 != 0 {
+            let padding = usize::from(*r.0.last()?);
+            r.0 = &r.0[..r.0.len().checked_sub(padding)?];
+        }
+
+        let header = r.u8()?;
+        let mut length = usize::from(header & 0x0F);
+        if header & 0x80 != 0 {
+            length = (length << 8) | usize::from(r.u8()?);
+        }
+        let commands = parse_commands(r.take(length)?, header & 0x20 != 0)?;
+        let journal = if header & 0x40 != 0 {
+            Some(RecoveryJournal::parse(r.0)?)
+        } else {
+            None
+        };
+        Some(Self {
+            sequence,
+            timestamp,
+            ssrc,
+            commands,
+            journal,
+        })
+    }
+
+    #[must_use]
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = vec![RTP_VERSION, RTP_MIDI_PAYLOAD_TYPE];
+        out.extend(self.sequence.to_be_bytes());
+        out.extend(self.timestamp.to_be_bytes());
+        out.extend(self.ssrc.to_be_bytes());
+
+        // Every command after the first is sent at the same time
+        let mut list = Vec::new();
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                list.push(0);
+            }
+            list.extend(command);
+        }
+        debug_assert!(list.len() <= 0x0FFF);
+        let journal = if self.journal.is_some() { 0x40 } else { 0 };
+        if list.len() < 0x10 {
+            out.push(journal | list.len() as u8);
+        } else {
+            out.push(0x80 | journal | ((list.len() >> 8) as u8 & 0x0F));
+            out.push(list.len() as u8);
+        }
+        out.extend(list);
+        if let Some(journal) = &self.journal {
+            journal.write(&mut out);
+        }
+        out
+    }
+}
+
+// Splits a command list into complete messages, dropping the delta times.
+fn parse_commands(mut list: &[u8], first_has_delta: bool) -> Option<Vec<Vec<u8>>> {
+    let mut commands = Vec::new();
+    let mut running = None;
+    while !list.is_empty() {
+        if !commands.is_empty() || first_has_delta {
+            let delta = list.iter().take(4).position(|b| b & 0x80 == 0)? + 1;
+            list = &list[delta..];
+        }
+        let status = match *list.first()? {
+            status if status & 0x80 != 0 => {
+                list = &list[1..];
+                status
+            }
+            _ => running?,
+        };
+        let length = match status {
+            0xF0 => list.iter().position(|b| *b == 0xF7)? + 1,
+            0xF1 | 0xF3 => 1,
+            0xF2 => 2,
+            0xF4..=0xFF => 0,
+            _ if matches!(status & 0xF0, 0xC0 | 0xD0) => 1,
+            _ => 2,
+        };
+        if status < 0xF0 {
+            running = Some(status);
+        } else if status < 0xF8 {
+            running = None;
+        }
+        let mut command = vec![status];
+        command.extend(list.get(..length)?);
+        list = &list[length..];
+        commands.push(command);
+    }
+    Some(commands)
+}
+
+/// The recovery journal of an [`RtpMidiPacket`]: the note state of the sender since the
+/// last packet the receiver acknowledged.
+///
+/// Only chapter N (notes) is written; other chapters are skipped when reading.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryJournal {
+    /// The first sequence number this journal covers.
+    pub checkpoint: u16,
+    pub channels: Vec<ChannelJournal>,
+}
+
+/// The notes of a channel in a [`RecoveryJournal`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChannelJournal {
+    pub channel: u8,
+    /// Notes being held, with their velocities.
+    pub notes: Vec<(u8, u8)>,
+    /// Notes released since the checkpoint.
+    pub released: Vec<u8>,
+}
+
+impl RecoveryJournal {
+    #[must_use]
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let mut r = Reader(bytes);
+        let flags = r.u8()?;
+        let checkpoint = r.u16()?;
+        if flags & 0x40 != 0 {
+            let length = r.u16()? & 0x03FF;
+            r.take(usize::from(length).checked_sub(2)?)?;
+        }
+        let mut channels = Vec::new();
+        if flags & 0x20 != 0 {
+            for _ in 0..=flags & 0x0F {
+                let header = r.u16()?;
+                let toc = r.u8()?;
+                let length = usize::from(header & 0x03FF);
+                let chapters = r.take(length.checked_sub(3)?)?;
+                let channel = (header >> 11) as u8 & 0x0F;
+                if let Some(journal) = ChannelJournal::parse(channel, toc, chapters) {
+                    channels.push(journal);
+                }
+            }
+        }
+        Some(Self {
+            checkpoint,
+            channels,
+        })
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let channels = self.channels.len().min(16);
+        let flags = if channels > 0 {
+            0x20 | (channels - 1) as u8
+        } else {
+            0
+        };
+        out.push(flags);
+        out.extend(self.checkpoint.to_be_bytes());
+        for journal in &self.channels[..channels] {
+            journal.write(out);
+        }
+    }
+
+    /// The messages bringing a receiver whose notes are `held` back in line with the
+    /// sender.
+    #[must_use]
+    pub fn recover(&self, held: &HeldNotes) -> Vec<[u8; 3]> {
+        let mut messages = Vec::new();
+        for journal in &self.channels {
+            let channel = journal.channel & 0x0F;
+            for &(note, velocity) in &journal.notes {
+                if !held.is_held(channel, note) {
+                    messages.push([0x90 | channel, note, velocity]);
+                }
+            }
+            for &note in &journal.released {
+                let pressed = journal.notes.iter().any(|(n, _)| *n == note);
+                if held.is_held(channel, note) && !pressed {
+                    messages.push([0x80 | channel, note, 0]);
+                }
+            }
+        }
+        messages
+    }
+}
+
+impl ChannelJournal {
+    const CHAPTER_N: u8 = 0x08;
+
+    fn parse(channel: u8, toc: u8, chapters: &[u8]) -> Option<Self> {
+        if toc & Self::CHAPTER_N == 0 {
+            return None;
+        }
+        // Skip chapters P, C, M and W, which come before N
+        let mut r = Reader(chapters);
+        if toc & 0x80 != 0 {
+            r.take(3)?;
+        }
+        if toc & 0x40 != 0 {
+            let logs = usize::from(r.u8()? & 0x7F) + 1;
+            r.take(2 * logs)?;
+        }
+        if toc & 0x20 != 0 {
+            let length = r.u16()? & 0x03FF;
+            r.take(usize::from(length).checked_sub(2)?)?;
+        }
+        if toc & 0x10 != 0 {
+            r.take(2)?;
+        }
+
+        let [length, bounds] = r.take(2)?.try_into().ok()?;
+        let (low, high) = (bounds >> 4, bounds & 0x0F);
+        let logs = match length & 0x7F {
+            127 if low == 1 && high == 0 => 128,
+            length => length,
+        };
+        let mut notes = Vec::new();
+        for _ in 0..logs {
+            let [note, velocity] = r.take(2)?.try_into().ok()?;
+            if velocity & 0x7F != 0 {
+                notes.push((note & 0x7F, velocity & 0x7F));
+            }
+        }
+        let mut released = Vec::new();
+        if low <= high {
+            for octet in low..=high {
+                let bits = r.u8()?;
+                released.extend(
+                    (0..8)
+                        .filter(|b| bits & (0x80 >> b) != 0)
+                        .map(|b| octet * 8 + b),
+                );
+            }
+        }
+        Some(Self {
+            channel,
+            notes,
+            released,
+        })
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let notes = &self.notes[..self.notes.len().min(127)];
+        let mut chapter = vec![notes.len() as u8];
+        // LOW = 15 and HIGH = 0 means there are no offbits
+        let (low, high) = match (self.released.iter().min(), self.released.iter().max()) {
+            (Some(min), Some(max)) => (min / 8, max / 8),
+            _ => (15, 0),
+        };
+        chapter.push((low << 4) | high);
+        for &(note, velocity) in notes {
+            chapter.extend([note & 0x7F, 0x80 | velocity & 0x7F]);
+        }
+        if low <= high {
+            let mut offbits = vec![0u8; usize::from(high - low) + 1];
+            for note in &self.released {
+                offbits[usize::from(note / 8 - low)] |= 0x80 >> (note % 8);
+            }
+            chapter.extend(offbits);
+        }
+
+        let length = 3 + chapter.len();
+        out.push(((self.channel & 0x0F) << 3) | ((length >> 8) as u8 & 0x03));
+        out.push(length as u8);
+        out.push(Self::CHAPTER_N);
+        out.extend(chapter);
+    }
+}
+
+/// The notes held on each channel, as seen by one end of a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeldNotes([u128; 16]);
+
+impl HeldNotes {
+    pub fn apply(&mut self, message: &[u8]) {
+        let &[status, data1, data2] = message else {
+            return;
+        };
+        let channel = usize::from(status & 0x0F);
+        let bit = 1u128 << (data1 & 0x7F);
+        match status & 0xF0 {
+            0x90 if data2 != 0 => self.0[channel] |= bit,
+            0x80 | 0x90 => self.0[channel] &= !bit,
+            0xB0 if data1 == CONTROL_ALL_NOTES_OFF || data1 == CONTROL_ALL_SOUND_OFF => {
+                self.0[channel] = 0;
+            }
+            _ => {}
+        }
+    }
+
+    #[must_use]
+    pub fn is_held(&self, channel: u8, note: u8) -> bool {
+        self.0[usize::from(channel & 0x0F)] & (1 << (note & 0x7F)) != 0
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        (0..16u8).flat_map(move |channel| {
+            (0..128u8)
+                .filter(move |note| self.is_held(channel, *note))
+                .map(move |note| (channel, note))
+        })
+    }
+}
+
+// What the sending side puts in its recovery journals.
+#[derive(Default)]
+struct JournalState {
+    held: BTreeMap<(u8, u8), u8>,
+    // Released notes, with the sequence number that released them
+    released: BTreeMap<(u8, u8), u16>,
+    checkpoint: u16,
+}
+
+impl JournalState {
+    fn journal(&self) -> Option<RecoveryJournal> {
+        if self.held.is_empty() && self.released.is_empty() {
+            return None;
+        }
+        let mut channels = BTreeMap::<u8, ChannelJournal>::new();
+        for (&(channel, note), &velocity) in &self.held {
+            let journal = channels.entry(channel).or_default();
+            journal.channel = channel;
+            journal.notes.push((note, velocity));
+        }
+        for &(channel, note) in self.released.keys() {
+            let journal = channels.entry(channel).or_default();
+            journal.channel = channel;
+            journal.released.push(note);
+        }
+        Some(RecoveryJournal {
+            checkpoint: self.checkpoint,
+            channels: channels.into_values().collect(),
+        })
+    }
+
+    // Records `message`, sent with `sequence`.
+    fn apply(&mut self, sequence: u16, message: &[u8]) {
+        let &[status, data1, data2] = message else {
+            return;
+        };
+        let channel = status & 0x0F;
+        let released = match status & 0xF0 {
+            0x90 if data2 != 0 => {
+                self.held.insert((channel, data1), data2);
+                self.released.remove(&(channel, data1));
+                vec![]
+            }
+            0x80 | 0x90 => vec![data1],
+            0xB0 if data1 == CONTROL_ALL_NOTES_OFF || data1 == CONTROL_ALL_SOUND_OFF => self
+                .held
+                .keys()
+                .filter(|(c, _)| *c == channel)
+                .map(|(_, note)| *note)
+                .collect(),
+            _ => vec![],
+        };
+        for note in released {
+            if self.held.remove(&(channel, note)).is_some() {
+                self.released.insert((channel, note), sequence);
+            }
+        }
+    }
+
+    // The receiver has everything up to `sequence`, so stop journaling it.
+    fn acknowledge(&mut self, sequence: u16) {
+        self.released.retain(|_, s| sequence_after(*s, sequence));
+        self.checkpoint = sequence.wrapping_add(1);
+    }
+}
+
+fn sequence_after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+const INVITE_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// [`Resource`](bevy::ecs::system::Resource) for a running RTP-MIDI session with a single
+/// peer.
+///
+/// The session runs on its own thread until the last clone is dropped, which says goodbye
+/// to the peer.
+#[derive(Resource, Clone)]
+pub struct RtpMidiSession(Arc<SessionHandle>);
+
+struct SessionHandle {
+    shared: Arc<Shared>,
+    data: UdpSocket,
+    control_addr: SocketAddr,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for SessionHandle {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Shared {
+    name: String,
+    ssrc: u32,
+    start: Instant,
+    state: Mutex<SessionState>,
+    changed: Condvar,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    // Our session clock, in units of 100 microseconds
+    fn ticks(&self) -> u64 {
+        (self.start.elapsed().as_micros() / 100) as u64
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SessionState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    peer: Option<Peer>,
+    callback: Option<MidiCallback>,
+    // Sending
+    sequence: u16,
+    journal: JournalState,
+    // Receiving
+    expected: Option<u16>,
+    unacknowledged: Option<u16>,
+    held: HeldNotes,
+    latency: Option<Duration>,
+    // Ticks to add to the peer's clock to get ours
+    offset: Option<i64>,
+}
+
+impl SessionState {
+    fn connected(&self) -> Option<&Peer> {
+        self.peer.as_ref().filter(|p| p.data.is_some())
+    }
+
+    // Forgets the peer, releasing the notes it left held.
+    fn hang_up(&mut self, stamp: u64) {
+        let held = std::mem::take(&mut self.held);
+        if let Some(callback) = &mut self.callback {
+            for (channel, note) in held.iter() {
+                callback(stamp, &[0x80 | channel, note, 0]);
+            }
+        }
+        let callback = self.callback.take();
+        *self = Self {
+            callback,
+            ..default()
+        };
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Peer {
+    name: String,
+    ssrc: u32,
+    control: SocketAddr,
+    data: Option<SocketAddr>,
+}
+
+impl RtpMidiSession {
+    /// Binds the session's ports and starts listening, or inviting its peer.
+    pub fn start(settings: &RtpMidiSettings) -> io::Result<Self> {
+        let port = match settings.role {
+            RtpMidiRole::Responder { port } | RtpMidiRole::Initiator { port, .. } => port,
+        };
+        let (control, data) = bind_pair(settings.address, port)?;
+        control.set_nonblocking(true)?;
+        data.set_read_timeout(Some(POLL_INTERVAL))?;
+        let control_addr = control.local_addr()?;
+
+        let random = RandomState::new().hash_one(Instant::now());
+        let shared = Arc::new(Shared {
+            name: settings.name.clone(),
+            ssrc: random as u32,
+            start: Instant::now(),
+            state: Mutex::default(),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+        let worker = SessionWorker {
+            shared: shared.clone(),
+            role: settings.role.clone(),
+            allowed_peers: settings.allowed_peers.clone(),
+            token: (random >> 32) as u32,
+            data: data.try_clone()?,
+            control,
+            last_invite: None,
+            last_sync: None,
+            last_feedback: None,
+        };
+        let worker = thread::Builder::new()
+            .name("rtp-midi session".to_string())
+            .spawn(move || worker.run())?;
+        Ok(Self(Arc::new(SessionHandle {
+            shared,
+            data,
+            control_addr,
+            worker: Some(worker),
+        })))
+    }
+
+    /// Sends a complete midi message to the peer.
+    pub fn send(&self, message: &[u8]) -> io::Result<()> {
+        let shared = &self.0.shared;
+        let mut state = shared.lock();
+        let Some(to) = state.connected().and_then(|p| p.data) else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        let sequence = state.sequence;
+        state.sequence = sequence.wrapping_add(1);
+        // The journal covers what was sent before this packet
+        let packet = RtpMidiPacket {
+            sequence,
+            timestamp: shared.ticks() as u32,
+            ssrc: shared.ssrc,
+            commands: vec![message.to_vec()],
+            journal: state.journal.journal(),
+        };
+        state.journal.apply(sequence, message);
+        self.0.data.send_to(&packet.write(), to).map(|_| ())
+    }
+
+    /// Sets the callback receiving the peer's midi, timestamped in microseconds.
+    pub fn set_callback(&self, callback: Option<MidiCallback>) {
+        self.0.shared.lock().callback = callback;
+    }
+
+    #[must_use]
+    pub fn is_connected(&self) -> bool {
+        self.0.shared.lock().connected().is_some()
+    }
+
+    /// Blocks until a peer is connected.
+    ///
+    /// Returns `false` if `timeout` elapsed first.
+    pub fn wait_connected(&self, timeout: Duration) -> bool {
+        let shared = &self.0.shared;
+        let state = shared
+            .changed
+            .wait_timeout_while(shared.lock(), timeout, |s| s.connected().is_none())
+            .map(|(state, _)| state)
+            .unwrap_or_else(|e| e.into_inner().0);
+        state.connected().is_some()
+    }
+
+    /// The name the connected peer gave.
+    #[must_use]
+    pub fn peer_name(&self) -> Option<String> {
+        self.0.shared.lock().connected().map(|p| p.name.clone())
+    }
+
+    /// The one-way network latency to the peer, once clocks have been synchronized.
+    #[must_use]
+    pub fn latency(&self) -> Option<Duration> {
+        self.0.shared.lock().latency
+    }
+
+    /// Our control port; the data port is the one after it.
+    #[must_use]
+    pub fn control_addr(&self) -> SocketAddr {
+        self.0.control_addr
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.0.shared.name
+    }
+
+    fn port_id(&self) -> String {
+        format!("rtp:{}", self.name())
+    }
+
+    fn port_name(&self) -> String {
+        match self.peer_name() {
+            Some(peer) => format!("RTP-MIDI: {peer}"),
+            None => format!("RTP-MIDI: {} (waiting)", self.name()),
+        }
+    }
+}
+
+// Binds the control port and the data port after it.
+fn bind_pair(address: IpAddr, port: u16) -> io::Result<(UdpSocket, UdpSocket)> {
+    if port != 0 {
+        let control = UdpSocket::bind((address, port))?;
+        let data = UdpSocket::bind((address, port.wrapping_add(1)))?;
+        return Ok((control, data));
+    }
+    let mut error = io::ErrorKind::AddrInUse.into();
+    for _ in 0..16 {
+        let control = UdpSocket::bind((address, 0))?;
+        let Some(next) = control.local_addr()?.port().checked_add(1) else {
+            continue;
+        };
+        match UdpSocket::bind((address, next)) {
+            Ok(data) => return Ok((control, data)),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+struct SessionWorker {
+    shared: Arc<Shared>,
+    role: RtpMidiRole,
+    allowed_peers: Vec<IpAddr>,
+    token: u32,
+    control: UdpSocket,
+    data: UdpSocket,
+    last_invite: Option<Instant>,
+    last_sync: Option<Instant>,
+    last_feedback: Option<Instant>,
+}
+
+impl SessionWorker {
+    fn run(mut self) {
+        let mut buffer = vec![0; 0x10000];
+        while !self.shared.stop.load(Ordering::Relaxed) {
+            while let Ok((n, from)) = self.control.recv_from(&mut buffer) {
+                self.on_control(&buffer[..n], from);
+            }
+            // Also paces the loop, as the data socket times out
+            if let Ok((n, from)) = self.data.recv_from(&mut buffer) {
+                self.on_data(&buffer[..n], from);
+            }
+            self.on_tick();
+        }
+
+        let now = self.shared.ticks() * 100;
+        let mut state = self.shared.lock();
+        if let Some(peer) = &state.peer {
+            let bye = AppleMidiPacket::End {
+                ssrc: self.shared.ssrc,
+            };
+            let _ = self.control.send_to(&bye.write(), peer.control);
+        }
+        state.hang_up(now);
+    }
+
+    fn reply(&self, on_data: bool, packet: &AppleMidiPacket, to: SocketAddr) {
+        let socket = if on_data { &self.data } else { &self.control };
+        if let Err(e) = socket.send_to(&packet.write(), to) {
+            debug!("Couldn't send to RTP-MIDI peer {}: {}", to, e);
+        }
+    }
+
+    fn on_control(&mut self, bytes: &[u8], from: SocketAddr) {
+        let Some(packet) = AppleMidiPacket::parse(bytes) else {
+            return;
+        };
+        match packet {
+            AppleMidiPacket::Invitation { token, ssrc, name } => {
+                self.on_invitation(false, token, ssrc, name, from);
+            }
+            AppleMidiPacket::Accepted { ssrc, name, .. } => {
+                let mut state = self.shared.lock();
+                let invited =
+                    matches!(self.role, RtpMidiRole::Initiator { remote, .. } if remote == from);
+                if state.peer.is_none() && invited {
+                    state.peer = Some(Peer {
+                        name,
+                        ssrc,
+                        control: from,
+                        data: None,
+                    });
+                    // Invite the data port right away
+                    self.last_invite = None;
+                }
+            }
+            AppleMidiPacket::Feedback { ssrc, sequence } => {
+                let mut state = self.shared.lock();
+                if state.peer.as_ref().is_some_and(|p| p.ssrc == ssrc) {
+                    state.journal.acknowledge(sequence);
+                }
+            }
+            packet => self.on_session(packet),
+        }
+    }
+
+    fn on_data(&mut self, bytes: &[u8], from: SocketAddr) {
+        if !bytes.starts_with(&SIGNATURE) {
+            if let Some(packet) = RtpMidiPacket::parse(bytes) {
+                self.on_midi(packet);
+            }
+            return;
+        }
+        match AppleMidiPacket::parse(bytes) {
+            Some(AppleMidiPacket::Invitation { token, ssrc, name }) => {
+                self.on_invitation(true, token, ssrc, name, from);
+            }
+            Some(AppleMidiPacket::Accepted { ssrc, .. }) => {
+                let mut state = self.shared.lock();
+                let peer = state.peer.as_mut();
+                if let Some(peer) = peer.filter(|p| p.ssrc == ssrc && p.data.is_none()) {
+                    peer.data = Some(from);
+                    info!("RTP-MIDI session connected to {}", peer.name);
+                    self.last_sync = None;
+                    self.shared.changed.notify_all();
+                }
+            }
+            Some(AppleMidiPacket::Sync {
+                ssrc,
+                count,
+                timestamps,
+            }) => self.on_sync(ssrc, count, timestamps, from),
+            Some(packet) => self.on_session(packet),
+            None => {}
+        }
+    }
+
+    // Rejections and goodbyes, on either port.
+    fn on_session(&mut self, packet: AppleMidiPacket) {
+        match packet {
+            AppleMidiPacket::Rejected { .. } => {
+                debug!("RTP-MIDI peer declined our invitation");
+            }
+            AppleMidiPacket::End { ssrc } => {
+                let mut state = self.shared.lock();
+                if state.peer.as_ref().is_some_and(|p| p.ssrc == ssrc) {
+                    info!("RTP-MIDI peer ended the session");
+                    state.hang_up(self.shared.ticks() * 100);
+                    self.shared.changed.notify_all();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_invitation(
+        &mut self,
+        on_data: bool,
+        token: u32,
+        ssrc: u32,
+        name: String,
+        from: SocketAddr,
+    ) {
+        let accept = AppleMidiPacket::Accepted {
+            token,
+            ssrc: self.shared.ssrc,
+            name: self.shared.name.clone(),
+        };
+        let reject = AppleMidiPacket::Rejected {
+            token,
+            ssrc: self.shared.ssrc,
+        };
+        if !matches!(self.role, RtpMidiRole::Responder { .. }) {
+            self.reply(on_data, &reject, from);
+            return;
+        }
+        if !self.allowed_peers.contains(&from.ip()) {
+            info!(
+                "Declined RTP-MIDI invitation from {}, which isn't an allowed peer",
+                from
+            );
+            self.reply(on_data, &reject, from);
+            return;
+        }
+
+        let mut state = self.shared.lock();
+        match &mut state.peer {
+            // One peer at a time
+            Some(peer) if peer.ssrc != ssrc => self.reply(on_data, &reject, from),
+            Some(peer) => {
+                if !on_data {
+                    peer.control = from;
+                } else if peer.data.is_none() {
+                    peer.data = Some(from);
+                    info!("RTP-MIDI session connected to {}", peer.name);
+                    self.shared.changed.notify_all();
+                }
+                self.reply(on_data, &accept, from);
+            }
+            None if !on_data => {
+                state.peer = Some(Peer {
+                    name,
+                    ssrc,
+                    control: from,
+                    data: None,
+                });
+                self.reply(on_data, &accept, from);
+            }
+            None => self.reply(on_data, &reject, from),
+        }
+    }
+
+    // The three-way clock exchange: the initiator sends its time, the responder adds its
+    // own, and the initiator adds the time it got that back.
+    fn on_sync(&mut self, ssrc: u32, count: u8, timestamps: [u64; 3], from: SocketAddr) {
+        let now = self.shared.ticks();
+        let mut state = self.shared.lock();
+        if state.connected().is_none_or(|p| p.ssrc != ssrc) {
+            return;
+        }
+        let [t1, t2, _] = timestamps;
+        let (round_trip, offset) = match count {
+            0 => {
+                let reply = AppleMidiPacket::Sync {
+                    ssrc: self.shared.ssrc,
+                    count: 1,
+                    timestamps: [t1, now, 0],
+                };
+                self.reply(true, &reply, from);
+                return;
+            }
+            1 => {
+                let reply = AppleMidiPacket::Sync {
+                    ssrc: self.shared.ssrc,
+                    count: 2,
+                    timestamps: [t1, t2, now],
+                };
+                self.reply(true, &reply, from);
+                // The responder read its clock halfway through our round trip
+                (now.saturating_sub(t1), (t1 + now) as i64 / 2 - t2 as i64)
+            }
+            _ => {
+                let t3 = timestamps[2];
+                (now.saturating_sub(t2), t2 as i64 - (t1 + t3) as i64 / 2)
+            }
+        };
+        state.latency = Some(Duration::from_micros(round_trip * 50));
+        state.offset = Some(offset);
+    }
+
+    fn on_midi(&mut self, packet: RtpMidiPacket) {
+        let now = self.shared.ticks();
+        let mut state = self.shared.lock();
+        let state = &mut *state;
+        if state.connected().is_none_or(|p| p.ssrc != packet.ssrc) {
+            return;
+        }
+        let stamp = match state.offset {
+            // Unwrap the peer's 32 bit timestamp around where we expect it to be
+            Some(offset) => {
+                let expected = now as i64 - offset;
+                let delta = i64::from(packet.timestamp.wrapping_sub(expected as u32) as i32);
+                (expected + delta + offset).clamp(0, now as i64) as u64
+            }
+            None => now,
+        } * 100;
+
+        let mut messages = Vec::new();
+        if let Some(expected) = state.expected.filter(|e| *e != packet.sequence) {
+            if !sequence_after(packet.sequence, expected) {
+                // Late or duplicated
+                return;
+            }
+            if let Some(journal) = &packet.journal {
+                debug!(
+                    "Recovering {} lost RTP-MIDI packets",
+                    packet.sequence.wrapping_sub(expected)
+                );
+                messages.extend(journal.recover(&state.held).iter().map(|m| m.to_vec()));
+            }
+        }
+        state.expected = Some(packet.sequence.wrapping_add(1));
+        state.unacknowledged = Some(packet.sequence);
+
+        messages.extend(packet.commands);
+        for message in messages {
+            state.held.apply(&message);
+            if let Some(callback) = &mut state.callback {
+                callback(stamp, &message);
+            }
+        }
+    }
+
+    fn on_tick(&mut self) {
+        fn due(last: &mut Option<Instant>, every: Duration) -> bool {
+            let due = last.is_none_or(|l| l.elapsed() >= every);
+            if due {
+                *last = Some(Instant::now());
+            }
+            due
+        }
+
+        let mut state = self.shared.lock();
+        if let RtpMidiRole::Initiator { remote, .. } = self.role {
+            let invitation = AppleMidiPacket::Invitation {
+                token: self.token,
+                ssrc: self.shared.ssrc,
+                name: self.shared.name.clone(),
+            };
+            match state.peer.as_ref().map(|p| p.data) {
+                None if due(&mut self.last_invite, INVITE_INTERVAL) => {
+                    self.reply(false, &invitation, remote);
+                }
+                Some(None) if due(&mut self.last_invite, INVITE_INTERVAL) => {
+                    let data = SocketAddr::new(remote.ip(), remote.port().wrapping_add(1));
+                    self.reply(true, &invitation, data);
+                }
+                Some(Some(data)) if due(&mut self.last_sync, SYNC_INTERVAL) => {
+                    let sync = AppleMidiPacket::Sync {
+                        ssrc: self.shared.ssrc,
+                        count: 0,
+                        timestamps: [self.shared.ticks(), 0, 0],
+                    };
+                    self.reply(true, &sync, data);
+                }
+                _ => {}
+            }
+        }
+
+        let control = state.connected().map(|p| p.control);
+        if let Some(control) = control
+            && due(&mut self.last_feedback, FEEDBACK_INTERVAL)
+            && let Some(sequence) = state.unacknowledged.take()
+        {
+            let feedback = AppleMidiPacket::Feedback {
+                ssrc: self.shared.ssrc,
+                sequence,
+            };
+            self.reply(false, &feedback, control);
+        }
+    }
+}
+
+/// The [`MidiInputBackend`] half of an [`RtpMidiSession`], with a single port.
+pub struct RtpMidiInput {
+    session: RtpMidiSession,
+    connected: bool,
+}
+
+impl RtpMidiInput {
+    #[must_use]
+    pub fn new(session: RtpMidiSession) -> Self {
+        Self {
+            session,
+            connected: false,
+        }
+    }
+}
+
+impl MidiInputBackend for RtpMidiInput {
+    fn ports(&mut self) -> Result<Vec<(String, MidiInputPort)>, MidiInputError> {
+        Ok(vec![(
+            self.session.port_name(),
+            MidiInputPort::new(self.session.port_id()),
+        )])
+    }
+
+    fn connect(
+        &mut self,
+        port: &MidiInputPort,
+        callback: MidiCallback,
+    ) -> Result<(), MidiInputError> {
+        self.disconnect();
+        if port.id() != self.session.port_id() {
+            return Err(MidiInputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        }
+        self.session.set_callback(Some(callback));
+        self.connected = true;
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.session.set_callback(None);
+        self.connected = false;
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+}
+
+/// The [`MidiOutputBackend`] half of an [`RtpMidiSession`], with a single port.
+///
+/// Sending fails while no peer is connected.
+pub struct RtpMidiOutput {
+    session: RtpMidiSession,
+    connected: bool,
+}
+
+impl RtpMidiOutput {
+    #[must_use]
+    pub fn new(session: RtpMidiSession) -> Self {
+        Self {
+            session,
+            connected: false,
+        }
+    }
+}
+
+impl MidiOutputBackend for RtpMidiOutput {
+    fn ports(&mut self) -> Result<Vec<(String, MidiOutputPort)>, MidiOutputError> {
+        Ok(vec![(
+            self.session.port_name(),
+            MidiOutputPort::new(self.session.port_id()),
+        )])
+    }
+
+    fn connect(&mut self, port: &MidiOutputPort) -> Result<(), MidiOutputError> {
+        self.connected = port.id() == self.session.port_id();
+        if !self.connected {
+            return Err(MidiOutputError::ConnectionError(
+                ConnectErrorKind::InvalidPort,
+            ));
+        }
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn send(&mut self, message: &[u8]) -> Result<(), MidiOutputError> {
+        if !self.connected {
+            return Err(MidiOutputError::SendError(midir::SendError::Other(
+                "output is disconnected",
+            )));
+        }
+        self.session.send(message).map_err(|e| {
+            MidiOutputError::SendError(midir::SendError::Other(
+                if e.kind() == io::ErrorKind::NotConnected {
+                    "no RTP-MIDI peer is connected"
+                } else {
+                    "couldn't send RTP-MIDI packet"
+                },
+            ))
+        })
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, rest) = self.0.split_at_checked(n)?;
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+#[test]
+fn test_rtp_midi_loopback_session() {
+    // A receiver that missed everything but the first NoteOn catches up from the journal
+    let mut journal = JournalState::default();
+    journal.apply(0, &[0x90, 60, 100]);
+    journal.apply(1, &[0x91, 64, 90]);
+    journal.apply(2, &[0x80, 60, 0]);
+    let packet = RtpMidiPacket {
+        sequence: 3,
+        timestamp: 1234,
+        ssrc: 7,
+        commands: vec![vec![0x90, 67, 80], vec![0xF0, 0x7D, 0x01, 0xF7]],
+        journal: journal.journal(),
+    };
+    let parsed = RtpMidiPacket::parse(&packet.write()).unwrap();
+    assert_eq!(parsed, packet);
+    let mut held = HeldNotes::default();
+    held.apply(&[0x90, 60, 100]);
+    assert_eq!(
+        parsed.journal.unwrap().recover(&held),
+        vec![[0x80, 60, 0], [0x91, 64, 90]]
+    );
+    journal.acknowledge(2);
+    assert_eq!(journal.journal().unwrap().channels.len(), 1);
+
+    let invite = |responder: &RtpMidiSession| {
+        RtpMidiSession::start(&RtpMidiSettings {
+            name: "initiator".to_string(),
+            role: RtpMidiRole::Initiator {
+                remote: SocketAddr::from((Ipv4Addr::LOCALHOST, responder.control_addr().port())),
+                port: 0,
+            },
+            ..default()
+        })
+        .unwrap()
+    };
+
+    // Peers that aren't allowed are turned away
+    let closed = RtpMidiSession::start(&RtpMidiSettings {
+        role: RtpMidiRole::Responder { port: 0 },
+        allowed_peers: Vec::new(),
+        ..default()
+    })
+    .unwrap();
+    assert!(!invite(&closed).wait_connected(Duration::from_millis(300)));
+    assert!(!closed.is_connected());
+
+    let responder = RtpMidiSession::start(&RtpMidiSettings {
+        name: "responder".to_string(),
+        role: RtpMidiRole::Responder { port: 0 },
+        ..default()
+    })
+    .unwrap();
+    let initiator = invite(&responder);
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    responder.set_callback(Some(Box::new(move |_, message| {
+        let _ = sender.send(message.to_vec());
+    })));
+
+    assert!(initiator.wait_connected(Duration::from_secs(5)));
+    assert!(responder.wait_connected(Duration::from_secs(5)));
+    assert_eq!(responder.peer_name().as_deref(), Some("initiator"));
+    initiator.send(&[0x90, 60, 100]).unwrap();
+    let timeout = Duration::from_secs(5);
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), vec![0x90, 60, 100]);
+
+    let deadline = Instant::now() + timeout;
+    while initiator.latency().is_none() && Instant::now() < deadline {
+        thread::sleep(POLL_INTERVAL);
+    }
+    assert!(initiator.latency().is_some());
+
+    // Hanging up releases what the peer left held
+    drop(initiator);
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), vec![0x80, 60, 0]);
+    assert!(!responder.is_connected());
+}
@@ -0,0 +1,225 @@
+use super::input::MidiData;
+use super::{
+    CONTROL_ALL_NOTES_OFF, CONTROL_ALL_SOUND_OFF, CONTROL_RESET_ALL_CONTROLLERS, CONTROL_SUSTAIN,
+};
+use bevy::prelude::*;
+
+/// Centre value of the 14-bit pitch bend range.
+pub const PITCH_BEND_CENTER: u16 = 8192;
+
+/// A note that is currently held down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeldNote {
+    pub velocity: u8,
+    /// The [`MidiData::stamp`] of the NoteOn that started it.
+    pub stamp: u64,
+}
+
+/// The state of a single midi channel.
+#[derive(Clone, Debug)]
+pub struct ChannelState {
+    notes: [Option<HeldNote>; 128],
+    controllers: [u8; 128],
+    pitch_bend: u16,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            notes: [None; 128],
+            controllers: [0; 128],
+            pitch_bend: PITCH_BEND_CENTER,
+        }
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) tracking the held notes, controllers and pitch
+/// bend of every input channel, so systems can ask `is_held(60)` instead of replaying
+/// [`MidiData`] events.
+///
+/// Updated from [`PreUpdate`] by [`MidiInputPlugin`](super::input::MidiInputPlugin).
+/// Change detection only fires on frames that received midi data.
+#[derive(Resource, Clone, Debug)]
+pub struct MidiState {
+    channels: [ChannelState; 16],
+}
+
+impl Default for MidiState {
+    fn default() -> Self {
+        Self {
+            channels: std::array::from_fn(|_| ChannelState::default()),
+        }
+    }
+}
+
+impl MidiState {
+    /// Whether `note` is held on any channel.
+    #[must_use]
+    pub fn is_held(&self, note: u8) -> bool {
+        self.channels.iter().any(|c| c.note(note).is_some())
+    }
+
+    /// The held `note` on `channel`, if it is held.
+    #[must_use]
+    pub fn note(&self, channel: u8, note: u8) -> Option<&HeldNote> {
+        self.channel(channel).note(note)
+    }
+
+    /// Every held note as `(channel, note, held)`, ordered by channel then pitch.
+    pub fn held_notes(&self) -> impl Iterator<Item = (u8, u8, &HeldNote)> {
+        self.channels.iter().zip(0u8..).flat_map(|(c, channel)| {
+            c.notes
+                .iter()
+                .zip(0u8..)
+                .filter_map(move |(held, note)| Some((channel, note, held.as_ref()?)))
+        })
+    }
+
+    /// The distinct held pitches across all channels, lowest first.
+    #[must_use]
+    pub fn held_keys(&self) -> Vec<u8> {
+        (0..128).filter(|&note| self.is_held(note)).collect()
+    }
+
+    /// The current value of controller `cc` on `channel`.
+    #[must_use]
+    pub fn controller(&self, channel: u8, cc: u8) -> u8 {
+        self.channel(channel).controllers[cc as usize & 0x7f]
+    }
+
+    /// The raw 14-bit pitch bend on `channel`, where [`PITCH_BEND_CENTER`] is no bend.
+    #[must_use]
+    pub fn pitch_bend(&self, channel: u8) -> u16 {
+        self.channel(channel).pitch_bend
+    }
+
+    /// The pitch bend on `channel`, scaled to `-1.0..=1.0`.
+    #[must_use]
+    pub fn pitch_bend_normalized(&self, channel: u8) -> f32 {
+        (self.pitch_bend(channel) as f32 - PITCH_BEND_CENTER as f32) / PITCH_BEND_CENTER as f32
+    }
+
+    /// Whether the sustain pedal (CC 64) is down on `channel`.
+    #[must_use]
+    pub fn sustain(&self, channel: u8) -> bool {
+        self.controller(channel, CONTROL_SUSTAIN) >= 64
+    }
+
+    /// Applies an incoming message.
+    ///
+    /// Returns `true` if the set of held notes changed.
+    pub fn apply(&mut self, data: &MidiData) -> bool {
+        let message = data.message;
+        let [_, data1, data2] = message.msg;
+        let channel = &mut self.channels[message.channel() as usize];
+
+        if message.is_note_on() {
+            let note = &mut channel.notes[data1 as usize & 0x7f];
+            let changed = note.is_none();
+            *note = Some(HeldNote {
+                velocity: data2,
+                stamp: data.stamp,
+            });
+            changed
+        } else if message.is_note_off() {
+            channel.notes[data1 as usize & 0x7f].take().is_some()
+        } else if message.is_control_change() {
+            let cc = data1 & 0x7f;
+            channel.controllers[cc as usize] = data2;
+            match cc {
+                CONTROL_ALL_SOUND_OFF | CONTROL_ALL_NOTES_OFF => {
+                    let changed = channel.notes.iter().any(Option::is_some);
+                    channel.notes = [None; 128];
+                    changed
+                }
+                CONTROL_RESET_ALL_CONTROLLERS => {
+                    channel.controllers = [0; 128];
+                    channel.pitch_bend = PITCH_BEND_CENTER;
+                    false
+                }
+                _ => false,
+            }
+        } else if message.is_pitch_bend() {
+            channel.pitch_bend = (u16::from(data2 & 0x7f) << 7) | u16::from(data1 & 0x7f);
+            false
+        } else {
+            false
+        }
+    }
+
+    /// Forgets every held note and resets all controllers.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    #[must_use]
+    pub fn channel(&self, channel: u8) -> &ChannelState {
+        &self.channels[channel as usize & 0x0f]
+    }
+}
+
+impl ChannelState {
+    #[must_use]
+    pub fn note(&self, note: u8) -> Option<&HeldNote> {
+        self.notes[note as usize & 0x7f].as_ref()
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) fired when the set of held keys changes, e.g.
+/// when a chord is played or released.
+///
+/// This event fires from [`PreUpdate`], at most once per frame.
+#[derive(Event, Clone, Debug, PartialEq, Eq)]
+pub struct HeldNotesChanged {
+    /// The distinct held pitches across all channels, lowest first.
+    pub notes: Vec<u8>,
+}
+
+pub(crate) fn update_state(
+    mut state: ResMut<MidiState>,
+    mut midi: EventReader<MidiData>,
+    mut changed: EventWriter<HeldNotesChanged>,
+) {
+    let mut notes_changed = false;
+    for data in midi.read() {
+        notes_changed |= state.apply(data);
+    }
+    if notes_changed {
+        changed.write(HeldNotesChanged {
+            notes: state.held_keys(),
+        });
+    }
+}
+
+#[test]
+fn test_midi_state_tracks_notes_and_controllers() {
+    let mut state = MidiState::default();
+    let data = |msg: [u8; 3]| MidiData {
+        stamp: 5,
+        message: msg.into(),
+    };
+
+    assert!(state.apply(&data([0x90, 60, 100])));
+    assert!(state.apply(&data([0x91, 64, 90])));
+    assert!(!state.apply(&data([0xB0, CONTROL_SUSTAIN, 127])));
+    assert!(!state.apply(&data([0xE1, 0x00, 0x60])));
+
+    assert!(state.is_held(60));
+    assert_eq!(
+        state.note(0, 60),
+        Some(&HeldNote {
+            velocity: 100,
+            stamp: 5
+        })
+    );
+    assert_eq!(state.held_keys(), vec![60, 64]);
+    assert!(state.sustain(0));
+    assert!(!state.sustain(1));
+    assert_eq!(state.pitch_bend(1), 0x60 << 7);
+
+    // NoteOn with velocity 0 is a NoteOff
+    assert!(state.apply(&data([0x90, 60, 0])));
+    assert!(!state.apply(&data([0x80, 60, 0])));
+    assert!(state.apply(&data([0xB1, CONTROL_ALL_NOTES_OFF, 0])));
+    assert!(state.held_keys().is_empty());
+}
@@ -0,0 +1,118 @@
+//! Mapping [`MidiData::stamp`](super::input::MidiData) to when the message actually arrived.
+//!
+//! Backends stamp messages in microseconds from an epoch of their own, e.g. when the port
+//! was opened. [`MidiTimestamps`] estimates that epoch from when messages reach the input
+//! worker, so systems running a frame later can still tell exactly when a key was pressed.
+
+use bevy::prelude::*;
+use std::time::{Duration, Instant};
+
+/// [`Resource`](bevy::ecs::system::Resource) mapping the stamps of the connected input to
+/// [`Instant`]s, and measuring how long messages take to reach the frame.
+///
+/// Updated from [`PreUpdate`] by [`MidiInputPlugin`](super::input::MidiInputPlugin), and
+/// reset whenever a port connects.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct MidiTimestamps {
+    /// When stamp 0 happened.
+    epoch: Option<Instant>,
+    last_received: Option<Instant>,
+    /// How long messages took from their stamp to the [`PreUpdate`] that fired them.
+    pub delivery: LatencyStats,
+}
+
+impl MidiTimestamps {
+    /// How fast the epoch may move forward, in case the backend's clock runs slower than
+    /// ours.
+    const DRIFT: u32 = 1000;
+
+    /// Records that the message stamped `stamp` reached the input worker at `received`.
+    pub fn observe(&mut self, stamp: u64, received: Instant) {
+        let Some(epoch) = received.checked_sub(Duration::from_micros(stamp)) else {
+            return;
+        };
+        // Messages are never received before they're stamped, so the earliest epoch seen
+        // has the least delivery delay in it.
+        self.epoch = Some(match (self.epoch, self.last_received) {
+            (Some(current), Some(last)) => {
+                epoch.min(current + received.saturating_duration_since(last) / Self::DRIFT)
+            }
+            _ => epoch,
+        });
+        self.last_received = Some(received);
+    }
+
+    /// Forgets the epoch, e.g. because another port connected.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// When the message stamped `stamp` happened, or `None` before any message arrived.
+    #[must_use]
+    pub fn instant(&self, stamp: u64) -> Option<Instant> {
+        Some(self.epoch? + Duration::from_micros(stamp))
+    }
+
+    /// The stamp the connected input would give a message at `instant`, so messages from
+    /// elsewhere can be mixed in with its own.
+    #[must_use]
+    pub fn stamp(&self, instant: Instant) -> Option<u64> {
+        let since = instant.saturating_duration_since(self.epoch?);
+        Some(since.as_micros() as u64)
+    }
+
+    /// How long ago the message stamped `stamp` happened.
+    #[must_use]
+    pub fn latency(&self, stamp: u64) -> Option<Duration> {
+        Some(self.instant(stamp)?.elapsed())
+    }
+}
+
+/// Running statistics of a latency.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyStats {
+    pub last: Duration,
+    /// Exponential moving average over roughly the last 32 measurements.
+    pub mean: Duration,
+    pub max: Duration,
+    pub count: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, latency: Duration) {
+        self.mean = if self.count == 0 {
+            latency
+        } else {
+            self.mean.mul_f64(31.0 / 32.0) + latency / 32
+        };
+        self.last = latency;
+        self.max = self.max.max(latency);
+        self.count += 1;
+    }
+}
+
+#[test]
+fn test_stamps_map_to_the_earliest_delivery() {
+    let start = Instant::now();
+    let at = |micros| start + Duration::from_micros(micros);
+    let mut timestamps = MidiTimestamps::default();
+    assert_eq!(timestamps.instant(0), None);
+
+    // Delivered 2ms late, then 500us late
+    timestamps.observe(1_000, at(3_000));
+    assert_eq!(timestamps.instant(1_000), Some(at(3_000)));
+    timestamps.observe(11_000, at(11_500));
+    assert_eq!(timestamps.instant(20_000), Some(at(20_500)));
+    assert_eq!(timestamps.stamp(at(20_500)), Some(20_000));
+    // A slower delivery only lets the epoch drift forward a little
+    timestamps.observe(12_000, at(20_000));
+    let drifted = timestamps.instant(20_000).unwrap() - at(20_500);
+    assert!(drifted < Duration::from_micros(10));
+
+    let mut stats = LatencyStats::default();
+    stats.record(Duration::from_millis(4));
+    stats.record(Duration::from_millis(36));
+    assert_eq!(stats.last, Duration::from_millis(36));
+    assert_eq!(stats.mean, Duration::from_millis(5));
+    assert_eq!((stats.max, stats.count), (Duration::from_millis(36), 2));
+}
@@ -0,0 +1,455 @@
+//! MIDI 2.0 channel voice messages, as carried in Universal MIDI Packets.
+//!
+//! MIDI 2.0 notes have 16 bit velocities and every controller is 32 bits wide, and notes can
+//! be bent and controlled on their own rather than through their channel. Messages from MIDI
+//! 1.0 devices are upscaled with [`Midi2Message::from_midi1`], so the synth only has to
+//! understand one kind of message.
+
+use super::MidiMessage;
+
+/// Message type of MIDI 2.0 channel voice packets, in the top nibble of the first word.
+const MIDI2_CHANNEL_VOICE: u32 = 0x4;
+
+const REGISTERED_PER_NOTE_CONTROLLER: u8 = 0x0;
+const ASSIGNABLE_PER_NOTE_CONTROLLER: u8 = 0x1;
+const PER_NOTE_PITCH_BEND: u8 = 0x6;
+const NOTE_OFF: u8 = 0x8;
+const NOTE_ON: u8 = 0x9;
+const POLY_PRESSURE: u8 = 0xA;
+const CONTROL_CHANGE: u8 = 0xB;
+const PROGRAM_CHANGE: u8 = 0xC;
+const CHANNEL_PRESSURE: u8 = 0xD;
+const PITCH_BEND: u8 = 0xE;
+
+/// The center of a 32 bit pitch bend, where notes play at their own pitch.
+pub const PITCH_BEND_CENTER_32: u32 = 0x8000_0000;
+
+/// A MIDI 2.0 channel voice message.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Midi2Message {
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u16,
+    },
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u16,
+    },
+    /// Pressure on a single held note.
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        value: u32,
+    },
+    ControlChange {
+        channel: u8,
+        index: u8,
+        value: u32,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    /// Pressure on every note of the channel.
+    ChannelPressure {
+        channel: u8,
+        value: u32,
+    },
+    /// Bends every note of the channel, centered on [`PITCH_BEND_CENTER_32`].
+    PitchBend {
+        channel: u8,
+        value: u32,
+    },
+    /// Bends a single held note, centered on [`PITCH_BEND_CENTER_32`].
+    PerNotePitchBend {
+        channel: u8,
+        note: u8,
+        value: u32,
+    },
+    /// A controller of a single held note. Registered controllers have a meaning defined by
+    /// the MIDI 2.0 specification, assignable ones are up to the receiver.
+    PerNoteController {
+        channel: u8,
+        note: u8,
+        index: u8,
+        registered: bool,
+        value: u32,
+    },
+}
+
+impl Midi2Message {
+    /// Upscales a MIDI 1.0 channel voice message, returning `None` for messages without a
+    /// MIDI 2.0 equivalent.
+    ///
+    /// A note on with velocity 0 becomes a note off, since MIDI 2.0 note ons can have any
+    /// velocity.
+    #[must_use]
+    pub fn from_midi1(message: MidiMessage) -> Option<Self> {
+        let [status, data1, data2] = message.msg;
+        let channel = message.channel();
+        let data1 = data1 & 0x7f;
+        let data2 = data2 & 0x7f;
+        let message = match status >> 4 {
+            NOTE_OFF => Self::NoteOff {
+                channel,
+                note: data1,
+                velocity: scale_up(data2.into(), 7, 16) as u16,
+            },
+            NOTE_ON if data2 == 0 => Self::NoteOff {
+                channel,
+                note: data1,
+                velocity: 0,
+            },
+            NOTE_ON => Self::NoteOn {
+                channel,
+                note: data1,
+                velocity: scale_up(data2.into(), 7, 16) as u16,
+            },
+            POLY_PRESSURE => Self::PolyPressure {
+                channel,
+                note: data1,
+                value: scale_up(data2.into(), 7, 32),
+            },
+            CONTROL_CHANGE => Self::ControlChange {
+                channel,
+                index: data1,
+                value: scale_up(data2.into(), 7, 32),
+            },
+            PROGRAM_CHANGE => Self::ProgramChange {
+                channel,
+                program: data1,
+            },
+            CHANNEL_PRESSURE => Self::ChannelPressure {
+                channel,
+                value: scale_up(data1.into(), 7, 32),
+            },
+            PITCH_BEND => Self::PitchBend {
+                channel,
+                value: scale_up(u32::from(data1) | (u32::from(data2) << 7), 14, 32),
+            },
+            _ => return None,
+        };
+        Some(message)
+    }
+
+    /// Downscales the message for MIDI 1.0 devices, returning `None` for per-note messages,
+    /// which MIDI 1.0 can't express.
+    #[must_use]
+    pub fn to_midi1(self) -> Option<MidiMessage> {
+        let (status, channel, data1, data2) = match self {
+            Self::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (NOTE_OFF, channel, note, (velocity >> 9) as u8),
+            Self::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (NOTE_ON, channel, note, ((velocity >> 9) as u8).max(1)),
+            Self::PolyPressure {
+                channel,
+                note,
+                value,
+            } => (POLY_PRESSURE, channel, note, (value >> 25) as u8),
+            Self::ControlChange {
+                channel,
+                index,
+                value,
+            } => (CONTROL_CHANGE, channel, index, (value >> 25) as u8),
+            Self::ProgramChange { channel, program } => (PROGRAM_CHANGE, channel, program, 0),
+            Self::ChannelPressure { channel, value } => {
+                (CHANNEL_PRESSURE, channel, (value >> 25) as u8, 0)
+            }
+            Self::PitchBend { channel, value } => {
+                let value = value >> 18;
+                (
+                    PITCH_BEND,
+                    channel,
+                    (value & 0x7f) as u8,
+                    (value >> 7) as u8,
+                )
+            }
+            Self::PerNotePitchBend { .. } | Self::PerNoteController { .. } => return None,
+        };
+        Some([(status << 4) | (channel & 0x0f), data1 & 0x7f, data2 & 0x7f].into())
+    }
+
+    /// The channel the message is sent on.
+    #[must_use]
+    pub fn channel(&self) -> u8 {
+        match *self {
+            Self::NoteOff { channel, .. }
+            | Self::NoteOn { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelPressure { channel, .. }
+            | Self::PitchBend { channel, .. }
+            | Self::PerNotePitchBend { channel, .. }
+            | Self::PerNoteController { channel, .. } => channel,
+        }
+    }
+
+    /// The same message sent on `channel` instead.
+    #[must_use]
+    pub fn with_channel(mut self, to: u8) -> Self {
+        match &mut self {
+            Self::NoteOff { channel, .. }
+            | Self::NoteOn { channel, .. }
+            | Self::PolyPressure { channel, .. }
+            | Self::ControlChange { channel, .. }
+            | Self::ProgramChange { channel, .. }
+            | Self::ChannelPressure { channel, .. }
+            | Self::PitchBend { channel, .. }
+            | Self::PerNotePitchBend { channel, .. }
+            | Self::PerNoteController { channel, .. } => *channel = to,
+        }
+        self
+    }
+
+    /// Encodes the message as a 64 bit Universal MIDI Packet in `group`.
+    #[must_use]
+    pub fn to_ump(self, group: u8) -> [u32; 2] {
+        let (opcode, channel, byte3, byte4, data) = match self {
+            Self::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => (NOTE_OFF, channel, note, 0, u32::from(velocity) << 16),
+            Self::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => (NOTE_ON, channel, note, 0, u32::from(velocity) << 16),
+            Self::PolyPressure {
+                channel,
+                note,
+                value,
+            } => (POLY_PRESSURE, channel, note, 0, value),
+            Self::ControlChange {
+                channel,
+                index,
+                value,
+            } => (CONTROL_CHANGE, channel, index, 0, value),
+            Self::ProgramChange { channel, program } => (
+                PROGRAM_CHANGE,
+                channel,
+                0,
+                0,
+                u32::from(program & 0x7f) << 24,
+            ),
+            Self::ChannelPressure { channel, value } => (CHANNEL_PRESSURE, channel, 0, 0, value),
+            Self::PitchBend { channel, value } => (PITCH_BEND, channel, 0, 0, value),
+            Self::PerNotePitchBend {
+                channel,
+                note,
+                value,
+            } => (PER_NOTE_PITCH_BEND, channel, note, 0, value),
+            Self::PerNoteController {
+                channel,
+                note,
+                index,
+                registered,
+                value,
+            } => (
+                if registered {
+                    REGISTERED_PER_NOTE_CONTROLLER
+                } else {
+                    ASSIGNABLE_PER_NOTE_CONTROLLER
+                },
+                channel,
+                note,
+                index,
+                value,
+            ),
+        };
+        [
+            (MIDI2_CHANNEL_VOICE << 28)
+                | (u32::from(group & 0x0f) << 24)
+                | (u32::from(opcode) << 20)
+                | (u32::from(channel & 0x0f) << 16)
+                | (u32::from(byte3 & 0x7f) << 8)
+                | u32::from(byte4),
+            data,
+        ]
+    }
+
+    /// Decodes a 64 bit Universal MIDI Packet, returning its group and message, or `None` if
+    /// it isn't a MIDI 2.0 channel voice message this understands.
+    #[must_use]
+    pub fn from_ump(packet: [u32; 2]) -> Option<(u8, Self)> {
+        let [header, data] = packet;
+        if header >> 28 != MIDI2_CHANNEL_VOICE {
+            return None;
+        }
+        let group = ((header >> 24) & 0x0f) as u8;
+        let opcode = ((header >> 20) & 0x0f) as u8;
+        let channel = ((header >> 16) & 0x0f) as u8;
+        let byte3 = ((header >> 8) & 0x7f) as u8;
+        let byte4 = (header & 0xff) as u8;
+        let message = match opcode {
+            NOTE_OFF => Self::NoteOff {
+                channel,
+                note: byte3,
+                velocity: (data >> 16) as u16,
+            },
+            NOTE_ON => Self::NoteOn {
+                channel,
+                note: byte3,
+                velocity: (data >> 16) as u16,
+            },
+            POLY_PRESSURE => Self::PolyPressure {
+                channel,
+                note: byte3,
+                value: data,
+            },
+            CONTROL_CHANGE => Self::ControlChange {
+                channel,
+                index: byte3,
+                value: data,
+            },
+            PROGRAM_CHANGE => Self::ProgramChange {
+                channel,
+                program: ((data >> 24) & 0x7f) as u8,
+            },
+            CHANNEL_PRESSURE => Self::ChannelPressure {
+                channel,
+                value: data,
+            },
+            PITCH_BEND => Self::PitchBend {
+                channel,
+                value: data,
+            },
+            PER_NOTE_PITCH_BEND => Self::PerNotePitchBend {
+                channel,
+                note: byte3,
+                value: data,
+            },
+            REGISTERED_PER_NOTE_CONTROLLER | ASSIGNABLE_PER_NOTE_CONTROLLER => {
+                Self::PerNoteController {
+                    channel,
+                    note: byte3,
+                    index: byte4,
+                    registered: opcode == REGISTERED_PER_NOTE_CONTROLLER,
+                    value: data,
+                }
+            }
+            _ => return None,
+        };
+        Some((group, message))
+    }
+}
+
+/// Scales `value` from `src_bits` up to `dst_bits` wide, so the minimum, center and maximum
+/// of the smaller range land on the minimum, center and maximum of the larger one.
+///
+/// This is the min-center-max scaling of the MIDI 2.0 specification: values above the
+/// center repeat their lower bits into the new low bits, so 127 becomes all ones.
+#[must_use]
+pub fn scale_up(value: u32, src_bits: u32, dst_bits: u32) -> u32 {
+    let scale_bits = dst_bits - src_bits;
+    let shifted = value << scale_bits;
+    let center = 1 << (src_bits - 1);
+    if value <= center {
+        return shifted;
+    }
+    let repeat_bits = src_bits - 1;
+    let mut repeat = value & ((1 << repeat_bits) - 1);
+    if scale_bits > repeat_bits {
+        repeat <<= scale_bits - repeat_bits;
+    } else {
+        repeat >>= repeat_bits - scale_bits;
+    }
+    let mut scaled = shifted;
+    while repeat != 0 {
+        scaled |= repeat;
+        repeat >>= repeat_bits;
+    }
+    scaled
+}
+
+/// A 16 bit velocity from 0.0 to 1.0.
+#[must_use]
+pub fn velocity_unit(velocity: u16) -> f32 {
+    f32::from(velocity) / f32::from(u16::MAX)
+}
+
+/// A 32 bit controller value from 0.0 to 1.0.
+#[must_use]
+pub fn controller_unit(value: u32) -> f32 {
+    (f64::from(value) / f64::from(u32::MAX)) as f32
+}
+
+/// A 32 bit pitch bend from -1.0 to 1.0, with 0.0 at [`PITCH_BEND_CENTER_32`].
+#[must_use]
+pub fn pitch_bend_unit(value: u32) -> f32 {
+    let offset = i64::from(value) - i64::from(PITCH_BEND_CENTER_32);
+    (offset as f64 / f64::from(PITCH_BEND_CENTER_32 - 1)).max(-1.0) as f32
+}
+
+#[test]
+fn test_midi1_translation_and_ump_encoding() {
+    assert_eq!(scale_up(0, 7, 16), 0);
+    assert_eq!(scale_up(64, 7, 16), 0x8000);
+    assert_eq!(scale_up(127, 7, 16), 0xffff);
+    assert_eq!(scale_up(127, 7, 32), u32::MAX);
+    assert_eq!(scale_up(0x2000, 14, 32), PITCH_BEND_CENTER_32);
+    assert_eq!(scale_up(0x3fff, 14, 32), u32::MAX);
+
+    let on = Midi2Message::from_midi1(MidiMessage::note_on(2, 60, 127)).unwrap();
+    assert_eq!(
+        on,
+        Midi2Message::NoteOn {
+            channel: 2,
+            note: 60,
+            velocity: u16::MAX
+        }
+    );
+    assert!(matches!(
+        Midi2Message::from_midi1(MidiMessage::note_on(2, 60, 0)),
+        Some(Midi2Message::NoteOff { velocity: 0, .. })
+    ));
+    let bend = Midi2Message::from_midi1([0xE0, 0x00, 0x40].into()).unwrap();
+    assert_eq!(
+        bend,
+        Midi2Message::PitchBend {
+            channel: 0,
+            value: PITCH_BEND_CENTER_32
+        }
+    );
+    assert_eq!(pitch_bend_unit(PITCH_BEND_CENTER_32), 0.0);
+    assert_eq!(pitch_bend_unit(u32::MAX), 1.0);
+    assert_eq!(pitch_bend_unit(0), -1.0);
+    assert_eq!(Midi2Message::from_midi1([0xF8, 0, 0].into()), None);
+
+    // MIDI 1.0 messages survive the round trip
+    for message in [
+        MidiMessage::note_on(2, 60, 100),
+        MidiMessage::note_off(3, 61, 40),
+        MidiMessage::control_change(4, 74, 90),
+        [0xE5, 0x12, 0x34].into(),
+        [0xD1, 0x33, 0x00].into(),
+    ] {
+        let upscaled = Midi2Message::from_midi1(message).unwrap();
+        assert_eq!(upscaled.to_midi1(), Some(message));
+    }
+
+    let per_note = Midi2Message::PerNoteController {
+        channel: 5,
+        note: 64,
+        index: 74,
+        registered: false,
+        value: 0x1234_5678,
+    };
+    for message in [on, bend, per_note] {
+        assert_eq!(
+            Midi2Message::from_ump(message.to_ump(3)),
+            Some((3, message))
+        );
+    }
+    assert_eq!(on.to_ump(0), [0x4092_3c00, 0xffff_0000]);
+    assert_eq!(per_note.to_midi1(), None);
+}
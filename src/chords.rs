@@ -0,0 +1,478 @@
+use crate::bevy_mic::microphone::{MicrophoneSamples, forward_samples};
+use crate::stft::{AnalysisFormat, Stft, StftAnalysis, needs_rebuild, spectral_peaks};
+use bevy::prelude::*;
+use std::fmt;
+
+/// Names the chord played on the microphone, and shows it over the game.
+///
+/// Each window of audio is reduced to a chromagram, the energy of each of the 12 pitch
+/// classes, which is smoothed over time and matched against chord templates.
+pub struct ChordRecognitionPlugin;
+
+impl Plugin for ChordRecognitionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ChordRecognitionSettings>()
+            .init_resource::<CurrentChord>()
+            .add_event::<ChordChanged>()
+            .add_systems(Startup, spawn_hud)
+            .add_systems(PreUpdate, recognize_chords.after(forward_samples))
+            .add_systems(Update, (toggle_on_key, update_hud).chain());
+    }
+}
+
+/// Settings for [`ChordRecognitionPlugin`] and [`ChordRecognizer`].
+#[derive(Resource, Clone, Debug)]
+pub struct ChordRecognitionSettings {
+    /// Whether the chord is shown over the game.
+    pub show_hud: bool,
+    pub toggle_key: KeyCode,
+    /// Samples analysed at once. Long windows tell low notes apart.
+    pub window: usize,
+    /// Samples between the starts of consecutive windows.
+    pub hop: usize,
+    /// Spectral peaks outside this range don't count towards the chromagram.
+    pub min_hz: f32,
+    pub max_hz: f32,
+    /// How much of each new chromagram is mixed into the smoothed one.
+    pub smoothing: f32,
+    /// Quieter chromagrams, by their loudest pitch class, are taken as silence.
+    pub min_level: f32,
+    /// How well the chromagram has to match the best template, from 0.0 to 1.0.
+    pub min_match: f32,
+    /// Windows in a row a new chord has to win before it's reported.
+    pub hold: u32,
+}
+
+impl Default for ChordRecognitionSettings {
+    fn default() -> Self {
+        Self {
+            show_hud: true,
+            toggle_key: KeyCode::F6,
+            window: 8192,
+            hop: 2048,
+            min_hz: 50.0,
+            max_hz: 4200.0,
+            smoothing: 0.4,
+            min_level: 0.01,
+            min_match: 0.75,
+            hold: 2,
+        }
+    }
+}
+
+impl ChordRecognitionSettings {
+    /// Whether a recognizer built with `other` would hear the same chords, whatever the HUD
+    /// does.
+    #[must_use]
+    pub fn analyses_like(&self, other: &Self) -> bool {
+        self.window == other.window
+            && self.hop == other.hop
+            && self.min_hz == other.min_hz
+            && self.max_hz == other.max_hz
+            && self.smoothing == other.smoothing
+            && self.min_level == other.min_level
+            && self.min_match == other.min_match
+            && self.hold == other.hold
+    }
+}
+
+pub const PITCH_CLASS_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The energy in each pitch class, from C up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Chroma(pub [f32; 12]);
+
+impl Chroma {
+    /// How alike the two are, from 0.0 to 1.0, regardless of loudness.
+    #[must_use]
+    pub fn similarity(&self, other: &Chroma) -> f32 {
+        let dot: f32 = self.0.iter().zip(&other.0).map(|(a, b)| a * b).sum();
+        let norms = self.norm() * other.norm();
+        if norms > f32::EPSILON {
+            dot / norms
+        } else {
+            0.0
+        }
+    }
+
+    #[must_use]
+    pub fn norm(&self) -> f32 {
+        self.0.iter().map(|c| c * c).sum::<f32>().sqrt()
+    }
+
+    #[must_use]
+    pub fn loudest(&self) -> f32 {
+        self.0.iter().copied().fold(0.0, f32::max)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Dominant7,
+    Major7,
+    Minor7,
+    Sus2,
+    Sus4,
+    Diminished,
+    Augmented,
+}
+
+impl ChordQuality {
+    pub const ALL: [ChordQuality; 9] = [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Dominant7,
+        ChordQuality::Major7,
+        ChordQuality::Minor7,
+        ChordQuality::Sus2,
+        ChordQuality::Sus4,
+        ChordQuality::Diminished,
+        ChordQuality::Augmented,
+    ];
+
+    /// Semitones above the root of each note of the chord.
+    #[must_use]
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+            ChordQuality::Sus2 => &[0, 2, 7],
+            ChordQuality::Sus4 => &[0, 5, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+        }
+    }
+
+    /// Written after the root, as in `Am` or `G7`.
+    #[must_use]
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+            ChordQuality::Sus2 => "sus2",
+            ChordQuality::Sus4 => "sus4",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Chord {
+    /// The pitch class of the root, 0 for C.
+    pub root: u8,
+    pub quality: ChordQuality,
+}
+
+impl Chord {
+    /// The chromagram of the chord played with equally loud notes.
+    #[must_use]
+    pub fn template(&self) -> Chroma {
+        let mut chroma = Chroma::default();
+        for interval in self.quality.intervals() {
+            chroma.0[usize::from((self.root + interval) % 12)] = 1.0;
+        }
+        chroma
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let root = PITCH_CLASS_NAMES[usize::from(self.root % 12)];
+        write!(f, "{}{}", root, self.quality.suffix())
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding the chord being played on the
+/// microphone, if any.
+#[derive(Resource, Clone, Copy, Debug, Default, PartialEq)]
+pub struct CurrentChord {
+    pub chord: Option<Chord>,
+    pub chroma: Chroma,
+}
+
+/// An [`Event`](bevy::ecs::event::Event) for when the chord on the microphone changes, or
+/// stops.
+///
+/// This event fires from [`PreUpdate`].
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChordChanged {
+    pub chord: Option<Chord>,
+}
+
+/// Turns a stream of mono samples into chromagrams and chords.
+pub struct ChordRecognizer {
+    settings: ChordRecognitionSettings,
+    sample_rate: u32,
+    stft: Stft,
+    templates: Vec<(Chord, Chroma)>,
+    chroma: Chroma,
+    chord: Option<Chord>,
+    /// The chord that would take over, and for how many windows it has won.
+    challenger: Option<(Option<Chord>, u32)>,
+}
+
+impl ChordRecognizer {
+    /// How much a loud root counts for, next to how well the notes match.
+    const ROOT_WEIGHT: f32 = 0.05;
+
+    #[must_use]
+    pub fn new(sample_rate: u32, settings: &ChordRecognitionSettings) -> Self {
+        let templates = ChordQuality::ALL
+            .iter()
+            .flat_map(|&quality| {
+                (0..12).map(move |root| {
+                    let chord = Chord { root, quality };
+                    (chord, chord.template())
+                })
+            })
+            .collect();
+        Self {
+            settings: settings.clone(),
+            sample_rate,
+            stft: Stft::new(settings.window, settings.hop),
+            templates,
+            chroma: Chroma::default(),
+            chord: None,
+            challenger: None,
+        }
+    }
+
+    /// The smoothed chromagram.
+    #[must_use]
+    pub fn chroma(&self) -> Chroma {
+        self.chroma
+    }
+
+    #[must_use]
+    pub fn chord(&self) -> Option<Chord> {
+        self.chord
+    }
+
+    /// Analyses `samples`, returning whether the chord changed.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        let previous = self.chord;
+        self.stft.push(samples);
+        while self.stft.next_frame().is_some() {
+            let chroma = self.chromagram();
+            let mix = self.settings.smoothing.clamp(0.0, 1.0);
+            for (smoothed, new) in self.chroma.0.iter_mut().zip(chroma.0) {
+                *smoothed += (new - *smoothed) * mix;
+            }
+            let best = self.best_match();
+            self.follow(best);
+        }
+        self.chord != previous
+    }
+
+    /// Adds up the energy of the spectral peaks of the last window by pitch class.
+    fn chromagram(&self) -> Chroma {
+        let mut chroma = Chroma::default();
+        for (bin, magnitude) in spectral_peaks(self.stft.magnitudes()) {
+            let hz = self.stft.bin_hz(bin, self.sample_rate);
+            if hz < self.settings.min_hz || hz > self.settings.max_hz {
+                continue;
+            }
+            let note = (69.0 + 12.0 * (hz / 440.0).log2()).round();
+            chroma.0[note.rem_euclid(12.0) as usize] += magnitude * magnitude;
+        }
+        // Back to amplitudes, so quiet notes of a chord aren't swamped by loud ones
+        for class in &mut chroma.0 {
+            *class = class.sqrt();
+        }
+        chroma
+    }
+
+    /// The chord whose template is most like the smoothed chromagram, if any is close.
+    fn best_match(&self) -> Option<Chord> {
+        let loudest = self.chroma.loudest();
+        if loudest < self.settings.min_level {
+            return None;
+        }
+        let (chord, similarity, _) = self
+            .templates
+            .iter()
+            .map(|(chord, template)| {
+                let similarity = self.chroma.similarity(template);
+                // Chords with the same notes, like Csus2 and Gsus4, go to the loudest root
+                let root = self.chroma.0[usize::from(chord.root)] / loudest;
+                (*chord, similarity, similarity + Self::ROOT_WEIGHT * root)
+            })
+            .max_by(|a, b| a.2.total_cmp(&b.2))?;
+        (similarity >= self.settings.min_match).then_some(chord)
+    }
+
+    fn follow(&mut self, best: Option<Chord>) {
+        if best == self.chord {
+            self.challenger = None;
+            return;
+        }
+        let wins = match self.challenger {
+            Some((chord, wins)) if chord == best => wins + 1,
+            _ => 1,
+        };
+        if wins >= self.settings.hold {
+            self.chord = best;
+            self.challenger = None;
+        } else {
+            self.challenger = Some((best, wins));
+        }
+    }
+}
+
+impl StftAnalysis for ChordRecognizer {
+    fn format(&self) -> AnalysisFormat {
+        AnalysisFormat::new(self.sample_rate, self.settings.window, self.settings.hop)
+    }
+}
+
+fn recognize_chords(
+    settings: Res<ChordRecognitionSettings>,
+    mut samples: EventReader<MicrophoneSamples>,
+    mut recognizer: Local<Option<ChordRecognizer>>,
+    mut current: ResMut<CurrentChord>,
+    mut changed: EventWriter<ChordChanged>,
+) {
+    // Showing or hiding the HUD doesn't start the analysis over
+    if settings.is_changed()
+        && recognizer
+            .as_ref()
+            .is_some_and(|r| !r.settings.analyses_like(&settings))
+    {
+        *recognizer = None;
+    }
+    for chunk in samples.read() {
+        let format = AnalysisFormat::new(chunk.sample_rate, settings.window, settings.hop);
+        if needs_rebuild(recognizer.as_ref(), format) {
+            *recognizer = Some(ChordRecognizer::new(chunk.sample_rate, &settings));
+        }
+        let Some(recognizer) = recognizer.as_mut() else {
+            continue;
+        };
+        if recognizer.push(&chunk.samples) {
+            changed.write(ChordChanged {
+                chord: recognizer.chord(),
+            });
+        }
+        current.set_if_neq(CurrentChord {
+            chord: recognizer.chord(),
+            chroma: recognizer.chroma(),
+        });
+    }
+}
+
+fn toggle_on_key(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<ChordRecognitionSettings>) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.show_hud = !settings.show_hud;
+    }
+}
+
+/// The text showing the [`CurrentChord`].
+#[derive(Component)]
+struct ChordHud;
+
+fn spawn_hud(mut commands: Commands) {
+    commands.spawn((
+        ChordHud,
+        Text::new(""),
+        TextFont {
+            font_size: 48.0,
+            ..default()
+        },
+        TextColor(Color::srgb(0.9, 0.9, 0.9)),
+        TextShadow::default(),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            left: Val::Percent(50.0),
+            ..default()
+        },
+    ));
+}
+
+fn update_hud(
+    settings: Res<ChordRecognitionSettings>,
+    current: Res<CurrentChord>,
+    mut hud: Query<(&mut Text, &mut Visibility), With<ChordHud>>,
+) {
+    if !settings.is_changed() && !current.is_changed() {
+        return;
+    }
+    for (mut text, mut visibility) in &mut hud {
+        *visibility = if settings.show_hud {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+        text.0 = current.chord.map(|c| c.to_string()).unwrap_or_default();
+    }
+}
+
+#[test]
+fn test_recognizes_chords() {
+    let sample_rate = 48_000;
+    let chord = |notes: &[u8], seconds: f32| -> Vec<f32> {
+        (0..(seconds * sample_rate as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                notes
+                    .iter()
+                    .flat_map(|&note| {
+                        let hz = 440.0 * 2f32.powf((f32::from(note) - 69.0) / 12.0);
+                        (1..=4).map(move |h| {
+                            0.1 * 0.5f32.powi(h - 1)
+                                * (std::f32::consts::TAU * hz * h as f32 * t).sin()
+                        })
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    let settings = ChordRecognitionSettings::default();
+    let mut recognizer = ChordRecognizer::new(sample_rate, &settings);
+    let mut heard = |samples: &[f32]| {
+        for chunk in samples.chunks(1024) {
+            recognizer.push(chunk);
+        }
+        recognizer.chord().map(|c| c.to_string())
+    };
+
+    assert_eq!(heard(&chord(&[57, 60, 64], 1.0)).as_deref(), Some("Am"));
+    assert_eq!(
+        heard(&chord(&[43, 55, 59, 62, 65], 1.0)).as_deref(),
+        Some("G7")
+    );
+    assert_eq!(
+        heard(&chord(&[48, 60, 62, 67], 1.0)).as_deref(),
+        Some("Csus2")
+    );
+    assert_eq!(heard(&chord(&[59, 62, 65], 1.0)).as_deref(), Some("Bdim"));
+    assert_eq!(heard(&vec![0.0; sample_rate as usize]), None);
+    assert_eq!(
+        Chord {
+            root: 3,
+            quality: ChordQuality::Major7
+        }
+        .to_string(),
+        "D#maj7"
+    );
+    let hidden = ChordRecognitionSettings {
+        show_hud: false,
+        ..default()
+    };
+    assert!(settings.analyses_like(&hidden));
+    assert!(!settings.analyses_like(&ChordRecognitionSettings {
+        hop: 1024,
+        ..hidden
+    }));
+}
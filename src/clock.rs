@@ -0,0 +1,199 @@
+use crate::bevy_midi::prelude::*;
+use crate::playback::SongPlayer;
+use bevy::prelude::*;
+
+/// Syncs the song transport with external gear over midi beat clock, either leading it or
+/// following it.
+pub struct MidiClockPlugin;
+
+impl Plugin for MidiClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiClockSettings>()
+            .init_resource::<MidiClockFollower>()
+            .add_systems(Update, (apply_mode, follow_clock).chain());
+    }
+}
+
+/// Whether we lead, follow, or ignore midi beat clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClockMode {
+    #[default]
+    Off,
+    /// The song transport sends clock, Start/Stop/Continue and Song Position Pointer on
+    /// [`MidiOutput`].
+    Master,
+    /// Clock and transport messages from [`MidiInput`] drive the song transport and its tempo.
+    Slave,
+}
+
+/// Settings for [`MidiClockPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct MidiClockSettings {
+    pub mode: ClockMode,
+    /// How much of the previous tempo estimate is kept on each pulse, from 0.0 (none) to
+    /// just under 1.0. Higher values ride out more jitter but follow tempo changes slower.
+    pub smoothing: f64,
+}
+
+impl Default for MidiClockSettings {
+    fn default() -> Self {
+        Self {
+            mode: ClockMode::Off,
+            smoothing: 0.9,
+        }
+    }
+}
+
+/// Estimates tempo from the timestamps of incoming clock pulses.
+///
+/// Pulse intervals are smoothed with a one-pole filter. A single interval far from the
+/// estimate, e.g. a late or dropped pulse, is ignored; several in a row are taken as a
+/// tempo jump and the estimate starts over from them.
+#[derive(Clone, Debug, Default)]
+pub struct TempoEstimator {
+    last_stamp: Option<u64>,
+    // Smoothed microseconds per pulse
+    interval: Option<f64>,
+    outliers: u32,
+}
+
+impl TempoEstimator {
+    const MAX_OUTLIERS: u32 = 3;
+
+    /// Feeds the timestamp of a clock pulse, in microseconds.
+    pub fn pulse(&mut self, stamp: u64, smoothing: f64) {
+        let Some(last) = self.last_stamp.replace(stamp) else {
+            return;
+        };
+        let dt = stamp.saturating_sub(last) as f64;
+        if dt <= 0.0 {
+            return;
+        }
+
+        match self.interval {
+            Some(interval) if dt > interval / 1.5 && dt < interval * 1.5 => {
+                let smoothing = smoothing.clamp(0.0, 0.999);
+                self.interval = Some(interval * smoothing + dt * (1.0 - smoothing));
+                self.outliers = 0;
+            }
+            Some(_) if self.outliers < Self::MAX_OUTLIERS => self.outliers += 1,
+            _ => {
+                self.interval = Some(dt);
+                self.outliers = 0;
+            }
+        }
+    }
+
+    /// Forgets the last pulse, e.g. when the leader restarts, but keeps the tempo estimate.
+    pub fn restart(&mut self) {
+        self.last_stamp = None;
+        self.outliers = 0;
+    }
+
+    /// The estimated tempo, in microseconds per beat.
+    #[must_use]
+    pub fn tempo(&self) -> Option<f64> {
+        self.interval
+            .map(|i| i * f64::from(MidiClockMessage::PULSES_PER_BEAT))
+    }
+
+    /// The estimated tempo, in beats per minute.
+    #[must_use]
+    pub fn bpm(&self) -> Option<f64> {
+        self.tempo().map(|tempo| 60_000_000.0 / tempo)
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding the tempo we follow in
+/// [`ClockMode::Slave`].
+#[derive(Resource, Clone, Debug, Default)]
+pub struct MidiClockFollower {
+    pub estimator: TempoEstimator,
+}
+
+impl MidiClockFollower {
+    /// The leader's tempo, in beats per minute, once enough clock has arrived.
+    #[must_use]
+    pub fn bpm(&self) -> Option<f64> {
+        self.estimator.bpm()
+    }
+}
+
+pub(crate) fn apply_mode(settings: Res<MidiClockSettings>, player: Option<Res<SongPlayer>>) {
+    let Some(player) = player else { return };
+    if !settings.is_changed() && !player.is_added() {
+        return;
+    }
+    player.set_clock_output(settings.mode == ClockMode::Master);
+    if settings.mode != ClockMode::Slave {
+        player.set_tempo_scale(1.0);
+    }
+}
+
+fn follow_clock(
+    settings: Res<MidiClockSettings>,
+    mut follower: ResMut<MidiClockFollower>,
+    mut clock: EventReader<MidiClockData>,
+    player: Option<Res<SongPlayer>>,
+) {
+    let Some(player) = player.filter(|_| settings.mode == ClockMode::Slave) else {
+        clock.clear();
+        return;
+    };
+
+    let mut pulsed = false;
+    for data in clock.read() {
+        match data.message {
+            MidiClockMessage::Clock => {
+                follower.estimator.pulse(data.stamp, settings.smoothing);
+                pulsed = true;
+            }
+            MidiClockMessage::Start => {
+                follower.estimator.restart();
+                player.seek(std::time::Duration::ZERO);
+                player.play();
+            }
+            MidiClockMessage::Continue => {
+                follower.estimator.restart();
+                player.play();
+            }
+            MidiClockMessage::Stop => player.pause(),
+            MidiClockMessage::SongPosition(sixteenths) => {
+                player.seek_beats(f64::from(sixteenths) / 4.0);
+            }
+        }
+    }
+
+    // Match the leader's tempo against the song's tempo where we are
+    if let Some(tempo) = follower.estimator.tempo().filter(|_| pulsed) {
+        let position = player.position().as_micros() as u64;
+        let song_tempo = player.sequence().tempo_at(position);
+        player.set_tempo_scale(f64::from(song_tempo) / tempo);
+    }
+}
+
+#[test]
+fn test_tempo_estimator_smooths_jitter() {
+    let mut estimator = TempoEstimator::default();
+    // 120 bpm is 500ms per beat, 20833us per pulse; add +-1ms of jitter
+    let mut stamp = 0u64;
+    for i in 0..200u64 {
+        let jitter = if i % 2 == 0 { 1_000 } else { 0 };
+        estimator.pulse(stamp + jitter, 0.9);
+        stamp += 20_833;
+    }
+    let bpm = estimator.bpm().unwrap();
+    assert!((bpm - 120.0).abs() < 0.5, "{bpm}");
+
+    // A single dropped pulse doesn't move the estimate
+    stamp += 20_833;
+    estimator.pulse(stamp, 0.9);
+    assert!((estimator.bpm().unwrap() - bpm).abs() < 0.01);
+
+    // A sustained jump to 60 bpm is picked up
+    for _ in 0..50 {
+        stamp += 41_666;
+        estimator.pulse(stamp, 0.9);
+    }
+    assert!((estimator.bpm().unwrap() - 60.0).abs() < 0.5);
+}
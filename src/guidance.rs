@@ -0,0 +1,307 @@
+use crate::bevy_midi::prelude::*;
+use crate::playback::{Sequence, SongPlayer};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Lights the upcoming notes of the song on the player's keyboard, for keyboards with
+/// per-key lights.
+///
+/// How a key is lit comes from an [`LedProfile`] data file.
+pub struct LedGuidancePlugin;
+
+impl Plugin for LedGuidancePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LedGuidanceSettings>()
+            .init_resource::<LedGuidance>()
+            .add_systems(Update, (toggle_on_key, load_profile, update_leds).chain())
+            .add_systems(PostUpdate, shutdown);
+    }
+}
+
+/// Settings for [`LedGuidancePlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct LedGuidanceSettings {
+    pub enabled: bool,
+    /// Directory holding the `.ron` profiles.
+    pub profile_dir: PathBuf,
+    /// File name of the profile to use, without extension.
+    pub profile: String,
+    /// How far ahead of a note its key lights up, in song time.
+    pub lead: Duration,
+    /// Only light notes from these tracks of the song, e.g. the part being practised.
+    pub tracks: Option<Vec<usize>>,
+    pub toggle_key: KeyCode,
+}
+
+impl Default for LedGuidanceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            profile_dir: PathBuf::from("assets/led_profiles"),
+            profile: "generic_channel16".to_string(),
+            lead: Duration::from_millis(1500),
+            tracks: None,
+            toggle_key: KeyCode::F7,
+        }
+    }
+}
+
+/// A byte of an [`LedProfile`] message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LedByte {
+    Byte(u8),
+    /// The key to light, after [`LedProfile::note_offset`].
+    Note,
+    /// [`LedProfile::velocity`], which many keyboards use for brightness or colour.
+    Velocity,
+}
+
+/// How a keyboard model lights its keys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LedProfile {
+    pub name: String,
+    pub light_on: Vec<LedByte>,
+    pub light_off: Vec<LedByte>,
+    /// Added to the midi note to get the key the keyboard expects.
+    #[serde(default)]
+    pub note_offset: i8,
+    #[serde(default = "full_velocity")]
+    pub velocity: u8,
+}
+
+fn full_velocity() -> u8 {
+    127
+}
+
+impl LedProfile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// The message lighting or darkening the key of `note`, or `None` if the keyboard has
+    /// no such key.
+    #[must_use]
+    pub fn message(&self, note: u8, on: bool) -> Option<Vec<u8>> {
+        let key = u8::try_from(i16::from(note) + i16::from(self.note_offset))
+            .ok()
+            .filter(|key| *key <= 127)?;
+        let template = if on { &self.light_on } else { &self.light_off };
+        Some(
+            template
+                .iter()
+                .map(|byte| match byte {
+                    LedByte::Byte(b) => *b,
+                    LedByte::Note => key,
+                    LedByte::Velocity => self.velocity & 0x7f,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// A note of the song to light, in microseconds from the start.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuideNote {
+    pub start: u64,
+    pub end: u64,
+    pub note: u8,
+}
+
+impl GuideNote {
+    /// Pairs the NoteOns and NoteOffs of `sequence`, keeping only `tracks` if given.
+    #[must_use]
+    pub fn from_sequence(sequence: &Sequence, tracks: Option<&[usize]>) -> Vec<Self> {
+        let mut started = HashMap::new();
+        let mut notes = Vec::new();
+        for event in &sequence.events {
+            if tracks.is_some_and(|t| !t.contains(&event.track)) {
+                continue;
+            }
+            let key = (event.track, event.message.channel(), event.message.msg[1]);
+            if event.message.is_note_on() {
+                started.entry(key).or_insert(event.micros);
+            } else if event.message.is_note_off()
+                && let Some(start) = started.remove(&key)
+            {
+                notes.push(Self {
+                    start,
+                    end: event.micros,
+                    note: key.2,
+                });
+            }
+        }
+        notes.extend(started.into_iter().map(|((_, _, note), start)| Self {
+            start,
+            end: sequence.duration(),
+            note,
+        }));
+        notes
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) tracking which keys we've lit.
+#[derive(Resource, Default)]
+pub struct LedGuidance {
+    profile: Option<LedProfile>,
+    notes: Vec<GuideNote>,
+    lit: HashSet<u8>,
+}
+
+impl LedGuidance {
+    /// The loaded profile, if it loaded.
+    #[must_use]
+    pub fn profile(&self) -> Option<&LedProfile> {
+        self.profile.as_ref()
+    }
+
+    /// The keys that should be lit at song `position`.
+    #[must_use]
+    pub fn upcoming(&self, position: u64, lead: Duration) -> HashSet<u8> {
+        let horizon = position + lead.as_micros() as u64;
+        self.notes
+            .iter()
+            .filter(|n| n.start <= horizon && position < n.end)
+            .map(|n| n.note)
+            .collect()
+    }
+
+    // Lights exactly the keys in `wanted`.
+    fn show(&mut self, wanted: &HashSet<u8>, output: &MidiOutput) {
+        let Some(profile) = &self.profile else {
+            return;
+        };
+        for &note in self.lit.difference(wanted) {
+            if let Some(bytes) = profile.message(note, false) {
+                output.send_raw(bytes);
+            }
+        }
+        for &note in wanted.difference(&self.lit) {
+            if let Some(bytes) = profile.message(note, true) {
+                output.send_raw(bytes);
+            }
+        }
+        self.lit.clone_from(wanted);
+    }
+}
+
+fn toggle_on_key(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<LedGuidanceSettings>) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.enabled = !settings.enabled;
+        info!(
+            "Keyboard light guidance {}",
+            if settings.enabled { "on" } else { "off" }
+        );
+    }
+}
+
+fn load_profile(
+    settings: Res<LedGuidanceSettings>,
+    mut guidance: ResMut<LedGuidance>,
+    output: Option<Res<MidiOutput>>,
+) {
+    if !settings.is_changed() {
+        return;
+    }
+    let path = settings
+        .profile_dir
+        .join(&settings.profile)
+        .with_extension("ron");
+    let profile = match LedProfile::load(&path) {
+        Ok(profile) => Some(profile),
+        Err(e) => {
+            warn!("Couldn't load light profile {}: {}", path.display(), e);
+            None
+        }
+    };
+    if profile != guidance.profile {
+        // Darken keys with the old profile before switching
+        if let Some(output) = output {
+            guidance.show(&HashSet::new(), &output);
+        }
+        guidance.lit.clear();
+        guidance.profile = profile;
+    }
+}
+
+fn update_leds(
+    settings: Res<LedGuidanceSettings>,
+    mut guidance: ResMut<LedGuidance>,
+    player: Option<Res<SongPlayer>>,
+    output: Option<Res<MidiOutput>>,
+    conn: Res<MidiOutputConnection>,
+) {
+    let (Some(player), Some(output)) = (player, output) else {
+        return;
+    };
+    if player.is_changed() || settings.is_changed() {
+        guidance.notes = GuideNote::from_sequence(player.sequence(), settings.tracks.as_deref());
+    }
+    if conn.is_changed() {
+        // A newly connected keyboard has no lights on
+        guidance.lit.clear();
+    }
+
+    let wanted = if settings.enabled && conn.is_connected() {
+        guidance.upcoming(player.position().as_micros() as u64, settings.lead)
+    } else {
+        HashSet::new()
+    };
+    if wanted != guidance.lit {
+        guidance.show(&wanted, &output);
+    }
+}
+
+// Runs before `Last`, so the lights go out while `MidiOutput` is still up.
+fn shutdown(
+    mut guidance: ResMut<LedGuidance>,
+    output: Option<Res<MidiOutput>>,
+    mut exit: EventReader<AppExit>,
+) {
+    if let (Some(output), Some(_)) = (output, exit.read().next()) {
+        guidance.show(&HashSet::new(), &output);
+    }
+}
+
+#[test]
+fn test_led_profiles_and_upcoming_notes() {
+    let generic = LedProfile {
+        name: "generic".to_string(),
+        light_on: vec![LedByte::Byte(0x9F), LedByte::Note, LedByte::Velocity],
+        light_off: vec![LedByte::Byte(0x8F), LedByte::Note, LedByte::Byte(0)],
+        note_offset: -21,
+        velocity: 100,
+    };
+    assert_eq!(generic.message(60, true), Some(vec![0x9F, 39, 100]));
+    assert_eq!(generic.message(60, false), Some(vec![0x8F, 39, 0]));
+    assert_eq!(generic.message(20, true), None);
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/led_profiles");
+    for file in ["generic_channel16", "example_sysex"] {
+        let profile = LedProfile::load(&dir.join(file).with_extension("ron")).unwrap();
+        assert!(profile.message(60, true).is_some());
+    }
+
+    let guidance = LedGuidance {
+        notes: vec![
+            GuideNote {
+                start: 1_000_000,
+                end: 1_500_000,
+                note: 60,
+            },
+            GuideNote {
+                start: 3_000_000,
+                end: 3_500_000,
+                note: 64,
+            },
+        ],
+        ..default()
+    };
+    let lead = Duration::from_secs(1);
+    assert_eq!(guidance.upcoming(0, lead), HashSet::from([60]));
+    assert_eq!(guidance.upcoming(1_200_000, lead), HashSet::from([60]));
+    assert!(guidance.upcoming(1_600_000, lead).is_empty());
+    assert_eq!(guidance.upcoming(2_500_000, lead), HashSet::from([64]));
+}
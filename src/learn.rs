@@ -0,0 +1,626 @@
+use crate::audio::SharedSynthEngine;
+use crate::bevy_midi::prelude::*;
+use crate::clock::{self, ClockMode, MidiClockSettings};
+use crate::playback::SongPlayer;
+use crate::synth::SynthEngine;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+const NORMAL_BUTTON: Color = Color::srgb(0.15, 0.15, 0.15);
+const LEARNING_BUTTON: Color = Color::srgb(0.75, 0.35, 0.15);
+
+/// Lets knobs and faders on a controller drive synth and playback parameters.
+///
+/// Click a parameter, then move a control to bind it. Bindings are remembered per input
+/// device.
+pub struct MidiLearnPlugin;
+
+impl Plugin for MidiLearnPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiLearnSettings>()
+            .init_resource::<MidiLearn>()
+            .init_resource::<ParameterValues>()
+            .add_systems(Startup, (load_bindings, spawn_panel))
+            .add_systems(
+                Update,
+                (
+                    cancel_on_key,
+                    MidiLearn::handle_controllers,
+                    save_bindings,
+                    // After clock mode changes reset the tempo, so the learned one wins
+                    push_parameters.after(clock::apply_mode),
+                    update_panel,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Settings for [`MidiLearnPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct MidiLearnSettings {
+    /// Where the [`MidiLearnBindings`] are loaded from, and saved to whenever they change.
+    pub path: PathBuf,
+    /// Stops learning without binding anything.
+    pub cancel_key: KeyCode,
+    /// Removes the binding of the parameter being learned.
+    pub unbind_key: KeyCode,
+}
+
+impl Default for MidiLearnSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("config/midi_learn.ron"),
+            cancel_key: KeyCode::Escape,
+            unbind_key: KeyCode::Delete,
+        }
+    }
+}
+
+/// Something a controller can be bound to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Parameter {
+    SynthCutoff,
+    ReverbAmount,
+    /// Song playback speed.
+    Tempo,
+    PlaybackVolume,
+}
+
+impl Parameter {
+    pub const ALL: [Self; 4] = [
+        Self::SynthCutoff,
+        Self::ReverbAmount,
+        Self::Tempo,
+        Self::PlaybackVolume,
+    ];
+
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SynthCutoff => "Cutoff",
+            Self::ReverbAmount => "Reverb",
+            Self::Tempo => "Tempo",
+            Self::PlaybackVolume => "Playback volume",
+        }
+    }
+
+    /// The normalized value the parameter starts at.
+    #[must_use]
+    pub fn default_value(self) -> f32 {
+        match self {
+            Self::SynthCutoff | Self::PlaybackVolume => 1.0,
+            Self::ReverbAmount => 0.0,
+            Self::Tempo => 0.5,
+        }
+    }
+
+    /// Cutoff in Hz, swept exponentially from a normalized `value`.
+    #[must_use]
+    pub fn cutoff(value: f32) -> f32 {
+        SynthEngine::MIN_CUTOFF * (SynthEngine::MAX_CUTOFF / SynthEngine::MIN_CUTOFF).powf(value)
+    }
+
+    /// Tempo scale from half to double speed, from a normalized `value`.
+    #[must_use]
+    pub fn tempo_scale(value: f32) -> f64 {
+        2f64.powf(f64::from(value) * 2.0 - 1.0)
+    }
+
+    #[must_use]
+    pub fn describe(self, value: f32) -> String {
+        match self {
+            Self::SynthCutoff => format!("{:.0} Hz", Self::cutoff(value)),
+            Self::ReverbAmount | Self::PlaybackVolume => format!("{:.0}%", value * 100.0),
+            Self::Tempo => format!("{:.2}x", Self::tempo_scale(value)),
+        }
+    }
+}
+
+/// How a relative encoder encodes its steps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelativeEncoding {
+    /// 1 is +1, 127 is -1.
+    TwosComplement,
+    /// 65 is +1, 63 is -1.
+    BinaryOffset,
+    /// 1 is +1, 65 is -1.
+    SignedBit,
+}
+
+impl RelativeEncoding {
+    /// The number of steps a value moves by.
+    #[must_use]
+    pub fn delta(self, value: u8) -> i8 {
+        let value = value & 0x7f;
+        match self {
+            Self::TwosComplement if value >= 64 => (i16::from(value) - 128) as i8,
+            Self::TwosComplement => value as i8,
+            Self::BinaryOffset => value as i8 - 64,
+            Self::SignedBit if value >= 64 => -((value - 64) as i8),
+            Self::SignedBit => value as i8,
+        }
+    }
+}
+
+/// How a bound controller sends its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControllerMode {
+    /// One 7-bit controller.
+    Absolute,
+    /// A 7-bit MSB controller (0-31) paired with an LSB at `controller + 32`.
+    Absolute14Bit,
+    /// An endless encoder sending steps rather than positions.
+    Relative(RelativeEncoding),
+}
+
+/// A controller bound to a [`Parameter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControllerBinding {
+    pub channel: u8,
+    /// The controller number, or the MSB for [`ControllerMode::Absolute14Bit`].
+    pub controller: u8,
+    pub mode: ControllerMode,
+    pub parameter: Parameter,
+}
+
+impl ControllerBinding {
+    /// Fraction of the range a relative encoder moves per step.
+    pub const RELATIVE_STEP: f32 = 1.0 / 128.0;
+
+    /// Whether `message` comes from this controller.
+    #[must_use]
+    pub fn matches(&self, message: &MidiMessage) -> bool {
+        let controller = message.msg[1];
+        message.is_control_change()
+            && message.channel() == self.channel
+            && (controller == self.controller
+                || (self.mode == ControllerMode::Absolute14Bit
+                    && controller == self.controller + 32))
+    }
+
+    /// Guesses a binding from the controller messages that arrived while learning, as
+    /// (channel, controller, value).
+    ///
+    /// A controller paired with its LSB is 14-bit, and one repeating the same small step is
+    /// a relative encoder. Encodings can't always be told apart, so the guess can be
+    /// corrected in the bindings file.
+    #[must_use]
+    pub fn learn(parameter: Parameter, samples: &[(u8, u8, u8)]) -> Option<Self> {
+        let &(channel, first, _) = samples.first()?;
+        let sent = |cc: u8| samples.iter().any(|s| s.0 == channel && s.1 == cc);
+
+        // Some controllers send the LSB first
+        let controller = if (32..64).contains(&first) && sent(first - 32) {
+            first - 32
+        } else {
+            first
+        };
+        let values: Vec<u8> = samples
+            .iter()
+            .filter(|s| s.0 == channel && s.1 == controller)
+            .map(|s| s.2)
+            .collect();
+
+        let mode = if controller < 32 && sent(controller + 32) {
+            ControllerMode::Absolute14Bit
+        } else if values.len() >= 3 && values.windows(2).all(|w| w[0] == w[1]) {
+            match values[0] {
+                56..=72 => ControllerMode::Relative(RelativeEncoding::BinaryOffset),
+                _ => ControllerMode::Relative(RelativeEncoding::TwosComplement),
+            }
+        } else {
+            ControllerMode::Absolute
+        };
+
+        Some(Self {
+            channel,
+            controller,
+            mode,
+            parameter,
+        })
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding the learned bindings of each input
+/// device, keyed by [`MidiInputPort::id`].
+///
+/// Changes are saved to [`MidiLearnSettings::path`].
+#[derive(Resource, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MidiLearnBindings {
+    pub devices: BTreeMap<String, Vec<ControllerBinding>>,
+}
+
+impl MidiLearnBindings {
+    /// Device key used while no input port is connected.
+    pub const NO_DEVICE: &'static str = "";
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let bindings = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, bindings)?;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn device(&self, port: Option<&MidiInputPort>) -> &[ControllerBinding] {
+        let id = port.map_or(Self::NO_DEVICE, MidiInputPort::id);
+        self.devices.get(id).map_or(&[], Vec::as_slice)
+    }
+
+    /// Binds a controller on `port`, replacing whatever the parameter or the controller
+    /// was bound to before.
+    pub fn bind(&mut self, port: Option<&MidiInputPort>, binding: ControllerBinding) {
+        let id = port.map_or(Self::NO_DEVICE, MidiInputPort::id);
+        let bindings = self.devices.entry(id.to_string()).or_default();
+        bindings.retain(|b| {
+            b.parameter != binding.parameter
+                && (b.channel, b.controller) != (binding.channel, binding.controller)
+        });
+        bindings.push(binding);
+    }
+
+    pub fn unbind(&mut self, port: Option<&MidiInputPort>, parameter: Parameter) {
+        let id = port.map_or(Self::NO_DEVICE, MidiInputPort::id);
+        if let Some(bindings) = self.devices.get_mut(id) {
+            bindings.retain(|b| b.parameter != parameter);
+        }
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding every [`Parameter`], normalized to
+/// `0.0..=1.0`.
+#[derive(Resource, Clone, Debug)]
+pub struct ParameterValues {
+    values: HashMap<Parameter, f32>,
+}
+
+impl Default for ParameterValues {
+    fn default() -> Self {
+        Self {
+            values: Parameter::ALL
+                .iter()
+                .map(|&p| (p, p.default_value()))
+                .collect(),
+        }
+    }
+}
+
+impl ParameterValues {
+    #[must_use]
+    pub fn get(&self, parameter: Parameter) -> f32 {
+        self.values
+            .get(&parameter)
+            .copied()
+            .unwrap_or_else(|| parameter.default_value())
+    }
+
+    pub fn set(&mut self, parameter: Parameter, value: f32) {
+        self.values.insert(parameter, value.clamp(0.0, 1.0));
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) driving midi learn.
+#[derive(Resource, Default)]
+pub struct MidiLearn {
+    learning: Option<Learning>,
+    // Last MSB of each 14-bit (channel, controller), waiting for its LSB
+    msb: HashMap<(u8, u8), u8>,
+}
+
+struct Learning {
+    parameter: Parameter,
+    samples: Vec<(u8, u8, u8)>,
+    // Elapsed seconds when the first sample arrived
+    since: Option<f32>,
+}
+
+impl MidiLearn {
+    /// Controller messages gathered before guessing the binding.
+    const SAMPLES: usize = 6;
+    /// Seconds to wait for more messages after the first.
+    const WINDOW: f32 = 0.5;
+
+    /// Binds the next controller that moves to `parameter`.
+    pub fn start(&mut self, parameter: Parameter) {
+        self.learning = Some(Learning {
+            parameter,
+            samples: Vec::new(),
+            since: None,
+        });
+    }
+
+    pub fn cancel(&mut self) {
+        self.learning = None;
+    }
+
+    /// The parameter waiting for a controller, if any.
+    #[must_use]
+    pub fn learning(&self) -> Option<Parameter> {
+        self.learning.as_ref().map(|l| l.parameter)
+    }
+
+    /// The new normalized value of `binding`'s parameter after `message`.
+    fn value(&mut self, binding: &ControllerBinding, message: &MidiMessage, current: f32) -> f32 {
+        let [_, controller, value] = message.msg;
+        match binding.mode {
+            ControllerMode::Absolute => f32::from(value) / 127.0,
+            ControllerMode::Absolute14Bit => {
+                let key = (binding.channel, binding.controller);
+                let (msb, lsb) = if controller == binding.controller {
+                    // A new MSB clears the LSB
+                    self.msb.insert(key, value);
+                    (value, 0)
+                } else {
+                    (self.msb.get(&key).copied().unwrap_or(0), value)
+                };
+                f32::from((u16::from(msb) << 7) | u16::from(lsb)) / 16383.0
+            }
+            ControllerMode::Relative(encoding) => {
+                current + f32::from(encoding.delta(value)) * ControllerBinding::RELATIVE_STEP
+            }
+        }
+    }
+
+    fn handle_controllers(
+        mut learn: ResMut<MidiLearn>,
+        mut bindings: ResMut<MidiLearnBindings>,
+        mut values: ResMut<ParameterValues>,
+        mut midi: EventReader<MidiData>,
+        conn: Res<MidiInputConnection>,
+        time: Res<Time>,
+    ) {
+        let learn = &mut *learn;
+        for data in midi.read().filter(|d| d.message.is_control_change()) {
+            let [_, controller, value] = data.message.msg;
+            if let Some(learning) = &mut learn.learning {
+                learning
+                    .samples
+                    .push((data.message.channel(), controller, value));
+                learning.since.get_or_insert(time.elapsed_secs());
+                continue;
+            }
+
+            for binding in bindings.device(conn.port()) {
+                if binding.matches(&data.message) {
+                    let current = values.get(binding.parameter);
+                    let value = learn.value(binding, &data.message, current);
+                    values.set(binding.parameter, value);
+                }
+            }
+        }
+
+        let Some(learning) = &learn.learning else {
+            return;
+        };
+        let done = learning.samples.len() >= Self::SAMPLES
+            || learning
+                .since
+                .is_some_and(|since| time.elapsed_secs() - since >= Self::WINDOW);
+        if done {
+            if let Some(binding) = ControllerBinding::learn(learning.parameter, &learning.samples) {
+                info!("Bound {:?}", binding);
+                bindings.bind(conn.port(), binding);
+            }
+            learn.learning = None;
+        }
+    }
+}
+
+fn cancel_on_key(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<MidiLearnSettings>,
+    mut learn: ResMut<MidiLearn>,
+    mut bindings: ResMut<MidiLearnBindings>,
+    conn: Res<MidiInputConnection>,
+) {
+    if keys.just_pressed(settings.cancel_key) {
+        learn.cancel();
+    }
+    if keys.just_pressed(settings.unbind_key)
+        && let Some(parameter) = learn.learning()
+    {
+        bindings.unbind(conn.port(), parameter);
+        learn.cancel();
+    }
+}
+
+fn load_bindings(mut commands: Commands, settings: Res<MidiLearnSettings>) {
+    let bindings = if settings.path.exists() {
+        MidiLearnBindings::load(&settings.path).unwrap_or_else(|e| {
+            warn!("Couldn't load {}: {}", settings.path.display(), e);
+            MidiLearnBindings::default()
+        })
+    } else {
+        MidiLearnBindings::default()
+    };
+    commands.insert_resource(bindings);
+}
+
+fn save_bindings(bindings: Res<MidiLearnBindings>, settings: Res<MidiLearnSettings>) {
+    if bindings.is_changed()
+        && !bindings.is_added()
+        && let Err(e) = bindings.save(&settings.path)
+    {
+        warn!("Couldn't save {}: {}", settings.path.display(), e);
+    }
+}
+
+fn push_parameters(
+    values: Res<ParameterValues>,
+    synth: Option<Res<SharedSynthEngine>>,
+    player: Option<Res<SongPlayer>>,
+    clock: Option<Res<MidiClockSettings>>,
+    mut pushed_tempo: Local<Option<f32>>,
+) {
+    let player_added = player.as_ref().is_some_and(|p| p.is_added());
+    let clock_changed = clock.as_ref().is_some_and(|c| c.is_changed());
+    if !values.is_changed() && !player_added && !clock_changed {
+        return;
+    }
+    if let Some(Ok(synth)) = synth.as_ref().map(|s| s.0.lock()) {
+        synth.set_cutoff(Parameter::cutoff(values.get(Parameter::SynthCutoff)));
+        synth.set_reverb_amount(values.get(Parameter::ReverbAmount));
+    }
+    if let Some(player) = player {
+        // The tempo is left alone while following clock, which sets it from the leader's
+        let following = clock.is_some_and(|c| c.mode == ClockMode::Slave);
+        let tempo = values.get(Parameter::Tempo);
+        if following {
+            *pushed_tempo = None;
+        } else if *pushed_tempo != Some(tempo) || player_added || clock_changed {
+            player.set_tempo_scale(Parameter::tempo_scale(tempo));
+            *pushed_tempo = Some(tempo);
+        }
+        player.set_volume(values.get(Parameter::PlaybackVolume));
+    }
+}
+
+/// A button in the midi learn panel.
+#[derive(Component)]
+struct ParameterButton(Parameter);
+
+fn spawn_panel(mut commands: Commands) {
+    commands
+        .spawn(Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            left: Val::Px(8.0),
+            flex_direction: FlexDirection::Column,
+            row_gap: Val::Px(4.0),
+            ..default()
+        })
+        .with_children(|panel| {
+            for parameter in Parameter::ALL {
+                panel
+                    .spawn((
+                        Button,
+                        ParameterButton(parameter),
+                        Node {
+                            padding: UiRect::axes(Val::Px(8.0), Val::Px(4.0)),
+                            ..default()
+                        },
+                        BackgroundColor(NORMAL_BUTTON),
+                        children![(
+                            Text::new(parameter.name()),
+                            TextFont {
+                                font_size: 14.0,
+                                ..default()
+                            },
+                            TextColor(Color::srgb(0.9, 0.9, 0.9)),
+                        )],
+                    ))
+                    .observe(on_click_parameter);
+            }
+        });
+}
+
+// Clicking a parameter learns it; clicking it again cancels.
+fn on_click_parameter(
+    click: Trigger<Pointer<Click>>,
+    buttons: Query<&ParameterButton>,
+    mut learn: ResMut<MidiLearn>,
+) {
+    let Ok(ParameterButton(parameter)) = buttons.get(click.target()) else {
+        return;
+    };
+    if learn.learning() == Some(*parameter) {
+        learn.cancel();
+    } else {
+        learn.start(*parameter);
+    }
+}
+
+fn update_panel(
+    learn: Res<MidiLearn>,
+    bindings: Res<MidiLearnBindings>,
+    values: Res<ParameterValues>,
+    conn: Res<MidiInputConnection>,
+    mut buttons: Query<(&ParameterButton, &mut BackgroundColor, &Children)>,
+    mut texts: Query<&mut Text>,
+) {
+    if !learn.is_changed() && !bindings.is_changed() && !values.is_changed() {
+        return;
+    }
+    let device = bindings.device(conn.port());
+    for (ParameterButton(parameter), mut color, children) in &mut buttons {
+        let Ok(mut text) = texts.get_mut(children[0]) else {
+            continue;
+        };
+        let learning = learn.learning() == Some(*parameter);
+        let label = if learning {
+            format!("{}: move a control...", parameter.name())
+        } else {
+            let value = parameter.describe(values.get(*parameter));
+            match device.iter().find(|b| b.parameter == *parameter) {
+                Some(b) => format!(
+                    "{}: {} [ch {} CC {}]",
+                    parameter.name(),
+                    value,
+                    b.channel + 1,
+                    b.controller
+                ),
+                None => format!("{}: {}", parameter.name(), value),
+            }
+        };
+        **text = label;
+        *color = if learning {
+            LEARNING_BUTTON
+        } else {
+            NORMAL_BUTTON
+        }
+        .into();
+    }
+}
+
+#[test]
+fn test_learn_controller_modes() {
+    let learn = |samples: &[(u8, u8, u8)]| {
+        ControllerBinding::learn(Parameter::SynthCutoff, samples).map(|b| (b.controller, b.mode))
+    };
+    assert_eq!(learn(&[]), None);
+    assert_eq!(
+        learn(&[(0, 74, 10), (0, 74, 12), (0, 74, 15)]),
+        Some((74, ControllerMode::Absolute))
+    );
+    assert_eq!(
+        learn(&[(0, 39, 5), (0, 7, 64), (0, 39, 9)]),
+        Some((7, ControllerMode::Absolute14Bit))
+    );
+    assert_eq!(
+        learn(&[(0, 20, 65), (0, 20, 65), (0, 20, 65)]),
+        Some((20, ControllerMode::Relative(RelativeEncoding::BinaryOffset)))
+    );
+    assert_eq!(
+        learn(&[(0, 20, 127), (0, 20, 127), (0, 20, 127)]),
+        Some((
+            20,
+            ControllerMode::Relative(RelativeEncoding::TwosComplement)
+        ))
+    );
+
+    assert_eq!(RelativeEncoding::TwosComplement.delta(127), -1);
+    assert_eq!(RelativeEncoding::BinaryOffset.delta(63), -1);
+    assert_eq!(RelativeEncoding::SignedBit.delta(66), -2);
+
+    let mut state = MidiLearn::default();
+    let fine = ControllerBinding {
+        channel: 0,
+        controller: 7,
+        mode: ControllerMode::Absolute14Bit,
+        parameter: Parameter::PlaybackVolume,
+    };
+    assert!(fine.matches(&MidiMessage::control_change(0, 39, 0)));
+    assert!(!fine.matches(&MidiMessage::control_change(1, 7, 0)));
+    state.value(&fine, &MidiMessage::control_change(0, 7, 127), 0.0);
+    let full = state.value(&fine, &MidiMessage::control_change(0, 39, 127), 0.0);
+    assert_eq!(full, 1.0);
+}
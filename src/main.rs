@@ -6,18 +6,47 @@ use bevy_midi::prelude::*;
 mod piano;
 use bevy_procedural_audio::prelude::*;
 mod audio;
+mod audio_to_midi;
 mod bevy_mic;
+mod chords;
+mod clock;
 pub mod gizmo;
+mod guidance;
 mod keys;
+mod learn;
 mod mic;
+mod onset;
+mod osc;
+mod panic;
+mod pitch;
+mod playback;
 mod record_visualizer;
+mod recorder;
+mod routing;
+mod score;
 mod songs;
+mod spectrogram;
+mod stft;
 mod synth;
+mod transcription;
+mod tuner;
 use bevy_text_mesh::prelude::*;
 
 fn main() {
     // keys::main();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args
+        .first()
+        .is_some_and(|command| command == audio_to_midi::COMMAND)
+    {
+        if let Err(e) = audio_to_midi::run(&args[1..]) {
+            eprintln!("{e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let synth_mutex = Arc::new(Mutex::new(0.0f32));
     let micamp = mic::MicAmplitude(synth_mutex.clone());
 
@@ -45,10 +74,30 @@ fn main() {
         .add_plugins(audio::PianoPlugin)
         .add_plugins(record_visualizer::RecordVisualizerPlugin)
         .add_plugins(MidiOutputPlugin)
+        .add_plugins(RtpMidiPlugin)
         .add_plugins(songs::SongLoaderPlugin)
+        .add_plugins(recorder::MidiRecorderPlugin)
+        .add_plugins(playback::SongPlaybackPlugin)
+        .add_plugins(clock::MidiClockPlugin)
+        .add_plugins(routing::MidiRoutingPlugin)
+        .add_plugins(learn::MidiLearnPlugin)
+        .add_plugins(guidance::LedGuidancePlugin)
+        .add_plugins(score::PracticeScorePlugin)
+        .add_plugins(osc::OscBridgePlugin)
+        .add_plugins(panic::MidiPanicPlugin)
+        .add_plugins(pitch::PitchDetectionPlugin)
+        .add_plugins(transcription::TranscriptionPlugin)
+        .add_plugins(onset::OnsetDetectionPlugin)
+        .add_plugins(chords::ChordRecognitionPlugin)
+        .add_plugins(spectrogram::SpectrogramPlugin)
+        .add_plugins(tuner::TunerPlugin)
         // RESOURCES
         .init_resource::<MidiInputSettings>()
         .init_resource::<MidiOutputSettings>()
+        .insert_resource(record_visualizer::KeySpectrumSettings {
+            keys: Key::notes(),
+            ..default()
+        })
         // Add RecordingState resource
         .insert_resource(micamp)
         .insert_resource(audio_buffer)
@@ -72,6 +121,8 @@ fn main() {
                 connect_to_first_output_port,
                 Key::display_press,
                 Key::display_release,
+                Key::display_expression.after(Key::display_press),
+                Key::display_spectrum.after(Key::display_release),
                 // mic::ui_system_update_button,
                 // mic::mic_update,
             ),
@@ -101,12 +152,20 @@ struct Key {
 }
 
 impl Key {
+    /// Octaves of keys on the keyboard, from midi note 0 up.
+    const OCTAVES: i32 = 8;
+
+    /// The midi notes of the keys spawned by [`Key::system_startup`].
+    pub fn notes() -> std::ops::RangeInclusive<u8> {
+        Key::new(0, 0).note()..=Key::new(Self::OCTAVES - 1, 11).note()
+    }
+
     pub fn system_startup(
         mut cmds: Commands,
         mut standard_materials: ResMut<Assets<StandardMaterial>>,
         asset_server: Res<AssetServer>,
     ) {
-        for oct in 0..8 {
+        for oct in 0..Self::OCTAVES {
             for key_in_octal in 0..12 {
                 Key::new(oct, key_in_octal).spawn_note(
                     &mut cmds,
@@ -273,32 +332,65 @@ impl Key {
         }
     }
 
+    /// Makes held keys glow brighter the harder they are pressed, e.g. on an MPE controller.
+    pub fn display_expression(
+        query: Query<(&Key, &MeshMaterial3d<StandardMaterial>), With<PressedKey>>,
+        expression: Res<PerNoteExpression>,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+    ) {
+        if !expression.is_changed() {
+            return;
+        }
+        for (key, mat) in &query {
+            let glow = expression.note(key.note()).map_or(0.0, |e| e.pressure);
+            if let Some(material) = materials.get_mut(mat) {
+                material.emissive = LinearRgba::from(tailwind::RED_500) * glow * 4.0;
+            }
+        }
+    }
+
+    /// Makes keys that aren't held glow with how loud their pitch is on the microphone, or
+    /// not at all once it's quiet.
+    ///
+    /// This owns the glow of released keys, so it's redrawn when the spectrum changes or a
+    /// key is let go and loses its pressed glow.
+    pub fn display_spectrum(
+        query: Query<(&Key, &MeshMaterial3d<StandardMaterial>), Without<PressedKey>>,
+        spectrum: Res<record_visualizer::KeySpectrum>,
+        mut released: RemovedComponents<PressedKey>,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+    ) {
+        if released.read().count() == 0 && !spectrum.is_changed() {
+            return;
+        }
+        for (key, mat) in &query {
+            let glow = spectrum.level(key.note());
+            if let Some(material) = materials.get_mut(mat) {
+                material.emissive = LinearRgba::from(tailwind::SKY_400) * glow * 2.0;
+            }
+        }
+    }
+
+    /// The midi note number this key plays.
+    pub fn note(&self) -> u8 {
+        (self.oct * 12) as u8 + self.key_in_octal
+    }
+
     pub fn handle_midi_input(
         mut commands: Commands,
-        mut midi_events: EventReader<MidiData>,
-        query: Query<(Entity, &Key)>,
+        midi_state: Res<MidiState>,
+        query: Query<(Entity, &Key, Has<PressedKey>)>,
     ) {
-        for data in midi_events.read() {
-            let [_, index, _value] = data.message.msg;
-            let off = index % 12;
-            let oct = index.overflowing_div(12).0;
-            let key_str = KEY_RANGE.iter().nth(off.into()).unwrap();
-
-            println!("MIDI Event: {:?} {} {}", data.message, key_str, oct);
-
-            if data.message.is_note_on() {
-                for (entity, key) in query.iter() {
-                    if key.key_val.eq(&format!("{}{}", key_str, oct).to_string()) {
-                        commands.entity(entity).insert(PressedKey);
-                    }
-                }
-            } else if data.message.is_note_off() {
-                for (entity, key) in query.iter() {
-                    if key.key_val.eq(&format!("{}{}", key_str, oct).to_string()) {
-                        commands.entity(entity).remove::<PressedKey>();
-                    }
-                }
-            } else {
+        if !midi_state.is_changed() {
+            return;
+        }
+
+        for (entity, key, pressed) in query.iter() {
+            let held = midi_state.is_held(key.note());
+            if held && !pressed {
+                commands.entity(entity).insert(PressedKey);
+            } else if !held && pressed {
+                commands.entity(entity).remove::<PressedKey>();
             }
         }
     }
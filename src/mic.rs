@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use bevy::{color::palettes::basic::*, prelude::*};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use flume::Receiver;
@@ -30,24 +30,14 @@ pub struct AudioBuffer(pub Arc<Mutex<Vec<f32>>>);
 
 static mut MIC_STREAM: Option<cpal::Stream> = None;
 
-fn start_microphone_stream<F: FnMut(&[f32]) + Send + 'static>(
-    mut callback: F,
-) -> Result<cpal::Stream> {
+fn start_microphone_stream<F: FnMut(&[f32]) + Send + 'static>(callback: F) -> Result<cpal::Stream> {
     let host = cpal::default_host();
     let device = host
         .default_input_device()
-        .expect("no input device available");
+        .context("no input device available")?;
     let config = device.default_input_config()?;
 
-    let stream = match config.sample_format() {
-        cpal::SampleFormat::F32 => device.build_input_stream(
-            &config.into(),
-            move |data: &[f32], _| callback(data),
-            move |err| eprintln!("stream error: {}", err),
-            None,
-        )?,
-        _ => panic!("unsupported format"),
-    };
+    let stream = bevy_mic::microphone::build_input_stream(&device, &config, callback)?;
 
     stream.play()?;
     Ok(stream)
@@ -0,0 +1,299 @@
+use crate::bevy_mic::microphone::{MicrophoneSamples, forward_samples};
+use crate::stft::{AnalysisFormat, Stft, StftAnalysis, needs_rebuild};
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Finds where notes start in the microphone audio, for scoring the rhythm of acoustic
+/// instruments.
+///
+/// A detection function rises whenever new energy appears in the spectrum. Its peaks are
+/// onsets if they stand out from its recent median.
+pub struct OnsetDetectionPlugin;
+
+impl Plugin for OnsetDetectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OnsetDetectionSettings>()
+            .add_event::<Onset>()
+            .add_systems(PreUpdate, detect_onsets.after(forward_samples));
+    }
+}
+
+/// How an [`OnsetDetector`] measures new energy in the spectrum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OnsetFunction {
+    /// How much louder each bin got, on a log scale. Works for most instruments.
+    SpectralFlux,
+    /// Spectral flux weighted towards high frequencies, like the high frequency content
+    /// measure. Best for percussive attacks, and less fooled by a swelling bass.
+    HighFrequencyContent,
+}
+
+/// Settings for [`OnsetDetectionPlugin`] and [`OnsetDetector`].
+#[derive(Resource, Clone, Debug)]
+pub struct OnsetDetectionSettings {
+    pub function: OnsetFunction,
+    /// Samples analysed at once.
+    pub window: usize,
+    /// Samples between the starts of consecutive windows, and so the timing resolution.
+    pub hop: usize,
+    /// The detection function has to exceed its recent median by this factor...
+    pub sensitivity: f32,
+    /// ...plus this much.
+    pub threshold: f32,
+    /// How far back the median looks.
+    pub median_window: Duration,
+    /// Onsets closer together than this are counted once.
+    pub min_interval: Duration,
+}
+
+impl Default for OnsetDetectionSettings {
+    fn default() -> Self {
+        Self {
+            function: OnsetFunction::SpectralFlux,
+            window: 1024,
+            hop: 256,
+            sensitivity: 1.5,
+            threshold: 0.003,
+            median_window: Duration::from_millis(100),
+            min_interval: Duration::from_millis(50),
+        }
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) for each note start heard on the microphone.
+///
+/// This event fires from [`PreUpdate`].
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub struct Onset {
+    /// When the note started, estimated from where in the audio it was found.
+    pub at: Instant,
+    /// The peak of the detection function, so louder attacks are stronger.
+    pub strength: f32,
+}
+
+/// An onset found by an [`OnsetDetector`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectedOnset {
+    /// Seconds since the first sample given to the detector.
+    pub time: f64,
+    pub strength: f32,
+}
+
+/// Finds onsets in a stream of mono samples.
+pub struct OnsetDetector {
+    settings: OnsetDetectionSettings,
+    sample_rate: u32,
+    stft: Stft,
+    /// The previous window's spectrum, log compressed.
+    previous: Vec<f32>,
+    /// Recent values of the detection function, up to `history_len`.
+    history: VecDeque<f32>,
+    history_len: usize,
+    sorted: Vec<f32>,
+    /// The last two values of the detection function, and whether the newer one passed
+    /// the threshold.
+    candidate: Option<(f32, f32, bool)>,
+    last_onset: Option<f64>,
+}
+
+impl OnsetDetector {
+    /// Scales magnitudes before taking their log, so quiet partials still count.
+    const COMPRESSION: f32 = 100.0;
+
+    #[must_use]
+    pub fn new(sample_rate: u32, settings: &OnsetDetectionSettings) -> Self {
+        let stft = Stft::new(settings.window, settings.hop);
+        let frames_per_second = sample_rate as f32 / stft.hop() as f32;
+        let history_len =
+            ((settings.median_window.as_secs_f32() * frames_per_second).ceil() as usize).max(1);
+        Self {
+            settings: settings.clone(),
+            sample_rate,
+            previous: vec![0.0; stft.size() / 2 + 1],
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            sorted: Vec::with_capacity(history_len),
+            candidate: None,
+            last_onset: None,
+            stft,
+        }
+    }
+
+    /// Seconds of audio looked at so far.
+    #[must_use]
+    pub fn time(&self) -> f64 {
+        self.frame_time(self.stft.frames())
+    }
+
+    /// Looks for onsets in `samples`, adding them to `onsets`.
+    ///
+    /// Each onset is only found once the window after its peak has been seen.
+    pub fn push(&mut self, samples: &[f32], onsets: &mut Vec<DetectedOnset>) {
+        self.stft.push(samples);
+        while self.stft.next_frame().is_some() {
+            let value = self.detection_function();
+            let threshold = self.threshold();
+            let frame = self.stft.frames() - 1;
+
+            // A peak is higher than the windows on either side
+            if let Some((before, peak, above)) = self.candidate
+                && above
+                && peak > before
+                && peak >= value
+            {
+                let time = self.frame_time(frame - 1);
+                let spaced = self
+                    .last_onset
+                    .is_none_or(|last| time - last >= self.settings.min_interval.as_secs_f64());
+                if spaced {
+                    self.last_onset = Some(time);
+                    onsets.push(DetectedOnset {
+                        time,
+                        strength: peak,
+                    });
+                }
+            }
+            let before = self.candidate.map_or(0.0, |(_, peak, _)| peak);
+            self.candidate = Some((before, value, value > threshold));
+
+            if self.history.len() == self.history_len {
+                self.history.pop_front();
+            }
+            self.history.push_back(value);
+        }
+    }
+
+    /// The time at the start of window `frame`'s second half, where a sound starting in it
+    /// is first caught.
+    fn frame_time(&self, frame: u64) -> f64 {
+        let start = frame * self.stft.hop() as u64;
+        (start as f64 + self.stft.size() as f64 / 2.0) / f64::from(self.sample_rate)
+    }
+
+    fn detection_function(&mut self) -> f32 {
+        let magnitudes = self.stft.magnitudes();
+        let bins = magnitudes.len() as f32;
+        let mut growth = 0.0;
+        for (bin, (previous, magnitude)) in self.previous.iter_mut().zip(magnitudes).enumerate() {
+            let compressed = (1.0 + Self::COMPRESSION * magnitude).ln();
+            let weight = match self.settings.function {
+                OnsetFunction::SpectralFlux => 1.0,
+                // On average 1.0 too, so both suit the same thresholds
+                OnsetFunction::HighFrequencyContent => 2.0 * bin as f32 / bins,
+            };
+            growth += weight * (compressed - *previous).max(0.0);
+            *previous = compressed;
+        }
+        // The first window has nothing to grow from
+        if self.stft.frames() == 1 {
+            return 0.0;
+        }
+        growth / bins
+    }
+
+    /// The recent median scaled by the sensitivity, plus the fixed threshold.
+    fn threshold(&mut self) -> f32 {
+        self.sorted.clear();
+        self.sorted.extend(&self.history);
+        self.sorted.sort_by(f32::total_cmp);
+        let median = self
+            .sorted
+            .get(self.sorted.len() / 2)
+            .copied()
+            .unwrap_or(0.0);
+        median * self.settings.sensitivity + self.settings.threshold
+    }
+}
+
+impl StftAnalysis for OnsetDetector {
+    fn format(&self) -> AnalysisFormat {
+        AnalysisFormat::new(self.sample_rate, self.settings.window, self.settings.hop)
+    }
+}
+
+fn detect_onsets(
+    settings: Res<OnsetDetectionSettings>,
+    mut samples: EventReader<MicrophoneSamples>,
+    mut detector: Local<Option<OnsetDetector>>,
+    mut found: Local<Vec<DetectedOnset>>,
+    mut onsets: EventWriter<Onset>,
+) {
+    found.clear();
+    // Every setting changes the analysis
+    if settings.is_changed() {
+        *detector = None;
+    }
+    for chunk in samples.read() {
+        let format = AnalysisFormat::new(chunk.sample_rate, settings.window, settings.hop);
+        if needs_rebuild(detector.as_ref(), format) {
+            *detector = Some(OnsetDetector::new(chunk.sample_rate, &settings));
+        }
+        if let Some(detector) = detector.as_mut() {
+            detector.push(&chunk.samples, &mut found);
+        }
+    }
+    let Some(detector) = detector.as_ref() else {
+        return;
+    };
+    // The audio looked at so far ends about now
+    let now = Instant::now();
+    let end = detector.time();
+    onsets.write_batch(found.iter().map(|onset| Onset {
+        at: now - Duration::from_secs_f64((end - onset.time).max(0.0)),
+        strength: onset.strength,
+    }));
+}
+
+#[test]
+fn test_onsets_of_plucked_notes() {
+    let sample_rate = 48_000;
+    let starts = [0.25, 0.6, 0.75, 1.3];
+    let hz = [220.0, 330.0, 262.0, 196.0];
+    // Decaying plucks over a little noise
+    let mut noise = 1u32;
+    let samples: Vec<f32> = (0..sample_rate as usize * 2)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let hiss = (noise >> 8) as f32 / (1 << 24) as f32 * 0.002 - 0.001;
+            starts
+                .iter()
+                .zip(hz)
+                .filter(|(start, _)| t >= **start)
+                .map(|(start, hz)| {
+                    let since = t - start;
+                    let pluck: f32 = (1..=5)
+                        .map(|h| (std::f32::consts::TAU * hz * h as f32 * since).sin() / h as f32)
+                        .sum();
+                    0.2 * pluck * (-since * 6.0).exp()
+                })
+                .sum::<f32>()
+                + hiss
+        })
+        .collect();
+
+    for function in [
+        OnsetFunction::SpectralFlux,
+        OnsetFunction::HighFrequencyContent,
+    ] {
+        let settings = OnsetDetectionSettings {
+            function,
+            ..default()
+        };
+        let mut detector = OnsetDetector::new(sample_rate, &settings);
+        let mut onsets = Vec::new();
+        for chunk in samples.chunks(480) {
+            detector.push(chunk, &mut onsets);
+        }
+        let times: Vec<f64> = onsets.iter().map(|o| o.time).collect();
+        assert_eq!(times.len(), starts.len(), "{function:?} {times:?}");
+        for (time, start) in times.iter().zip(starts) {
+            assert!(
+                (time - f64::from(start)).abs() < 0.02,
+                "{function:?} {times:?}"
+            );
+        }
+        assert!(onsets.iter().all(|o| o.strength > settings.threshold));
+    }
+}
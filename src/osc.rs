@@ -0,0 +1,515 @@
+use crate::bevy_midi::prelude::*;
+use crate::learn::{Parameter, ParameterValues};
+use crate::playback::SongPlayer;
+use crate::score::{NoteJudged, PracticeScore};
+use bevy::prelude::*;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+
+/// Bridges notes, the song transport and synth parameters to Open Sound Control over UDP,
+/// for lighting rigs and control surfaces such as TouchOSC.
+///
+/// Sent to every [`OscSettings::targets`]:
+/// - `/orion/note/on` and `/orion/note/off` with channel, note and velocity, for notes
+///   played on the midi input
+/// - `/orion/song/position` with seconds, beats and 1 if playing, else 0
+/// - `/orion/score` with hits, misses and streak, and `/orion/score/note` with the note
+///   and 1 if it was hit, else 0
+/// - `/orion/param/<name>` with a parameter's value whenever it changes
+///
+/// Received on [`OscSettings::listen`]: the [`OscCommand`]s. Only this machine can send
+/// them unless [`OscSettings::remote_control`] is set.
+pub struct OscBridgePlugin;
+
+impl Plugin for OscBridgePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<OscSettings>()
+            .add_systems(Startup, setup)
+            .add_systems(
+                Update,
+                (
+                    receive_commands,
+                    publish_notes,
+                    publish_position,
+                    publish_score,
+                    publish_parameters,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Settings for [`OscBridgePlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct OscSettings {
+    /// Where commands are received, and what everything is published from. Loopback only
+    /// reaches this machine; publishing to other machines needs
+    /// [`Ipv4Addr::UNSPECIFIED`] or the address of the interface they're on.
+    pub listen: SocketAddr,
+    /// Where everything is published.
+    pub targets: Vec<SocketAddr>,
+    /// Whether to take commands from other machines, rather than only this one.
+    pub remote_control: bool,
+    /// How often the song position is published while playing.
+    pub position_interval: Duration,
+}
+
+impl Default for OscSettings {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 9000)),
+            targets: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 9001))],
+            remote_control: false,
+            position_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+/// An argument of an [`OscMessage`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    #[must_use]
+    pub fn as_f32(&self) -> Option<f32> {
+        match self {
+            Self::Int(i) => Some(*i as f32),
+            Self::Float(f) => Some(*f),
+            Self::Bool(b) => Some(if *b { 1.0 } else { 0.0 }),
+            Self::String(_) => None,
+        }
+    }
+}
+
+/// An Open Sound Control message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    #[must_use]
+    pub fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        Self {
+            address: address.into(),
+            args,
+        }
+    }
+
+    #[must_use]
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_string(&mut out, &self.address);
+        let tags: String = std::iter::once(',')
+            .chain(self.args.iter().map(|arg| match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            }))
+            .collect();
+        write_string(&mut out, &tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(i) => out.extend(i.to_be_bytes()),
+                OscArg::Float(f) => out.extend(f.to_be_bytes()),
+                OscArg::String(s) => write_string(&mut out, s),
+                OscArg::Bool(_) => {}
+            }
+        }
+        out
+    }
+
+    /// Parses a packet, which is a message or a bundle of them.
+    ///
+    /// Bundles are flattened and their time tags ignored, so everything applies at once.
+    #[must_use]
+    pub fn parse_packet(bytes: &[u8]) -> Option<Vec<Self>> {
+        let Some(mut elements) = bytes.strip_prefix(b"#bundle\0") else {
+            return Some(vec![Self::parse(bytes)?]);
+        };
+        elements = elements.get(8..)?;
+        let mut messages = Vec::new();
+        while !elements.is_empty() {
+            let size = i32::from_be_bytes(elements.get(..4)?.try_into().ok()?);
+            let element = elements.get(4..4 + usize::try_from(size).ok()?)?;
+            messages.extend(Self::parse_packet(element)?);
+            elements = &elements[4 + element.len()..];
+        }
+        Some(messages)
+    }
+
+    fn parse(mut bytes: &[u8]) -> Option<Self> {
+        let address = read_string(&mut bytes)?;
+        if !address.starts_with('/') {
+            return None;
+        }
+        // Very old senders leave the type tags out
+        let tags = if bytes.is_empty() {
+            String::new()
+        } else {
+            read_string(&mut bytes)?
+        };
+        let mut args = Vec::new();
+        for tag in tags.strip_prefix(',')?.chars() {
+            let mut take = |n: usize| {
+                let (head, rest) = bytes.split_at_checked(n)?;
+                bytes = rest;
+                Some(head)
+            };
+            match tag {
+                'i' => args.push(OscArg::Int(i32::from_be_bytes(take(4)?.try_into().ok()?))),
+                'f' => args.push(OscArg::Float(f32::from_be_bytes(take(4)?.try_into().ok()?))),
+                'h' => {
+                    let i = i64::from_be_bytes(take(8)?.try_into().ok()?);
+                    args.push(OscArg::Int(i as i32));
+                }
+                'd' => {
+                    let f = f64::from_be_bytes(take(8)?.try_into().ok()?);
+                    args.push(OscArg::Float(f as f32));
+                }
+                's' | 'S' => args.push(OscArg::String(read_string(&mut bytes)?)),
+                'T' => args.push(OscArg::Bool(true)),
+                'F' => args.push(OscArg::Bool(false)),
+                'b' => {
+                    // Blobs are skipped
+                    let size = u32::from_be_bytes(take(4)?.try_into().ok()?) as usize;
+                    take(size.next_multiple_of(4))?;
+                }
+                'N' | 'I' => {}
+                _ => return None,
+            }
+        }
+        Some(Self { address, args })
+    }
+}
+
+// Strings are NUL terminated and padded to 4 bytes.
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend(s.as_bytes());
+    out.resize((out.len() + 1).next_multiple_of(4), 0);
+}
+
+fn read_string(bytes: &mut &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|b| *b == 0)?;
+    let s = std::str::from_utf8(&bytes[..end]).ok()?.to_string();
+    *bytes = bytes.get((end + 1).next_multiple_of(4)..)?;
+    Some(s)
+}
+
+/// A command received over OSC.
+///
+/// Transport commands with a first argument of 0 are ignored, since buttons on control
+/// surfaces also send their release.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OscCommand {
+    /// `/orion/transport/play`
+    Play,
+    /// `/orion/transport/pause`
+    Pause,
+    /// `/orion/transport/toggle`, between playing and paused
+    Toggle,
+    /// `/orion/transport/stop`
+    Stop,
+    /// `/orion/transport/seek` with the position in seconds
+    Seek(Duration),
+    /// `/orion/param/<name>` with a normalized value from 0.0 to 1.0; see
+    /// [`osc_parameter_name`]
+    SetParameter(Parameter, f32),
+}
+
+impl OscCommand {
+    #[must_use]
+    pub fn from_message(message: &OscMessage) -> Option<Self> {
+        let value = message.args.first().and_then(OscArg::as_f32);
+        if let Some(name) = message.address.strip_prefix("/orion/param/") {
+            let parameter = Parameter::ALL
+                .into_iter()
+                .find(|p| osc_parameter_name(*p) == name)?;
+            return Some(Self::SetParameter(parameter, value?.clamp(0.0, 1.0)));
+        }
+
+        let command = message.address.strip_prefix("/orion/transport/")?;
+        if command != "seek" && value == Some(0.0) {
+            return None;
+        }
+        Some(match command {
+            "play" => Self::Play,
+            "pause" => Self::Pause,
+            "toggle" => Self::Toggle,
+            "stop" => Self::Stop,
+            "seek" => Self::Seek(Duration::try_from_secs_f32(value?.max(0.0)).ok()?),
+            _ => return None,
+        })
+    }
+}
+
+/// The last part of a parameter's OSC address.
+#[must_use]
+pub fn osc_parameter_name(parameter: Parameter) -> &'static str {
+    match parameter {
+        Parameter::SynthCutoff => "cutoff",
+        Parameter::ReverbAmount => "reverb",
+        Parameter::Tempo => "tempo",
+        Parameter::PlaybackVolume => "volume",
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) for the bridge's socket.
+#[derive(Resource)]
+pub struct OscBridge {
+    socket: UdpSocket,
+    targets: Vec<SocketAddr>,
+    remote_control: bool,
+}
+
+impl OscBridge {
+    pub fn bind(settings: &OscSettings) -> io::Result<Self> {
+        let socket = UdpSocket::bind(settings.listen)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            targets: settings.targets.clone(),
+            remote_control: settings.remote_control,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Sends `message` to every target.
+    pub fn send(&self, message: &OscMessage) {
+        let bytes = message.write();
+        for target in &self.targets {
+            if let Err(e) = self.socket.send_to(&bytes, target) {
+                debug!("Couldn't send OSC to {}: {}", target, e);
+            }
+        }
+    }
+
+    /// Takes every message received since the last call, dropping those from other
+    /// machines unless remote control is on.
+    #[must_use]
+    pub fn receive(&self) -> Vec<OscMessage> {
+        let mut messages = Vec::new();
+        let mut buffer = [0; 0x10000];
+        // Stops at WouldBlock, or at an error from an earlier send to a closed port
+        while let Ok((n, from)) = self.socket.recv_from(&mut buffer) {
+            if !self.remote_control && !from.ip().is_loopback() {
+                debug!("Ignoring OSC from {}, as remote control is off", from);
+                continue;
+            }
+            match OscMessage::parse_packet(&buffer[..n]) {
+                Some(packet) => messages.extend(packet),
+                None => debug!("Ignoring malformed OSC packet from {}", from),
+            }
+        }
+        messages
+    }
+}
+
+fn setup(mut commands: Commands, settings: Res<OscSettings>) {
+    match OscBridge::bind(&settings) {
+        Ok(bridge) => commands.insert_resource(bridge),
+        Err(e) => warn!("Couldn't start OSC bridge on {}: {}", settings.listen, e),
+    }
+}
+
+fn receive_commands(
+    bridge: Option<Res<OscBridge>>,
+    player: Option<Res<SongPlayer>>,
+    values: Option<ResMut<ParameterValues>>,
+) {
+    let Some(bridge) = bridge else { return };
+    let mut values = values;
+    for message in bridge.receive() {
+        let Some(command) = OscCommand::from_message(&message) else {
+            debug!("Ignoring OSC message {}", message.address);
+            continue;
+        };
+        match (command, &player) {
+            (OscCommand::Play, Some(player)) => player.play(),
+            (OscCommand::Pause, Some(player)) => player.pause(),
+            (OscCommand::Toggle, Some(player)) if player.is_playing() => player.pause(),
+            (OscCommand::Toggle, Some(player)) => player.play(),
+            (OscCommand::Stop, Some(player)) => player.stop(),
+            (OscCommand::Seek(position), Some(player)) => player.seek(position),
+            (OscCommand::SetParameter(parameter, value), _) => {
+                if let Some(values) = &mut values {
+                    values.set(parameter, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn publish_notes(bridge: Option<Res<OscBridge>>, mut midi: EventReader<MidiData>) {
+    let Some(bridge) = bridge else {
+        midi.clear();
+        return;
+    };
+    for data in midi.read() {
+        let address = if data.message.is_note_on() {
+            "/orion/note/on"
+        } else if data.message.is_note_off() {
+            "/orion/note/off"
+        } else {
+            continue;
+        };
+        let [_, note, velocity] = data.message.msg;
+        bridge.send(&OscMessage::new(
+            address,
+            vec![
+                OscArg::Int(data.message.channel().into()),
+                OscArg::Int(note.into()),
+                OscArg::Int(velocity.into()),
+            ],
+        ));
+    }
+}
+
+fn publish_position(
+    bridge: Option<Res<OscBridge>>,
+    settings: Res<OscSettings>,
+    player: Option<Res<SongPlayer>>,
+    time: Res<Time>,
+    mut last: Local<Option<(Duration, bool)>>,
+) {
+    let (Some(bridge), Some(player)) = (bridge, player) else {
+        return;
+    };
+    let playing = player.is_playing();
+    // While playing on an interval, otherwise only when playing starts or stops
+    let due = match *last {
+        Some((sent, was_playing)) => {
+            was_playing != playing
+                || (playing && time.elapsed() >= sent + settings.position_interval)
+        }
+        None => true,
+    };
+    if !due {
+        return;
+    }
+    *last = Some((time.elapsed(), playing));
+
+    let position = player.position();
+    let beats = player.sequence().beats_at(position.as_micros() as u64);
+    bridge.send(&OscMessage::new(
+        "/orion/song/position",
+        vec![
+            OscArg::Float(position.as_secs_f32()),
+            OscArg::Float(beats as f32),
+            OscArg::Int(playing.into()),
+        ],
+    ));
+}
+
+fn publish_score(
+    bridge: Option<Res<OscBridge>>,
+    score: Option<Res<PracticeScore>>,
+    mut judged: EventReader<NoteJudged>,
+) {
+    let (Some(bridge), Some(score)) = (bridge, score) else {
+        judged.clear();
+        return;
+    };
+    for note in judged.read() {
+        bridge.send(&OscMessage::new(
+            "/orion/score/note",
+            vec![
+                OscArg::Int(note.note.into()),
+                OscArg::Int(note.is_hit().into()),
+            ],
+        ));
+    }
+    if score.is_changed() {
+        let count = |n: u32| OscArg::Int(n.try_into().unwrap_or(i32::MAX));
+        bridge.send(&OscMessage::new(
+            "/orion/score",
+            vec![count(score.hits), count(score.misses), count(score.streak)],
+        ));
+    }
+}
+
+fn publish_parameters(bridge: Option<Res<OscBridge>>, values: Option<Res<ParameterValues>>) {
+    let (Some(bridge), Some(values)) = (bridge, values) else {
+        return;
+    };
+    if !values.is_changed() && !bridge.is_added() {
+        return;
+    }
+    for parameter in Parameter::ALL {
+        bridge.send(&OscMessage::new(
+            format!("/orion/param/{}", osc_parameter_name(parameter)),
+            vec![OscArg::Float(values.get(parameter))],
+        ));
+    }
+}
+
+#[test]
+fn test_osc_bridge_over_local_socket() {
+    let seek = OscMessage::new("/orion/transport/seek", vec![OscArg::Float(12.5)]);
+    let bytes = seek.write();
+    assert_eq!(bytes.len() % 4, 0);
+    assert_eq!(OscMessage::parse_packet(&bytes), Some(vec![seek.clone()]));
+    let mut bundle = b"#bundle\0".to_vec();
+    bundle.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+    bundle.extend((bytes.len() as i32).to_be_bytes());
+    bundle.extend(&bytes);
+    assert_eq!(OscMessage::parse_packet(&bundle), Some(vec![seek.clone()]));
+
+    // A tablet stand-in
+    let tablet = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    tablet
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let bridge = OscBridge::bind(&OscSettings {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        targets: vec![tablet.local_addr().unwrap()],
+        ..default()
+    })
+    .unwrap();
+    let bridge_addr = bridge.local_addr().unwrap();
+    tablet.send_to(&bytes, bridge_addr).unwrap();
+    let release = OscMessage::new("/orion/transport/play", vec![OscArg::Float(0.0)]);
+    tablet.send_to(&release.write(), bridge_addr).unwrap();
+    let cutoff = OscMessage::new("/orion/param/cutoff", vec![OscArg::Float(1.5)]);
+    tablet.send_to(&cutoff.write(), bridge_addr).unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..500 {
+        received.extend(bridge.receive());
+        if received.len() == 3 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let commands: Vec<_> = received.iter().map(OscCommand::from_message).collect();
+    assert_eq!(
+        commands,
+        vec![
+            Some(OscCommand::Seek(Duration::from_secs_f32(12.5))),
+            None,
+            Some(OscCommand::SetParameter(Parameter::SynthCutoff, 1.0)),
+        ]
+    );
+
+    let note_on = OscMessage::new(
+        "/orion/note/on",
+        vec![OscArg::Int(0), OscArg::Int(60), OscArg::Int(100)],
+    );
+    bridge.send(&note_on);
+    let mut buffer = [0; 1024];
+    let n = tablet.recv(&mut buffer).unwrap();
+    assert_eq!(OscMessage::parse_packet(&buffer[..n]), Some(vec![note_on]));
+}
@@ -0,0 +1,180 @@
+use crate::audio::SharedSynthEngine;
+use crate::bevy_midi::prelude::*;
+use bevy::prelude::*;
+
+/// Silences every note when asked to, or when the input goes away mid-note.
+///
+/// A panic sends All Notes Off and All Sound Off on every channel to the output port and
+/// the internal synth, and forgets the held notes so no key stays pressed on screen.
+pub struct MidiPanicPlugin;
+
+impl Plugin for MidiPanicPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiPanicSettings>()
+            .add_event::<MidiPanic>()
+            .add_systems(Update, panic_on_key)
+            .add_systems(
+                PreUpdate,
+                (panic_on_disconnect, handle_panic)
+                    .chain()
+                    .after(MidiInputSystems),
+            );
+    }
+}
+
+/// Settings for [`MidiPanicPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct MidiPanicSettings {
+    pub key: KeyCode,
+    /// Panic when the input port disconnects, or disappears when the ports are refreshed.
+    pub on_disconnect: bool,
+}
+
+impl Default for MidiPanicSettings {
+    fn default() -> Self {
+        Self {
+            key: KeyCode::F12,
+            on_disconnect: true,
+        }
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) that silences every note.
+#[derive(Event, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MidiPanic;
+
+impl MidiPanic {
+    /// The messages a panic sends on each channel.
+    #[must_use]
+    pub fn messages() -> Vec<MidiMessage> {
+        (0..16)
+            .flat_map(|channel| {
+                [CONTROL_ALL_NOTES_OFF, CONTROL_ALL_SOUND_OFF]
+                    .map(|controller| MidiMessage::control_change(channel, controller, 0))
+            })
+            .collect()
+    }
+}
+
+fn panic_on_key(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<MidiPanicSettings>,
+    mut panic: EventWriter<MidiPanic>,
+) {
+    if keys.just_pressed(settings.key) {
+        panic.write(MidiPanic);
+    }
+}
+
+fn panic_on_disconnect(
+    settings: Res<MidiPanicSettings>,
+    input: Option<Res<MidiInput>>,
+    conn: Res<MidiInputConnection>,
+    mut was_connected: Local<bool>,
+    mut panic: EventWriter<MidiPanic>,
+) {
+    if !settings.on_disconnect {
+        return;
+    }
+    if conn.is_changed() {
+        if *was_connected && !conn.is_connected() {
+            info!("Midi input disconnected, silencing its notes");
+            panic.write(MidiPanic);
+        }
+        *was_connected = conn.is_connected();
+    }
+    // Unplugged devices are only noticed once the ports are refreshed
+    if let Some(input) = input
+        && input.is_changed()
+        && let Some(port) = conn.port()
+        && !input.ports().iter().any(|(_, p)| p == port)
+    {
+        input.disconnect();
+    }
+}
+
+fn handle_panic(
+    mut panic: EventReader<MidiPanic>,
+    mut state: ResMut<MidiState>,
+    mut expression: ResMut<PerNoteExpression>,
+    mut changed: EventWriter<HeldNotesChanged>,
+    output: Option<Res<MidiOutput>>,
+    synth: Option<Res<SharedSynthEngine>>,
+) {
+    if panic.read().count() == 0 {
+        return;
+    }
+    let messages = MidiPanic::messages();
+    if let Some(output) = output {
+        for message in &messages {
+            output.send(*message);
+        }
+    }
+    if let Some(synth) = synth {
+        let mut synth = synth.0.lock().unwrap();
+        for message in messages.iter().filter_map(|m| Midi2Message::from_midi1(*m)) {
+            synth.handle(&message);
+        }
+    }
+    if state.held_notes().next().is_some() {
+        changed.write(HeldNotesChanged { notes: Vec::new() });
+    }
+    state.clear();
+    *expression = PerNoteExpression::default();
+}
+
+#[test]
+fn test_panic_on_disconnect_clears_held_notes_and_silences_output() {
+    use std::time::{Duration, Instant};
+
+    let mock = MockMidi::new()
+        .with_input_port("keys")
+        .with_output_port("piano");
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .init_resource::<ButtonInput<KeyCode>>()
+        .insert_resource(MidiInputBackendOverride::new(mock.input()))
+        .insert_resource(MidiOutputBackendOverride::new(mock.output()))
+        .add_plugins((MidiInputPlugin, MidiOutputPlugin, MidiPanicPlugin));
+
+    let timeout = Duration::from_secs(5);
+    let deadline = Instant::now() + timeout;
+    let update_until = |app: &mut App, done: &dyn Fn(&World) -> bool| {
+        while !done(app.world()) {
+            assert!(Instant::now() < deadline, "timed out");
+            app.update();
+        }
+    };
+    app.update();
+    update_until(&mut app, &|world| {
+        !world.resource::<MidiInput>().ports().is_empty()
+            && !world.resource::<MidiOutput>().ports().is_empty()
+    });
+    let input = app.world().resource::<MidiInput>();
+    input.connect(input.ports()[0].1.clone());
+    let output = app.world().resource::<MidiOutput>();
+    output.connect(output.ports()[0].1.clone());
+    assert!(mock.wait_for_input(timeout));
+    assert!(mock.wait_for_output(timeout));
+    update_until(&mut app, &|world| {
+        world.resource::<MidiInputConnection>().is_connected()
+    });
+
+    mock.inject(1_000, &[0x90, 60, 100]);
+    update_until(&mut app, &|world| world.resource::<MidiState>().is_held(60));
+
+    app.world().resource::<MidiInput>().disconnect();
+    update_until(&mut app, &|world| {
+        !world.resource::<MidiState>().is_held(60)
+    });
+
+    let expected: Vec<_> = MidiPanic::messages()
+        .iter()
+        .map(|m| m.msg.to_vec())
+        .collect();
+    let mut sent = Vec::new();
+    while sent.len() < expected.len() && Instant::now() < deadline {
+        sent.extend(mock.take_sent());
+    }
+    assert_eq!(sent, expected);
+}
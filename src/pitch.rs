@@ -0,0 +1,268 @@
+use crate::bevy_mic::microphone::{MicrophoneSamples, SampleFrames};
+use crate::stft::{AnalysisFormat, StftAnalysis, needs_rebuild};
+use bevy::prelude::*;
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use std::sync::Arc;
+
+/// Detects the pitch of a single voice or instrument on the microphone, so singers and
+/// acoustic instruments can play along without midi.
+pub struct PitchDetectionPlugin;
+
+impl Plugin for PitchDetectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PitchDetectionSettings>()
+            .add_event::<DetectedPitch>()
+            .add_systems(Update, detect_pitch);
+    }
+}
+
+/// Settings for [`PitchDetectionPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct PitchDetectionSettings {
+    /// Samples analysed at once. Longer windows reach lower notes but react slower.
+    pub window: usize,
+    /// Samples between the starts of consecutive windows.
+    pub hop: usize,
+    pub min_hz: f32,
+    pub max_hz: f32,
+    /// Quieter windows, by RMS, are treated as silence.
+    pub min_rms: f32,
+    /// Less confident pitches aren't reported.
+    pub min_confidence: f32,
+    /// The pitch of A4, which note names are relative to.
+    pub reference_hz: f32,
+}
+
+impl Default for PitchDetectionSettings {
+    fn default() -> Self {
+        Self {
+            window: 2048,
+            hop: 512,
+            min_hz: 40.0,
+            max_hz: 2000.0,
+            min_rms: 0.01,
+            min_confidence: 0.7,
+            reference_hz: 440.0,
+        }
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) for each window of microphone audio with a clear
+/// pitch.
+#[derive(Event, Clone, Copy, Debug, PartialEq)]
+pub struct DetectedPitch {
+    pub hz: f32,
+    /// The nearest midi note.
+    pub midi_note: u8,
+    /// How far `hz` is from `midi_note`, from -50 to 50.
+    pub cents_off: f32,
+    /// How periodic the window is, from 0.0 to 1.0.
+    pub confidence: f32,
+}
+
+impl DetectedPitch {
+    /// Describes `hz`, tuned relative to `reference_hz` for A4.
+    #[must_use]
+    pub fn new(hz: f32, confidence: f32, reference_hz: f32) -> Self {
+        let note = 69.0 + 12.0 * (hz / reference_hz).log2();
+        let nearest = note.round().clamp(0.0, 127.0);
+        Self {
+            hz,
+            midi_note: nearest as u8,
+            cents_off: (note - nearest) * 100.0,
+            confidence,
+        }
+    }
+}
+
+/// The McLeod Pitch Method: finds the period of a window from its normalized square
+/// difference function, which is 1.0 at lags where the signal repeats itself exactly.
+///
+/// Autocorrelation is done through an FFT, with the plans and buffers kept between windows.
+pub struct PitchDetector {
+    sample_rate: f32,
+    window: usize,
+    hop: usize,
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    padded: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    nsdf: Vec<f32>,
+    peaks: Vec<usize>,
+}
+
+impl PitchDetector {
+    /// Of the maxima of the NSDF, the first within this fraction of the highest is the
+    /// period, so an octave below the true pitch isn't picked.
+    const PEAK_THRESHOLD: f32 = 0.9;
+
+    /// Makes a detector for windows of `window` samples, which start every `hop` samples.
+    #[must_use]
+    pub fn new(sample_rate: u32, window: usize, hop: usize) -> Self {
+        let mut planner = RealFftPlanner::new();
+        // Padded so the circular autocorrelation doesn't wrap around
+        let forward = planner.plan_fft_forward(window * 2);
+        let inverse = planner.plan_fft_inverse(window * 2);
+        Self {
+            sample_rate: sample_rate as f32,
+            window,
+            hop,
+            padded: forward.make_input_vec(),
+            spectrum: forward.make_output_vec(),
+            nsdf: vec![0.0; window],
+            peaks: Vec::new(),
+            forward,
+            inverse,
+        }
+    }
+
+    /// Finds the pitch of `samples` between `min_hz` and `max_hz`, returning it with its
+    /// clarity as confidence, or `None` if nothing in that range repeats.
+    ///
+    /// Only the first `window` samples, as given to [`new`](Self::new), are looked at.
+    pub fn detect(&mut self, samples: &[f32], min_hz: f32, max_hz: f32) -> Option<(f32, f32)> {
+        let n = self.window.min(samples.len());
+        let samples = &samples[..n];
+        let min_lag = ((self.sample_rate / max_hz).floor() as usize).max(1);
+        let max_lag = ((self.sample_rate / min_hz).ceil() as usize).min(n / 2);
+        if min_lag + 2 >= max_lag {
+            return None;
+        }
+
+        self.padded.fill(0.0);
+        self.padded[..n].copy_from_slice(samples);
+        self.forward
+            .process(&mut self.padded, &mut self.spectrum)
+            .ok()?;
+        for bin in &mut self.spectrum {
+            *bin = Complex::new(bin.norm_sqr(), 0.0);
+        }
+        self.inverse
+            .process(&mut self.spectrum, &mut self.padded)
+            .ok()?;
+        let scale = 1.0 / self.padded.len() as f32;
+
+        // The energy of both overlapping parts of the window, shrinking as the lag grows
+        let mut energy = 2.0 * samples.iter().map(|s| s * s).sum::<f32>();
+        for lag in 0..=max_lag {
+            if lag > 0 {
+                energy -= samples[lag - 1].powi(2) + samples[n - lag].powi(2);
+            }
+            self.nsdf[lag] = if energy > f32::EPSILON {
+                2.0 * self.padded[lag] * scale / energy
+            } else {
+                0.0
+            };
+        }
+
+        // The highest point between each upward and downward zero crossing
+        let nsdf = &self.nsdf[..=max_lag];
+        let peaks = &mut self.peaks;
+        peaks.clear();
+        let mut lag = 1;
+        while lag < max_lag && nsdf[lag] > 0.0 {
+            lag += 1;
+        }
+        while lag < max_lag {
+            while lag < max_lag && nsdf[lag] <= 0.0 {
+                lag += 1;
+            }
+            let mut best = lag;
+            while lag < max_lag && nsdf[lag] > 0.0 {
+                if nsdf[lag] > nsdf[best] {
+                    best = lag;
+                }
+                lag += 1;
+            }
+            if best >= min_lag && best < max_lag && nsdf[best] > 0.0 {
+                peaks.push(best);
+            }
+        }
+        let highest = peaks.iter().map(|&p| nsdf[p]).fold(0.0, f32::max);
+        let peak = *peaks
+            .iter()
+            .find(|&&p| nsdf[p] >= Self::PEAK_THRESHOLD * highest)?;
+
+        // Parabolic interpolation between the neighbouring lags
+        let (left, middle, right) = (nsdf[peak - 1], nsdf[peak], nsdf[peak + 1]);
+        let curvature = left - 2.0 * middle + right;
+        let (offset, clarity) = if curvature.abs() > f32::EPSILON {
+            let offset = 0.5 * (left - right) / curvature;
+            (offset, middle - 0.25 * (left - right) * offset)
+        } else {
+            (0.0, middle)
+        };
+        let period = peak as f32 + offset;
+        Some((self.sample_rate / period, clarity.clamp(0.0, 1.0)))
+    }
+}
+
+impl StftAnalysis for PitchDetector {
+    fn format(&self) -> AnalysisFormat {
+        AnalysisFormat::new(self.sample_rate as u32, self.window, self.hop)
+    }
+}
+
+fn detect_pitch(
+    settings: Res<PitchDetectionSettings>,
+    mut samples: EventReader<MicrophoneSamples>,
+    mut detector: Local<Option<(PitchDetector, SampleFrames)>>,
+    mut pitches: EventWriter<DetectedPitch>,
+) {
+    for chunk in samples.read() {
+        let format = AnalysisFormat::new(chunk.sample_rate, settings.window, settings.hop);
+        if needs_rebuild(detector.as_ref().map(|(d, _)| d), format) {
+            *detector = Some((
+                PitchDetector::new(chunk.sample_rate, settings.window, settings.hop),
+                SampleFrames::new(settings.window, settings.hop),
+            ));
+        }
+        let Some((detector, frames)) = detector.as_mut() else {
+            continue;
+        };
+        frames.push(&chunk.samples, |window| {
+            let rms = (window.iter().map(|s| s * s).sum::<f32>() / window.len() as f32).sqrt();
+            if rms >= settings.min_rms
+                && let Some((hz, confidence)) =
+                    detector.detect(window, settings.min_hz, settings.max_hz)
+                && confidence >= settings.min_confidence
+            {
+                pitches.write(DetectedPitch::new(hz, confidence, settings.reference_hz));
+            }
+        });
+    }
+}
+
+#[test]
+fn test_pitch_detection_of_a_tone() {
+    let sample_rate = 48_000;
+    let tone = |hz: f32| -> Vec<f32> {
+        (0..2048)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                // A few harmonics, like a real instrument
+                (0.5 * (std::f32::consts::TAU * hz * t).sin()
+                    + 0.3 * (std::f32::consts::TAU * 2.0 * hz * t).sin()
+                    + 0.2 * (std::f32::consts::TAU * 3.0 * hz * t).sin())
+                    * 0.5
+            })
+            .collect()
+    };
+    let mut detector = PitchDetector::new(sample_rate, 2048, 512);
+
+    let (hz, confidence) = detector.detect(&tone(220.0), 40.0, 2000.0).unwrap();
+    assert!((hz - 220.0).abs() < 0.5, "{hz}");
+    assert!(confidence > 0.9);
+    let pitch = DetectedPitch::new(hz, confidence, 440.0);
+    assert_eq!(pitch.midi_note, 57);
+    assert!(pitch.cents_off.abs() < 5.0);
+
+    // 445 Hz is 19.6 cents sharp of A4
+    let (hz, _) = detector.detect(&tone(445.0), 40.0, 2000.0).unwrap();
+    let pitch = DetectedPitch::new(hz, 1.0, 440.0);
+    assert_eq!(pitch.midi_note, 69);
+    assert!((pitch.cents_off - 19.6).abs() < 2.0, "{}", pitch.cents_off);
+
+    assert_eq!(detector.detect(&[0.0; 2048], 40.0, 2000.0), None);
+}
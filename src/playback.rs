@@ -0,0 +1,794 @@
+use crate::bevy_midi::prelude::*;
+use crate::songs::{CurrentSong, DEFAULT_TEMPO};
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use midly::{MetaMessage, Smf, Timing, TrackEventKind, live::LiveEvent};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Plays the current song out to the connected [`MidiOutput`] port, e.g. so a digital
+/// piano can play the backing parts.
+pub struct SongPlaybackPlugin;
+
+impl Plugin for SongPlaybackPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SongPlaybackSettings>()
+            .add_systems(PostStartup, setup)
+            .add_systems(Update, (load_current_song, playback_keys))
+            .add_systems(PostUpdate, shutdown);
+    }
+}
+
+/// Settings for [`SongPlaybackPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct SongPlaybackSettings {
+    pub play_pause_key: KeyCode,
+    /// Stops playback and rewinds to the start.
+    pub stop_key: KeyCode,
+}
+
+impl Default for SongPlaybackSettings {
+    fn default() -> Self {
+        Self {
+            play_pause_key: KeyCode::Space,
+            stop_key: KeyCode::Backspace,
+        }
+    }
+}
+
+/// A channel message of a [`Sequence`], at its absolute time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SequenceEvent {
+    /// Microseconds from the start of the song.
+    pub micros: u64,
+    /// Index of the track the message came from.
+    pub track: usize,
+    pub message: MidiMessage,
+}
+
+/// A song flattened to a time-ordered list of notes and controllers, ready to be played.
+#[derive(Clone, Debug)]
+pub struct Sequence {
+    pub events: Vec<SequenceEvent>,
+    pub track_count: usize,
+    /// Tempo changes as (microseconds, microseconds per beat), starting at 0.
+    pub tempos: Vec<(u64, u32)>,
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self {
+            events: Vec::new(),
+            track_count: 0,
+            tempos: vec![(0, DEFAULT_TEMPO)],
+        }
+    }
+}
+
+impl Sequence {
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        Ok(Self::from_smf(&Smf::parse(&data)?))
+    }
+
+    /// Flattens `smf`, following its tempo changes.
+    ///
+    /// Only three-byte channel messages are kept: notes, aftertouch, controllers and pitch bend.
+    #[must_use]
+    pub fn from_smf(smf: &Smf) -> Self {
+        let mut timed: Vec<(u64, usize, TrackEventKind)> = Vec::new();
+        for (track, events) in smf.tracks.iter().enumerate() {
+            let mut tick = 0u64;
+            for event in events {
+                tick += u64::from(u32::from(event.delta));
+                timed.push((tick, track, event.kind));
+            }
+        }
+        // Stable, so events on the same tick keep their track order.
+        timed.sort_by_key(|(tick, _, _)| *tick);
+
+        // Microseconds per tick; metrical timing starts at the default 120 bpm.
+        let (ticks_per_beat, mut micros_per_tick) = match smf.header.timing {
+            Timing::Metrical(tpb) => {
+                let tpb = f64::from(u16::from(tpb).max(1));
+                (Some(tpb), 500_000.0 / tpb)
+            }
+            Timing::Timecode(fps, subframe) => (
+                None,
+                1_000_000.0 / (fps.as_f32() as f64 * f64::from(subframe.max(1))),
+            ),
+        };
+
+        let mut events = Vec::new();
+        let mut tempos = vec![(0, DEFAULT_TEMPO)];
+        let (mut last_tick, mut micros) = (0u64, 0.0f64);
+        for (tick, track, kind) in timed {
+            micros += (tick - last_tick) as f64 * micros_per_tick;
+            last_tick = tick;
+
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    if let Some(tpb) = ticks_per_beat {
+                        micros_per_tick = f64::from(u32::from(tempo)) / tpb;
+                        let change = (micros as u64, u32::from(tempo).max(1));
+                        match tempos.last_mut() {
+                            Some(last) if last.0 == change.0 => *last = change,
+                            _ => tempos.push(change),
+                        }
+                    }
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let mut bytes = Vec::with_capacity(3);
+                    let live = LiveEvent::Midi { channel, message };
+                    if live.write_std(&mut bytes).is_ok() && bytes.len() == 3 {
+                        events.push(SequenceEvent {
+                            micros: micros as u64,
+                            track,
+                            message: [bytes[0], bytes[1], bytes[2]].into(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            events,
+            track_count: smf.tracks.len(),
+            tempos,
+        }
+    }
+
+    /// Microseconds until the last event.
+    #[must_use]
+    pub fn duration(&self) -> u64 {
+        self.events.last().map_or(0, |e| e.micros)
+    }
+
+    /// The loop from `start` to `end`, cut off at the end of the song, or `None` if nothing
+    /// is left of it to play.
+    #[must_use]
+    pub fn loop_range(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        let end = end.min(self.duration());
+        (start < end).then_some((start, end))
+    }
+
+    /// The tempo at `micros`, in microseconds per beat.
+    #[must_use]
+    pub fn tempo_at(&self, micros: u64) -> u32 {
+        self.tempos
+            .iter()
+            .take_while(|(start, _)| *start <= micros)
+            .last()
+            .map_or(DEFAULT_TEMPO, |(_, tempo)| *tempo)
+    }
+
+    /// Converts a time to beats from the start of the song, following the tempo changes.
+    #[must_use]
+    pub fn beats_at(&self, micros: u64) -> f64 {
+        let mut beats = 0.0;
+        for (i, &(start, tempo)) in self.tempos.iter().enumerate() {
+            if start >= micros {
+                break;
+            }
+            let end = self.tempos.get(i + 1).map_or(micros, |t| t.0.min(micros));
+            beats += (end - start) as f64 / f64::from(tempo);
+        }
+        beats
+    }
+
+    /// Converts beats from the start of the song to a time, the inverse of
+    /// [`beats_at`](Self::beats_at).
+    #[must_use]
+    pub fn micros_at(&self, beats: f64) -> u64 {
+        let mut remaining = beats.max(0.0);
+        for (i, &(start, tempo)) in self.tempos.iter().enumerate() {
+            let length = self
+                .tempos
+                .get(i + 1)
+                .map(|t| (t.0 - start) as f64 / f64::from(tempo));
+            match length {
+                Some(length) if remaining > length => remaining -= length,
+                _ => return start + (remaining * f64::from(tempo)) as u64,
+            }
+        }
+        (remaining * f64::from(DEFAULT_TEMPO)) as u64
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) controlling song playback.
+///
+/// Messages are scheduled on a dedicated thread rather than the frame loop, so their timing
+/// doesn't depend on the frame rate.
+#[derive(Resource)]
+pub struct SongPlayer {
+    sender: Sender<PlayerCommand>,
+    sequence: Arc<Sequence>,
+    status: Arc<Mutex<PlaybackStatus>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SongPlayer {
+    /// Replaces the song, stopping playback.
+    pub fn load(&mut self, sequence: Sequence) {
+        self.sequence = Arc::new(sequence);
+        self.request(PlayerCommand::Load(self.sequence.clone()));
+    }
+
+    /// The loaded song.
+    #[must_use]
+    pub fn sequence(&self) -> &Sequence {
+        &self.sequence
+    }
+
+    pub fn play(&self) {
+        self.request(PlayerCommand::Play);
+    }
+
+    /// Pauses playback, releasing any sounding notes.
+    pub fn pause(&self) {
+        self.request(PlayerCommand::Pause);
+    }
+
+    /// Stops playback and rewinds to the start, resetting every channel.
+    pub fn stop(&self) {
+        self.request(PlayerCommand::Stop);
+    }
+
+    /// Jumps to `position` from the start of the song.
+    pub fn seek(&self, position: Duration) {
+        self.request(PlayerCommand::Seek(position.as_micros() as u64));
+    }
+
+    /// Jumps to `beats` from the start of the song, following its tempo changes.
+    pub fn seek_beats(&self, beats: f64) {
+        self.request(PlayerCommand::Seek(self.sequence.micros_at(beats)));
+    }
+
+    /// Repeats the song between the given start and end, or plays it through when `None`.
+    ///
+    /// The end is cut off at the end of the song, and a range with nothing left in it turns
+    /// looping off.
+    pub fn set_loop(&self, range: Option<(Duration, Duration)>) {
+        self.request(PlayerCommand::SetLoop(range.map(|(start, end)| {
+            (start.as_micros() as u64, end.as_micros() as u64)
+        })));
+    }
+
+    /// Plays faster (> 1.0) or slower (< 1.0) than the song's own tempo.
+    pub fn set_tempo_scale(&self, scale: f64) {
+        self.request(PlayerCommand::SetTempoScale(scale));
+    }
+
+    /// Scales the velocity of every played note, from 0.0 (silent) to 1.0 (as written).
+    pub fn set_volume(&self, volume: f32) {
+        self.request(PlayerCommand::SetVolume(volume));
+    }
+
+    /// Mutes or unmutes a track of the song, e.g. the part the player is practising.
+    pub fn set_track_muted(&self, track: usize, muted: bool) {
+        self.request(PlayerCommand::SetTrackMuted(track, muted));
+    }
+
+    /// Sends midi beat clock and transport messages along with the song, so external
+    /// devices can follow it.
+    ///
+    /// While enabled, seeking snaps to sixteenth notes, the resolution of Song Position
+    /// Pointer.
+    pub fn set_clock_output(&self, enabled: bool) {
+        self.request(PlayerCommand::SetClockOutput(enabled));
+    }
+
+    #[must_use]
+    pub fn is_playing(&self) -> bool {
+        self.status().playing
+    }
+
+    /// The current position from the start of the song.
+    #[must_use]
+    pub fn position(&self) -> Duration {
+        Duration::from_micros(self.status().position())
+    }
+
+    /// The length of the loaded song.
+    #[must_use]
+    pub fn duration(&self) -> Duration {
+        Duration::from_micros(self.status().duration)
+    }
+
+    fn status(&self) -> PlaybackStatus {
+        *self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn request(&self, command: PlayerCommand) {
+        if self.sender.send(command).is_err() {
+            warn!("Song player has stopped");
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            let _ = self.sender.send(PlayerCommand::Shutdown);
+            if worker.join().is_err() {
+                warn!("song player panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SongPlayer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+enum PlayerCommand {
+    Load(Arc<Sequence>),
+    Play,
+    Pause,
+    Stop,
+    Seek(u64),
+    SetLoop(Option<(u64, u64)>),
+    SetTempoScale(f64),
+    SetVolume(f32),
+    SetTrackMuted(usize, bool),
+    SetClockOutput(bool),
+    Shutdown,
+}
+
+// Shared with the worker so the app can read the position without a round trip.
+#[derive(Clone, Copy, Debug)]
+struct PlaybackStatus {
+    playing: bool,
+    // Song position at `anchor_instant`
+    anchor_micros: u64,
+    anchor_instant: Instant,
+    tempo_scale: f64,
+    duration: u64,
+}
+
+impl PlaybackStatus {
+    fn position(&self) -> u64 {
+        if self.playing {
+            let elapsed = self.anchor_instant.elapsed().as_micros() as f64 * self.tempo_scale;
+            self.anchor_micros + elapsed as u64
+        } else {
+            self.anchor_micros
+        }
+    }
+}
+
+struct PlayerWorker {
+    receiver: Receiver<PlayerCommand>,
+    output: MidiOutputHandle,
+    status: Arc<Mutex<PlaybackStatus>>,
+    sequence: Arc<Sequence>,
+    // Index of the next event to send
+    next: usize,
+    loop_range: Option<(u64, u64)>,
+    muted: HashSet<usize>,
+    // (track, channel, note) of notes we started and haven't released
+    sounding: HashSet<(usize, u8, u8)>,
+    clock_output: bool,
+    // Index of the next clock pulse, counted from the start of the song
+    next_pulse: u64,
+    volume: f32,
+}
+
+impl PlayerWorker {
+    // Runs until told to shut down, or until the app drops the player.
+    fn run(mut self) {
+        loop {
+            let command = match self.time_until_next() {
+                Some(timeout) => self.receiver.recv_timeout(timeout),
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match command {
+                Ok(PlayerCommand::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            self.dispatch_due();
+        }
+        self.halt();
+        self.release_all(true);
+    }
+
+    fn handle(&mut self, command: PlayerCommand) {
+        match command {
+            PlayerCommand::Load(sequence) => {
+                self.halt();
+                self.release_all(true);
+                self.sequence = sequence;
+                self.loop_range = self
+                    .loop_range
+                    .and_then(|(start, end)| self.sequence.loop_range(start, end));
+                self.update(|s| s.duration = self.sequence.duration());
+                self.seek(0);
+            }
+            PlayerCommand::Play => {
+                if !self.status().playing {
+                    // A song that played to the end starts over
+                    if self.next >= self.sequence.events.len() && self.loop_range.is_none() {
+                        self.seek(0);
+                    }
+                    if self.clock_output {
+                        let position = self.position();
+                        self.output.send_clock(if position == 0 {
+                            MidiClockMessage::Start
+                        } else {
+                            MidiClockMessage::Continue
+                        });
+                    }
+                    self.set_playing(true);
+                }
+            }
+            PlayerCommand::Pause => {
+                self.halt();
+                self.release_all(false);
+            }
+            PlayerCommand::Stop => {
+                self.halt();
+                self.release_all(true);
+                self.seek(0);
+            }
+            PlayerCommand::Seek(micros) => {
+                self.release_all(false);
+                self.seek(micros);
+            }
+            // An empty loop would seek back to its end over and over
+            PlayerCommand::SetLoop(range) => {
+                self.loop_range =
+                    range.and_then(|(start, end)| self.sequence.loop_range(start, end));
+            }
+            PlayerCommand::SetTempoScale(scale) => {
+                let position = self.position();
+                self.update(|s| {
+                    s.anchor_micros = position;
+                    s.anchor_instant = Instant::now();
+                    s.tempo_scale = scale.max(0.01);
+                });
+            }
+            PlayerCommand::SetVolume(volume) => self.volume = volume.clamp(0.0, 1.0),
+            PlayerCommand::SetTrackMuted(track, muted) => {
+                if muted {
+                    self.muted.insert(track);
+                    self.release_track(track);
+                } else {
+                    self.muted.remove(&track);
+                }
+            }
+            PlayerCommand::SetClockOutput(enabled) => {
+                if enabled == self.clock_output {
+                    return;
+                }
+                let playing = self.status().playing;
+                if enabled {
+                    self.clock_output = true;
+                    self.seek(self.position());
+                    if playing {
+                        self.output.send_clock(MidiClockMessage::Continue);
+                    }
+                } else {
+                    if playing {
+                        self.output.send_clock(MidiClockMessage::Stop);
+                    }
+                    self.clock_output = false;
+                }
+            }
+            PlayerCommand::Shutdown => {}
+        }
+    }
+
+    // Sends every event that is due, wrapping around the loop if needed.
+    fn dispatch_due(&mut self) {
+        let status = self.status();
+        if !status.playing {
+            return;
+        }
+        let mut position = status.position();
+
+        if let Some((start, end)) = self.loop_range
+            && position >= end
+        {
+            self.send_until(end);
+            self.release_all(false);
+            self.seek(start);
+            position = self.position();
+        }
+        self.send_until(position);
+
+        if self.next >= self.sequence.events.len() && self.loop_range.is_none() {
+            self.halt();
+            self.release_all(false);
+        }
+    }
+
+    fn send_until(&mut self, position: u64) {
+        let sequence = self.sequence.clone();
+        loop {
+            let pulse = self.clock_output.then(|| self.pulse_micros());
+            let event = sequence.events.get(self.next);
+            match (pulse, event) {
+                // Clock goes first on ties, so followers reach the beat before its notes
+                (Some(pulse), event)
+                    if pulse <= position && event.is_none_or(|e| pulse <= e.micros) =>
+                {
+                    self.next_pulse += 1;
+                    self.output.send_clock(MidiClockMessage::Clock);
+                }
+                (_, Some(event)) if event.micros <= position => {
+                    self.next += 1;
+                    if self.muted.contains(&event.track) {
+                        continue;
+                    }
+
+                    let mut message = event.message;
+                    let key = (event.track, message.channel(), message.msg[1]);
+                    if message.is_note_on() {
+                        let velocity = (f32::from(message.msg[2]) * self.volume).round() as u8;
+                        if velocity == 0 {
+                            continue;
+                        }
+                        message.msg[2] = velocity;
+                        self.sounding.insert(key);
+                    } else if message.is_note_off() {
+                        self.sounding.remove(&key);
+                    }
+                    self.output.send(message);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // How long until the next event, clock pulse or loop point, or `None` if there's
+    // nothing to wait for.
+    fn time_until_next(&self) -> Option<Duration> {
+        let status = self.status();
+        if !status.playing {
+            return None;
+        }
+        let next = self.sequence.events.get(self.next).map(|e| e.micros);
+        let pulse = self.clock_output.then(|| self.pulse_micros());
+        let loop_end = self.loop_range.map(|(_, end)| end);
+        let Some(target) = [next, pulse, loop_end].into_iter().flatten().min() else {
+            return Some(Duration::ZERO);
+        };
+        let wait = target.saturating_sub(status.position()) as f64 / status.tempo_scale;
+        Some(Duration::from_micros(wait as u64))
+    }
+
+    fn pulse_micros(&self) -> u64 {
+        let beats = self.next_pulse as f64 / f64::from(MidiClockMessage::PULSES_PER_BEAT);
+        self.sequence.micros_at(beats)
+    }
+
+    // With clock output, snaps to the sixteenth note before `micros` and tells followers
+    // where we are.
+    fn seek(&mut self, mut micros: u64) {
+        if self.clock_output {
+            let sixteenths = (self.sequence.beats_at(micros) * 4.0).floor();
+            micros = self.sequence.micros_at(sixteenths / 4.0);
+            self.next_pulse = sixteenths as u64 * u64::from(MidiClockMessage::PULSES_PER_BEAT / 4);
+
+            // Followers only honour Song Position Pointer while stopped
+            let playing = self.status().playing;
+            if playing {
+                self.output.send_clock(MidiClockMessage::Stop);
+            }
+            let sixteenths = sixteenths.min(f64::from(0x3fff)) as u16;
+            self.output
+                .send_clock(MidiClockMessage::SongPosition(sixteenths));
+            if playing {
+                self.output.send_clock(MidiClockMessage::Continue);
+            }
+        }
+
+        self.next = self.sequence.events.partition_point(|e| e.micros < micros);
+        self.update(|s| {
+            s.anchor_micros = micros;
+            s.anchor_instant = Instant::now();
+        });
+    }
+
+    // Stops playing in place, telling followers to stop too.
+    fn halt(&mut self) {
+        if self.status().playing {
+            if self.clock_output {
+                self.output.send_clock(MidiClockMessage::Stop);
+            }
+            self.set_playing(false);
+        }
+    }
+
+    fn set_playing(&mut self, playing: bool) {
+        let position = self.position();
+        self.update(|s| {
+            s.playing = playing;
+            s.anchor_micros = position;
+            s.anchor_instant = Instant::now();
+        });
+    }
+
+    fn release_track(&mut self, track: usize) {
+        let released: Vec<_> = self
+            .sounding
+            .iter()
+            .filter(|(t, _, _)| *t == track)
+            .copied()
+            .collect();
+        for (_, channel, note) in released {
+            self.sounding.remove(&(track, channel, note));
+            self.output.send(MidiMessage::note_off(channel, note, 0));
+        }
+    }
+
+    // Releases our notes, and with `reset` sends All Notes Off and resets controllers on
+    // every channel.
+    fn release_all(&mut self, reset: bool) {
+        for (_, channel, note) in self.sounding.drain() {
+            self.output.send(MidiMessage::note_off(channel, note, 0));
+        }
+        if reset {
+            for channel in 0..16 {
+                self.output.send(MidiMessage::control_change(
+                    channel,
+                    CONTROL_ALL_NOTES_OFF,
+                    0,
+                ));
+                self.output.send(MidiMessage::control_change(
+                    channel,
+                    CONTROL_RESET_ALL_CONTROLLERS,
+                    0,
+                ));
+            }
+        }
+    }
+
+    fn position(&self) -> u64 {
+        self.status().position()
+    }
+
+    fn status(&self) -> PlaybackStatus {
+        *self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PlaybackStatus)) {
+        f(&mut self.status.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+}
+
+fn setup(mut commands: Commands, output: Res<MidiOutput>) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let status = Arc::new(Mutex::new(PlaybackStatus {
+        playing: false,
+        anchor_micros: 0,
+        anchor_instant: Instant::now(),
+        tempo_scale: 1.0,
+        duration: 0,
+    }));
+
+    let worker = PlayerWorker {
+        receiver,
+        output: output.handle(),
+        status: status.clone(),
+        sequence: Arc::default(),
+        next: 0,
+        loop_range: None,
+        muted: HashSet::new(),
+        sounding: HashSet::new(),
+        clock_output: false,
+        next_pulse: 0,
+        volume: 1.0,
+    };
+    let worker = thread::Builder::new()
+        .name("song player".to_string())
+        .spawn(move || worker.run())
+        .map_err(|e| warn!("Couldn't start song player: {}", e))
+        .ok();
+
+    commands.insert_resource(SongPlayer {
+        sender,
+        sequence: Arc::default(),
+        status,
+        worker,
+    });
+}
+
+fn load_current_song(song: Option<Res<CurrentSong>>, player: Option<ResMut<SongPlayer>>) {
+    let (Some(song), Some(mut player)) = (song, player) else {
+        return;
+    };
+    if !song.is_changed() && !player.is_added() {
+        return;
+    }
+    match Sequence::load(&song.path) {
+        Ok(sequence) => player.load(sequence),
+        Err(e) => warn!("Couldn't load {} for playback: {}", song.path, e),
+    }
+}
+
+fn playback_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<SongPlaybackSettings>,
+    player: Option<Res<SongPlayer>>,
+) {
+    let Some(player) = player else { return };
+    if keys.just_pressed(settings.play_pause_key) {
+        if player.is_playing() {
+            player.pause();
+        } else {
+            player.play();
+        }
+    }
+    if keys.just_pressed(settings.stop_key) {
+        player.stop();
+    }
+}
+
+// Runs before `Last`, so the player can release its notes while `MidiOutput` is still up.
+fn shutdown(player: Option<ResMut<SongPlayer>>, mut exit: EventReader<AppExit>) {
+    if let Some(mut player) = player
+        && exit.read().next().is_some()
+    {
+        player.bypass_change_detection().shutdown();
+    }
+}
+
+#[test]
+fn test_sequence_follows_tempo_changes() {
+    use midly::num::{u4, u7, u15, u24, u28};
+    use midly::{Format, Header, TrackEvent};
+
+    let note = |delta: u32, key: u8| TrackEvent {
+        delta: u28::from(delta),
+        kind: TrackEventKind::Midi {
+            channel: u4::from(1),
+            message: midly::MidiMessage::NoteOn {
+                key: u7::from(key),
+                vel: u7::from(100),
+            },
+        },
+    };
+    let mut smf = Smf::new(Header::new(
+        Format::Parallel,
+        Timing::Metrical(u15::from(480)),
+    ));
+    smf.tracks.push(vec![
+        TrackEvent {
+            delta: u28::from(480),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::from(250_000))),
+        },
+        TrackEvent {
+            delta: u28::from(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        },
+    ]);
+    smf.tracks
+        .push(vec![note(0, 60), note(480, 62), note(480, 64)]);
+
+    let sequence = Sequence::from_smf(&smf);
+    assert_eq!(sequence.track_count, 2);
+    let times: Vec<_> = sequence.events.iter().map(|e| e.micros).collect();
+    assert_eq!(times, vec![0, 500_000, 750_000]);
+    assert!(sequence.events.iter().all(|e| e.track == 1));
+    assert_eq!(sequence.events[1].message, MidiMessage::note_on(1, 62, 100));
+
+    assert_eq!(sequence.tempos, vec![(0, 500_000), (500_000, 250_000)]);
+    assert_eq!(sequence.beats_at(750_000), 2.0);
+    assert_eq!(sequence.micros_at(2.0), 750_000);
+
+    assert_eq!(
+        sequence.loop_range(500_000, 2_000_000),
+        Some((500_000, 750_000))
+    );
+    assert_eq!(sequence.loop_range(500_000, 500_000), None);
+    assert_eq!(sequence.loop_range(750_000, 0), None);
+    assert_eq!(sequence.loop_range(800_000, 900_000), None);
+}
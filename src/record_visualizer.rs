@@ -1,108 +1,221 @@
-use bevy::{log::tracing::span::Record, prelude::*};
-
+use crate::bevy_mic::microphone::{MicrophoneSamples, forward_samples};
+use crate::stft::{AnalysisFormat, Stft, StftAnalysis, needs_rebuild, spectral_peaks};
+use bevy::prelude::*;
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// Shows what the microphone hears on the piano: each key glows with the energy at its
+/// pitch.
 pub struct RecordVisualizerPlugin;
 
 impl Plugin for RecordVisualizerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, VolumeBar::startup)
-            .add_systems(Update, VolumeBar::update);
+        app.init_resource::<KeySpectrumSettings>()
+            .init_resource::<KeySpectrum>()
+            .add_systems(PreUpdate, KeySpectrum::update.after(forward_samples));
     }
 }
 
-#[derive(Component)]
-pub struct VolumeBar {
-    id: usize,
-    frequency: f64,
+/// Settings for [`KeySpectrum`].
+#[derive(Resource, Clone, Debug)]
+pub struct KeySpectrumSettings {
+    /// The midi notes of the keys to light, A0 to C8 on a piano.
+    pub keys: RangeInclusive<u8>,
+    /// Samples in each analysis window. Long windows tell low keys apart.
+    pub window: usize,
+    /// Samples between the starts of consecutive windows.
+    pub hop: usize,
+    /// The level of a silent key.
+    pub floor_db: f32,
+    /// The level of a fully lit key.
+    pub ceiling_db: f32,
+    /// How long a key takes to fade once its pitch stops.
+    pub release: Duration,
 }
 
-impl VolumeBar {
-    pub fn startup(mut commands: Commands) {
-        for idx in 1..1025 {
-            let bar = VolumeBar::calculate_positions(&idx);
-            commands.spawn(bar);
+impl Default for KeySpectrumSettings {
+    fn default() -> Self {
+        Self {
+            keys: 21..=108,
+            window: 8192,
+            hop: 1024,
+            floor_db: -45.0,
+            ceiling_db: -6.0,
+            release: Duration::from_millis(150),
         }
     }
+}
 
-    fn calculate_positions(idx: &usize) -> (Node, BackgroundColor, VolumeBar) {
-        let idx_f32 = *idx as f32;
-        let height = Val::Px(20.0);
-        let width = Val::Px(1.0);
-        let left = Val::Percent(300.0 * (idx_f32 / 1025.0));
-
-        return (
-            Node {
-                height,
-                width,
-                position_type: PositionType::Absolute,
-                bottom: Val::Px(0.0),
-                left,
-                ..Default::default()
-            },
-            BackgroundColor(Color::srgb(idx_f32 / 1024.0, 1024.0 / idx_f32, 0.0)),
-            VolumeBar {
-                id: *idx,
-                frequency: idx_f32 as f64 / (48000.0 / 2.0 + 1.0),
-            },
-        );
-    }
-
-    pub fn update(
-        // sink: Single<&mut bevy_mic::spatial_audio::SpatialAudioSink>,
-        mic: Res<crate::bevy_mic::microphone::MicrophoneAudio>,
-        // mut query: Single<&mut Node, With<VolumeBar>>,
-        mut query: Query<(&mut Node, &VolumeBar)>,
-    ) {
-        // info!("mic count: {}", mic.len());
+/// [`Resource`](bevy::ecs::system::Resource) holding how loud the pitch of each of the
+/// [`KeySpectrumSettings::keys`] is on the microphone.
+#[derive(Resource, Clone, Debug)]
+pub struct KeySpectrum {
+    lowest_note: u8,
+    /// From the lowest key up, between 0.0 at [`KeySpectrumSettings::floor_db`] and 1.0 at
+    /// [`KeySpectrumSettings::ceiling_db`].
+    pub levels: Vec<f32>,
+}
 
-        for owo in mic.try_iter() {
-            let total_volume: f32 = owo.iter().map(|&x| x.abs()).sum();
+impl Default for KeySpectrum {
+    fn default() -> Self {
+        Self::new(KeySpectrumSettings::default().keys)
+    }
+}
 
-            let frequencies = VolumeBar::analyze_frequencies(&owo);
+impl KeySpectrum {
+    #[must_use]
+    pub fn new(keys: RangeInclusive<u8>) -> Self {
+        Self {
+            lowest_note: *keys.start(),
+            levels: vec![0.0; keys.len()],
+        }
+    }
 
-            // todo detect piano notes
+    /// The midi notes of the keys.
+    #[must_use]
+    pub fn keys(&self) -> RangeInclusive<u8> {
+        self.lowest_note..=self.lowest_note + self.levels.len().saturating_sub(1) as u8
+    }
 
-            for (mut volbar, voldata) in query.iter_mut() {
-                let freqency = frequencies.get(voldata.id);
-                if let Some(freq) = freqency {
-                    let height = (freq * 10.0).max(1.0);
+    /// The level of the key playing midi `note`, or 0.0 off the keyboard.
+    #[must_use]
+    pub fn level(&self, note: u8) -> f32 {
+        note.checked_sub(self.lowest_note)
+            .and_then(|key| self.levels.get(usize::from(key)))
+            .copied()
+            .unwrap_or(0.0)
+    }
 
-                    volbar.height = Val::Px(height as f32);
-                    volbar.bottom = Val::Px(0.0);
-                };
+    fn update(
+        settings: Res<KeySpectrumSettings>,
+        mut samples: EventReader<MicrophoneSamples>,
+        mut analyzer: Local<Option<KeySpectrumAnalyzer>>,
+        mut spectrum: ResMut<KeySpectrum>,
+    ) {
+        if settings.is_changed() && spectrum.keys() != settings.keys {
+            *spectrum = KeySpectrum::new(settings.keys.clone());
+        }
+        for chunk in samples.read() {
+            let format = AnalysisFormat::new(chunk.sample_rate, settings.window, settings.hop);
+            if needs_rebuild(analyzer.as_ref(), format) {
+                *analyzer = Some(KeySpectrumAnalyzer::new(chunk.sample_rate, &settings));
+            }
+            if let Some(analyzer) = analyzer.as_mut()
+                && analyzer.push(
+                    &chunk.samples,
+                    &settings,
+                    spectrum.bypass_change_detection(),
+                )
+            {
+                spectrum.set_changed();
             }
-
-            // sink.append(rodio::buffer::SamplesBuffer::new(
-            //     mic.config.channels,
-            //     mic.config.sample_rate,
-            //     owo,
-            // ));
         }
     }
+}
 
-    fn analyze_frequencies(samples: &[f32]) -> Vec<f64> {
-        let mut planner = rustfft::FftPlanner::new();
-        let fft = planner.plan_fft_forward(samples.len());
-
-        // Apply window function
-        let windowed: Vec<f32> = samples
-            .iter()
-            .zip(apodize::hanning_iter(samples.len()))
-            .map(|(s, w)| s * w as f32)
-            .collect();
-
-        use rustfft::num_complex::Complex;
+/// Folds the spectrum of each window into the keys of the piano.
+pub struct KeySpectrumAnalyzer {
+    format: AnalysisFormat,
+    stft: Stft,
+    /// The loudest peak at each key in the last window.
+    peaks: Vec<f32>,
+}
 
-        // Convert to complex for FFT
-        let mut buffer: Vec<Complex<f32>> =
-            windowed.iter().map(|&x| Complex::new(x, 0.0)).collect();
+impl KeySpectrumAnalyzer {
+    #[must_use]
+    pub fn new(sample_rate: u32, settings: &KeySpectrumSettings) -> Self {
+        Self {
+            format: AnalysisFormat::new(sample_rate, settings.window, settings.hop),
+            stft: Stft::new(settings.window, settings.hop),
+            peaks: Vec::new(),
+        }
+    }
 
-        fft.process(&mut buffer);
+    /// Analyses `samples`, updating `spectrum` and returning whether any window was done.
+    ///
+    /// Keys light up as soon as their pitch is heard, and fade over
+    /// [`KeySpectrumSettings::release`].
+    pub fn push(
+        &mut self,
+        samples: &[f32],
+        settings: &KeySpectrumSettings,
+        spectrum: &mut KeySpectrum,
+    ) -> bool {
+        let hop = self.stft.hop() as f32 / self.format.sample_rate as f32;
+        let fade = (-hop / settings.release.as_secs_f32().max(f32::EPSILON)).exp();
+        let range = (settings.ceiling_db - settings.floor_db).max(f32::EPSILON);
+        let mut analysed = false;
+
+        self.stft.push(samples);
+        while self.stft.next_frame().is_some() {
+            analysed = true;
+            // Peaks rather than bands, so a low key narrower than a bin still gets one and
+            // the skirts of a loud pitch don't light its neighbours
+            self.peaks.clear();
+            self.peaks.resize(spectrum.levels.len(), 0.0);
+            for (bin, magnitude) in spectral_peaks(self.stft.magnitudes()) {
+                let hz = self.stft.bin_hz(bin, self.format.sample_rate);
+                if hz <= 0.0 {
+                    continue;
+                }
+                let key =
+                    (69.0 + 12.0 * (hz / 440.0).log2()).round() - f32::from(spectrum.lowest_note);
+                if key >= 0.0
+                    && let Some(peak) = self.peaks.get_mut(key as usize)
+                {
+                    *peak = peak.max(magnitude);
+                }
+            }
+            for (level, &peak) in spectrum.levels.iter_mut().zip(&self.peaks) {
+                let db = 20.0 * peak.max(f32::MIN_POSITIVE).log10();
+                let heard = ((db - settings.floor_db) / range).clamp(0.0, 1.0);
+                *level = heard.max(*level * fade);
+            }
+        }
+        analysed
+    }
+}
 
-        // Convert to magnitude spectrum
-        buffer.iter().map(|c| c.norm() as f64).collect()
+impl StftAnalysis for KeySpectrumAnalyzer {
+    fn format(&self) -> AnalysisFormat {
+        self.format
     }
+}
 
-    fn note_frequency(midi_note: u8) -> f64 {
-        440.0 * 2.0_f64.powf((midi_note as f64 - 69.0) / 12.0)
+#[test]
+fn test_key_spectrum_lights_the_key_of_a_tone() {
+    let sample_rate = 48_000;
+    let settings = KeySpectrumSettings::default();
+    let mut analyzer = KeySpectrumAnalyzer::new(sample_rate, &settings);
+    let mut spectrum = KeySpectrum::default();
+
+    // A2 with its octave, at 110 Hz and 220 Hz
+    let tone: Vec<f32> = (0..sample_rate as usize / 2)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            0.5 * (std::f32::consts::TAU * 110.0 * t).sin()
+                + 0.1 * (std::f32::consts::TAU * 220.0 * t).sin()
+        })
+        .collect();
+    assert!(analyzer.push(&tone, &settings, &mut spectrum));
+    assert!(spectrum.level(45) > 0.9);
+    assert!(spectrum.level(57) > 0.5 && spectrum.level(57) < spectrum.level(45));
+    for note in [44, 46, 52, 56, 58, 69] {
+        assert!(
+            spectrum.level(note) < 0.2,
+            "{note}: {}",
+            spectrum.level(note)
+        );
     }
+    assert_eq!(spectrum.level(10), 0.0);
+
+    // Silence fades it out
+    analyzer.push(&vec![0.0; sample_rate as usize], &settings, &mut spectrum);
+    assert!(spectrum.level(45) < 0.01);
+
+    // A keyboard from C-1 has its keys where their notes are
+    let mut spectrum = KeySpectrum::new(0..=95);
+    analyzer.push(&tone, &settings, &mut spectrum);
+    assert_eq!(spectrum.keys(), 0..=95);
+    assert!(spectrum.level(45) > 0.9);
 }
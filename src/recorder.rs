@@ -0,0 +1,314 @@
+use crate::bevy_midi::prelude::*;
+use crate::songs::{CurrentSong, DEFAULT_TEMPO};
+use bevy::prelude::*;
+use midly::num::{u15, u24, u28};
+use midly::{
+    Format, Header, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind, live::LiveEvent,
+};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records what the player plays and saves each take as a Standard MIDI File.
+pub struct MidiRecorderPlugin;
+
+impl Plugin for MidiRecorderPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiRecorderSettings>()
+            .init_resource::<MidiRecorder>()
+            .add_event::<RecordingCommand>()
+            .add_event::<TakeSaved>()
+            .add_systems(
+                Update,
+                (toggle_on_key, handle_commands, MidiRecorder::record).chain(),
+            );
+    }
+}
+
+/// Settings for [`MidiRecorderPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct MidiRecorderSettings {
+    /// Where takes are saved.
+    pub directory: PathBuf,
+    /// [`Format::SingleTrack`] writes everything to one track, [`Format::Parallel`] writes a
+    /// conductor track plus one track per channel.
+    pub format: Format,
+    /// Starts recording, or stops and saves the take.
+    pub toggle_key: KeyCode,
+}
+
+impl Default for MidiRecorderSettings {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            format: Format::SingleTrack,
+            toggle_key: KeyCode::F9,
+        }
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) controlling the [`MidiRecorder`].
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordingCommand {
+    /// Discards anything unsaved and starts a new take.
+    Start,
+    /// Stops recording and saves the take to [`MidiRecorderSettings::directory`].
+    Stop,
+}
+
+/// An [`Event`](bevy::ecs::event::Event) fired once a take has been written to disk.
+#[derive(Event, Clone, Debug)]
+pub struct TakeSaved {
+    pub path: PathBuf,
+}
+
+/// A message recorded during a take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecordedEvent {
+    /// Microseconds since the first message of the take.
+    pub micros: u64,
+    pub message: MidiMessage,
+}
+
+/// A finished recording.
+#[derive(Clone, Debug)]
+pub struct Take {
+    pub events: Vec<RecordedEvent>,
+    /// Microseconds per beat.
+    pub tempo: u32,
+    /// As (numerator, denominator).
+    pub time_signature: (u8, u8),
+}
+
+impl Take {
+    pub const TICKS_PER_BEAT: u16 = 480;
+
+    /// Converts the take to a Standard MIDI File of the given `format`.
+    #[must_use]
+    pub fn to_smf(&self, format: Format) -> Smf<'static> {
+        let (numerator, denominator) = self.time_signature;
+        let conductor = [
+            TrackEventKind::Meta(MetaMessage::Tempo(u24::from(self.tempo))),
+            TrackEventKind::Meta(MetaMessage::TimeSignature(
+                numerator,
+                denominator.max(1).ilog2() as u8,
+                24,
+                8,
+            )),
+        ];
+
+        let mut smf = Smf::new(Header::new(
+            format,
+            Timing::Metrical(u15::from(Self::TICKS_PER_BEAT)),
+        ));
+        match format {
+            Format::Parallel => {
+                smf.tracks.push(self.track(&conductor, |_| false));
+                for channel in 0..16 {
+                    if self.events.iter().any(|e| e.message.channel() == channel) {
+                        smf.tracks
+                            .push(self.track(&[], |e| e.message.channel() == channel));
+                    }
+                }
+            }
+            Format::SingleTrack | Format::Sequential => {
+                smf.tracks.push(self.track(&conductor, |_| true));
+            }
+        }
+        smf
+    }
+
+    /// Writes the take to `path` as a Standard MIDI File of the given `format`.
+    pub fn save(&self, path: &Path, format: Format) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        self.to_smf(format).save(path)
+    }
+
+    // Builds a track starting with `head`, followed by the events matching `filter`.
+    fn track(
+        &self,
+        head: &[TrackEventKind<'static>],
+        filter: impl Fn(&RecordedEvent) -> bool,
+    ) -> Vec<TrackEvent<'static>> {
+        let mut track: Vec<_> = head
+            .iter()
+            .map(|&kind| TrackEvent {
+                delta: u28::from(0),
+                kind,
+            })
+            .collect();
+
+        let mut last_tick = 0;
+        for event in self.events.iter().filter(|e| filter(e)) {
+            let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(&event.message.msg)
+            else {
+                continue;
+            };
+            let tick =
+                event.micros * u64::from(Self::TICKS_PER_BEAT) / u64::from(self.tempo.max(1));
+            track.push(TrackEvent {
+                delta: u28::from(tick.saturating_sub(last_tick) as u32),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+            last_tick = last_tick.max(tick);
+        }
+
+        track.push(TrackEvent {
+            delta: u28::from(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        track
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) buffering [`MidiData`] between
+/// [`RecordingCommand::Start`] and [`RecordingCommand::Stop`].
+///
+/// Timing comes from [`MidiData::stamp`], so the take starts at its first message.
+#[derive(Resource, Default)]
+pub struct MidiRecorder {
+    recording: bool,
+    first_stamp: Option<u64>,
+    events: Vec<RecordedEvent>,
+}
+
+impl MidiRecorder {
+    #[must_use]
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    pub fn start(&mut self) {
+        self.recording = true;
+        self.first_stamp = None;
+        self.events.clear();
+    }
+
+    /// Stops recording and returns the take, with tempo and time signature from `song`.
+    pub fn stop(&mut self, song: Option<&CurrentSong>) -> Take {
+        self.recording = false;
+        self.first_stamp = None;
+        Take {
+            events: std::mem::take(&mut self.events),
+            tempo: song.map_or(DEFAULT_TEMPO, |s| s.tempo),
+            time_signature: song.map_or((4, 4), |s| s.time_signature),
+        }
+    }
+
+    /// Adds `data` to the take, if recording.
+    pub fn push(&mut self, data: &MidiData) {
+        if !self.recording {
+            return;
+        }
+        let first = *self.first_stamp.get_or_insert(data.stamp);
+        self.events.push(RecordedEvent {
+            micros: data.stamp.saturating_sub(first),
+            message: data.message,
+        });
+    }
+
+    fn record(mut recorder: ResMut<MidiRecorder>, mut midi: EventReader<MidiData>) {
+        if !recorder.recording {
+            midi.clear();
+            return;
+        }
+        for data in midi.read() {
+            recorder.push(data);
+        }
+    }
+}
+
+fn toggle_on_key(
+    keys: Res<ButtonInput<KeyCode>>,
+    settings: Res<MidiRecorderSettings>,
+    recorder: Res<MidiRecorder>,
+    mut commands: EventWriter<RecordingCommand>,
+) {
+    if keys.just_pressed(settings.toggle_key) {
+        commands.write(if recorder.is_recording() {
+            RecordingCommand::Stop
+        } else {
+            RecordingCommand::Start
+        });
+    }
+}
+
+fn handle_commands(
+    mut commands: EventReader<RecordingCommand>,
+    mut recorder: ResMut<MidiRecorder>,
+    mut saved: EventWriter<TakeSaved>,
+    settings: Res<MidiRecorderSettings>,
+    song: Option<Res<CurrentSong>>,
+) {
+    for command in commands.read() {
+        match command {
+            RecordingCommand::Start => {
+                info!("Recording started");
+                recorder.start();
+            }
+            RecordingCommand::Stop => {
+                if !recorder.is_recording() {
+                    continue;
+                }
+                let take = recorder.stop(song.as_deref());
+                let secs = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let path = take_path(&settings.directory, secs);
+                match take.save(&path, settings.format) {
+                    Ok(()) => {
+                        info!("Saved take to {}", path.display());
+                        saved.write(TakeSaved { path });
+                    }
+                    Err(e) => warn!("Couldn't save take to {}: {}", path.display(), e),
+                }
+            }
+        }
+    }
+}
+
+// Names a take after the second it was saved in, numbering those saved in the same second
+// so none overwrites another.
+fn take_path(directory: &Path, secs: u64) -> PathBuf {
+    let mut path = directory.join(format!("take-{}.mid", secs));
+    let mut count = 1;
+    while path.exists() {
+        count += 1;
+        path = directory.join(format!("take-{}-{}.mid", secs, count));
+    }
+    path
+}
+
+#[test]
+fn test_take_to_smf() {
+    let take = Take {
+        events: vec![
+            RecordedEvent {
+                micros: 0,
+                message: [0x90, 60, 100].into(),
+            },
+            RecordedEvent {
+                micros: 500_000,
+                message: [0x81, 62, 0].into(),
+            },
+        ],
+        tempo: 500_000,
+        time_signature: (3, 4),
+    };
+
+    let single = take.to_smf(Format::SingleTrack);
+    assert_eq!(single.tracks.len(), 1);
+    assert_eq!(
+        single.tracks[0][1].kind,
+        TrackEventKind::Meta(MetaMessage::TimeSignature(3, 2, 24, 8))
+    );
+    assert_eq!(single.tracks[0][3].delta, u28::from(480));
+
+    let parallel = take.to_smf(Format::Parallel);
+    assert_eq!(parallel.tracks.len(), 3);
+
+    let mut bytes = Vec::new();
+    parallel.write_std(&mut bytes).unwrap();
+    assert_eq!(Smf::parse(&bytes).unwrap().tracks.len(), 3);
+}
@@ -0,0 +1,372 @@
+use crate::bevy_midi::prelude::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Routes incoming midi to the internal synth and the output port, transforming it on the
+/// way.
+///
+/// Sits between [`MidiInputPlugin`] and its consumers: the synth plays [`RoutedMidi`] rather
+/// than raw [`MidiData`], and routes to [`RouteTarget::Output`] act as a midi thru.
+pub struct MidiRoutingPlugin;
+
+impl Plugin for MidiRoutingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<MidiRoutingSettings>()
+            .add_event::<RoutedMidi>()
+            .add_systems(Startup, load_config)
+            .add_systems(
+                PreUpdate,
+                (save_config, route).chain().after(MidiInputSystems),
+            );
+    }
+}
+
+/// Settings for [`MidiRoutingPlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct MidiRoutingSettings {
+    /// Where the [`RoutingConfig`] is loaded from, and saved to whenever it changes.
+    pub path: PathBuf,
+}
+
+impl Default for MidiRoutingSettings {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("config/midi_routing.ron"),
+        }
+    }
+}
+
+/// Where a route sends its messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RouteTarget {
+    /// The internal synth of [`PianoPlugin`](crate::audio::PianoPlugin).
+    Synth,
+    /// The connected [`MidiOutput`] port.
+    Output,
+}
+
+/// Changes applied to each message passing through a [`Route`], in field order.
+///
+/// Key range and note filters look at the note as played, before transposing.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RouteTransform {
+    /// Only pass messages on this channel.
+    pub channel: Option<u8>,
+    /// Only pass notes in this inclusive range, e.g. to split the keyboard.
+    pub key_range: (u8, u8),
+    /// Drop these notes.
+    pub filtered_notes: Vec<u8>,
+    /// Semitones to shift notes by; notes shifted out of range are dropped.
+    pub transpose: i8,
+    /// Multiplies NoteOn velocities, clamped so a NoteOn stays a NoteOn.
+    pub velocity_scale: f32,
+    /// Send everything on this channel instead.
+    pub remap_channel: Option<u8>,
+}
+
+impl Default for RouteTransform {
+    fn default() -> Self {
+        Self {
+            channel: None,
+            key_range: (0, 127),
+            filtered_notes: Vec::new(),
+            transpose: 0,
+            velocity_scale: 1.0,
+            remap_channel: None,
+        }
+    }
+}
+
+impl RouteTransform {
+    /// Transforms `message`, or returns `None` if it should be dropped.
+    #[must_use]
+    pub fn apply(&self, message: MidiMessage) -> Option<MidiMessage> {
+        let [mut status, mut data1, mut data2] = message.msg;
+        if status >= 0xF0 || self.channel.is_some_and(|c| c != message.channel()) {
+            return None;
+        }
+
+        // NoteOff, NoteOn and polyphonic aftertouch carry a note
+        if matches!(status & 0xF0, 0x80..=0xA0) {
+            let (low, high) = self.key_range;
+            if data1 < low || data1 > high || self.filtered_notes.contains(&data1) {
+                return None;
+            }
+            data1 = u8::try_from(i16::from(data1) + i16::from(self.transpose))
+                .ok()
+                .filter(|note| *note <= 127)?;
+            if message.is_note_on() {
+                data2 = (f32::from(data2) * self.velocity_scale)
+                    .round()
+                    .clamp(1.0, 127.0) as u8;
+            }
+        }
+
+        if let Some(channel) = self.remap_channel {
+            status = (status & 0xF0) | (channel & 0x0F);
+        }
+        Some([status, data1, data2].into())
+    }
+}
+
+/// A connection in the routing matrix, from an input port to a [`RouteTarget`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    /// The [`MidiInputPort::id`] to take messages from, or any port when `None`.
+    #[serde(default)]
+    pub input: Option<String>,
+    pub target: RouteTarget,
+    #[serde(default)]
+    pub transform: RouteTransform,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl Route {
+    #[must_use]
+    pub fn new(target: RouteTarget) -> Self {
+        Self {
+            input: None,
+            target,
+            transform: RouteTransform::default(),
+            enabled: true,
+        }
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding every [`Route`].
+///
+/// A message goes through every enabled route matching its port, so routing to both the
+/// synth and the output takes two routes. Changes are saved to
+/// [`MidiRoutingSettings::path`].
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RoutingConfig {
+    pub routes: Vec<Route>,
+}
+
+impl Default for RoutingConfig {
+    // Everything plays the synth, untouched
+    fn default() -> Self {
+        Self {
+            routes: vec![Route::new(RouteTarget::Synth)],
+        }
+    }
+}
+
+impl RoutingConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let config = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, config)?;
+        Ok(())
+    }
+
+    /// Sends `message`, arriving from `port`, through every matching route.
+    pub fn route<'a>(
+        &'a self,
+        port: Option<&'a MidiInputPort>,
+        message: MidiMessage,
+    ) -> impl Iterator<Item = (RouteTarget, MidiMessage)> + 'a {
+        self.routes
+            .iter()
+            .filter(move |r| {
+                r.enabled
+                    && r.input
+                        .as_deref()
+                        .is_none_or(|id| port.is_some_and(|p| p.id() == id))
+            })
+            .filter_map(move |r| Some((r.target, r.transform.apply(message)?)))
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) for midi that has been routed to an internal
+/// [`RouteTarget`].
+///
+/// This event fires from [`PreUpdate`]. Messages routed to [`RouteTarget::Output`] are sent
+/// directly and also reported here.
+#[derive(Event, Clone, Copy, Debug)]
+pub struct RoutedMidi {
+    pub target: RouteTarget,
+    pub stamp: u64,
+    pub message: MidiMessage,
+}
+
+/// The notes started through the routes and not released yet, as each route sent them.
+#[derive(Default, Debug)]
+struct RoutedNotes(HashMap<(u8, u8), Vec<(RouteTarget, MidiMessage)>>);
+
+impl RoutedNotes {
+    /// Follows `played` as it was routed to `target` as `routed`.
+    fn track(&mut self, played: MidiMessage, target: RouteTarget, routed: MidiMessage) {
+        if played.is_note_on() && routed.is_note_on() {
+            let key = (played.channel(), played.msg[1]);
+            self.0.entry(key).or_default().push((target, routed));
+        }
+    }
+
+    /// Forgets the notes `played` releases.
+    fn release(&mut self, played: MidiMessage) {
+        if played.is_note_off() {
+            self.0.remove(&(played.channel(), played.msg[1]));
+        }
+    }
+
+    /// Note offs for every note still sounding, matching the note ons that were sent.
+    fn release_all(&mut self) -> impl Iterator<Item = (RouteTarget, MidiMessage)> + '_ {
+        self.0
+            .drain()
+            .flat_map(|(_, routed)| routed)
+            .map(|(target, message)| {
+                (
+                    target,
+                    MidiMessage::note_off(message.channel(), message.msg[1], 0),
+                )
+            })
+    }
+}
+
+fn load_config(mut commands: Commands, settings: Res<MidiRoutingSettings>) {
+    let config = if settings.path.exists() {
+        RoutingConfig::load(&settings.path).unwrap_or_else(|e| {
+            warn!("Couldn't load {}: {}", settings.path.display(), e);
+            RoutingConfig::default()
+        })
+    } else {
+        RoutingConfig::default()
+    };
+    commands.insert_resource(config);
+}
+
+fn save_config(config: Res<RoutingConfig>, settings: Res<MidiRoutingSettings>) {
+    if !config.is_changed() || config.is_added() {
+        return;
+    }
+    if let Err(e) = config.save(&settings.path) {
+        warn!("Couldn't save {}: {}", settings.path.display(), e);
+    }
+}
+
+fn route(
+    config: Res<RoutingConfig>,
+    conn: Res<MidiInputConnection>,
+    output: Option<Res<MidiOutput>>,
+    timestamps: Res<MidiTimestamps>,
+    mut midi: EventReader<MidiData>,
+    mut routed: EventWriter<RoutedMidi>,
+    mut sounding: Local<RoutedNotes>,
+) {
+    let mut send = |target, stamp, message| {
+        if target == RouteTarget::Output
+            && let Some(output) = &output
+        {
+            output.send(message);
+        }
+        routed.write(RoutedMidi {
+            target,
+            stamp,
+            message,
+        });
+    };
+
+    // The old routes would send the note offs somewhere else, so notes started through them
+    // are released as they were sent
+    if config.is_changed() && !config.is_added() {
+        let stamp = timestamps.stamp(Instant::now()).unwrap_or_default();
+        for (target, message) in sounding.release_all() {
+            send(target, stamp, message);
+        }
+    }
+    for data in midi.read() {
+        sounding.release(data.message);
+        for (target, message) in config.route(conn.port(), data.message) {
+            sounding.track(data.message, target, message);
+            send(target, data.stamp, message);
+        }
+    }
+}
+
+#[test]
+fn test_route_transforms() {
+    let split = RouteTransform {
+        key_range: (60, 127),
+        filtered_notes: vec![61],
+        transpose: -12,
+        velocity_scale: 0.5,
+        remap_channel: Some(3),
+        ..default()
+    };
+    assert_eq!(
+        split.apply(MidiMessage::note_on(0, 64, 100)),
+        Some(MidiMessage::note_on(3, 52, 50))
+    );
+    assert_eq!(split.apply(MidiMessage::note_on(0, 59, 100)), None);
+    assert_eq!(split.apply(MidiMessage::note_on(0, 61, 100)), None);
+    // Controllers are only remapped
+    assert_eq!(
+        split.apply(MidiMessage::control_change(0, CONTROL_SUSTAIN, 127)),
+        Some(MidiMessage::control_change(3, CONTROL_SUSTAIN, 127))
+    );
+
+    let up = RouteTransform {
+        transpose: 12,
+        velocity_scale: 0.0,
+        channel: Some(1),
+        ..default()
+    };
+    assert_eq!(up.apply(MidiMessage::note_on(1, 120, 100)), None);
+    assert_eq!(up.apply(MidiMessage::note_on(0, 60, 100)), None);
+    assert_eq!(
+        up.apply(MidiMessage::note_on(1, 60, 100)),
+        Some(MidiMessage::note_on(1, 72, 1))
+    );
+
+    let mut config = RoutingConfig::default();
+    config.routes.push(Route {
+        input: Some("keys".to_string()),
+        ..Route::new(RouteTarget::Output)
+    });
+    let port = MidiInputPort::new("keys");
+    assert_eq!(
+        config
+            .route(Some(&port), MidiMessage::note_on(0, 60, 1))
+            .count(),
+        2
+    );
+    assert_eq!(
+        config.route(None, MidiMessage::note_on(0, 60, 1)).count(),
+        1
+    );
+
+    // Notes are released as they were routed, after the routes change
+    let mut sounding = RoutedNotes::default();
+    let played = MidiMessage::note_on(0, 64, 100);
+    sounding.track(played, RouteTarget::Synth, split.apply(played).unwrap());
+    sounding.track(
+        MidiMessage::note_on(0, 65, 100),
+        RouteTarget::Output,
+        played,
+    );
+    sounding.release(MidiMessage::note_off(0, 65, 0));
+    assert_eq!(
+        sounding.release_all().collect::<Vec<_>>(),
+        [(RouteTarget::Synth, MidiMessage::note_off(3, 52, 0))]
+    );
+    assert_eq!(sounding.release_all().count(), 0);
+
+    let text = ron::ser::to_string(&config).unwrap();
+    assert_eq!(ron::from_str::<RoutingConfig>(&text).unwrap(), config);
+}
@@ -0,0 +1,276 @@
+use crate::bevy_midi::prelude::*;
+use crate::guidance::GuideNote;
+use crate::onset::Onset;
+use crate::playback::SongPlayer;
+use bevy::prelude::*;
+use std::time::Duration;
+
+/// Scores the notes played on the midi input against the song as it plays.
+///
+/// A note of the song is hit when its key is pressed close enough to its start, and missed
+/// once that window has passed. Keys pressed where the song has no note aren't counted.
+///
+/// With [`PracticeScoreSettings::score_onsets`], note starts heard on the microphone are
+/// scored too, for rhythm alone.
+pub struct PracticeScorePlugin;
+
+impl Plugin for PracticeScorePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PracticeScoreSettings>()
+            .init_resource::<PracticeScore>()
+            .add_event::<NoteJudged>()
+            .add_event::<Onset>()
+            .add_systems(Update, judge_notes);
+    }
+}
+
+/// Settings for [`PracticeScorePlugin`].
+#[derive(Resource, Clone, Debug)]
+pub struct PracticeScoreSettings {
+    /// How far from a note's start its key can be pressed and still count as a hit.
+    pub window: Duration,
+    /// Only score notes from these tracks of the song, e.g. the part being practised.
+    pub tracks: Option<Vec<usize>>,
+    /// Each [`Onset`] hits the notes of the song starting closest to it, whatever their
+    /// pitch, so acoustic instruments can be scored on rhythm.
+    pub score_onsets: bool,
+}
+
+impl Default for PracticeScoreSettings {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(150),
+            tracks: None,
+            score_onsets: false,
+        }
+    }
+}
+
+/// An [`Event`](bevy::ecs::event::Event) for each note of the song that was hit or missed.
+#[derive(Event, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoteJudged {
+    pub note: u8,
+    /// How late the key was pressed, in microseconds (negative if early), or `None` if the
+    /// note was missed.
+    pub offset: Option<i64>,
+}
+
+impl NoteJudged {
+    #[must_use]
+    pub fn is_hit(&self) -> bool {
+        self.offset.is_some()
+    }
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding the score of the current attempt at
+/// the song.
+///
+/// Starts over when a song is loaded or the transport jumps back.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PracticeScore {
+    pub hits: u32,
+    pub misses: u32,
+    pub streak: u32,
+    pub best_streak: u32,
+    // Sorted by start
+    notes: Vec<GuideNote>,
+    judged: Vec<bool>,
+    position: u64,
+}
+
+impl PracticeScore {
+    /// Starts scoring `notes` from the beginning.
+    pub fn reset(&mut self, mut notes: Vec<GuideNote>) {
+        notes.sort_by_key(|n| n.start);
+        *self = Self {
+            judged: vec![false; notes.len()],
+            notes,
+            ..default()
+        };
+    }
+
+    /// Starts the same notes over.
+    pub fn restart(&mut self) {
+        let notes = std::mem::take(&mut self.notes);
+        self.reset(notes);
+    }
+
+    /// Judges `note` pressed at song `position`, against the nearest unjudged note of the
+    /// song within `window` microseconds.
+    pub fn press(&mut self, note: u8, position: u64, window: u64) -> Option<NoteJudged> {
+        let (index, start) = self
+            .notes
+            .iter()
+            .enumerate()
+            .filter(|(i, n)| {
+                !self.judged[*i] && n.note == note && n.start.abs_diff(position) <= window
+            })
+            .map(|(i, n)| (i, n.start))
+            .min_by_key(|(_, start)| start.abs_diff(position))?;
+        self.judged[index] = true;
+        self.hits += 1;
+        self.streak += 1;
+        self.best_streak = self.best_streak.max(self.streak);
+        Some(NoteJudged {
+            note,
+            offset: Some(position as i64 - start as i64),
+        })
+    }
+
+    /// Judges a note start heard at song `position`, without knowing its pitch. It hits
+    /// every unjudged note starting at the nearest start within `window` microseconds, so
+    /// a strummed chord counts once per note.
+    pub fn strike(&mut self, position: u64, window: u64) -> Vec<NoteJudged> {
+        let Some(start) = self
+            .notes
+            .iter()
+            .zip(&self.judged)
+            .filter(|(n, judged)| !**judged && n.start.abs_diff(position) <= window)
+            .map(|(n, _)| n.start)
+            .min_by_key(|start| start.abs_diff(position))
+        else {
+            return Vec::new();
+        };
+        let mut hits = Vec::new();
+        for (note, judged) in self.notes.iter().zip(&mut self.judged) {
+            if note.start == start && !*judged {
+                *judged = true;
+                hits.push(NoteJudged {
+                    note: note.note,
+                    offset: Some(position as i64 - start as i64),
+                });
+            }
+        }
+        self.hits += hits.len() as u32;
+        self.streak += hits.len() as u32;
+        self.best_streak = self.best_streak.max(self.streak);
+        hits
+    }
+
+    /// Misses every unjudged note whose window closed before song `position`.
+    pub fn advance(&mut self, position: u64, window: u64) -> Vec<NoteJudged> {
+        let mut missed = Vec::new();
+        for (note, judged) in self.notes.iter().zip(&mut self.judged) {
+            if note.start + window >= position {
+                break;
+            }
+            if !*judged {
+                *judged = true;
+                missed.push(NoteJudged {
+                    note: note.note,
+                    offset: None,
+                });
+            }
+        }
+        self.misses += missed.len() as u32;
+        if !missed.is_empty() {
+            self.streak = 0;
+        }
+        self.position = position;
+        missed
+    }
+}
+
+fn judge_notes(
+    settings: Res<PracticeScoreSettings>,
+    mut score: ResMut<PracticeScore>,
+    player: Option<Res<SongPlayer>>,
+    mut midi: EventReader<MidiData>,
+    mut onsets: EventReader<Onset>,
+    mut judged: EventWriter<NoteJudged>,
+) {
+    let Some(player) = player else {
+        midi.clear();
+        onsets.clear();
+        return;
+    };
+    let window = settings.window.as_micros() as u64;
+    if player.is_changed() || settings.is_changed() {
+        score.reset(GuideNote::from_sequence(
+            player.sequence(),
+            settings.tracks.as_deref(),
+        ));
+    }
+    let position = player.position().as_micros() as u64;
+    if position + window < score.position {
+        score.restart();
+    }
+    if !player.is_playing() {
+        midi.clear();
+        onsets.clear();
+        return;
+    }
+
+    for data in midi.read().filter(|d| d.message.is_note_on()) {
+        if let Some(hit) = score.press(data.message.msg[1], position, window) {
+            judged.write(hit);
+        }
+    }
+    if settings.score_onsets {
+        for onset in onsets.read() {
+            // Onsets are only found a window or so after they're heard
+            let heard = position.saturating_sub(onset.at.elapsed().as_micros() as u64);
+            judged.write_batch(score.strike(heard, window));
+        }
+    } else {
+        onsets.clear();
+    }
+    judged.write_batch(score.advance(position, window));
+}
+
+#[test]
+fn test_practice_score_judges_hits_and_misses() {
+    let note = |start, note| GuideNote {
+        start,
+        end: start + 100_000,
+        note,
+    };
+    let mut score = PracticeScore::default();
+    score.reset(vec![
+        note(2_000_000, 64),
+        note(1_000_000, 60),
+        note(1_000_000, 67),
+    ]);
+    let window = 150_000;
+
+    assert_eq!(score.press(60, 500_000, window), None);
+    assert_eq!(
+        score.press(60, 1_050_000, window),
+        Some(NoteJudged {
+            note: 60,
+            offset: Some(50_000)
+        })
+    );
+    // Each note only counts once
+    assert_eq!(score.press(60, 1_060_000, window), None);
+    assert!(score.advance(1_100_000, window).is_empty());
+    assert_eq!(
+        score.advance(1_200_000, window),
+        vec![NoteJudged {
+            note: 67,
+            offset: None
+        }]
+    );
+    assert!(score.press(64, 1_950_000, window).unwrap().is_hit());
+    assert_eq!(
+        (score.hits, score.misses, score.streak, score.best_streak),
+        (2, 1, 1, 1)
+    );
+
+    score.restart();
+    assert_eq!((score.hits, score.misses), (0, 0));
+    assert!(score.press(60, 1_000_000, window).is_some());
+
+    // An onset hits the rest of the chord, whatever was played
+    let hits = score.strike(980_000, window);
+    assert_eq!(
+        hits,
+        vec![NoteJudged {
+            note: 67,
+            offset: Some(-20_000)
+        }]
+    );
+    assert_eq!(score.strike(1_500_000, window), vec![]);
+    assert_eq!(score.strike(2_100_000, window)[0].note, 64);
+    assert_eq!((score.hits, score.streak), (3, 3));
+}
@@ -20,10 +20,27 @@ pub struct SongNote {
     pub key_name: String,
 }
 
+/// Tempo assumed until a song sets its own, in microseconds per beat (120 bpm).
+pub const DEFAULT_TEMPO: u32 = 500_000;
+
 #[derive(Component)]
 pub struct SongLoader {
     pub path: String,
     pub notes: Vec<SongNote>,
+    /// The song's first tempo, in microseconds per beat.
+    pub tempo: u32,
+    /// The song's first time signature, as (numerator, denominator).
+    pub time_signature: (u8, u8),
+}
+
+/// Metadata of the song that is currently loaded.
+#[derive(Resource, Clone, Debug)]
+pub struct CurrentSong {
+    pub path: String,
+    /// Microseconds per beat.
+    pub tempo: u32,
+    /// As (numerator, denominator).
+    pub time_signature: (u8, u8),
 }
 
 #[hot(rerun_on_hot_patch = true)]
@@ -32,6 +49,12 @@ pub fn setup(mut commands: Commands, prev_setup: Query<Entity, With<SongNote>>)
 
     let song = SongLoader::new("/home/rouan/work/orion/assets/songs/happy_bday_v1.mid");
 
+    commands.insert_resource(CurrentSong {
+        path: song.path.clone(),
+        tempo: song.tempo,
+        time_signature: song.time_signature,
+    });
+
     for note in song.notes {
         commands.spawn((note, Transform::default(), GlobalTransform::default()));
     }
@@ -39,22 +62,26 @@ pub fn setup(mut commands: Commands, prev_setup: Query<Entity, With<SongNote>>)
 
 impl SongLoader {
     pub fn new(path: &str) -> Self {
-        let song = Self {
+        let mut song = Self {
             path: path.to_string(),
             notes: vec![],
+            tempo: DEFAULT_TEMPO,
+            time_signature: (4, 4),
         };
 
-        song.load_midi_binary();
+        song.notes = song.load_midi_binary();
         song
     }
 
-    fn load_midi_binary(&self) -> Vec<SongNote> {
+    fn load_midi_binary(&mut self) -> Vec<SongNote> {
         let data = std::fs::read(&self.path).unwrap();
         let smf = midly::Smf::parse(&data).unwrap();
 
         let mut song_notes: Vec<SongNote> = vec![];
         let mut time_tracker = 0.0;
         let mut ms_per_tick = 2.6;
+        let mut tempo_seen = false;
+        let mut time_signature_seen = false;
 
         for track in smf.tracks.iter() {
             // println!("Track {}: {:?}", i, track.len());
@@ -104,6 +131,11 @@ impl SongLoader {
                     },
                     midly::TrackEventKind::Meta(meta) => match meta {
                         midly::MetaMessage::Tempo(tempo) => {
+                            if !tempo_seen {
+                                self.tempo = u32::from(tempo);
+                                tempo_seen = true;
+                            }
+
                             let tempo_ms_per_beat = u32::from(tempo) as f64;
                             let bpm = 60_000_000.0 / tempo_ms_per_beat;
 
@@ -114,6 +146,12 @@ impl SongLoader {
                                 tempo_ms_per_beat, bpm, ms_per_tick
                             );
                         }
+                        midly::MetaMessage::TimeSignature(numerator, denominator, _, _) => {
+                            if !time_signature_seen {
+                                self.time_signature = (numerator, 1 << denominator.min(7));
+                                time_signature_seen = true;
+                            }
+                        }
                         _ => {
                             println!("Other Meta message: {:?}", meta);
                         }
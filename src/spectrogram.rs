@@ -0,0 +1,449 @@
+use crate::bevy_mic::microphone::MicrophoneSamples;
+use crate::stft::{AnalysisFormat, Stft, StftAnalysis, needs_rebuild};
+use bevy::asset::RenderAssetUsages;
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use std::time::Duration;
+
+/// A scrolling waterfall of the microphone spectrum, for showing the overtones of a note
+/// and whether the right notes were played.
+///
+/// Time runs from left to right, and pitch upwards on a log-frequency axis lined up with
+/// the piano keys. The picture is drawn on the CPU into an [`Image`].
+pub struct SpectrogramPlugin;
+
+impl Plugin for SpectrogramPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpectrogramSettings>()
+            .add_systems(Startup, spawn_view)
+            .add_systems(Update, (control_on_keys, update_view).chain());
+    }
+}
+
+/// Settings for [`SpectrogramPlugin`] and [`Spectrogram`].
+#[derive(Resource, Clone, Debug)]
+pub struct SpectrogramSettings {
+    /// Whether the spectrogram is shown over the game.
+    pub visible: bool,
+    pub toggle_key: KeyCode,
+    /// While paused the picture stands still, so it can be talked through.
+    pub paused: bool,
+    pub pause_key: KeyCode,
+    /// How many times taller each key is drawn than when the whole range fits.
+    pub zoom: f32,
+    pub zoom_in_key: KeyCode,
+    pub zoom_out_key: KeyCode,
+    /// How much audio the picture spans.
+    pub duration: Duration,
+    /// Windows drawn across the picture, one pixel wide each.
+    pub columns: usize,
+    /// Samples analysed at once. Long windows tell low notes apart.
+    pub window: usize,
+    /// The midi notes at the bottom and the top of the picture when not zoomed.
+    pub lowest_note: u8,
+    pub highest_note: u8,
+    /// The level drawn black.
+    pub floor_db: f32,
+    /// The level drawn brightest.
+    pub ceiling_db: f32,
+}
+
+impl Default for SpectrogramSettings {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            toggle_key: KeyCode::F5,
+            paused: false,
+            pause_key: KeyCode::F4,
+            zoom: 1.0,
+            zoom_in_key: KeyCode::Equal,
+            zoom_out_key: KeyCode::Minus,
+            duration: Duration::from_secs(8),
+            columns: 512,
+            window: 4096,
+            lowest_note: 21,
+            highest_note: 108,
+            floor_db: -70.0,
+            ceiling_db: -10.0,
+        }
+    }
+}
+
+impl SpectrogramSettings {
+    /// Zooming stops once this many keys fill the picture.
+    const MIN_KEYS: f32 = 12.0;
+
+    /// The fractional midi notes at the bottom and the top of the picture, zoomed in on
+    /// the middle of the range.
+    #[must_use]
+    pub fn visible_notes(&self) -> (f32, f32) {
+        let low = f32::from(self.lowest_note) - 0.5;
+        let high = f32::from(self.highest_note.max(self.lowest_note)) + 0.5;
+        let middle = (low + high) / 2.0;
+        let span =
+            ((high - low) / self.zoom.max(1.0)).clamp(Self::MIN_KEYS.min(high - low), high - low);
+        (middle - span / 2.0, middle + span / 2.0)
+    }
+
+    /// Samples between the starts of consecutive windows, so the columns span
+    /// [`SpectrogramSettings::duration`].
+    #[must_use]
+    pub fn hop(&self, sample_rate: u32) -> usize {
+        let samples = self.duration.as_secs_f64() * f64::from(sample_rate);
+        ((samples / self.columns.max(1) as f64).round() as usize).max(1)
+    }
+
+    /// What audio at `sample_rate` is analysed in.
+    #[must_use]
+    pub fn format(&self, sample_rate: u32) -> AnalysisFormat {
+        AnalysisFormat::new(sample_rate, self.window, self.hop(sample_rate))
+    }
+}
+
+/// The spectrum of the last [`SpectrogramSettings::columns`] windows of a stream of mono
+/// samples, at a few rows per key.
+pub struct Spectrogram {
+    format: AnalysisFormat,
+    stft: Stft,
+    columns: usize,
+    lowest_note: u8,
+    highest_note: u8,
+    /// The range of bins, fractional, under each row from the bottom.
+    bands: Vec<(f32, f32)>,
+    /// Levels from 0.0 to 1.0, `bands.len()` to a column. Columns are written round in a
+    /// ring, so the oldest is at `next`.
+    levels: Vec<f32>,
+    next: usize,
+    floor_db: f32,
+    ceiling_db: f32,
+}
+
+impl Spectrogram {
+    /// Rows analysed for each key, so the picture stays smooth when zoomed.
+    const ROWS_PER_KEY: usize = 4;
+
+    #[must_use]
+    pub fn new(sample_rate: u32, settings: &SpectrogramSettings) -> Self {
+        let hop = settings.hop(sample_rate);
+        // Hops longer than the window would skip audio
+        let stft = Stft::new(settings.window.max(hop), hop);
+        let keys =
+            usize::from(settings.highest_note.max(settings.lowest_note) - settings.lowest_note) + 1;
+        let bin_of = |note: f32| {
+            let hz = 440.0 * 2f32.powf((note - 69.0) / 12.0);
+            hz * stft.size() as f32 / sample_rate as f32
+        };
+        let bands = (0..keys * Self::ROWS_PER_KEY)
+            .map(|row| {
+                let note =
+                    f32::from(settings.lowest_note) - 0.5 + row as f32 / Self::ROWS_PER_KEY as f32;
+                (bin_of(note), bin_of(note + 1.0 / Self::ROWS_PER_KEY as f32))
+            })
+            .collect::<Vec<_>>();
+        let columns = settings.columns.max(1);
+        Self {
+            format: settings.format(sample_rate),
+            columns,
+            lowest_note: settings.lowest_note,
+            highest_note: settings.highest_note,
+            levels: vec![0.0; columns * bands.len()],
+            bands,
+            next: 0,
+            floor_db: settings.floor_db,
+            ceiling_db: settings.ceiling_db,
+            stft,
+        }
+    }
+
+    #[must_use]
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    /// Whether this was made from settings equal to `settings` where it matters, other than
+    /// its [`AnalysisFormat`].
+    #[must_use]
+    pub fn fits(&self, settings: &SpectrogramSettings) -> bool {
+        self.columns == settings.columns.max(1)
+            && self.lowest_note == settings.lowest_note
+            && self.highest_note == settings.highest_note
+            && self.floor_db == settings.floor_db
+            && self.ceiling_db == settings.ceiling_db
+    }
+
+    /// Analyses `samples`, returning whether any new column was added.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        let range = (self.ceiling_db - self.floor_db).max(f32::EPSILON);
+        let rows = self.bands.len();
+        let mut added = false;
+
+        self.stft.push(samples);
+        while self.stft.next_frame().is_some() {
+            added = true;
+            let magnitudes = self.stft.magnitudes();
+            let column = &mut self.levels[self.next * rows..(self.next + 1) * rows];
+            for (level, &(low, high)) in column.iter_mut().zip(&self.bands) {
+                let db = 20.0
+                    * band_magnitude(magnitudes, low, high)
+                        .max(f32::MIN_POSITIVE)
+                        .log10();
+                *level = ((db - self.floor_db) / range).clamp(0.0, 1.0);
+            }
+            self.next = (self.next + 1) % self.columns;
+        }
+        added
+    }
+
+    /// The level from 0.0 to 1.0 at the fractional midi `note`, `age` columns before the
+    /// newest. Outside the analysed range it's 0.0.
+    #[must_use]
+    pub fn level(&self, age: usize, note: f32) -> f32 {
+        let row = (note - f32::from(self.lowest_note) + 0.5) * Self::ROWS_PER_KEY as f32;
+        if age >= self.columns || row < 0.0 || row as usize >= self.bands.len() {
+            return 0.0;
+        }
+        let column = (self.next + self.columns - 1 - age) % self.columns;
+        self.levels[column * self.bands.len() + row as usize]
+    }
+
+    /// Draws the picture into `pixels`, an sRGB image `columns()` wide and `height` tall,
+    /// between the [`SpectrogramSettings::visible_notes`].
+    pub fn draw(&self, settings: &SpectrogramSettings, pixels: &mut [u8], height: usize) {
+        let (low, high) = settings.visible_notes();
+        let per_pixel = (high - low) / height as f32;
+        for (y, line) in pixels
+            .chunks_exact_mut(self.columns * 4)
+            .take(height)
+            .enumerate()
+        {
+            // Pixel rows count down from the top
+            let note = high - (y as f32 + 0.5) * per_pixel;
+            let grid = gridline(note, per_pixel);
+            for (x, pixel) in line.chunks_exact_mut(4).enumerate() {
+                let mut color = heat(self.level(self.columns - 1 - x, note));
+                if let Some(strength) = grid {
+                    for channel in &mut color {
+                        *channel += (0.6 - *channel) * strength;
+                    }
+                }
+                for (byte, channel) in pixel.iter_mut().zip(color) {
+                    *byte = (channel * 255.0).round() as u8;
+                }
+                pixel[3] = u8::MAX;
+            }
+        }
+    }
+}
+
+impl StftAnalysis for Spectrogram {
+    fn format(&self) -> AnalysisFormat {
+        self.format
+    }
+}
+
+/// The loudest bin between fractional bins `low` and `high`, or the spectrum between bins
+/// where the band is narrower than one.
+fn band_magnitude(magnitudes: &[f32], low: f32, high: f32) -> f32 {
+    let first = low.ceil() as usize;
+    let last = (high.floor() as usize).min(magnitudes.len().saturating_sub(1));
+    if first <= last {
+        return magnitudes[first..=last].iter().copied().fold(0.0, f32::max);
+    }
+    let middle = (low + high) / 2.0;
+    let below = middle.floor() as usize;
+    let (Some(a), Some(b)) = (magnitudes.get(below), magnitudes.get(below + 1)) else {
+        return 0.0;
+    };
+    a + (b - a) * middle.fract()
+}
+
+/// How strongly to draw a gridline through the pixel row at fractional `note`, `per_pixel`
+/// notes tall: bright under each C, and faint between the other keys when they're tall
+/// enough to tell apart.
+fn gridline(note: f32, per_pixel: f32) -> Option<f32> {
+    // The pixel row holding the lower edge of a key
+    let edge = (note + 0.5).floor();
+    if note + 0.5 - edge >= per_pixel {
+        return None;
+    }
+    if (edge as i32).rem_euclid(12) == 0 {
+        Some(0.5)
+    } else if per_pixel <= 0.25 {
+        Some(0.15)
+    } else {
+        None
+    }
+}
+
+/// Black through purple and orange to pale yellow as `level` goes from 0.0 to 1.0.
+fn heat(level: f32) -> [f32; 4] {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [0.2, 0.05, 0.4],
+        [0.7, 0.1, 0.35],
+        [1.0, 0.5, 0.1],
+        [1.0, 1.0, 0.7],
+    ];
+    let position = level.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let below = (position as usize).min(STOPS.len() - 2);
+    let t = position - below as f32;
+    let [a, b] = [STOPS[below], STOPS[below + 1]];
+    [
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+        1.0,
+    ]
+}
+
+/// The node showing the spectrogram [`Image`].
+#[derive(Component)]
+struct SpectrogramView;
+
+/// Pixel rows in the picture.
+const HEIGHT: usize = 360;
+
+fn spawn_view(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    let image = Image::new_fill(
+        Extent3d {
+            width: 1,
+            height: HEIGHT as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, u8::MAX],
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+    );
+    commands.spawn((
+        SpectrogramView,
+        ImageNode::new(images.add(image)),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(8.0),
+            right: Val::Px(8.0),
+            width: Val::Px(640.0),
+            height: Val::Px(HEIGHT as f32),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+}
+
+fn control_on_keys(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<SpectrogramSettings>) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.visible = !settings.visible;
+    }
+    if keys.just_pressed(settings.pause_key) {
+        settings.paused = !settings.paused;
+    }
+    if keys.just_pressed(settings.zoom_in_key) {
+        settings.zoom *= 2.0;
+    }
+    if keys.just_pressed(settings.zoom_out_key) {
+        settings.zoom = (settings.zoom / 2.0).max(1.0);
+    }
+}
+
+fn update_view(
+    settings: Res<SpectrogramSettings>,
+    mut samples: EventReader<MicrophoneSamples>,
+    mut spectrogram: Local<Option<Spectrogram>>,
+    mut view: Query<(&ImageNode, &mut Visibility), With<SpectrogramView>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    let mut redraw = settings.is_changed();
+    if settings.paused {
+        samples.clear();
+    }
+    for chunk in samples.read() {
+        let fitting = spectrogram.as_ref().filter(|s| s.fits(&settings));
+        if needs_rebuild(fitting, settings.format(chunk.sample_rate)) {
+            *spectrogram = Some(Spectrogram::new(chunk.sample_rate, &settings));
+        }
+        if let Some(spectrogram) = spectrogram.as_mut() {
+            redraw |= spectrogram.push(&chunk.samples);
+        }
+    }
+
+    for (node, mut visibility) in &mut view {
+        visibility.set_if_neq(if settings.visible {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        });
+        // Nothing is drawn while hidden, so it costs nothing but the analysis
+        let Some(spectrogram) = spectrogram.as_ref() else {
+            continue;
+        };
+        if !redraw || !settings.visible {
+            continue;
+        }
+        let Some(image) = images.get_mut(&node.image) else {
+            continue;
+        };
+        if image.width() as usize != spectrogram.columns() {
+            image.resize(Extent3d {
+                width: spectrogram.columns() as u32,
+                height: HEIGHT as u32,
+                depth_or_array_layers: 1,
+            });
+        }
+        if let Some(pixels) = image.data.as_mut() {
+            spectrogram.draw(&settings, pixels, HEIGHT);
+        }
+    }
+}
+
+#[test]
+fn test_spectrogram_draws_a_tone_at_its_key() {
+    let sample_rate = 48_000;
+    let settings = SpectrogramSettings::default();
+    let mut spectrogram = Spectrogram::new(sample_rate, &settings);
+    assert!(spectrogram.fits(&settings));
+    assert_eq!(spectrogram.format(), settings.format(sample_rate));
+    assert_eq!(settings.hop(sample_rate), 750);
+
+    // One second of A4 with a quieter octave above
+    let tone: Vec<f32> = (0..sample_rate as usize)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            0.5 * (std::f32::consts::TAU * 440.0 * t).sin()
+                + 0.05 * (std::f32::consts::TAU * 880.0 * t).sin()
+        })
+        .collect();
+    assert!(spectrogram.push(&tone));
+    assert!(spectrogram.level(0, 69.0) > 0.9);
+    assert!(spectrogram.level(0, 81.0) > 0.5);
+    assert!(spectrogram.level(0, 81.0) < spectrogram.level(0, 69.0));
+    for note in [57.0, 64.0, 75.0, 93.0] {
+        assert!(spectrogram.level(0, note) < 0.3, "{note}");
+    }
+    // Older than the audio, and off the range
+    assert_eq!(spectrogram.level(settings.columns - 1, 69.0), 0.0);
+    assert_eq!(spectrogram.level(0, 10.0), 0.0);
+
+    let mut pixels = vec![0; settings.columns * HEIGHT * 4];
+    spectrogram.draw(&settings, &mut pixels, HEIGHT);
+    let (low, high) = settings.visible_notes();
+    let pixel = |x: usize, note: f32| {
+        let y = ((high - note) / (high - low) * HEIGHT as f32) as usize;
+        let at = (y * settings.columns + x) * 4;
+        [pixels[at], pixels[at + 1], pixels[at + 2]]
+    };
+    assert!(pixel(settings.columns - 1, 69.0)[0] > 200);
+    assert_eq!(pixel(0, 69.0), [0, 0, 0]);
+    // A gridline under middle C, on silence
+    let per_pixel = (high - low) / HEIGHT as f32;
+    assert!(pixel(0, 59.5 + per_pixel / 2.0)[0] > 50);
+    assert_eq!(pixel(0, 62.0), [0, 0, 0]);
+
+    // Zooming in narrows the range around the middle
+    let zoomed = SpectrogramSettings {
+        zoom: 4.0,
+        ..default()
+    };
+    let (zoomed_low, zoomed_high) = zoomed.visible_notes();
+    assert!((zoomed_high - zoomed_low - 22.0).abs() < 0.01);
+    assert!((zoomed_low + zoomed_high - low - high).abs() < 0.01);
+}
@@ -0,0 +1,183 @@
+use realfft::num_complex::Complex;
+use realfft::{RealFftPlanner, RealToComplex};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// Plans are shared, so every analysis of the same size reuses one.
+static PLANNER: LazyLock<Mutex<RealFftPlanner<f32>>> =
+    LazyLock::new(|| Mutex::new(RealFftPlanner::new()));
+
+/// A short-time Fourier transform over a stream of samples, in fixed-size Hann windows that
+/// start every `hop` samples.
+///
+/// Samples can be pushed in chunks of any size; each full window is transformed once.
+pub struct Stft {
+    hop: usize,
+    fft: Arc<dyn RealToComplex<f32>>,
+    window: Vec<f32>,
+    /// Samples not yet moved past by a hop.
+    pending: Vec<f32>,
+    input: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+    frames: u64,
+}
+
+impl Stft {
+    #[must_use]
+    pub fn new(size: usize, hop: usize) -> Self {
+        let fft = PLANNER.lock().unwrap().plan_fft_forward(size);
+        let window: Vec<f32> = apodize::hanning_iter(size).map(|w| w as f32).collect();
+        Self {
+            hop: hop.clamp(1, size),
+            window,
+            pending: Vec::with_capacity(size * 2),
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            magnitudes: vec![0.0; size / 2 + 1],
+            fft,
+            frames: 0,
+        }
+    }
+
+    /// Samples in each window.
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.window.len()
+    }
+
+    #[must_use]
+    pub fn hop(&self) -> usize {
+        self.hop
+    }
+
+    /// Windows transformed so far. The next one starts at sample `frames() * hop()`.
+    #[must_use]
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// The frequency at the middle of `bin`.
+    #[must_use]
+    pub fn bin_hz(&self, bin: f32, sample_rate: u32) -> f32 {
+        bin * sample_rate as f32 / self.size() as f32
+    }
+
+    /// The magnitudes of the last window, as returned by [`next_frame`](Self::next_frame).
+    #[must_use]
+    pub fn magnitudes(&self) -> &[f32] {
+        &self.magnitudes
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.pending.extend_from_slice(samples);
+    }
+
+    /// Transforms the next window, if enough samples were pushed, returning the magnitude of
+    /// each bin from 0 Hz to Nyquist. A sine of amplitude 1.0 peaks at about 1.0.
+    pub fn next_frame(&mut self) -> Option<&[f32]> {
+        let size = self.size();
+        if self.pending.len() < size {
+            return None;
+        }
+        for ((input, sample), w) in self.input.iter_mut().zip(&self.pending).zip(&self.window) {
+            *input = sample * w;
+        }
+        self.fft
+            .process_with_scratch(&mut self.input, &mut self.spectrum, &mut self.scratch)
+            .ok()?;
+        // A Hann window halves the energy of the signal
+        let scale = 4.0 / size as f32;
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.spectrum) {
+            *magnitude = bin.norm() * scale;
+        }
+        self.pending.drain(..self.hop);
+        self.frames += 1;
+        Some(&self.magnitudes)
+    }
+}
+
+/// The audio an analysis was built for: its sample rate, and the window and hop it was
+/// asked for, before [`Stft::new`] clamps the hop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AnalysisFormat {
+    pub sample_rate: u32,
+    pub window: usize,
+    pub hop: usize,
+}
+
+impl AnalysisFormat {
+    #[must_use]
+    pub fn new(sample_rate: u32, window: usize, hop: usize) -> Self {
+        Self {
+            sample_rate,
+            window,
+            hop,
+        }
+    }
+}
+
+/// An analysis of a stream of samples, which only suits the [`AnalysisFormat`] it was built
+/// for.
+pub trait StftAnalysis {
+    fn format(&self) -> AnalysisFormat;
+}
+
+/// Whether `analysis` has to be built for `format`, because there's none yet or it was
+/// built for another, e.g. before the microphone or the settings changed.
+#[must_use]
+pub fn needs_rebuild<A: StftAnalysis>(analysis: Option<&A>, format: AnalysisFormat) -> bool {
+    analysis.is_none_or(|analysis| analysis.format() != format)
+}
+
+/// The local maxima of `magnitudes`, as a fractional bin and magnitude refined by fitting a
+/// parabola through each peak and its neighbours.
+pub fn spectral_peaks(magnitudes: &[f32]) -> impl Iterator<Item = (f32, f32)> + '_ {
+    magnitudes.windows(3).enumerate().filter_map(|(i, w)| {
+        let [left, middle, right] = [w[0], w[1], w[2]];
+        if middle <= left || middle < right || middle <= 0.0 {
+            return None;
+        }
+        let curvature = left - 2.0 * middle + right;
+        let offset = if curvature < 0.0 {
+            0.5 * (left - right) / curvature
+        } else {
+            0.0
+        };
+        let magnitude = middle - 0.25 * (left - right) * offset;
+        Some((i as f32 + 1.0 + offset, magnitude))
+    })
+}
+
+#[test]
+fn test_stft_frames_overlap_and_find_a_sine() {
+    let sample_rate = 48_000;
+    let mut stft = Stft::new(1024, 256);
+    // 1500 Hz falls exactly on bin 32
+    let sine: Vec<f32> = (0..2048)
+        .map(|i| 0.5 * (std::f32::consts::TAU * 1500.0 * i as f32 / sample_rate as f32).sin())
+        .collect();
+    stft.push(&sine[..1000]);
+    assert!(stft.next_frame().is_none());
+    stft.push(&sine[1000..]);
+
+    let magnitudes = stft.next_frame().unwrap().to_vec();
+    let peak = (0..magnitudes.len())
+        .max_by(|&a, &b| magnitudes[a].total_cmp(&magnitudes[b]))
+        .unwrap();
+    assert_eq!(peak, 32);
+    assert_eq!(stft.bin_hz(peak as f32, sample_rate), 1500.0);
+    assert!(
+        (magnitudes[peak] - 0.5).abs() < 0.01,
+        "{}",
+        magnitudes[peak]
+    );
+
+    // (2048 - 1024) / 256 more windows fit
+    let mut frames = 1;
+    while stft.next_frame().is_some() {
+        frames += 1;
+    }
+    assert_eq!((frames, stft.frames()), (5, 5));
+}
@@ -1,8 +1,62 @@
 //! Synth engine extracted from keys.rs for use in both egui and Bevy MIDI systems.
 #![allow(clippy::precedence)]
 
+use crate::bevy_midi::ump::{Midi2Message, controller_unit, pitch_bend_unit, velocity_unit};
+use crate::bevy_midi::{
+    CONTROL_ALL_NOTES_OFF, CONTROL_ALL_SOUND_OFF, CONTROL_RESET_ALL_CONTROLLERS,
+};
 use fundsp::hacker::*;
 use funutd::Rnd;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Controller number of the brightness (timbre) controller, also used for per-note brightness.
+const CONTROL_BRIGHTNESS: u8 = 74;
+/// How long a released note takes to fade out, in seconds.
+const RELEASE_TIME: f64 = 0.2;
+/// How long All Sound Off takes to silence a note, just long enough not to click.
+const CUT_TIME: f64 = 0.01;
+
+/// A sounding note, with the controls that can change while it plays.
+struct Voice {
+    event: EventId,
+    /// Pitch ratio from the channel and per-note pitch bend.
+    bend: Shared,
+    /// Gain from the velocity and pressure.
+    gain: Shared,
+    /// Lowpass cutoff in Hz from the brightness controller.
+    brightness: Shared,
+    velocity: f32,
+    /// Per-note pitch bend in semitones.
+    note_bend: f32,
+    pressure: f32,
+}
+
+/// How far the synth's audio stream has rendered, in seconds, written from the audio thread.
+#[derive(Clone, Default)]
+pub struct AudioClock(Arc<AtomicU64>);
+
+impl AudioClock {
+    pub fn time(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+
+    fn set_time(&self, time: f64) {
+        self.0.store(time.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Controls a channel applies to all of its notes.
+#[derive(Clone, Copy, Default)]
+struct ChannelControls {
+    /// Pitch bend in semitones.
+    bend: f32,
+    pressure: f32,
+    /// Brightness from 0.0 to 1.0, if the channel has set it.
+    brightness: Option<f32>,
+}
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Waveform {
@@ -35,6 +89,10 @@ pub struct SynthEngine {
     pub filter: Filter,
     pub vibrato_amount: f64,
 
+    /// Output lowpass cutoff in Hz.
+    cutoff: Shared,
+    /// Output gain.
+    volume: Shared,
     /// Chorus amount.
     chorus_amount: Shared,
     /// Reverb amount.
@@ -59,12 +117,35 @@ pub struct SynthEngine {
     snoop0: Snoop,
     /// Right channel data for the oscilloscope.
     snoop1: Snoop,
+    /// Range of channel pitch bends, in semitones either way.
+    pub bend_range: f32,
+    /// Range of per-note pitch bends, in semitones either way.
+    pub note_bend_range: f32,
+    /// Sounding notes by channel and note.
+    voices: HashMap<(u8, u8), Voice>,
+    /// Released notes that may still be fading, with their channel and the audio time their
+    /// fade ends, so All Sound Off can cut them short too.
+    released: Vec<(u8, EventId, f64)>,
+    channels: [ChannelControls; 16],
+    /// How long after a message happened [`handle_at`](Self::handle_at) plays it, in
+    /// seconds. Long enough to cover a frame and an audio block, so every message gets the
+    /// same delay instead of waiting for the next ones.
+    pub schedule_delay: f64,
+    clock: AudioClock,
+    /// When audio time was measured from.
+    started: Instant,
+    /// Audio time minus the seconds since `started`, and when that was estimated.
+    clock_offset: Option<(f64, f64)>,
 }
 
 impl SynthEngine {
+    pub const MIN_CUTOFF: f32 = 100.0;
+    pub const MAX_CUTOFF: f32 = 18000.0;
+
     pub fn new() -> Self {
         let room_size = 10.0;
-        let reverb_amount = shared(0.25);
+        // Dry until a learned control or OSC asks for reverb
+        let reverb_amount = shared(0.0);
         let reverb_time = 2.0;
         let reverb_diffusion = 0.5;
         let chorus_amount = shared(1.0);
@@ -73,6 +154,8 @@ impl SynthEngine {
         let (snoop1, snoop_backend1) = snoop(32768);
 
         Self {
+            cutoff: shared(Self::MAX_CUTOFF),
+            volume: shared(1.0),
             rnd: Rnd::from_u64(0),
             sequencer: Sequencer::new(false, 1),
             waveform: Waveform::Sine,
@@ -88,10 +171,201 @@ impl SynthEngine {
             phaser_enabled: false,
             flanger_id: NodeId::new(),
             flanger_enabled: false,
+            bend_range: 2.0,
+            note_bend_range: 48.0,
+            voices: HashMap::new(),
+            released: Vec::new(),
+            channels: [ChannelControls::default(); 16],
+            schedule_delay: 0.02,
+            clock: AudioClock::default(),
+            started: Instant::now(),
+            clock_offset: None,
         }
     }
 
+    /// Plays a MIDI 2.0 message, using its full resolution for velocity, pressure, pitch bend
+    /// and brightness. Notes can be bent and controlled on their own with per-note messages.
+    pub fn handle(&mut self, message: &Midi2Message) {
+        self.dispatch(message, None);
+    }
+
+    /// Plays a message that happened at `at`, starting or releasing its note exactly
+    /// [`schedule_delay`](Self::schedule_delay) later in the audio stream. Controllers still
+    /// change straight away.
+    ///
+    /// Returns how long after `at` the message will be heard, in seconds, which is more
+    /// than the delay if it arrived too late.
+    pub fn handle_at(&mut self, message: &Midi2Message, at: Instant) -> f64 {
+        let target = self.audio_time(at) + self.schedule_delay;
+        let start = target.max(self.clock.time());
+        self.dispatch(message, Some(start));
+        self.schedule_delay + start - target
+    }
+
+    /// The clock of the audio stream.
+    pub fn clock(&self) -> &AudioClock {
+        &self.clock
+    }
+
+    /// Estimates where in the audio stream `instant` falls.
+    fn audio_time(&mut self, instant: Instant) -> f64 {
+        let wall = self.started.elapsed().as_secs_f64();
+        // Audio renders ahead in blocks, so the furthest ahead it has been is the steady
+        // mapping. Let that fall back slowly in case the two clocks drift apart.
+        let offset = self.clock.time() - wall;
+        let offset = match self.clock_offset {
+            Some((previous, since)) => offset.max(previous - (wall - since) * 0.001),
+            None => offset,
+        };
+        self.clock_offset = Some((offset, wall));
+        let instant = match instant.checked_duration_since(self.started) {
+            Some(after) => after.as_secs_f64(),
+            None => -self.started.duration_since(instant).as_secs_f64(),
+        };
+        instant + offset
+    }
+
+    /// Plays a message, scheduling its notes at audio time `at`, or straight away.
+    fn dispatch(&mut self, message: &Midi2Message, at: Option<f64>) {
+        match *message {
+            Midi2Message::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => self.start_voice(channel, note, velocity_unit(velocity), at),
+            Midi2Message::NoteOff { channel, note, .. } => self.release_voice(channel, note, at),
+            Midi2Message::PolyPressure {
+                channel,
+                note,
+                value,
+            } => {
+                if let Some(voice) = self.voices.get_mut(&(channel, note)) {
+                    voice.pressure = controller_unit(value);
+                }
+                self.update_voices(channel);
+            }
+            Midi2Message::ChannelPressure { channel, value } => {
+                let pressure = controller_unit(value);
+                self.channels[usize::from(channel & 0x0f)].pressure = pressure;
+                for (_, voice) in self.voices.iter_mut().filter(|((c, _), _)| *c == channel) {
+                    voice.pressure = pressure;
+                }
+                self.update_voices(channel);
+            }
+            Midi2Message::PitchBend { channel, value } => {
+                self.channels[usize::from(channel & 0x0f)].bend =
+                    pitch_bend_unit(value) * self.bend_range;
+                self.update_voices(channel);
+            }
+            Midi2Message::PerNotePitchBend {
+                channel,
+                note,
+                value,
+            } => {
+                if let Some(voice) = self.voices.get_mut(&(channel, note)) {
+                    voice.note_bend = pitch_bend_unit(value) * self.note_bend_range;
+                }
+                self.update_voices(channel);
+            }
+            Midi2Message::ControlChange {
+                channel,
+                index: CONTROL_BRIGHTNESS,
+                value,
+            } => {
+                let brightness = controller_unit(value);
+                self.channels[usize::from(channel & 0x0f)].brightness = Some(brightness);
+                for (_, voice) in self.voices.iter().filter(|((c, _), _)| *c == channel) {
+                    voice.brightness.set_value(brightness_hz(brightness));
+                }
+            }
+            Midi2Message::PerNoteController {
+                channel,
+                note,
+                index: CONTROL_BRIGHTNESS,
+                value,
+                ..
+            } => {
+                if let Some(voice) = self.voices.get(&(channel, note)) {
+                    voice
+                        .brightness
+                        .set_value(brightness_hz(controller_unit(value)));
+                }
+            }
+            Midi2Message::ControlChange {
+                channel,
+                index: CONTROL_ALL_NOTES_OFF,
+                ..
+            } => {
+                for note in self.channel_notes(channel) {
+                    self.release_voice(channel, note, at);
+                }
+            }
+            Midi2Message::ControlChange {
+                channel,
+                index: CONTROL_ALL_SOUND_OFF,
+                ..
+            } => {
+                for note in self.channel_notes(channel) {
+                    self.release_voice(channel, note, None);
+                }
+                let (cut, fading): (Vec<_>, Vec<_>) =
+                    self.released.drain(..).partition(|&(c, _, _)| c == channel);
+                self.released = fading;
+                for (_, event, _) in cut {
+                    self.sequencer.edit_relative(event, CUT_TIME, CUT_TIME);
+                }
+            }
+            Midi2Message::ControlChange {
+                channel,
+                index: CONTROL_RESET_ALL_CONTROLLERS,
+                ..
+            } => {
+                self.channels[usize::from(channel & 0x0f)] = ChannelControls::default();
+                for (_, voice) in self.voices.iter_mut().filter(|((c, _), _)| *c == channel) {
+                    voice.note_bend = 0.0;
+                    voice.pressure = 0.0;
+                    voice.brightness.set_value(Self::MAX_CUTOFF);
+                }
+                self.update_voices(channel);
+            }
+            _ => {}
+        }
+    }
+
+    /// The notes sounding on `channel`.
+    fn channel_notes(&self, channel: u8) -> Vec<u8> {
+        self.voices
+            .keys()
+            .filter(|(c, _)| *c == channel)
+            .map(|(_, note)| *note)
+            .collect()
+    }
+
+    /// Applies the bends and pressure of `channel` to its sounding notes.
+    fn update_voices(&self, channel: u8) {
+        let controls = self.channels[usize::from(channel & 0x0f)];
+        for (_, voice) in self.voices.iter().filter(|((c, _), _)| *c == channel) {
+            voice
+                .bend
+                .set_value(((controls.bend + voice.note_bend) / 12.0).exp2());
+            voice
+                .gain
+                .set_value(voice.velocity * (1.0 + 0.5 * voice.pressure));
+        }
+    }
+
+    /// Plays `midi_note` on the first channel, with a `velocity` from 0.0 to 1.0.
     pub fn note_on(&mut self, midi_note: u8, velocity: f32) {
+        self.start_voice(0, midi_note, velocity, None);
+    }
+
+    fn start_voice(&mut self, channel: u8, midi_note: u8, velocity: f32, at: Option<f64>) {
+        self.release_voice(channel, midi_note, at);
+        let controls = self.channels[usize::from(channel & 0x0f)];
+        let bend = shared((controls.bend / 12.0).exp2());
+        let gain = shared(velocity * (1.0 + 0.5 * controls.pressure));
+        let brightness = shared(controls.brightness.map_or(Self::MAX_CUTOFF, brightness_hz));
+
         let pitch_hz = midi_hz(midi_note as f64);
         let v = self.vibrato_amount * 0.006;
         let pitch = lfo(move |t| {
@@ -101,26 +375,25 @@ impl SynthEngine {
                     1.0 + v,
                     0.5 * (sin_hz(6.0, t) + sin_hz(6.1, t)),
                 )
-        });
+        }) * var(&bend);
         let waveform = match self.waveform {
-            Waveform::Sine => Net::wrap(Box::new(pitch * 2.0 >> sine() * 0.1 * velocity as f32)),
-            Waveform::Saw => Net::wrap(Box::new(pitch >> saw() * 0.2 * velocity as f32)),
-            Waveform::Square => Net::wrap(Box::new(pitch >> square() * 0.2 * velocity as f32)),
-            Waveform::Triangle => Net::wrap(Box::new(pitch >> triangle() * 0.2 * velocity as f32)),
-            Waveform::Organ => Net::wrap(Box::new(pitch >> organ() * 0.2 * velocity as f32)),
-            Waveform::Hammond => Net::wrap(Box::new(pitch >> hammond() * 0.2 * velocity as f32)),
+            Waveform::Sine => Net::wrap(Box::new(pitch * 2.0 >> sine() * 0.1)),
+            Waveform::Saw => Net::wrap(Box::new(pitch >> saw() * 0.2)),
+            Waveform::Square => Net::wrap(Box::new(pitch >> square() * 0.2)),
+            Waveform::Triangle => Net::wrap(Box::new(pitch >> triangle() * 0.2)),
+            Waveform::Organ => Net::wrap(Box::new(pitch >> organ() * 0.2)),
+            Waveform::Hammond => Net::wrap(Box::new(pitch >> hammond() * 0.2)),
             Waveform::Pulse => Net::wrap(Box::new(
-                (pitch | lfo(move |t| lerp11(0.01, 0.99, sin_hz(0.1, t))))
-                    >> pulse() * 0.2 * velocity as f32,
-            )),
-            Waveform::Pluck => Net::wrap(Box::new(
-                zero() >> pluck(pitch_hz as f32, 0.5, 0.5) * 0.5 * velocity as f32,
+                (pitch | lfo(move |t| lerp11(0.01, 0.99, sin_hz(0.1, t)))) >> pulse() * 0.2,
             )),
+            Waveform::Pluck => {
+                Net::wrap(Box::new(zero() >> pluck(pitch_hz as f32, 0.5, 0.5) * 0.5))
+            }
             Waveform::Noise => Net::wrap(Box::new(
                 (noise() | pitch * 4.0 | lfo(|t| funutd::math::lerp(2.0, 20.0, clamp01(t * 3.0))))
                     >> !resonator()
                     >> resonator()
-                    >> shape(Adaptive::new(0.1, Atan(0.05))) * 0.5 * velocity as f32,
+                    >> shape(Adaptive::new(0.1, Atan(0.05))) * 0.5,
             )),
         };
         let filter = match self.filter {
@@ -149,29 +422,123 @@ impl SynthEngine {
                     >> fresonator(Softsign(1.10)),
             )),
         };
-        let mut note = Box::new(waveform >> filter >> dcblock());
+        // Smooth the gain so pressure swells without zipper noise.
+        let mut note = Box::new(
+            waveform
+                >> filter
+                >> dcblock()
+                >> ((pass() | var(&brightness)) >> butterpass())
+                >> (pass() * (var(&gain) >> follow(0.01))),
+        );
         note.ping(false, AttoHash::new(self.rnd.u64()));
 
-        let duration = 0.5 + (velocity as f64 / 127.0) * 5.0;
+        let duration = 0.5 + velocity as f64 * 5.0;
 
-        self.sequencer
-            .push_relative(0.0, duration, Fade::Smooth, 0.03, duration, note);
+        let event = match at {
+            Some(start) => {
+                self.sequencer
+                    .push(start, start + duration, Fade::Smooth, 0.03, duration, note)
+            }
+            None => self
+                .sequencer
+                .push_relative(0.0, duration, Fade::Smooth, 0.03, duration, note),
+        };
+        self.voices.insert(
+            (channel, midi_note),
+            Voice {
+                event,
+                bend,
+                gain,
+                brightness,
+                velocity,
+                note_bend: 0.0,
+                pressure: controls.pressure,
+            },
+        );
     }
 
+    /// Releases `midi_note` on the first channel.
     pub fn note_off(&mut self, midi_note: u8) {
-        // For now, just fade out all notes (improve to track note IDs per note)
-        // for id in self.sequencer.events() {
-        //     self.sequencer.edit_relative(*id, 0.2, 0.2);
-        // }
+        self.release_voice(0, midi_note, None);
+    }
 
-        // self.sequencer.
+    fn release_voice(&mut self, channel: u8, midi_note: u8, at: Option<f64>) {
+        if let Some(voice) = self.voices.remove(&(channel, midi_note)) {
+            let now = self.clock.time();
+            let end = match at {
+                Some(end) => {
+                    self.sequencer
+                        .edit(voice.event, end + RELEASE_TIME, RELEASE_TIME);
+                    end
+                }
+                None => {
+                    self.sequencer
+                        .edit_relative(voice.event, RELEASE_TIME, RELEASE_TIME);
+                    now
+                }
+            };
+            self.released.retain(|&(_, _, until)| until > now);
+            self.released
+                .push((channel, voice.event, end + RELEASE_TIME));
+        }
+    }
 
-        // self.sequencer.
+    /// Sets the cutoff of the output lowpass, in Hz.
+    pub fn set_cutoff(&self, hz: f32) {
+        self.cutoff
+            .set_value(hz.clamp(Self::MIN_CUTOFF, Self::MAX_CUTOFF));
     }
 
-    pub fn backend(&mut self) -> Box<dyn AudioUnit> {
-        Box::new(self.sequencer.backend())
+    /// Sets the reverb wet/dry mix, from 0.0 (dry) to 1.0.
+    pub fn set_reverb_amount(&self, amount: f32) {
+        self.reverb_amount.set_value(amount.clamp(0.0, 1.0));
+    }
 
-        // self.sequencer.backend()
+    /// Sets the output gain, from 0.0 (silent) to 1.0.
+    pub fn set_volume(&self, volume: f32) {
+        self.volume.set_value(volume.clamp(0.0, 1.0));
     }
+
+    pub fn backend(&mut self) -> Box<dyn AudioUnit> {
+        let mut net = Net::wrap(Box::new(self.sequencer.backend()));
+        // Smooth the parameters to prevent zipper noise while a knob turns.
+        net = net
+            >> Net::wrap(Box::new(
+                (pass() | var(&self.cutoff) >> follow(0.01)) >> butterpass(),
+            ));
+        let reverb = reverb2_stereo(
+            self.room_size as f32,
+            self.reverb_time as f32,
+            self.reverb_diffusion as f32,
+            1.0,
+            highshelf_hz(5000.0, 1.0, db_amp(-1.0)),
+        );
+        net = net
+            >> Net::wrap(Box::new(
+                (1.0 - var(&self.reverb_amount) >> follow(0.01)) * pass()
+                    & (var(&self.reverb_amount) >> follow(0.01))
+                        * (split::<U2>() >> reverb >> join::<U2>()),
+            ));
+        net = net >> Net::wrap(Box::new(pass() * (var(&self.volume) >> follow(0.01))));
+        // Share the time the sequencer schedules notes in, as it renders. A new graph
+        // starts from zero again.
+        self.clock.set_time(0.0);
+        self.clock_offset = None;
+        let clock = self.clock.clone();
+        net = net
+            >> Net::wrap(Box::new(
+                pass()
+                    + lfo(move |t| {
+                        clock.set_time(t);
+                        0.0
+                    }),
+            ));
+        Box::new(net)
+    }
+}
+
+/// The lowpass cutoff for a brightness from 0.0 to 1.0, spread evenly in pitch.
+fn brightness_hz(brightness: f32) -> f32 {
+    SynthEngine::MIN_CUTOFF
+        * (SynthEngine::MAX_CUTOFF / SynthEngine::MIN_CUTOFF).powf(brightness.clamp(0.0, 1.0))
 }
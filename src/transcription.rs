@@ -0,0 +1,440 @@
+use crate::bevy_mic::microphone::{MicrophoneSamples, forward_samples};
+use crate::bevy_midi::prelude::*;
+use crate::stft::{AnalysisFormat, Stft, StftAnalysis, needs_rebuild, spectral_peaks};
+use bevy::prelude::*;
+use std::time::{Duration, Instant};
+
+/// Transcribes the notes of an acoustic piano from the microphone into [`MidiData`], so it
+/// can play the game like a midi keyboard.
+///
+/// The peaks of the spectrum of each window are folded into semitone bands, which are
+/// explained as a mix of harmonic note templates by non-negative matrix factorization. Each
+/// note is held while its share of the mix is loud enough.
+pub struct TranscriptionPlugin;
+
+impl Plugin for TranscriptionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TranscriptionSettings>()
+            .add_systems(Update, toggle_on_key)
+            .add_systems(
+                PreUpdate,
+                transcribe.after(forward_samples).before(MidiInputSystems),
+            );
+    }
+}
+
+/// Settings for [`TranscriptionPlugin`] and [`Transcriber`].
+#[derive(Resource, Clone, Debug)]
+pub struct TranscriptionSettings {
+    /// Off by default, since the speakers playing the synth would be transcribed too.
+    pub enabled: bool,
+    pub toggle_key: KeyCode,
+    /// The channel transcribed notes are sent on.
+    pub channel: u8,
+    /// Samples analysed at once. Longer windows separate low notes better but react slower.
+    pub window: usize,
+    /// Samples between the starts of consecutive windows.
+    pub hop: usize,
+    /// The activation, roughly the amplitude of the fundamental, that starts a note.
+    pub on_threshold: f32,
+    /// The activation a held note has to fall below to be released.
+    pub off_threshold: f32,
+    /// Notes also need this fraction of the loudest note's activation, so partials of a loud
+    /// note aren't taken for quiet notes of their own.
+    pub relative_threshold: f32,
+    pub lowest_note: u8,
+    pub highest_note: u8,
+}
+
+impl Default for TranscriptionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            toggle_key: KeyCode::F8,
+            channel: 0,
+            window: 4096,
+            hop: 512,
+            on_threshold: 0.02,
+            off_threshold: 0.01,
+            relative_threshold: 0.15,
+            lowest_note: 21,
+            highest_note: 108,
+        }
+    }
+}
+
+/// A note on or off found by a [`Transcriber`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TranscribedEvent {
+    /// Seconds since the first sample given to the transcriber.
+    pub time: f64,
+    pub message: MidiMessage,
+}
+
+/// Turns a stream of mono samples into note ons and offs.
+pub struct Transcriber {
+    settings: TranscriptionSettings,
+    sample_rate: u32,
+    stft: Stft,
+    /// The loudest peak in each semitone band, from the lowest note up.
+    spectrum: Vec<f32>,
+    /// One row of band weights per note.
+    templates: Vec<Vec<f32>>,
+    /// How much each pair of templates overlap, for the factorization.
+    overlap: Vec<Vec<f32>>,
+    fit: Vec<f32>,
+    activations: Vec<f32>,
+    previous: Vec<f32>,
+    held: Vec<bool>,
+    /// The quietest each held note has been since it started to fade, or `None` while it's
+    /// still getting louder.
+    troughs: Vec<Option<f32>>,
+}
+
+impl Transcriber {
+    /// Partials in each note template.
+    const HARMONICS: usize = 8;
+    /// How much quieter each partial of a template is than the one below.
+    const ROLLOFF: f32 = 0.8;
+    const ITERATIONS: usize = 30;
+    /// How much a fading note's activation has to jump for it to count as struck again.
+    const RETRIGGER: f32 = 2.0;
+    /// The range of activations mapped onto velocities 1 to 127.
+    const VELOCITY_DB: f32 = 48.0;
+
+    #[must_use]
+    pub fn new(sample_rate: u32, settings: &TranscriptionSettings) -> Self {
+        let settings = settings.clone();
+        let stft = Stft::new(settings.window, settings.hop);
+        let lowest = settings.lowest_note;
+        let notes = usize::from(settings.highest_note.saturating_sub(lowest)) + 1;
+        let top_harmonic = (12.0 * (Self::HARMONICS as f32).log2()).round() as usize;
+        let bands = (0..notes + top_harmonic)
+            .take_while(|&band| {
+                note_hz(f32::from(lowest) + band as f32 + 0.5) < sample_rate as f32 / 2.0
+            })
+            .count();
+        // Notes above Nyquist can't be heard at this rate
+        let notes = notes.min(bands);
+
+        let templates: Vec<Vec<f32>> = (0..notes)
+            .map(|note| {
+                let mut template = vec![0.0; bands];
+                for harmonic in 1..=Self::HARMONICS {
+                    let band = note + (12.0 * (harmonic as f32).log2()).round() as usize;
+                    if let Some(weight) = template.get_mut(band) {
+                        *weight += Self::ROLLOFF.powi(harmonic as i32 - 1);
+                    }
+                }
+                let norm = template.iter().map(|w| w * w).sum::<f32>().sqrt();
+                template
+                    .iter_mut()
+                    .for_each(|w| *w /= norm.max(f32::EPSILON));
+                template
+            })
+            .collect();
+        let overlap = templates
+            .iter()
+            .map(|a| {
+                templates
+                    .iter()
+                    .map(|b| a.iter().zip(b).map(|(a, b)| a * b).sum())
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            settings,
+            sample_rate,
+            stft,
+            spectrum: vec![0.0; bands],
+            templates,
+            overlap,
+            fit: vec![0.0; notes],
+            activations: vec![0.0; notes],
+            previous: vec![0.0; notes],
+            held: vec![false; notes],
+            troughs: vec![None; notes],
+        }
+    }
+
+    /// Seconds of audio transcribed so far.
+    #[must_use]
+    pub fn time(&self) -> f64 {
+        self.frame_time(self.stft.frames())
+    }
+
+    /// Transcribes `samples`, adding the notes that started or stopped to `events`.
+    pub fn push(&mut self, samples: &[f32], events: &mut Vec<TranscribedEvent>) {
+        self.stft.push(samples);
+        while self.stft.next_frame().is_some() {
+            self.fold_peaks();
+            self.factorize();
+            let time = self.frame_time(self.stft.frames() - 1);
+            self.track_notes(time, events);
+        }
+    }
+
+    /// Releases every held note, e.g. at the end of a recording.
+    pub fn finish(&mut self, events: &mut Vec<TranscribedEvent>) {
+        let time = self.time();
+        for note in 0..self.held.len() {
+            if self.held[note] {
+                self.held[note] = false;
+                events.push(self.event(time, note, None));
+            }
+        }
+        self.previous.fill(0.0);
+    }
+
+    /// Puts each peak of the last window in the band of its nearest note, where it's
+    /// counted however wide the band is.
+    fn fold_peaks(&mut self) {
+        self.spectrum.fill(0.0);
+        let floor = self.settings.off_threshold / 2.0;
+        let lowest = f32::from(self.settings.lowest_note);
+        for (bin, magnitude) in spectral_peaks(self.stft.magnitudes()) {
+            let hz = self.stft.bin_hz(bin, self.sample_rate);
+            if magnitude < floor || hz <= 0.0 {
+                continue;
+            }
+            let band = (69.0 + 12.0 * (hz / 440.0).log2() - lowest).round();
+            if band >= 0.0
+                && let Some(value) = self.spectrum.get_mut(band as usize)
+            {
+                *value = value.max(magnitude);
+            }
+        }
+    }
+
+    /// The time at the middle of window `frame`, where its notes are heard the most.
+    fn frame_time(&self, frame: u64) -> f64 {
+        let start = frame * self.stft.hop() as u64;
+        (start as f64 + self.stft.size() as f64 / 2.0) / f64::from(self.sample_rate)
+    }
+
+    /// Finds the activations that best rebuild the spectrum out of the note templates,
+    /// with multiplicative updates.
+    fn factorize(&mut self) {
+        let loudest = self.spectrum.iter().copied().fold(0.0, f32::max);
+        if loudest < self.settings.off_threshold {
+            self.activations.fill(0.0);
+            return;
+        }
+        for (fit, template) in self.fit.iter_mut().zip(&self.templates) {
+            *fit = template
+                .iter()
+                .zip(&self.spectrum)
+                .map(|(w, v)| w * v)
+                .sum();
+        }
+        self.activations.copy_from_slice(&self.fit);
+        for _ in 0..Self::ITERATIONS {
+            for note in 0..self.activations.len() {
+                let rebuilt: f32 = self.overlap[note]
+                    .iter()
+                    .zip(&self.activations)
+                    .map(|(o, a)| o * a)
+                    .sum();
+                self.activations[note] *= self.fit[note] / (rebuilt + 1e-9);
+            }
+        }
+    }
+
+    fn track_notes(&mut self, time: f64, events: &mut Vec<TranscribedEvent>) {
+        let loudest = self.activations.iter().copied().fold(0.0, f32::max);
+        let threshold = self
+            .settings
+            .on_threshold
+            .max(loudest * self.settings.relative_threshold);
+        for note in 0..self.activations.len() {
+            let activation = self.activations[note];
+            let previous = self.previous[note];
+            if self.held[note] {
+                // Notes swell over a few windows as they fill them, so only a fading note
+                // can be struck again
+                let trough = &mut self.troughs[note];
+                if activation < previous || trough.is_some() {
+                    *trough = Some(trough.unwrap_or(activation).min(activation));
+                }
+                let struck = trough.is_some_and(|trough| {
+                    activation > trough * Self::RETRIGGER
+                        && activation - trough > self.settings.on_threshold
+                });
+                if activation < self.settings.off_threshold || struck {
+                    self.held[note] = false;
+                    events.push(self.event(time, note, None));
+                }
+            }
+            // The partials of a note can add up to the template of one an octave or two
+            // below, but that one's fundamental is missing.
+            let sounding = self.spectrum[note] >= self.settings.off_threshold;
+            if !self.held[note] && activation >= threshold && activation > previous && sounding {
+                self.held[note] = true;
+                self.troughs[note] = None;
+                events.push(self.event(time, note, Some(velocity(activation))));
+            }
+            self.previous[note] = activation;
+        }
+    }
+
+    fn event(&self, time: f64, note: usize, velocity: Option<u8>) -> TranscribedEvent {
+        let channel = self.settings.channel;
+        let note = self.settings.lowest_note + note as u8;
+        let message = match velocity {
+            Some(velocity) => MidiMessage::note_on(channel, note, velocity),
+            None => MidiMessage::note_off(channel, note, 0),
+        };
+        TranscribedEvent { time, message }
+    }
+}
+
+fn note_hz(note: f32) -> f32 {
+    440.0 * 2f32.powf((note - 69.0) / 12.0)
+}
+
+fn velocity(activation: f32) -> u8 {
+    let db = 20.0 * activation.log10();
+    let velocity = (db + Transcriber::VELOCITY_DB) / Transcriber::VELOCITY_DB * 127.0;
+    velocity.round().clamp(1.0, 127.0) as u8
+}
+
+fn toggle_on_key(keys: Res<ButtonInput<KeyCode>>, mut settings: ResMut<TranscriptionSettings>) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.enabled = !settings.enabled;
+        info!(
+            "Piano transcription {}",
+            if settings.enabled { "on" } else { "off" }
+        );
+    }
+}
+
+impl StftAnalysis for Transcriber {
+    fn format(&self) -> AnalysisFormat {
+        AnalysisFormat::new(self.sample_rate, self.settings.window, self.settings.hop)
+    }
+}
+
+/// Sends the notes transcribed from the microphone as [`MidiData`], stamped as if the midi
+/// input had received them.
+fn transcribe(
+    settings: Res<TranscriptionSettings>,
+    timestamps: Res<MidiTimestamps>,
+    mut samples: EventReader<MicrophoneSamples>,
+    mut transcriber: Local<Option<(Transcriber, Instant)>>,
+    mut events: Local<Vec<TranscribedEvent>>,
+    mut midi: EventWriter<MidiData>,
+) {
+    events.clear();
+    // Every setting changes what's transcribed, so the held notes are released first
+    if (!settings.enabled || settings.is_changed())
+        && let Some((mut stopped, _)) = transcriber.take()
+    {
+        stopped.finish(&mut events);
+    }
+    if !settings.enabled {
+        samples.clear();
+    }
+    for chunk in samples.read() {
+        let format = AnalysisFormat::new(chunk.sample_rate, settings.window, settings.hop);
+        if needs_rebuild(transcriber.as_ref().map(|(t, _)| t), format) {
+            if let Some((previous, _)) = transcriber.as_mut() {
+                previous.finish(&mut events);
+            }
+            *transcriber = Some((
+                Transcriber::new(chunk.sample_rate, &settings),
+                Instant::now(),
+            ));
+        }
+        if let Some((transcriber, _)) = transcriber.as_mut() {
+            transcriber.push(&chunk.samples, &mut events);
+        }
+    }
+    if events.is_empty() {
+        return;
+    }
+
+    // The last window ends about now
+    let now = Instant::now();
+    let (end, started) = match transcriber.as_ref() {
+        Some((transcriber, started)) => (transcriber.time(), *started),
+        None => (events.iter().map(|e| e.time).fold(0.0, f64::max), now),
+    };
+    midi.write_batch(events.iter().map(|event| {
+        let at = now - Duration::from_secs_f64((end - event.time).max(0.0));
+        let stamp = timestamps
+            .stamp(at)
+            .unwrap_or_else(|| at.saturating_duration_since(started).as_micros() as u64);
+        MidiData {
+            stamp,
+            message: event.message,
+        }
+    }));
+}
+
+#[test]
+fn test_transcribes_a_chord() {
+    let sample_rate = 48_000;
+    let settings = TranscriptionSettings::default();
+    let mut transcriber = Transcriber::new(sample_rate, &settings);
+
+    // Half a second of C major with decaying partials, then a second of silence
+    let chord = [60, 64, 67];
+    let samples: Vec<f32> = (0..sample_rate as usize * 3 / 2)
+        .map(|i| {
+            let t = i as f32 / sample_rate as f32;
+            if t >= 0.5 {
+                return 0.0;
+            }
+            chord
+                .iter()
+                .flat_map(|&note| {
+                    (1..=6).map(move |harmonic| {
+                        let hz = note_hz(note as f32) * harmonic as f32;
+                        0.15 * 0.7f32.powi(harmonic - 1) * (std::f32::consts::TAU * hz * t).sin()
+                    })
+                })
+                .sum()
+        })
+        .collect();
+    let mut events = Vec::new();
+    for chunk in samples.chunks(1000) {
+        transcriber.push(chunk, &mut events);
+    }
+    transcriber.finish(&mut events);
+
+    let notes = |on: bool| {
+        let mut notes: Vec<u8> = events
+            .iter()
+            .filter(|e| e.message.is_note_on() == on)
+            .map(|e| e.message.msg[1])
+            .collect();
+        notes.sort();
+        notes
+    };
+    assert_eq!(notes(true), chord);
+    assert_eq!(notes(false), chord);
+
+    // At 8 kHz the top of the keyboard is past Nyquist, and A4 is still heard
+    let mut low = Transcriber::new(8_000, &settings);
+    let a4: Vec<f32> = (0..8_000)
+        .map(|i| 0.3 * (std::f32::consts::TAU * 440.0 * i as f32 / 8_000.0).sin())
+        .collect();
+    let mut heard = Vec::new();
+    low.push(&a4, &mut heard);
+    low.finish(&mut heard);
+    assert!(
+        heard
+            .iter()
+            .any(|e| e.message.is_note_on() && e.message.msg[1] == 69),
+        "{heard:?}"
+    );
+    for event in &events {
+        if event.message.is_note_on() {
+            assert!(event.time < 0.1, "{event:?}");
+            assert!(event.message.msg[2] > 64, "{event:?}");
+        } else {
+            assert!((0.5..0.6).contains(&event.time), "{event:?}");
+        }
+    }
+}
@@ -0,0 +1,466 @@
+use crate::chords::PITCH_CLASS_NAMES;
+use crate::pitch::{DetectedPitch, PitchDetectionSettings};
+use bevy::prelude::*;
+use std::fmt;
+use std::time::Duration;
+
+/// A chromatic tuner screen for any instrument, driven by the microphone pitch detector.
+///
+/// It names the nearest note, shows how many cents off it is on a needle, and runs a strobe
+/// that drifts one way when sharp, the other way when flat, and stands still in tune.
+pub struct TunerPlugin;
+
+impl Plugin for TunerPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TunerSettings>()
+            .init_resource::<TunerReading>()
+            .add_systems(Startup, spawn_tuner)
+            .add_systems(
+                Update,
+                (control_on_keys, TunerReading::update, update_tuner).chain(),
+            );
+    }
+}
+
+/// The key an instrument is written in, so the tuner names notes as its player reads them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transposition {
+    /// Sounds as written: piano, guitar, flute, violin...
+    #[default]
+    C,
+    /// Sounds a tone below written: clarinet, trumpet, tenor sax...
+    BFlat,
+    /// Sounds a major sixth below written: alto sax...
+    EFlat,
+    /// Sounds a fifth below written: french horn...
+    F,
+}
+
+impl Transposition {
+    pub const ALL: [Transposition; 4] = [
+        Transposition::C,
+        Transposition::BFlat,
+        Transposition::EFlat,
+        Transposition::F,
+    ];
+
+    /// Semitones from a sounding note up to the note written for it.
+    #[must_use]
+    pub fn semitones(&self) -> i8 {
+        match self {
+            Transposition::C => 0,
+            Transposition::BFlat => 2,
+            Transposition::EFlat => 9,
+            Transposition::F => 7,
+        }
+    }
+
+    /// The transposition after this one in [`Transposition::ALL`], going round.
+    #[must_use]
+    pub fn next(&self) -> Self {
+        let index = Self::ALL.iter().position(|t| t == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+impl fmt::Display for Transposition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Transposition::C => "C",
+            Transposition::BFlat => "Bb",
+            Transposition::EFlat => "Eb",
+            Transposition::F => "F",
+        })
+    }
+}
+
+/// Settings for [`TunerPlugin`]. The reference pitch is
+/// [`PitchDetectionSettings::reference_hz`], which the tuner's keys adjust.
+#[derive(Resource, Clone, Debug)]
+pub struct TunerSettings {
+    /// Whether the tuner is shown over the game.
+    pub visible: bool,
+    pub toggle_key: KeyCode,
+    pub transposition: Transposition,
+    /// Cycles through [`Transposition::ALL`].
+    pub transposition_key: KeyCode,
+    /// Raise and lower the reference pitch by `reference_step` Hz, while the tuner is shown.
+    pub reference_up_key: KeyCode,
+    pub reference_down_key: KeyCode,
+    pub reference_step: f32,
+    /// How much of each new reading is mixed into the shown cents, so the needle doesn't
+    /// jitter.
+    pub smoothing: f32,
+    /// Readings closer than this to the note count as in tune.
+    pub in_tune_cents: f32,
+    /// How long the last note stays shown once nothing is heard.
+    pub hold: Duration,
+    /// Stripe spacings the strobe moves each second for every cent off.
+    pub strobe_speed: f32,
+}
+
+impl Default for TunerSettings {
+    fn default() -> Self {
+        Self {
+            visible: false,
+            toggle_key: KeyCode::F3,
+            transposition: Transposition::C,
+            transposition_key: KeyCode::F2,
+            reference_up_key: KeyCode::ArrowUp,
+            reference_down_key: KeyCode::ArrowDown,
+            reference_step: 1.0,
+            smoothing: 0.3,
+            in_tune_cents: 5.0,
+            hold: Duration::from_secs(1),
+            strobe_speed: 0.05,
+        }
+    }
+}
+
+impl TunerSettings {
+    /// The lowest and highest reference pitches the keys reach.
+    pub const REFERENCE_RANGE: (f32, f32) = (400.0, 480.0);
+}
+
+/// [`Resource`](bevy::ecs::system::Resource) holding what the tuner shows.
+#[derive(Resource, Clone, Debug, Default, PartialEq)]
+pub struct TunerReading {
+    /// The midi note nearest the pitch heard, as written for the
+    /// [`TunerSettings::transposition`]. `None` once nothing has been heard for
+    /// [`TunerSettings::hold`].
+    pub note: Option<u8>,
+    /// The sounding pitch.
+    pub hz: f32,
+    /// How far the pitch is from `note`, from -50 to 50, smoothed.
+    pub cents: f32,
+    /// How far the strobe has drifted, in stripe spacings from 0.0 to 1.0.
+    pub strobe: f32,
+    /// Time since a pitch was last heard.
+    pub silence: Duration,
+}
+
+impl TunerReading {
+    /// Takes in the `pitches` heard over the last `elapsed`.
+    pub fn hear<'a>(
+        &mut self,
+        pitches: impl IntoIterator<Item = &'a DetectedPitch>,
+        elapsed: Duration,
+        settings: &TunerSettings,
+    ) {
+        self.silence += elapsed;
+        for pitch in pitches {
+            let written =
+                i16::from(pitch.midi_note) + i16::from(settings.transposition.semitones());
+            let note = written.clamp(0, 127) as u8;
+            if self.note == Some(note) {
+                self.cents += (pitch.cents_off - self.cents) * settings.smoothing;
+            } else {
+                self.note = Some(note);
+                self.cents = pitch.cents_off;
+            }
+            self.hz = pitch.hz;
+            self.silence = Duration::ZERO;
+        }
+        if self.silence >= settings.hold {
+            self.note = None;
+        }
+        if self.note.is_some() {
+            let drift = self.cents * settings.strobe_speed * elapsed.as_secs_f32();
+            self.strobe = (self.strobe + drift).rem_euclid(1.0);
+        }
+    }
+
+    /// Whether a note is shown, and close enough to count as in tune.
+    #[must_use]
+    pub fn in_tune(&self, settings: &TunerSettings) -> bool {
+        self.note.is_some() && self.cents.abs() <= settings.in_tune_cents
+    }
+
+    fn update(
+        time: Res<Time>,
+        settings: Res<TunerSettings>,
+        mut pitches: EventReader<DetectedPitch>,
+        mut reading: ResMut<TunerReading>,
+    ) {
+        if !settings.visible {
+            pitches.clear();
+            return;
+        }
+        reading.hear(pitches.read(), time.delta(), &settings);
+    }
+}
+
+/// The name and octave of midi `note`, like C4 for middle C.
+#[must_use]
+pub fn note_name(note: u8) -> String {
+    let octave = i32::from(note / 12) - 1;
+    format!("{}{}", PITCH_CLASS_NAMES[usize::from(note % 12)], octave)
+}
+
+fn control_on_keys(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut settings: ResMut<TunerSettings>,
+    mut pitch_settings: ResMut<PitchDetectionSettings>,
+) {
+    if keys.just_pressed(settings.toggle_key) {
+        settings.visible = !settings.visible;
+    }
+    if !settings.visible {
+        return;
+    }
+    if keys.just_pressed(settings.transposition_key) {
+        settings.transposition = settings.transposition.next();
+    }
+    let (lowest, highest) = TunerSettings::REFERENCE_RANGE;
+    let mut reference = pitch_settings.reference_hz;
+    if keys.just_pressed(settings.reference_up_key) {
+        reference += settings.reference_step;
+    }
+    if keys.just_pressed(settings.reference_down_key) {
+        reference -= settings.reference_step;
+    }
+    if reference != pitch_settings.reference_hz {
+        pitch_settings.reference_hz = reference.clamp(lowest, highest);
+    }
+}
+
+#[derive(Component)]
+struct Tuner;
+
+#[derive(Component)]
+enum TunerText {
+    Note,
+    Details,
+}
+
+#[derive(Component)]
+enum TunerGauge {
+    Needle,
+    /// One of the strobe's stripes, by its place in the row.
+    Stripe(usize),
+}
+
+/// The width of the needle's scale and the strobe.
+const WIDTH: f32 = 480.0;
+const STRIPES: usize = 12;
+
+fn spawn_tuner(mut commands: Commands) {
+    let spacing = WIDTH / STRIPES as f32;
+    commands
+        .spawn((
+            Tuner,
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Percent(20.0),
+                left: Val::Percent(50.0),
+                margin: UiRect::left(Val::Px(-WIDTH / 2.0 - 16.0)),
+                padding: UiRect::all(Val::Px(16.0)),
+                flex_direction: FlexDirection::Column,
+                align_items: AlignItems::Center,
+                row_gap: Val::Px(12.0),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0.05, 0.05, 0.08, 0.9)),
+            Visibility::Hidden,
+        ))
+        .with_children(|tuner| {
+            tuner.spawn((
+                TunerText::Note,
+                Text::new("-"),
+                TextFont {
+                    font_size: 96.0,
+                    ..default()
+                },
+                TextColor(Color::srgb(0.9, 0.9, 0.9)),
+            ));
+            tuner.spawn((
+                TunerText::Details,
+                Text::new(""),
+                TextFont {
+                    font_size: 20.0,
+                    ..default()
+                },
+                TextColor(Color::srgb(0.7, 0.7, 0.7)),
+            ));
+            // The needle's scale, from 50 cents flat to 50 cents sharp
+            tuner
+                .spawn((
+                    Node {
+                        width: Val::Px(WIDTH),
+                        height: Val::Px(40.0),
+                        ..default()
+                    },
+                    BackgroundColor(Color::srgb(0.15, 0.15, 0.18)),
+                ))
+                .with_children(|scale| {
+                    scale.spawn((
+                        Node {
+                            position_type: PositionType::Absolute,
+                            left: Val::Percent(50.0),
+                            width: Val::Px(2.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        BackgroundColor(Color::srgb(0.5, 0.5, 0.5)),
+                    ));
+                    scale.spawn((
+                        TunerGauge::Needle,
+                        Node {
+                            position_type: PositionType::Absolute,
+                            left: Val::Percent(50.0),
+                            margin: UiRect::left(Val::Px(-2.0)),
+                            width: Val::Px(4.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        BackgroundColor(Color::NONE),
+                    ));
+                });
+            tuner
+                .spawn((
+                    Node {
+                        width: Val::Px(WIDTH),
+                        height: Val::Px(24.0),
+                        overflow: Overflow::clip(),
+                        ..default()
+                    },
+                    BackgroundColor(Color::srgb(0.15, 0.15, 0.18)),
+                ))
+                .with_children(|strobe| {
+                    // One stripe more than fits, so there's no gap as they drift
+                    for stripe in 0..=STRIPES {
+                        strobe.spawn((
+                            TunerGauge::Stripe(stripe),
+                            Node {
+                                position_type: PositionType::Absolute,
+                                width: Val::Px(spacing / 2.0),
+                                height: Val::Percent(100.0),
+                                ..default()
+                            },
+                            BackgroundColor(Color::NONE),
+                        ));
+                    }
+                });
+        });
+}
+
+fn update_tuner(
+    settings: Res<TunerSettings>,
+    pitch_settings: Res<PitchDetectionSettings>,
+    reading: Res<TunerReading>,
+    mut tuner: Query<&mut Visibility, With<Tuner>>,
+    mut texts: Query<(&TunerText, &mut Text, &mut TextColor)>,
+    mut gauges: Query<(&TunerGauge, &mut Node, &mut BackgroundColor)>,
+) {
+    for mut visibility in &mut tuner {
+        visibility.set_if_neq(if settings.visible {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        });
+    }
+    let changed = settings.is_changed() || pitch_settings.is_changed() || reading.is_changed();
+    if !settings.visible || !changed {
+        return;
+    }
+
+    let color = if reading.in_tune(&settings) {
+        Color::srgb(0.3, 0.9, 0.4)
+    } else if reading.note.is_some() {
+        Color::srgb(1.0, 0.6, 0.2)
+    } else {
+        Color::NONE
+    };
+    let reference = format!(
+        "A4 = {:.0} Hz   {} instrument",
+        pitch_settings.reference_hz, settings.transposition
+    );
+    for (part, mut text, mut text_color) in &mut texts {
+        match part {
+            TunerText::Note => {
+                text.0 = reading.note.map_or_else(|| "-".to_string(), note_name);
+                text_color.0 = if reading.note.is_some() {
+                    color
+                } else {
+                    Color::srgb(0.5, 0.5, 0.5)
+                };
+            }
+            TunerText::Details if reading.note.is_some() => {
+                text.0 = format!(
+                    "{:.1} Hz   {:+.0} cents   {reference}",
+                    reading.hz, reading.cents
+                );
+            }
+            TunerText::Details => text.0.clone_from(&reference),
+        }
+    }
+    let spacing = WIDTH / STRIPES as f32;
+    for (part, mut node, mut background) in &mut gauges {
+        node.left = match part {
+            TunerGauge::Needle => Val::Percent(50.0 + reading.cents.clamp(-50.0, 50.0)),
+            // Stripes drifting off one end come back in at the other
+            TunerGauge::Stripe(stripe) => {
+                let x = (*stripe as f32 + reading.strobe) * spacing;
+                Val::Px(x.rem_euclid(WIDTH + spacing) - spacing)
+            }
+        };
+        background.0 = color;
+    }
+}
+
+#[test]
+fn test_tuner_reading_follows_pitches() {
+    let settings = TunerSettings::default();
+    let frame = Duration::from_millis(20);
+    let mut reading = TunerReading::default();
+    assert_eq!(note_name(60), "C4");
+    assert_eq!(note_name(70), "A#4");
+    assert_eq!(note_name(21), "A0");
+
+    // 445 Hz is about 20 cents sharp of A4
+    let sharp = DetectedPitch::new(445.0, 1.0, 440.0);
+    reading.hear([&sharp], frame, &settings);
+    assert_eq!(reading.note, Some(69));
+    assert!((reading.cents - 19.6).abs() < 0.1);
+    assert!(!reading.in_tune(&settings));
+    // The strobe drifts one way when sharp...
+    let before = reading.strobe;
+    reading.hear([&sharp], frame, &settings);
+    assert!(reading.strobe > before);
+
+    // ...and settles as the needle moves towards a new reading
+    let true_a = DetectedPitch::new(440.0, 1.0, 440.0);
+    reading.hear([&true_a], frame, &settings);
+    assert!(reading.cents > 1.0 && reading.cents < 19.0);
+    for _ in 0..30 {
+        reading.hear([&true_a], frame, &settings);
+    }
+    assert!(reading.in_tune(&settings));
+
+    // Against A4 = 445 Hz, 445 Hz is in tune
+    let reading_445 = DetectedPitch::new(445.0, 1.0, 445.0);
+    assert_eq!(reading_445.midi_note, 69);
+    assert!(reading_445.cents_off.abs() < 0.01);
+
+    // A concert Bb is a written C on a Bb instrument, and a written G on an Eb one
+    for (transposition, written) in [
+        (Transposition::BFlat, 72),
+        (Transposition::EFlat, 79),
+        (Transposition::F, 77),
+    ] {
+        let settings = TunerSettings {
+            transposition,
+            ..default()
+        };
+        let mut reading = TunerReading::default();
+        reading.hear([&DetectedPitch::new(466.16, 1.0, 440.0)], frame, &settings);
+        assert_eq!(reading.note, Some(written), "{transposition}");
+    }
+    assert_eq!(Transposition::F.next(), Transposition::C);
+
+    // The note stays shown through short gaps only
+    reading.hear([], settings.hold / 2, &settings);
+    assert_eq!(reading.note, Some(69));
+    reading.hear([], settings.hold, &settings);
+    assert_eq!(reading.note, None);
+}